@@ -13,7 +13,7 @@ use std::io::Read;
 use std::path::Path;
 use std::str::FromStr;
 
-use api::service::Disk;
+use api::service::{Disk, Led, ResultType};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use simplelog::{CombinedLogger, Config, TermLogger, WriteLogger};
 use zmq::Socket;
@@ -21,8 +21,14 @@ use zmq::Socket;
     CLI client to call functions over RPC
 */
 
-fn add_disk(s: &mut Socket, path: &Path, id: Option<u64>, simulate: bool) -> Result<(), String> {
-    helpers::add_disk_request(s, path, id, simulate)?;
+fn add_disk(
+    s: &mut Socket,
+    path: &Path,
+    id: Option<u64>,
+    replica_set: Option<Vec<String>>,
+    simulate: bool,
+) -> Result<(), String> {
+    helpers::add_disk_request(s, path, id, replica_set, simulate)?;
     Ok(())
 }
 
@@ -34,7 +40,19 @@ fn list_disks(s: &mut Socket) -> Result<Vec<Disk>, String> {
 }
 
 fn remove_disk(s: &mut Socket, path: &Path, id: Option<u64>, simulate: bool) -> Result<(), String> {
-    helpers::remove_disk_request(s, path, id, simulate)?;
+    let result = helpers::remove_disk_request(s, path, id, simulate)?;
+    for step in result.get_cleanup_steps() {
+        match step.get_result() {
+            ResultType::OK => println!("{}: ok", step.get_name()),
+            ResultType::ERR => println!("{}: {}", step.get_name(), step.get_error_msg()),
+        };
+    }
+    if result.has_osd_id() {
+        println!("osd {} was kept for the replacement", result.get_osd_id());
+    }
+    if result.has_wipe_certificate() {
+        println!("Wipe certificate: {}", result.get_wipe_certificate());
+    }
     Ok(())
 }
 
@@ -45,11 +63,14 @@ fn handle_add_disk(s: &mut Socket, matches: &ArgMatches) {
         Some(i) => Some(u64::from_str(&i).unwrap()),
         None => None,
     };
+    let replica_set = matches
+        .values_of("replica_set")
+        .map(|v| v.map(|b| b.to_string()).collect());
     let simulate = match matches.value_of("simulate") {
         Some(s) => bool::from_str(&s).unwrap(),
         None => false,
     };
-    match add_disk(s, &p, id, simulate) {
+    match add_disk(s, &p, id, replica_set, simulate) {
         Ok(_) => {
             println!("Adding disk successful");
         }
@@ -92,6 +113,24 @@ fn handle_remove_disk(s: &mut Socket, matches: &ArgMatches) {
     }
 }
 
+fn handle_led(s: &mut Socket, matches: &ArgMatches) {
+    let p = Path::new(matches.value_of("path").unwrap());
+    let led = match matches.value_of("led").unwrap() {
+        "fault" => Led::FAULT,
+        _ => Led::LOCATE,
+    };
+    let on = !matches.is_present("off");
+    info!("Setting {:?} led of {} to {}", led, p.display(), on);
+    match helpers::set_led_request(s, &p, led, on) {
+        Ok(_) => {
+            println!("Setting led successful");
+        }
+        Err(e) => {
+            println!("Setting led failed: {}", e);
+        }
+    }
+}
+
 fn get_cli_args<'a>() -> ArgMatches<'a> {
     App::new("Ceph Disk Manager Client")
         .version(crate_version!())
@@ -141,6 +180,17 @@ fn get_cli_args<'a>() -> ArgMatches<'a> {
                             Err(_) => Err("id must be a valid u64".to_string()),
                         }),
                 )
+                .arg(
+                    Arg::with_name("replica_set")
+                        .help(
+                            "The gluster replica set the new brick joins. Ex: \
+                             server1:/bricks/sdb/data",
+                        )
+                        .long("replica-set")
+                        .multiple(true)
+                        .required(false)
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("simulate")
                         .default_value("false")
@@ -151,6 +201,31 @@ fn get_cli_args<'a>() -> ArgMatches<'a> {
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("led")
+                .about("Turn the led of a disk's enclosure slot on or off")
+                .arg(
+                    Arg::with_name("path")
+                        .help("The disk whose slot to light up: Ex: /dev/sda")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("led")
+                        .default_value("locate")
+                        .help("Which led to set")
+                        .long("led")
+                        .possible_values(&["locate", "fault"])
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("off")
+                        .help("Turn the led off instead of on")
+                        .long("off")
+                        .required(false),
+                ),
+        )
         .subcommand(SubCommand::with_name("list").about("List all disks on a server"))
         .subcommand(
             SubCommand::with_name("remove")
@@ -226,6 +301,9 @@ fn main() {
     if let Some(ref matches) = matches.subcommand_matches("add") {
         handle_add_disk(&mut s, matches);
     }
+    if let Some(ref matches) = matches.subcommand_matches("led") {
+        handle_led(&mut s, matches);
+    }
     if matches.subcommand_matches("list").is_some() {
         handle_list_disks(&mut s);
     }
@@ -1,36 +1,327 @@
 extern crate block_utils;
 extern crate ceph;
-extern crate ceph_safe_disk;
 extern crate dirs;
 extern crate fstab;
+extern crate hashicorp_vault;
 extern crate helpers;
 extern crate init_daemon;
 extern crate libc;
 extern crate serde_json;
-extern crate tempdir;
+extern crate time;
 extern crate uuid;
 
-use std::fs::{create_dir, File};
+use std::collections::HashMap;
+use std::fs::{
+    create_dir, create_dir_all, read_dir, remove_dir_all, remove_file, File, OpenOptions,
+};
 use std::io::Result as IOResult;
 use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::symlink;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::str::FromStr;
 
-use backend::Backend;
+use backend::transaction::{Steps, TransactionLog};
+use backend::{Backend, BlockingPg, CleanupStep, RemovalCheck, RemovedDisk};
 
 use self::ceph::ceph::{connect_to_ceph, Rados};
 use self::ceph::cmd::*;
-use self::ceph_safe_disk::diag::{DiagMap, Format, Status};
 use self::dirs::home_dir;
 use self::fstab::FsTab;
+use self::hashicorp_vault::client::{TokenData, VaultClient};
 use self::helpers::host_information::Host;
 use self::init_daemon::{detect_daemon, Daemon};
-use self::tempdir::TempDir;
+use self::serde_json::Value;
+
+#[cfg(test)]
+mod tests {
+    use super::super::BlockingPg;
+    use std::path::Path;
+
+    static POOLS: &'static str = r#"[
+        {"pool": 1, "pool_name": "rbd", "size": 3, "min_size": 2},
+        {"pool": 2, "pool_name": "scratch", "size": 2, "min_size": 1}
+    ]"#;
+
+    #[test]
+    fn test_parse_pools() {
+        let pools = super::parse_pools(POOLS).unwrap();
+        assert_eq!(pools.get(&1), Some(&(3, 2)));
+        assert_eq!(pools.get(&2), Some(&(2, 1)));
+    }
+
+    #[test]
+    fn test_blocking_pgs() {
+        // Luminous returns a bare array, later releases wrap it in pg_stats
+        let pgs = r#"{"pg_ready": true, "pg_stats": [
+            {"pgid": "1.1f", "state": "active+clean", "up": [3, 4, 5], "acting": [3, 4, 5]},
+            {"pgid": "1.2a", "state": "active+undersized+degraded", "up": [3, 4],
+             "acting": [3, 4]},
+            {"pgid": "2.7", "state": "active+clean", "up": [3, 6], "acting": [3, 6]},
+            {"pgid": "2.9", "state": "peering", "up": [3, 6], "acting": [3, 6]}
+        ]}"#;
+        let pgs = super::parse_pgs(pgs).unwrap();
+        assert_eq!(pgs.len(), 4);
+        let pools = super::parse_pools(POOLS).unwrap();
+        let blocking = super::blocking_pgs(3, &pgs, &pools);
+        assert_eq!(
+            blocking,
+            vec![
+                BlockingPg {
+                    pgid: "1.2a".to_string(),
+                    state: "active+undersized+degraded".to_string(),
+                    reason: "Would be left with 1 of the 2 copies pool 1 needs".to_string(),
+                },
+                BlockingPg {
+                    pgid: "2.9".to_string(),
+                    state: "peering".to_string(),
+                    reason: "Not active".to_string(),
+                },
+            ]
+        );
+        let bare = r#"[{"pgid": "1.0", "state": "active+clean", "up": [1, 2147483647, 3],
+            "acting": [1, 2147483647, 3]}]"#;
+        let pgs = super::parse_pgs(bare).unwrap();
+        assert_eq!(super::blocking_pgs(3, &pgs, &pools).len(), 1);
+    }
+
+    #[test]
+    fn test_partition_path() {
+        assert_eq!(super::partition_path("/dev/sdb", 3), "/dev/sdb3");
+        assert_eq!(super::partition_path("/dev/nvme0n1", 2), "/dev/nvme0n1p2");
+    }
+
+    #[test]
+    fn test_osd_store_config() {
+        let config: super::CephConfig = super::serde_json::from_str(
+            r#"{"config_file": "/etc/ceph/ceph.conf", "user_id": "admin",
+                "osd_store": "bluestore",
+                "bluestore_db": {"device": "/dev/nvme0n1", "size_gb": 60}}"#,
+        ).unwrap();
+        assert_eq!(config.osd_store, Some(super::ObjectStore::Bluestore));
+        assert_eq!(config.bluestore_db.unwrap().size_gb, 60);
+        assert!(config.bluestore_wal.is_none());
+    }
+
+    #[test]
+    fn test_is_mounted() {
+        let mounts = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                      /dev/sdc1 /var/lib/ceph/osd/ceph-3 xfs rw,noatime 0 0\n\
+                      tmpfs /var/lib/ceph/osd/ceph-12 tmpfs rw 0 0\n";
+        assert!(super::is_mounted(
+            mounts,
+            Path::new("/var/lib/ceph/osd/ceph-3")
+        ));
+        assert!(super::is_mounted(
+            mounts,
+            Path::new("/var/lib/ceph/osd/ceph-12")
+        ));
+        assert!(!super::is_mounted(
+            mounts,
+            Path::new("/var/lib/ceph/osd/ceph-1")
+        ));
+    }
+
+    #[test]
+    fn test_tib_weight() {
+        // A 4TB disk
+        assert!((super::tib_weight(4_000_787_030_016) - 3.6387).abs() < 0.0001);
+        assert_eq!(super::tib_weight(1_099_511_627_776), 1.0);
+    }
+
+    #[test]
+    fn test_crush_location() {
+        use super::CrushLocation;
+
+        let config = CrushLocation {
+            root: None,
+            datacenter: None,
+            row: None,
+            rack: Some("r12".to_string()),
+        };
+        let inventory: super::HashMap<String, CrushLocation> = super::serde_json::from_str(
+            r#"{"ceph1": {"root": "ssd", "datacenter": "dc2", "rack": "r9"}}"#,
+        ).unwrap();
+        let location = super::merge_location(config.clone(), inventory["ceph1"].clone());
+        assert_eq!(
+            super::crush_location_args(&location, "ceph1"),
+            vec!["root=ssd", "datacenter=dc2", "rack=r12", "host=ceph1"]
+        );
+        assert_eq!(
+            super::crush_location_args(&CrushLocation::default(), "ceph1"),
+            vec!["root=default", "host=ceph1"]
+        );
+        assert_eq!(
+            super::device_class(&super::block_utils::MediaType::NVME),
+            Some("nvme")
+        );
+    }
+
+    #[test]
+    fn test_next_weight() {
+        assert_eq!(super::next_weight(0.0, 1.0, 0.25), 0.25);
+        assert_eq!(super::next_weight(0.9, 1.0, 0.25), 1.0);
+        assert_eq!(super::next_weight(1.0, 0.0, 0.25), 0.75);
+        assert_eq!(super::next_weight(0.1, 0.0, 0.25), 0.0);
+        assert_eq!(super::next_weight(0.5, 0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_parse_crush_weight() {
+        let tree = r#"{"nodes": [
+            {"id": -1, "name": "default", "type": "root", "children": [-2]},
+            {"id": -2, "name": "ceph1", "type": "host", "children": [3]},
+            {"id": 3, "name": "osd.3", "type": "osd", "crush_weight": 3.63869,
+             "status": "up", "reweight": 1.0}
+        ], "stray": []}"#;
+        assert_eq!(super::parse_crush_weight(tree, 3), Some(3.63869));
+        assert_eq!(super::parse_crush_weight(tree, 4), None);
+    }
+
+    #[test]
+    fn test_parse_unclean_pgs() {
+        let luminous = r#"{"num_pg_by_state": [
+            {"name": "active+clean", "num": 1000},
+            {"name": "active+remapped+backfilling", "num": 24}
+        ], "num_pgs": 1024}"#;
+        assert_eq!(super::parse_unclean_pgs(luminous).unwrap(), 24);
+        let nautilus = r#"{"pg_ready": true, "pg_summary": {"num_pg_by_state": [
+            {"name": "active+clean", "num": 512}], "num_pgs": 512}}"#;
+        assert_eq!(super::parse_unclean_pgs(nautilus).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_is_osd_destroyed() {
+        let dump = r#"{"epoch": 412, "osds": [
+            {"osd": 0, "uuid": "9e1a7b5d-1e4c-4a3c-8f55-2b1c5e0f6a11", "up": 1, "in": 1,
+             "state": ["exists", "up"]},
+            {"osd": 3, "uuid": "00000000-0000-0000-0000-000000000000", "up": 0, "in": 0,
+             "state": ["autoout", "exists", "destroyed"]}
+        ]}"#;
+        assert!(!super::is_osd_destroyed(dump, 0).unwrap());
+        assert!(super::is_osd_destroyed(dump, 3).unwrap());
+        assert!(!super::is_osd_destroyed(dump, 7).unwrap());
+    }
+
+    #[test]
+    fn test_parse_osd_metadata() {
+        let metadata = r#"[
+            {"id": 0, "hostname": "ceph1", "backend_filestore_dev_node": "sdb",
+             "osd_data": "/var/lib/ceph/osd/ceph-0"},
+            {"id": 3, "hostname": "ceph2", "backend_filestore_dev_node": "sdb"},
+            {"id": 4, "hostname": "ceph2", "bluestore_bdev_dev_node": "/dev/sdc",
+             "devices": "sdc"}
+        ]"#;
+        assert_eq!(super::parse_osd_metadata(metadata, "ceph2", "sdb"), Some(3));
+        assert_eq!(super::parse_osd_metadata(metadata, "ceph2", "sdc"), Some(4));
+        assert_eq!(super::parse_osd_metadata(metadata, "ceph1", "sdc"), None);
+    }
+
+    #[test]
+    fn test_parse_luks_devices() {
+        let output = "DEVNAME=/dev/sdc\nUUID=4f9c2a1e-6b1d-4c55-9a0e-3f7d2c1b0a99\n\
+                      TYPE=crypto_LUKS\n\nDEVNAME=/dev/sdd\nTYPE=crypto_LUKS\n\n\
+                      DEVNAME=/dev/sde\nUUID=0b1c2d3e-0000-4c55-9a0e-3f7d2c1b0a99\n\
+                      TYPE=crypto_LUKS\n";
+        assert_eq!(
+            super::parse_luks_devices(output),
+            vec![
+                (
+                    "/dev/sdc".to_string(),
+                    "4f9c2a1e-6b1d-4c55-9a0e-3f7d2c1b0a99".to_string(),
+                ),
+                (
+                    "/dev/sde".to_string(),
+                    "0b1c2d3e-0000-4c55-9a0e-3f7d2c1b0a99".to_string(),
+                ),
+            ]
+        );
+        assert!(super::parse_luks_devices("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_lv_tags() {
+        let tags = super::parse_lv_tags(
+            "  ceph.osd_id=3,ceph.type=block,ceph.block_device=/dev/ceph-4b7e/osd-block-9e1a\n",
+        );
+        assert_eq!(tags.get("ceph.osd_id"), Some(&"3".to_string()));
+        assert_eq!(
+            tags.get("ceph.block_device"),
+            Some(&"/dev/ceph-4b7e/osd-block-9e1a".to_string())
+        );
+        assert_eq!(tags.get("ceph.db_device"), None);
+        assert!(super::parse_lv_tags("\n").is_empty());
+    }
+
+    #[test]
+    fn test_transaction_records() {
+        // What a bluestore add cut short after its osd was created leaves
+        let add: super::AddRecord = super::serde_json::from_str(
+            r#"{"store": "bluestore", "requested_id": 3,
+            "osd_fsid": "9e1a7b5d-1e4c-4a3c-8f55-2b1c5e0f6a11", "osd_id": 3,
+            "reused": true, "journal": "/dev/disk/by-partuuid/6d2f0c1e",
+            "journal_device": {"device": "/dev/nvme0n1", "size_gb": 60}, "wal": null,
+            "vg_name": "ceph-4b7e", "done": ["journal", "lvm", "create"]}"#,
+        ).unwrap();
+        assert_eq!(
+            add.done,
+            vec![
+                super::AddStep::Journal,
+                super::AddStep::Lvm,
+                super::AddStep::Create,
+            ]
+        );
+        assert_eq!(add.store, super::ObjectStore::Bluestore);
+        assert!(add.reused);
+        assert!(!add.rolling_back);
+
+        let remove: super::RemoveRecord =
+            super::serde_json::from_str(r#"{"osd_id": 3, "done": ["noout", "stop"]}"#).unwrap();
+        assert!(!remove.done.iter().any(|s| s.irreversible()));
+        assert!(super::RemoveStep::Destroy.irreversible());
+        assert!(super::RemoveStep::CrushRemove.irreversible());
+    }
+
+    #[test]
+    fn test_luks_uuid() {
+        let mapping = super::mapping_name("6d2f0c1e-4b7e-4a3c-8f55-2b1c5e0f6a11");
+        assert_eq!(mapping, "crypt-6d2f0c1e-4b7e-4a3c-8f55-2b1c5e0f6a11");
+        assert_eq!(
+            super::luks_uuid(&mapping),
+            Some("6d2f0c1e-4b7e-4a3c-8f55-2b1c5e0f6a11")
+        );
+        // Logical volumes and mappings bynar didn't open are left alone
+        assert_eq!(super::luks_uuid("ceph--4b7e-osd--block--9e1a"), None);
+        assert_eq!(super::luks_uuid("crypt-"), None);
+    }
+}
 
 /// Ceph cluster
 pub struct CephBackend {
     cluster_handle: Rados,
+    config: CephConfig,
+    /// Where ceph.json and disk-manager.json were found
+    config_dir: PathBuf,
+}
+
+/// How new osds store their data
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ObjectStore {
+    /// xfs with a journal.  Jewel or earlier
+    Filestore,
+    /// Raw block device on lvm.  Luminous or later
+    Bluestore,
+}
+
+/// A partition carved out of a device shared by several osds
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct SharedDevice {
+    /// The ssd or nvme device.  Ex: /dev/nvme0n1
+    device: String,
+    /// How big each osd's partition is
+    size_gb: u64,
 }
 
 #[derive(Deserialize, Debug)]
@@ -39,6 +330,212 @@ struct CephConfig {
     config_file: String,
     /// The cephx user to connect to the Ceph service with
     user_id: String,
+    /// filestore or bluestore.  Default: filestore
+    osd_store: Option<ObjectStore>,
+    /// A filestore journal partition for each osd.  Without it the journal
+    /// is collocated on the osd's disk
+    journal: Option<SharedDevice>,
+    /// A bluestore rocksdb partition for each osd
+    bluestore_db: Option<SharedDevice>,
+    /// A bluestore write ahead log partition for each osd
+    bluestore_wal: Option<SharedDevice>,
+    /// Destroy removed osds instead of purging them so the replacement can
+    /// take over the id and crush position.  Default: true
+    keep_osd_ids: Option<bool>,
+    /// How data movement is handled around a disk swap
+    swap: Option<SwapConfig>,
+    /// Give every new osd this crush weight instead of its size in TiB
+    crush_weight: Option<f64>,
+    /// The crush device class of new osds.  Default: hdd, ssd or nvme
+    /// depending on the disk
+    device_class: Option<String>,
+    /// Where this host's osds go in the crush map
+    crush_location: Option<CrushLocation>,
+    /// A json file of crush locations keyed by hostname.  This host's entry
+    /// fills in anything crush_location leaves out
+    inventory_file: Option<String>,
+    /// Where unfinished adds and removes are recorded so they can be rolled
+    /// back or carried on.  Default: /var/lib/bynar/ceph_transactions.json
+    transaction_file: Option<String>,
+    /// Put new osds on a dm-crypt mapping with its key kept in Vault
+    encryption: Option<EncryptionConfig>,
+}
+
+/// How new osds are encrypted.  Vault is reached with the vault_endpoint and
+/// vault_token in disk-manager.json
+#[derive(Clone, Debug, Default, Deserialize)]
+struct EncryptionConfig {
+    /// Default: aes-xts-plain64
+    cipher: Option<String>,
+    /// In bits.  Default: 512
+    key_size: Option<u32>,
+    /// Keys are saved in Vault under <vault_path>/<hostname>/<luks uuid>.
+    /// Default: /bynar/luks
+    vault_path: Option<String>,
+}
+
+/// The Vault settings from disk-manager.json
+#[derive(Debug, Deserialize)]
+struct VaultConfig {
+    vault_token: Option<String>,
+    vault_endpoint: Option<String>,
+}
+
+/// The crush buckets above the host an osd is placed under
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+struct CrushLocation {
+    root: Option<String>,
+    datacenter: Option<String>,
+    row: Option<String>,
+    rack: Option<String>,
+}
+
+/// What noout is set on while a disk is swapped
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum NooutScope {
+    Osd,
+    Host,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct SwapConfig {
+    /// Set noout on the osd or its whole host instead of marking the osd out.
+    /// The data stays where it is until the replacement takes over the id.
+    /// Only used when keep_osd_ids is on
+    noout: Option<NooutScope>,
+    /// Move the data off an osd by stepping its crush weight down to 0
+    /// before it's removed
+    drain: Option<bool>,
+    /// Step a new osd's crush weight up instead of adding it at full weight
+    ramp_up: Option<bool>,
+    /// The fraction of the full weight each step moves.  Default: 0.2
+    weight_step: Option<f64>,
+    /// Minutes to wait between steps.  Default: 5
+    step_minutes: Option<i64>,
+    /// Pgs that may be something other than active+clean when the next
+    /// step is taken.  Default: 0
+    max_unclean_pgs: Option<u64>,
+    /// Where reweights in progress are kept so they survive restarts.
+    /// Default: /var/lib/bynar/ceph_reweights.json
+    state_file: Option<String>,
+}
+
+/// A crush weight change made in steps
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct Reweight {
+    osd_id: u64,
+    /// The weight being worked toward.  0 drains the osd
+    target: f64,
+    /// How far the weight moves each step
+    step: f64,
+    current: f64,
+    /// Seconds since the epoch when the last step was taken
+    last_step: i64,
+}
+
+/// A step of adding an osd.  Each one is recorded in the transaction log
+/// once it's done
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum AddStep {
+    /// Saved a new key in Vault and made a LUKS container with it
+    Encrypt,
+    /// Opened the dm-crypt mapping the osd is built on
+    Open,
+    /// Made the filestore journal or bluestore db partition
+    Journal,
+    /// Made the bluestore wal partition
+    Wal,
+    Format,
+    Lvm,
+    /// Created the osd id or took over a destroyed one
+    Create,
+    Mount,
+    Mkfs,
+    /// Gave the osd its cephx key and keyring
+    Auth,
+    Crush,
+    Fstab,
+    /// Tagged the logical volume for ceph-volume
+    Tags,
+    /// Enabled the ceph-volume unit
+    Enable,
+    Start,
+}
+
+/// An osd add that hasn't finished.  Everything a later step or a rollback
+/// needs is kept here so the add can carry on after a restart
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct AddRecord {
+    store: ObjectStore,
+    /// The id asked for
+    requested_id: Option<u64>,
+    osd_fsid: String,
+    osd_id: Option<u64>,
+    /// Whether the osd took over a destroyed one
+    reused: bool,
+    /// The filestore journal or bluestore db partition
+    journal: Option<PathBuf>,
+    /// Where the journal partition is made when the add wasn't given one
+    journal_device: Option<SharedDevice>,
+    /// The bluestore wal partition
+    wal: Option<PathBuf>,
+    /// The bluestore volume group
+    vg_name: String,
+    /// The LUKS container's uuid when the osd is encrypted
+    luks_uuid: Option<String>,
+    done: Vec<AddStep>,
+    /// The add failed and the steps left in done couldn't be undone.  They're
+    /// undone before the disk is added again
+    #[serde(default)]
+    rolling_back: bool,
+}
+
+impl Steps for AddRecord {
+    type Step = AddStep;
+    fn done(&mut self) -> &mut Vec<AddStep> {
+        &mut self.done
+    }
+}
+
+/// A step of removing an osd
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RemoveStep {
+    Out,
+    Noout,
+    Stop,
+    Destroy,
+    CrushRemove,
+    AuthDel,
+    Rm,
+}
+
+impl RemoveStep {
+    // Whether the osd can't be put back once this is done
+    fn irreversible(&self) -> bool {
+        match *self {
+            RemoveStep::Out | RemoveStep::Noout | RemoveStep::Stop => false,
+            _ => true,
+        }
+    }
+}
+
+/// An osd removal that hasn't finished
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct RemoveRecord {
+    osd_id: u64,
+    /// The dm-crypt mapping the osd was on
+    mapping: Option<String>,
+    done: Vec<RemoveStep>,
+}
+
+impl Steps for RemoveRecord {
+    type Step = RemoveStep;
+    fn done(&mut self) -> &mut Vec<RemoveStep> {
+        &mut self.done
+    }
 }
 
 fn choose_ceph_config(config_dir: Option<&Path>) -> IOResult<PathBuf> {
@@ -73,7 +570,7 @@ fn choose_ceph_config(config_dir: Option<&Path>) -> IOResult<PathBuf> {
 impl CephBackend {
     pub fn new(config_dir: Option<&Path>) -> IOResult<CephBackend> {
         let ceph_config = choose_ceph_config(config_dir)?;
-        let mut f = File::open(ceph_config)?;
+        let mut f = File::open(&ceph_config)?;
         let mut s = String::new();
         f.read_to_string(&mut s)?;
         let deserialized: CephConfig = serde_json::from_str(&s)?;
@@ -84,28 +581,490 @@ impl CephBackend {
         info!("Connected to Ceph");
         Ok(CephBackend {
             cluster_handle: cluster_handle,
+            config: deserialized,
+            config_dir: ceph_config
+                .parent()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_default(),
         })
     }
 
-    /// Add a new /dev/ path as an osd.
-    fn add_osd(&self, dev_path: &Path, id: Option<u64>, simulate: bool) -> Result<(), String> {
-        //Format the drive
-        let xfs_options = block_utils::Filesystem::Xfs {
-            stripe_size: None,
-            stripe_width: None,
-            block_size: None,
-            agcount: Some(32),
-            inode_size: Some(2048),
-            force: true,
+    fn transaction_log(&self) -> TransactionLog {
+        TransactionLog::new(Path::new(
+            self.config
+                .transaction_file
+                .as_ref()
+                .map(|f| f.as_str())
+                .unwrap_or("/var/lib/bynar/ceph_transactions.json"),
+        ))
+    }
+
+    /// Add a new /dev/ path as an osd.  The journal is a filestore journal
+    /// or the bluestore db device.  When no partition is given a new one is
+    /// made on it.  Each step is recorded in the transaction log as it
+    /// finishes.  A failure rolls the finished steps back and an add that
+    /// was cut short by a crash carries on from its last step when the disk
+    /// is added again
+    fn add_osd(
+        &self,
+        dev_path: &Path,
+        id: Option<u64>,
+        journal: Option<&str>,
+        journal_partition: Option<u32>,
+        simulate: bool,
+    ) -> Result<(), String> {
+        let key = format!("add {}", dev_path.display());
+        let log = self.transaction_log();
+        let mut record = match log.get::<AddRecord>(&key)? {
+            Some(record) => {
+                info!(
+                    "Resuming the add of {} after {:?}",
+                    dev_path.display(),
+                    record.done
+                );
+                record
+            }
+            None => self.new_add_record(id, journal, journal_partition),
         };
-        debug!(
-            "Formatting {:?} with XFS options: {:?}",
-            dev_path, xfs_options
-        );
+        if record.rolling_back {
+            info!(
+                "Finishing the rollback of an earlier add of {}: {:?}",
+                dev_path.display(),
+                record.done
+            );
+            let failed = self.rollback_add(dev_path, &mut record, simulate);
+            if !failed.is_empty() {
+                if !simulate {
+                    log.save(&key, &record)?;
+                }
+                return Err(format!(
+                    "Rolling back an earlier add of {} still left these behind: {}",
+                    dev_path.display(),
+                    failed.join(", ")
+                ));
+            }
+            record = self.new_add_record(id, journal, journal_partition);
+        }
+        if !simulate {
+            log.save(&key, &record)?;
+        }
+        let store = record.store.clone();
+        let result = self.encrypt_osd(dev_path, &key, &mut record, simulate)
+            .and_then(|osd_dev| match store {
+                ObjectStore::Filestore => {
+                    self.add_filestore_osd(&osd_dev, &key, &mut record, simulate)
+                }
+                ObjectStore::Bluestore => {
+                    self.add_bluestore_osd(&osd_dev, &key, &mut record, simulate)
+                }
+            });
+        if let Err(e) = result {
+            error!(
+                "Adding {} failed: {}.  Rolling back {:?}",
+                dev_path.display(),
+                e,
+                record.done
+            );
+            let failed = self.rollback_add(dev_path, &mut record, simulate);
+            if failed.is_empty() {
+                if !simulate {
+                    log.remove(&key)?;
+                }
+                return Err(e);
+            }
+            // Keep what's left so the next add of this disk finishes the job
+            if !simulate {
+                record.rolling_back = true;
+                log.save(&key, &record)?;
+            }
+            return Err(format!(
+                "{}.  Rolling back left these behind: {}",
+                e,
+                failed.join(", ")
+            ));
+        }
         if !simulate {
-            block_utils::format_block_device(dev_path, &xfs_options)?;
-            let _ = settle_udev();
+            log.remove(&key)?;
+        }
+        Ok(())
+    }
+
+    // Everything an add decides up front so a resumed add makes the same
+    // choices as the attempt that was cut short
+    fn new_add_record(
+        &self,
+        id: Option<u64>,
+        journal: Option<&str>,
+        journal_partition: Option<u32>,
+    ) -> AddRecord {
+        let store = self.config
+            .osd_store
+            .clone()
+            .unwrap_or(ObjectStore::Filestore);
+        let shared = match store {
+            ObjectStore::Filestore => &self.config.journal,
+            ObjectStore::Bluestore => &self.config.bluestore_db,
+        };
+        let (journal, journal_device) = match (journal, journal_partition) {
+            (Some(device), Some(num)) => (Some(PathBuf::from(partition_path(device, num))), None),
+            (Some(device), None) => (
+                None,
+                Some(SharedDevice {
+                    device: device.to_string(),
+                    size_gb: shared.as_ref().map(|s| s.size_gb).unwrap_or(10),
+                }),
+            ),
+            (None, _) => (None, shared.clone()),
+        };
+        AddRecord {
+            store: store,
+            requested_id: id,
+            osd_fsid: uuid::Uuid::new_v4().to_hyphenated().to_string(),
+            osd_id: None,
+            reused: false,
+            journal: journal,
+            journal_device: journal_device,
+            wal: None,
+            vg_name: format!("ceph-{}", uuid::Uuid::new_v4().to_hyphenated()),
+            luks_uuid: self.config
+                .encryption
+                .as_ref()
+                .map(|_| uuid::Uuid::new_v4().to_hyphenated().to_string()),
+            done: vec![],
+            rolling_back: false,
+        }
+    }
+
+    fn vault_client(&self) -> Result<VaultClient<TokenData>, String> {
+        let config: VaultConfig =
+            helpers::load_config(&self.config_dir.to_string_lossy(), "disk-manager.json")
+                .map_err(|e| format!("Unable to load disk-manager.json: {}", e))?;
+        match (config.vault_endpoint, config.vault_token) {
+            (Some(endpoint), Some(token)) => {
+                VaultClient::new(endpoint.as_str(), token).map_err(|e| e.to_string())
+            }
+            _ => Err("Encryption needs vault_endpoint and vault_token in disk-manager.json"
+                .to_string()),
+        }
+    }
+
+    // Where the key of a LUKS container is kept in Vault
+    fn key_path(&self, luks_uuid: &str) -> Result<String, String> {
+        let host_info = Host::new().map_err(|e| e.to_string())?;
+        let vault_path = self.config
+            .encryption
+            .as_ref()
+            .and_then(|e| e.vault_path.clone())
+            .unwrap_or_else(|| "/bynar/luks".to_string());
+        Ok(format!(
+            "{}/{}/{}",
+            vault_path.trim_end_matches('/'),
+            host_info.hostname,
+            luks_uuid
+        ))
+    }
+
+    // Make a LUKS container on the disk and open it when the osd is
+    // encrypted.  Returns the device the osd is built on
+    fn encrypt_osd(
+        &self,
+        dev_path: &Path,
+        key: &str,
+        record: &mut AddRecord,
+        simulate: bool,
+    ) -> Result<PathBuf, String> {
+        let luks_uuid = match record.luks_uuid {
+            Some(ref luks_uuid) => luks_uuid.clone(),
+            None => return Ok(dev_path.to_path_buf()),
+        };
+        let log = self.transaction_log();
+        let encryption = self.config.encryption.clone().unwrap_or_default();
+        let dev = dev_path.to_string_lossy().into_owned();
+        let key_path = self.key_path(&luks_uuid)?;
+        let mapping = mapping_name(&luks_uuid);
+        let mapper_path = mapper_path(&luks_uuid);
+
+        log.run_step(key, record, AddStep::Encrypt, simulate, |_| {
+            let secret = new_secret()?;
+            // The key is saved first so there's never a container nobody can open
+            debug!("Saving the key for {} in vault at {}", dev, key_path);
+            if !simulate {
+                self.vault_client()?
+                    .set_secret(key_path.clone(), secret.clone())
+                    .map_err(|e| e.to_string())?;
+            }
+            run_cmd_with_input(
+                "cryptsetup",
+                &[
+                    "--batch-mode",
+                    "--cipher",
+                    encryption.cipher.as_ref().map(|c| c.as_str()).unwrap_or("aes-xts-plain64"),
+                    "--key-size",
+                    &encryption.key_size.unwrap_or(512).to_string(),
+                    "--uuid",
+                    &luks_uuid,
+                    "--key-file",
+                    "-",
+                    "luksFormat",
+                    &dev,
+                ],
+                &secret,
+                simulate,
+            )
+        })?;
+
+        if record.done.contains(&AddStep::Open) && !simulate && !mapper_path.exists() {
+            // The host rebooted before the add finished
+            debug!("{} was closed before the add finished", mapping);
+            record.done.retain(|s| *s != AddStep::Open);
+        }
+        log.run_step(key, record, AddStep::Open, simulate, |_| {
+            let secret = if simulate {
+                String::new()
+            } else {
+                self.vault_client()?
+                    .get_secret(&key_path)
+                    .map_err(|e| e.to_string())?
+            };
+            run_cmd_with_input(
+                "cryptsetup",
+                &["--key-file", "-", "luksOpen", &dev, &mapping],
+                &secret,
+                simulate,
+            )
+        })?;
+        Ok(mapper_path)
+    }
+
+    // Open the LUKS containers of encrypted osds after a reboot and bring
+    // their osds back up.  Their keys are in Vault so nothing at boot can.
+    // Containers an add or remove is still working on are left to it
+    fn unlock_osds(&self) -> Result<(), String> {
+        if self.config.encryption.is_none() {
+            return Ok(());
+        }
+        // blkid fails when it finds nothing
+        let output = match cmd_output("blkid", &["-t", "TYPE=crypto_LUKS", "-o", "export"]) {
+            Ok(output) => output,
+            Err(e) => {
+                debug!("No LUKS containers found: {}", e);
+                return Ok(());
+            }
+        };
+        let log = self.transaction_log();
+        for (dev, luks_uuid) in parse_luks_devices(&output) {
+            if mapper_path(&luks_uuid).exists() {
+                continue;
+            }
+            let busy = log.get::<Value>(&format!("add {}", dev))?.is_some()
+                || log.get::<Value>(&format!("remove {}", dev))?.is_some();
+            if busy {
+                debug!("{} is being added or removed.  Not unlocking it", dev);
+                continue;
+            }
+            if let Err(e) = self.unlock_osd(&dev, &luks_uuid) {
+                warn!("Unable to unlock {}: {}", dev, e);
+            }
+        }
+        Ok(())
+    }
+
+    // Open one container with its key from Vault and start the osd on it.
+    // A container that doesn't hold an osd on this host anymore is closed
+    // again
+    fn unlock_osd(&self, dev: &str, luks_uuid: &str) -> Result<(), String> {
+        let key_path = self.key_path(luks_uuid)?;
+        let secret = self.vault_client()?
+            .get_secret(&key_path)
+            .map_err(|e| format!("Unable to get its key from vault: {}", e))?;
+        let mapping = mapping_name(luks_uuid);
+        info!("Opening {} as {}", dev, mapping);
+        run_cmd_with_input(
+            "cryptsetup",
+            &["--key-file", "-", "luksOpen", dev, &mapping],
+            &secret,
+            false,
+        )?;
+        let _ = settle_udev();
+        let osd_dev = mapper_path(luks_uuid);
+        let vg_name = cmd_output(
+            "pvs",
+            &["--noheadings", "-o", "vg_name", &osd_dev.to_string_lossy()],
+        ).map(|vg| vg.trim().to_string())
+            .unwrap_or_default();
+        let started = if vg_name.is_empty() {
+            self.start_filestore_osd(&osd_dev)
+        } else {
+            self.start_bluestore_osd(&vg_name)
+        };
+        match started {
+            Ok(Some(osd_id)) => {
+                info!("osd {} on {} is unlocked and started", osd_id, dev);
+                Ok(())
+            }
+            Ok(None) => {
+                info!("{} doesn't hold an osd on this host.  Closing it", dev);
+                close_mapping(&mapping, false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Mount a filestore osd through its fstab entry and start it.  Removing
+    // an osd removes its fstab entry
+    fn start_filestore_osd(&self, osd_dev: &Path) -> Result<Option<u64>, String> {
+        let info = block_utils::get_device_info(osd_dev)?;
+        let spec = match info.id {
+            Some(id) => format!("UUID={}", id.to_hyphenated()),
+            None => return Ok(None),
+        };
+        let entries = FsTab::default()
+            .get_entries()
+            .map_err(|e| e.to_string())?;
+        let mount_point = match entries.iter().find(|e| e.fs_spec == spec) {
+            Some(entry) => entry.mountpoint.clone(),
+            None => return Ok(None),
+        };
+        let mounts = read_trimmed(Path::new("/proc/mounts")).unwrap_or_default();
+        if !is_mounted(&mounts, &mount_point) {
+            run_cmd("mount", &[&mount_point.to_string_lossy()], false)?;
+        }
+        let osd_id = get_osd_id(&mount_point, false)?;
+        setup_osd_init(osd_id, false)?;
+        Ok(Some(osd_id))
+    }
+
+    // Do what ceph-volume activate does for a bluestore osd.  It can't be
+    // left to ceph-volume because it doesn't know where the key is.
+    // Removing an osd removes its directory
+    fn start_bluestore_osd(&self, vg_name: &str) -> Result<Option<u64>, String> {
+        run_cmd("vgchange", &["-ay", vg_name], false)?;
+        let tags = parse_lv_tags(&cmd_output(
+            "lvs",
+            &["--noheadings", "-o", "lv_tags", vg_name],
+        )?);
+        let osd_id = match tags.get("ceph.osd_id").and_then(|id| id.parse::<u64>().ok()) {
+            Some(osd_id) => osd_id,
+            None => return Ok(None),
+        };
+        let block = match tags.get("ceph.block_device") {
+            Some(block) => PathBuf::from(block),
+            None => return Ok(None),
+        };
+        let osd_dir = format!("/var/lib/ceph/osd/ceph-{}", osd_id);
+        if !Path::new(&osd_dir).exists() {
+            return Ok(None);
         }
+        let mounts = read_trimmed(Path::new("/proc/mounts")).unwrap_or_default();
+        if !is_mounted(&mounts, Path::new(&osd_dir)) {
+            mount_bluestore_dir(
+                &osd_dir,
+                &block,
+                tags.get("ceph.db_device").map(Path::new),
+                tags.get("ceph.wal_device").map(Path::new),
+                false,
+            )?;
+            // The files mkfs wrote are kept in the bluestore label
+            run_cmd(
+                "ceph-bluestore-tool",
+                &[
+                    "prime-osd-dir",
+                    "--dev",
+                    &block.to_string_lossy(),
+                    "--path",
+                    &osd_dir,
+                ],
+                false,
+            )?;
+            let key = auth_get_key(&self.cluster_handle, "osd", &osd_id.to_string())
+                .map_err(|e| e.to_string())?;
+            save_keyring(osd_id, &key, false).map_err(|e| e.to_string())?;
+            run_cmd("chown", &["-R", "ceph:ceph", &osd_dir], false)?;
+        }
+        setup_osd_init(osd_id, false)?;
+        Ok(Some(osd_id))
+    }
+
+    // Make the filestore journal or bluestore db partition when the add
+    // wasn't given an existing one
+    fn add_journal_step(
+        &self,
+        key: &str,
+        record: &mut AddRecord,
+        simulate: bool,
+    ) -> Result<(), String> {
+        let shared = match record.journal_device {
+            Some(ref shared) => shared.clone(),
+            None => return Ok(()),
+        };
+        let name = match record.store {
+            ObjectStore::Filestore => "journal",
+            ObjectStore::Bluestore => "block.db",
+        };
+        self.transaction_log()
+            .run_step(key, record, AddStep::Journal, simulate, |r| {
+                r.journal = Some(create_shared_partition(
+                    &shared.device,
+                    shared.size_gb,
+                    name,
+                    simulate,
+                )?);
+                Ok(())
+            })
+    }
+
+    // Create the osd id or take over a destroyed one and return it
+    fn create_step(
+        &self,
+        key: &str,
+        record: &mut AddRecord,
+        simulate: bool,
+    ) -> Result<u64, String> {
+        self.transaction_log()
+            .run_step(key, record, AddStep::Create, simulate, |r| {
+                let (osd_id, reused) =
+                    self.allocate_osd_id(r.requested_id, &r.osd_fsid, simulate)?;
+                debug!("New osd id created: {:?}", osd_id);
+                r.osd_id = Some(osd_id);
+                r.reused = reused;
+                Ok(())
+            })?;
+        record
+            .osd_id
+            .ok_or_else(|| "The osd id wasn't recorded".to_string())
+    }
+
+    // Add osds with xfs
+    // Jewel or earlier
+    fn add_filestore_osd(
+        &self,
+        dev_path: &Path,
+        key: &str,
+        record: &mut AddRecord,
+        simulate: bool,
+    ) -> Result<(), String> {
+        let log = self.transaction_log();
+        self.add_journal_step(key, record, simulate)?;
+        log.run_step(key, record, AddStep::Format, simulate, |_| {
+            //Format the drive
+            let xfs_options = block_utils::Filesystem::Xfs {
+                stripe_size: None,
+                stripe_width: None,
+                block_size: None,
+                agcount: Some(32),
+                inode_size: Some(2048),
+                force: true,
+            };
+            debug!(
+                "Formatting {:?} with XFS options: {:?}",
+                dev_path, xfs_options
+            );
+            if !simulate {
+                block_utils::format_block_device(dev_path, &xfs_options)?;
+                let _ = settle_udev();
+            }
+            Ok(())
+        })?;
 
         // Probe the drive
         debug!("udev Probing device {:?}", dev_path);
@@ -118,97 +1077,952 @@ impl CephBackend {
             ));
         }
 
-        // Create a new osd id
-        let new_osd_id =
-            osd_create(&self.cluster_handle, id, simulate).map_err(|e| e.to_string())?;
-        debug!("New osd id created: {:?}", new_osd_id);
+        let new_osd_id = self.create_step(key, record, simulate)?;
+        let mount_point = format!("/var/lib/ceph/osd/ceph-{}", new_osd_id);
+        if mount_lost(record, &mount_point) {
+            // Everything the osd wrote is still on the disk
+            debug!("{} was unmounted before the add finished", mount_point);
+            record.done.retain(|s| *s != AddStep::Mount);
+        }
+        log.run_step(key, record, AddStep::Mount, simulate, |_| {
+            if !simulate {
+                if !Path::new(&mount_point).exists() {
+                    debug!("Mount point {} doesn't exist.  Creating.", mount_point);
+                    create_dir(&mount_point).map_err(|e| e.to_string())?;
+                }
+                block_utils::mount_device(&info, &mount_point)?;
+            }
+            Ok(())
+        })?;
+
+        // Format the osd with the osd filesystem
+        log.run_step(key, record, AddStep::Mkfs, simulate, |r| {
+            ceph_mkfs(
+                new_osd_id,
+                &r.osd_fsid,
+                r.journal.as_ref().map(|j| j.as_path()),
+                simulate,
+            )
+        })?;
+        log.run_step(key, record, AddStep::Auth, simulate, |_| {
+            self.auth_add(new_osd_id, simulate)
+        })?;
+        log.run_step(key, record, AddStep::Crush, simulate, |r| {
+            self.crush_add(new_osd_id, &info, r.reused, simulate)
+        })?;
+        log.run_step(key, record, AddStep::Fstab, simulate, |r| {
+            add_osd_to_fstab(&info, new_osd_id, r.luks_uuid.is_some(), simulate)
+        })?;
+        // This step depends on whether it's systemctl, upstart, etc
+        // sudo start ceph-osd id={osd-num}
+        log.run_step(key, record, AddStep::Start, simulate, |_| {
+            setup_osd_init(new_osd_id, simulate)
+        })
+    }
+
+    // Add osds with bluestore
+    // Luminous or later
+    // This follows ceph-volume lvm: the disk becomes a volume group with one
+    // logical volume, the osd directory is a tmpfs holding symlinks to the
+    // block, db and wal devices and the lvm tags let ceph-volume activate
+    // the osd again after a reboot
+    fn add_bluestore_osd(
+        &self,
+        dev_path: &Path,
+        key: &str,
+        record: &mut AddRecord,
+        simulate: bool,
+    ) -> Result<(), String> {
+        let log = self.transaction_log();
+        debug!("udev Probing device {:?}", dev_path);
+        let info = block_utils::get_device_info(dev_path)?;
+        debug!("udev info {:?}", info);
+        self.add_journal_step(key, record, simulate)?;
+        if let Some(ref wal) = self.config.bluestore_wal {
+            log.run_step(key, record, AddStep::Wal, simulate, |r| {
+                r.wal = Some(create_shared_partition(
+                    &wal.device,
+                    wal.size_gb,
+                    "block.wal",
+                    simulate,
+                )?);
+                Ok(())
+            })?;
+        }
+
+        let lv_name = format!("osd-block-{}", record.osd_fsid);
+        let block = format!("/dev/{}/{}", record.vg_name, lv_name);
+        log.run_step(key, record, AddStep::Lvm, simulate, |r| {
+            let dev = dev_path.to_string_lossy().into_owned();
+            run_cmd("pvcreate", &["--yes", "--force", &dev], simulate)?;
+            run_cmd("vgcreate", &["--force", "--yes", &r.vg_name, &dev], simulate)?;
+            run_cmd(
+                "lvcreate",
+                &["--yes", "-l", "100%FREE", "-n", &lv_name, &r.vg_name],
+                simulate,
+            )
+        })?;
+
+        let new_osd_id = self.create_step(key, record, simulate)?;
+        let osd_dir = format!("/var/lib/ceph/osd/ceph-{}", new_osd_id);
+        if mount_lost(record, &osd_dir) {
+            // The keyring and the files mkfs wrote were in the tmpfs
+            return Err(format!(
+                "{} was unmounted before the add finished.  Starting over",
+                osd_dir
+            ));
+        }
+        log.run_step(key, record, AddStep::Mount, simulate, |r| {
+            mount_bluestore_dir(
+                &osd_dir,
+                Path::new(&block),
+                r.journal.as_ref().map(|d| d.as_path()),
+                r.wal.as_ref().map(|w| w.as_path()),
+                simulate,
+            )
+        })?;
+
+        log.run_step(key, record, AddStep::Mkfs, simulate, |r| {
+            bluestore_mkfs(
+                new_osd_id,
+                &r.osd_fsid,
+                r.journal.as_ref().map(|d| d.as_path()),
+                r.wal.as_ref().map(|w| w.as_path()),
+                simulate,
+            )
+        })?;
+        log.run_step(key, record, AddStep::Auth, simulate, |_| {
+            self.auth_add(new_osd_id, simulate)
+        })?;
+        log.run_step(key, record, AddStep::Crush, simulate, |r| {
+            self.crush_add(new_osd_id, &info, r.reused, simulate)
+        })?;
+
+        log.run_step(key, record, AddStep::Tags, simulate, |r| {
+            let mut tags = vec![
+                format!("ceph.osd_id={}", new_osd_id),
+                format!("ceph.osd_fsid={}", r.osd_fsid),
+                "ceph.cluster_name=ceph".to_string(),
+                "ceph.type=block".to_string(),
+                format!("ceph.block_device={}", block),
+                format!("ceph.encrypted={}", if r.luks_uuid.is_some() { 1 } else { 0 }),
+            ];
+            if let Some(ref db) = r.journal {
+                tags.push(format!("ceph.db_device={}", db.display()));
+            }
+            if let Some(ref wal) = r.wal {
+                tags.push(format!("ceph.wal_device={}", wal.display()));
+            }
+            let mut args: Vec<String> = Vec::new();
+            for tag in tags {
+                args.push("--addtag".to_string());
+                args.push(tag);
+            }
+            args.push(block.clone());
+            let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            run_cmd("lvchange", &args, simulate)
+        })?;
+
+        // ceph-volume mounts the tmpfs and starts the osd at boot.  It can't
+        // open a mapping whose key is in Vault
+        let systemd = match detect_daemon()? {
+            Daemon::Systemd => true,
+            _ => false,
+        };
+        if systemd && record.luks_uuid.is_none() {
+            log.run_step(key, record, AddStep::Enable, simulate, |r| {
+                run_cmd(
+                    "systemctl",
+                    &[
+                        "enable",
+                        &format!("ceph-volume@lvm-{}-{}", new_osd_id, r.osd_fsid),
+                    ],
+                    simulate,
+                )
+            })?;
+        }
+        log.run_step(key, record, AddStep::Start, simulate, |_| {
+            setup_osd_init(new_osd_id, simulate)
+        })
+    }
+
+    // Undo the steps a failed add finished, newest first.  Every step is
+    // tried even if a later one couldn't be undone.  The steps that couldn't
+    // be are left in done.  Returns why they couldn't be
+    fn rollback_add(&self, dev_path: &Path, record: &mut AddRecord, simulate: bool) -> Vec<String> {
+        let mut failed = Vec::new();
+        let mut left = Vec::new();
+        while let Some(step) = record.done.pop() {
+            debug!("Rolling back {:?} of {}", step, dev_path.display());
+            if let Err(e) = self.undo_add_step(dev_path, record, step, simulate) {
+                error!("Unable to roll back {:?}: {}", step, e);
+                failed.push(format!("{:?} ({})", step, e));
+                left.insert(0, step);
+            }
+        }
+        record.done = left;
+        failed
+    }
+
+    fn undo_add_step(
+        &self,
+        dev_path: &Path,
+        record: &AddRecord,
+        step: AddStep,
+        simulate: bool,
+    ) -> Result<(), String> {
+        let osd_id = record
+            .osd_id
+            .ok_or_else(|| "The osd id wasn't recorded".to_string());
+        // Everything after Open was done to the mapping
+        let osd_dev = match record.luks_uuid {
+            Some(ref luks_uuid) => mapper_path(luks_uuid),
+            None => dev_path.to_path_buf(),
+        };
+        match step {
+            // Without its key the container is as good as wiped
+            AddStep::Encrypt => match record.luks_uuid {
+                Some(ref luks_uuid) => {
+                    let key_path = self.key_path(luks_uuid)?;
+                    debug!("Deleting {} from vault", key_path);
+                    if simulate {
+                        return Ok(());
+                    }
+                    self.vault_client()?
+                        .delete_secret(&key_path)
+                        .map_err(|e| e.to_string())
+                }
+                None => Ok(()),
+            },
+            AddStep::Open => match record.luks_uuid {
+                Some(ref luks_uuid) => close_mapping(&mapping_name(luks_uuid), simulate),
+                None => Ok(()),
+            },
+            AddStep::Journal => match record.journal {
+                Some(ref part) => delete_partition(part, simulate),
+                None => Ok(()),
+            },
+            AddStep::Wal => match record.wal {
+                Some(ref part) => delete_partition(part, simulate),
+                None => Ok(()),
+            },
+            // Whatever these wrote goes when the disk is sanitised
+            AddStep::Format | AddStep::Mkfs | AddStep::Tags => Ok(()),
+            AddStep::Lvm => {
+                let dev = osd_dev.to_string_lossy().into_owned();
+                run_cmd(
+                    "vgremove",
+                    &["--force", "--yes", &record.vg_name],
+                    simulate,
+                )?;
+                run_cmd("pvremove", &["--force", "--yes", &dev], simulate)
+            }
+            AddStep::Create => {
+                let osd_id = osd_id?;
+                if record.reused {
+                    // Leave the id for the next replacement to try again
+                    self.destroy_osd(osd_id, simulate)
+                } else {
+                    osd_rm(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())
+                }
+            }
+            AddStep::Mount => {
+                let osd_dir = format!("/var/lib/ceph/osd/ceph-{}", osd_id?);
+                let mounts = read_trimmed(Path::new("/proc/mounts")).unwrap_or_default();
+                if !is_mounted(&mounts, Path::new(&osd_dir)) {
+                    return Ok(());
+                }
+                run_cmd("umount", &[&osd_dir], simulate)
+            }
+            AddStep::Auth => {
+                auth_del(&self.cluster_handle, osd_id?, simulate).map_err(|e| e.to_string())
+            }
+            AddStep::Crush => {
+                let osd_id = osd_id?;
+                self.cancel_reweight(osd_id, simulate)?;
+                if record.reused {
+                    // The destroyed osd's crush position stays for the next try
+                    return Ok(());
+                }
+                osd_crush_remove(&self.cluster_handle, osd_id, simulate)
+                    .map_err(|e| e.to_string())
+            }
+            AddStep::Fstab => {
+                let osd_dir = PathBuf::from(format!("/var/lib/ceph/osd/ceph-{}", osd_id?));
+                remove_fstab_entry(&osd_dev, &osd_dir, simulate)
+            }
+            AddStep::Enable => run_cmd(
+                "systemctl",
+                &[
+                    "disable",
+                    &format!("ceph-volume@lvm-{}-{}", osd_id?, record.osd_fsid),
+                ],
+                simulate,
+            ),
+            AddStep::Start => stop_osd_init(osd_id?, simulate),
+        }
+    }
+
+    // Allocate the new osd's id.  When the requested id belongs to a destroyed
+    // osd it's taken over with osd new so it keeps its crush position.
+    // Returns the id and whether a destroyed osd was taken over
+    fn allocate_osd_id(
+        &self,
+        id: Option<u64>,
+        osd_fsid: &str,
+        simulate: bool,
+    ) -> Result<(u64, bool), String> {
+        if let Some(id) = id {
+            let dump = self.mon_command(&json!({
+                "prefix": "osd dump",
+                "format": "json",
+            }))?;
+            if is_osd_destroyed(&dump, id)? {
+                debug!("Taking over destroyed osd {}", id);
+                if !simulate {
+                    self.mon_command(&json!({
+                        "prefix": "osd new",
+                        "uuid": osd_fsid,
+                        "id": id,
+                    }))?;
+                }
+                return Ok((id, true));
+            }
+        }
+        let osd_id = osd_create(&self.cluster_handle, id, simulate).map_err(|e| e.to_string())?;
+        Ok((osd_id, false))
+    }
+
+    // destroy drops the osd's keys and marks it destroyed but its id and
+    // crush position stay for the replacement
+    fn destroy_osd(&self, osd_id: u64, simulate: bool) -> Result<(), String> {
+        debug!("Destroying osd {}", osd_id);
+        if simulate {
+            return Ok(());
+        }
+        let ids = vec![osd_id.to_string()];
+        self.mon_command(&json!({"prefix": "osd down", "ids": ids}))?;
+        self.mon_command(&json!({
+            "prefix": "osd destroy",
+            "id": osd_id,
+            "sure": "--yes-i-really-mean-it",
+        }))?;
+        Ok(())
+    }
+
+    // Give a new osd its cephx key and save its keyring
+    fn auth_add(&self, osd_id: u64, simulate: bool) -> Result<(), String> {
+        debug!("Creating ceph authorization entry");
+        osd_auth_add(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())?;
+        let auth_key = auth_get_key(&self.cluster_handle, "osd", &osd_id.to_string())
+            .map_err(|e| e.to_string())?;
+        debug!("Saving ceph keyring");
+        save_keyring(osd_id, &auth_key, simulate).map_err(|e| e.to_string())
+    }
+
+    // Place a new osd in the crush map.  An osd that took over a destroyed
+    // one is already in the crush map
+    fn crush_add(
+        &self,
+        osd_id: u64,
+        info: &block_utils::Device,
+        in_crush: bool,
+        simulate: bool,
+    ) -> Result<(), String> {
+        let osd_weight = self.config
+            .crush_weight
+            .unwrap_or_else(|| tib_weight(info.capacity));
+        let swap = self.swap_config();
+        if in_crush {
+            debug!("osd {} kept its crush position", osd_id);
+            if swap.noout.is_some() {
+                self.set_noout(osd_id, false, simulate)?;
+            }
+            self.set_device_class(osd_id, &info.media_type, simulate)?;
+            // A drained osd comes back with no weight
+            let current = self.crush_weight(osd_id)?;
+            if current < osd_weight {
+                self.reweight(osd_id, current, osd_weight, simulate)?;
+            }
+            return Ok(());
+        }
+        let host_info = Host::new().map_err(|e| e.to_string())?;
+        let initial_weight = if swap.ramp_up.unwrap_or(false) {
+            next_weight(0.0, osd_weight, osd_weight * swap.weight_step.unwrap_or(0.2))
+        } else {
+            osd_weight
+        };
+        let location = crush_location_args(&self.crush_location()?, &host_info.hostname);
+        debug!(
+            "Adding OSD {} to crushmap at {:?} with weight: {}",
+            osd_id, location, initial_weight
+        );
+        if !simulate {
+            self.mon_command(&json!({
+                "prefix": "osd crush add",
+                "id": osd_id,
+                "weight": initial_weight,
+                "args": location,
+            }))?;
+        }
+        self.set_device_class(osd_id, &info.media_type, simulate)?;
+        if initial_weight < osd_weight {
+            self.reweight(osd_id, initial_weight, osd_weight, simulate)?;
+        }
+        Ok(())
+    }
+
+    // This host's crush location from ceph.json and the inventory file
+    fn crush_location(&self) -> Result<CrushLocation, String> {
+        let location = self.config.crush_location.clone().unwrap_or_default();
+        let inventory_file = match self.config.inventory_file {
+            Some(ref file) => file,
+            None => return Ok(location),
+        };
+        let host_info = Host::new().map_err(|e| e.to_string())?;
+        let mut f = File::open(inventory_file)
+            .map_err(|e| format!("Unable to open {}: {}", inventory_file, e))?;
+        let mut s = String::new();
+        f.read_to_string(&mut s).map_err(|e| e.to_string())?;
+        let mut inventory: HashMap<String, CrushLocation> =
+            serde_json::from_str(&s).map_err(|e| e.to_string())?;
+        match inventory.remove(&host_info.hostname) {
+            Some(entry) => Ok(merge_location(location, entry)),
+            None => {
+                warn!(
+                    "{} isn't in {}.  Using the crush_location from ceph.json",
+                    host_info.hostname, inventory_file
+                );
+                Ok(location)
+            }
+        }
+    }
+
+    // Replace whatever class ceph guessed with the one the disk really is
+    fn set_device_class(
+        &self,
+        osd_id: u64,
+        media_type: &block_utils::MediaType,
+        simulate: bool,
+    ) -> Result<(), String> {
+        let class = match self.config.device_class {
+            Some(ref class) => class.as_str(),
+            None => match device_class(media_type) {
+                Some(class) => class,
+                None => {
+                    debug!("No device class for {:?}.  Leaving it to ceph", media_type);
+                    return Ok(());
+                }
+            },
+        };
+        debug!("Setting device class of osd {} to {}", osd_id, class);
+        if simulate {
+            return Ok(());
+        }
+        let ids = vec![format!("osd.{}", osd_id)];
+        // set-device-class refuses to change a class that's already set
+        self.mon_command(&json!({"prefix": "osd crush rm-device-class", "ids": ids}))?;
+        self.mon_command(&json!({
+            "prefix": "osd crush set-device-class",
+            "class": class,
+            "ids": ids,
+        }))?;
+        Ok(())
+    }
+
+    fn swap_config(&self) -> SwapConfig {
+        self.config.swap.clone().unwrap_or_default()
+    }
+
+    // Set or clear noout on the osd or its host
+    fn set_noout(&self, osd_id: u64, on: bool, simulate: bool) -> Result<(), String> {
+        let ids = vec![osd_id.to_string()];
+        let cmd = match (self.swap_config().noout, on) {
+            (Some(NooutScope::Osd), true) => json!({"prefix": "osd add-noout", "ids": ids}),
+            (Some(NooutScope::Osd), false) => json!({"prefix": "osd rm-noout", "ids": ids}),
+            (Some(NooutScope::Host), on) => {
+                let host_info = Host::new().map_err(|e| e.to_string())?;
+                let prefix = if on {
+                    "osd set-group"
+                } else {
+                    "osd unset-group"
+                };
+                json!({
+                    "prefix": prefix,
+                    "flags": "noout",
+                    "who": vec![host_info.hostname],
+                })
+            }
+            (None, _) => return Ok(()),
+        };
+        debug!("Setting noout {} for osd {}", on, osd_id);
+        if simulate {
+            return Ok(());
+        }
+        self.mon_command(&cmd)?;
+        Ok(())
+    }
+
+    // The osd's crush weight from osd tree
+    fn crush_weight(&self, osd_id: u64) -> Result<f64, String> {
+        let tree = self.mon_command(&json!({
+            "prefix": "osd tree",
+            "format": "json",
+        }))?;
+        parse_crush_weight(&tree, osd_id)
+            .ok_or_else(|| format!("osd.{} isn't in the crush map", osd_id))
+    }
+
+    // Move an osd's crush weight from current to target.  With ramp_up or
+    // drain the move is made in steps by run_periodic
+    fn reweight(
+        &self,
+        osd_id: u64,
+        current: f64,
+        target: f64,
+        simulate: bool,
+    ) -> Result<(), String> {
+        let swap = self.swap_config();
+        let stepped = if target > current {
+            swap.ramp_up.unwrap_or(false)
+        } else {
+            swap.drain.unwrap_or(false)
+        };
+        if !stepped {
+            return self.crush_reweight(osd_id, target, simulate);
+        }
+        let reweight = self.stepped_reweight(osd_id, current, target);
+        self.update_reweights(simulate, |reweights| {
+            reweights.retain(|r| r.osd_id != osd_id);
+            reweights.push(reweight);
+            Ok(true)
+        })
+    }
+
+    fn stepped_reweight(&self, osd_id: u64, current: f64, target: f64) -> Reweight {
+        debug!(
+            "Reweighting osd {} from {} to {} in steps",
+            osd_id, current, target
+        );
+        let full = if target > current { target } else { current };
+        Reweight {
+            osd_id: osd_id,
+            target: target,
+            step: full * self.swap_config().weight_step.unwrap_or(0.2),
+            current: current,
+            last_step: time::get_time().sec,
+        }
+    }
+
+    fn crush_reweight(&self, osd_id: u64, weight: f64, simulate: bool) -> Result<(), String> {
+        debug!("Setting crush weight of osd {} to {}", osd_id, weight);
+        if simulate {
+            return Ok(());
+        }
+        self.mon_command(&json!({
+            "prefix": "osd crush reweight",
+            "name": format!("osd.{}", osd_id),
+            "weight": weight,
+        }))?;
+        Ok(())
+    }
+
+    fn state_file(&self) -> PathBuf {
+        PathBuf::from(
+            self.swap_config()
+                .state_file
+                .unwrap_or_else(|| "/var/lib/bynar/ceph_reweights.json".to_string()),
+        )
+    }
+
+    // The periodic thread and the request handlers each load a backend of
+    // their own so the state file is guarded by an flock on a file next to
+    // it.  The lock is held until the returned file is dropped
+    fn lock_reweights(&self) -> Result<File, String> {
+        let path = self.state_file().with_extension("lock");
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let f = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+        if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(format!(
+                "Unable to lock {}: {}",
+                path.display(),
+                Error::last_os_error()
+            ));
+        }
+        Ok(f)
+    }
+
+    // Change the stepped reweights while holding the lock.  update says
+    // whether it changed anything that needs saving
+    fn update_reweights<F>(&self, simulate: bool, update: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Vec<Reweight>) -> Result<bool, String>,
+    {
+        let _lock = self.lock_reweights()?;
+        let mut reweights = self.load_reweights()?;
+        if update(&mut reweights)? && !simulate {
+            self.save_reweights(&reweights)?;
+        }
+        Ok(())
+    }
+
+    fn load_reweights(&self) -> Result<Vec<Reweight>, String> {
+        let path = self.state_file();
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let mut f = File::open(&path).map_err(|e| e.to_string())?;
+        let mut s = String::new();
+        f.read_to_string(&mut s).map_err(|e| e.to_string())?;
+        serde_json::from_str(&s).map_err(|e| e.to_string())
+    }
+
+    fn save_reweights(&self, reweights: &[Reweight]) -> Result<(), String> {
+        let path = self.state_file();
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let json = serde_json::to_string_pretty(reweights).map_err(|e| e.to_string())?;
+        let mut f = File::create(&path).map_err(|e| e.to_string())?;
+        f.write_all(json.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    // Drop the stepped reweight of an osd whose add was rolled back
+    fn cancel_reweight(&self, osd_id: u64, simulate: bool) -> Result<(), String> {
+        self.update_reweights(simulate, |reweights| {
+            let count = reweights.len();
+            reweights.retain(|r| r.osd_id != osd_id);
+            Ok(reweights.len() != count)
+        })
+    }
+
+    // Take the next step of every reweight once the cluster has settled from
+    // the last one
+    fn step_reweights(&self) -> Result<(), String> {
+        self.update_reweights(false, |reweights| {
+            if reweights.is_empty() {
+                return Ok(false);
+            }
+            let swap = self.swap_config();
+            let now = time::get_time().sec;
+            let interval = swap.step_minutes.unwrap_or(5) * 60;
+            let unclean = parse_unclean_pgs(&self.mon_command(&json!({
+                "prefix": "pg stat",
+                "format": "json",
+            }))?)?;
+            if unclean > swap.max_unclean_pgs.unwrap_or(0) {
+                debug!("{} pgs aren't active+clean.  Waiting to reweight", unclean);
+                return Ok(false);
+            }
+            for r in reweights.iter_mut() {
+                if now - r.last_step < interval {
+                    continue;
+                }
+                let weight = next_weight(r.current, r.target, r.step);
+                self.crush_reweight(r.osd_id, weight, false)?;
+                r.current = weight;
+                r.last_step = now;
+            }
+            reweights.retain(|r| r.current != r.target);
+            Ok(true)
+        })
+    }
+
+    // Find the osd id a device belongs to.  A mounted osd says who it is in
+    // its whoami file.  A dead disk often isn't mounted anymore so ask the
+    // monitors which osd on this host was built on it
+    fn resolve_osd_id(&self, dev_path: &Path, simulate: bool) -> Result<u64, String> {
+        if let Some(mount_point) =
+            block_utils::get_mountpoint(&dev_path).map_err(|e| e.to_string())?
+        {
+            debug!("OSD mounted at: {:?}", mount_point);
+            return match get_osd_id(&mount_point, simulate) {
+                Ok(osd_id) => Ok(osd_id),
+                Err(e) => {
+                    error!(
+                        "Failed to discover osd id: {:?}.  Falling back on path name",
+                        e
+                    );
+                    get_osd_id_from_path(&mount_point)
+                }
+            };
+        }
+        let dev_name = match dev_path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => {
+                return Err(format!(
+                    "Unable to get device name from {}",
+                    dev_path.display()
+                ))
+            }
+        };
+        let host_info = Host::new().map_err(|e| e.to_string())?;
+        let metadata = self.mon_command(&json!({
+            "prefix": "osd metadata",
+            "format": "json",
+        }))?;
+        parse_osd_metadata(&metadata, &host_info.hostname, &dev_name).ok_or_else(|| {
+            format!(
+                "Unable to find an osd on {} for {}",
+                host_info.hostname,
+                dev_path.display()
+            )
+        })
+    }
+
+    // Run a mon command and return its output.  Ceph refuses with an error
+    // code such as EBUSY so a refusal comes back as Err
+    fn mon_command(&self, cmd: &Value) -> Result<String, String> {
+        debug!("mon command: {}", cmd);
+        let (output, status) = self
+            .cluster_handle
+            .ceph_mon_command_without_data(cmd)
+            .map_err(|e| e.to_string())?;
+        if let Some(status) = status {
+            debug!("mon status: {}", status);
+        }
+        String::from_utf8(output).map_err(|e| e.to_string())
+    }
 
-        // Mount the drive
-        let mount_point = format!("/var/lib/ceph/osd/ceph-{}", new_osd_id);
-        if !simulate {
-            if !Path::new(&mount_point).exists() {
-                debug!("Mount point {} doesn't exist.  Creating.", mount_point);
-                create_dir(&mount_point).map_err(|e| e.to_string())?;
-            }
-            block_utils::mount_device(&info, &mount_point)?;
+    /// Ask the monitors whether this one osd can go.  If Ceph says the osd
+    /// can be destroyed without reducing durability it's safe.  Otherwise
+    /// it's still safe if stopping it leaves every pg it holds active with
+    /// at least min_size copies.
+    fn osd_removal_check(&self, osd_id: u64) -> Result<RemovalCheck, String> {
+        let ids = vec![osd_id.to_string()];
+        if let Err(e) = self.mon_command(&json!({
+            "prefix": "osd safe-to-destroy",
+            "ids": ids,
+        })) {
+            debug!("osd.{} isn't safe to destroy: {}", osd_id, e);
+        } else {
+            return Ok(RemovalCheck {
+                safe: true,
+                reason: None,
+                blocking_pgs: vec![],
+            });
         }
 
-        // Format the osd with the osd filesystem
-        ceph_mkfs(new_osd_id, None, simulate)?;
-        debug!("Creating ceph authorization entry");
-        osd_auth_add(&self.cluster_handle, new_osd_id, simulate).map_err(|e| e.to_string())?;
-        let auth_key = auth_get_key(&self.cluster_handle, "osd", &new_osd_id.to_string())
-            .map_err(|e| e.to_string())?;
-        debug!("Saving ceph keyring");
-        save_keyring(new_osd_id, &auth_key, simulate).map_err(|e| e.to_string())?;
-        let host_info = Host::new().map_err(|e| e.to_string())?;
-        let gb_capacity = info.capacity / 1073741824;
-        let osd_weight = gb_capacity as f64 * 0.001_f64;
-        debug!(
-            "Adding OSD {} to crushmap under host {} with weight: {}",
-            new_osd_id, host_info.hostname, osd_weight
-        );
-        osd_crush_add(
-            &self.cluster_handle,
-            new_osd_id,
-            osd_weight,
-            &host_info.hostname,
-            simulate,
-        ).map_err(|e| e.to_string())?;
-        add_osd_to_fstab(&info, new_osd_id, simulate)?;
-        // This step depends on whether it's systemctl, upstart, etc
-        setup_osd_init(new_osd_id, simulate)?;
-        // sudo start ceph-osd id={osd-num}
-        Ok(())
+        let pools = parse_pools(&self.mon_command(&json!({
+            "prefix": "osd pool ls",
+            "detail": "detail",
+            "format": "json",
+        }))?)?;
+        let pgs = parse_pgs(&self.mon_command(&json!({
+            "prefix": "pg ls-by-osd",
+            "osd": format!("osd.{}", osd_id),
+            "format": "json",
+        }))?)?;
+        let blocking = blocking_pgs(osd_id, &pgs, &pools);
+        let ok_to_stop = self.mon_command(&json!({
+            "prefix": "osd ok-to-stop",
+            "ids": ids,
+        }));
+
+        let reason = match ok_to_stop {
+            Err(e) => Some(format!("osd.{} is not ok to stop: {}", osd_id, e)),
+            Ok(_) if !blocking.is_empty() => Some(format!(
+                "{} pgs on osd.{} would become unavailable",
+                blocking.len(),
+                osd_id
+            )),
+            Ok(_) => None,
+        };
+        Ok(RemovalCheck {
+            safe: reason.is_none(),
+            reason: reason,
+            blocking_pgs: blocking,
+        })
     }
 
-    fn remove_osd(&self, dev_path: &Path, simulate: bool) -> Result<(), String> {
-        //If the OSD is still running we can query its version.  If not then we
-        //should ask either another OSD or a monitor.
-        let mount_point = match block_utils::get_mountpoint(&dev_path).map_err(|e| e.to_string())? {
-            Some(osd_path) => osd_path,
-            None => {
-                let temp_dir = TempDir::new("osd").map_err(|e| e.to_string())?;
-                temp_dir.into_path()
+    // Start or follow the drain of an osd.  Returns why it can't be removed
+    // yet while it still has weight
+    fn drain(&self, osd_id: u64) -> Result<Option<RemovalCheck>, String> {
+        let weight = self.crush_weight(osd_id)?;
+        if weight <= 0.0 {
+            return Ok(None);
+        }
+        self.update_reweights(false, |reweights| {
+            if reweights.iter().any(|r| r.osd_id == osd_id) {
+                return Ok(false);
             }
-        };
-        debug!("OSD mounted at: {:?}", mount_point);
+            reweights.push(self.stepped_reweight(osd_id, weight, 0.0));
+            Ok(true)
+        })?;
+        Ok(Some(RemovalCheck {
+            safe: false,
+            reason: Some(format!(
+                "osd.{} is being drained.  Its crush weight is {}",
+                osd_id, weight
+            )),
+            blocking_pgs: vec![],
+        }))
+    }
 
-        let osd_id = match get_osd_id(&mount_point, simulate) {
-            Ok(osd_id) => osd_id,
-            Err(e) => {
-                error!(
-                    "Failed to discover osd id: {:?}.  Falling back on path name",
-                    e
+    // Take the osd out of the cluster and tidy up after it.  Each step is
+    // recorded in the transaction log.  A failure before the osd is
+    // destroyed or purged puts it back the way it was.  Past that there's no
+    // going back so a removal that failed or was cut short carries on from
+    // its last step when the disk is removed again.  Returns the id when it
+    // was kept for the replacement
+    fn remove_osd(&self, dev_path: &Path, simulate: bool) -> Result<RemovedDisk, String> {
+        let key = format!("remove {}", dev_path.display());
+        let log = self.transaction_log();
+        let mut record = match log.get::<RemoveRecord>(&key)? {
+            Some(record) => {
+                info!(
+                    "Resuming the removal of osd {} after {:?}",
+                    record.osd_id, record.done
                 );
-                get_osd_id_from_path(&mount_point)?
+                record
             }
+            None => RemoveRecord {
+                osd_id: self.resolve_osd_id(dev_path, simulate)?,
+                mapping: encrypted_mapping(dev_path),
+                done: vec![],
+            },
         };
-        debug!("Setting osd {} out", osd_id);
-        osd_out(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())?;
-        debug!("Removing osd {} from crush", osd_id);
-        osd_crush_remove(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())?;
-        debug!("Deleting osd {} auth key", osd_id);
-        auth_del(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())?;
-        debug!("Removing osd {}", osd_id);
-        osd_rm(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())?;
-
-        // Wipe the disk
-        debug!("Erasing disk {}", dev_path.display());
         if !simulate {
-            match block_utils::erase_block_device(&dev_path) {
-                Ok(_) => {
-                    debug!("{} erased", dev_path.display());
-                }
-                Err(e) => {
-                    // At this point the disk is about to be replaced anyways
-                    // so this doesn't really matter
-                    error!("{} failed to erase: {:?}", dev_path.display(), e);
+            log.save(&key, &record)?;
+        }
+        let osd_id = record.osd_id;
+        let mut cleanup = Vec::new();
+        if let Err(e) = self.take_out_osd(&key, &mut record, &mut cleanup, simulate) {
+            if record.done.iter().any(|s| s.irreversible()) {
+                error!(
+                    "Removing osd {} failed: {}.  It carries on after {:?} next time",
+                    osd_id, e, record.done
+                );
+                return Err(e);
+            }
+            error!(
+                "Removing osd {} failed: {}.  Rolling back {:?}",
+                osd_id, e, record.done
+            );
+            while let Some(step) = record.done.pop() {
+                if let Err(undo_err) = self.undo_remove_step(osd_id, step, simulate) {
+                    error!("Unable to roll back {:?}: {}", step, undo_err);
                 }
+            }
+            if !simulate {
+                log.remove(&key)?;
+            }
+            return Err(e);
+        }
+        cleanup.extend(cleanup_osd_host(osd_id, dev_path, simulate));
+        let mut encryption_key = None;
+        if let Some(ref mapping) = record.mapping {
+            // The osd's filesystem or logical volume is on the mapping
+            cleanup.push(cleanup_step(
+                format!("close {}", mapping),
+                close_mapping(mapping, simulate),
+            ));
+            // disk-manager destroys the key once the disk is sanitised
+            if let Some(luks_uuid) = luks_uuid(mapping) {
+                encryption_key = Some(self.key_path(luks_uuid)?);
+            }
+        }
+        for step in &cleanup {
+            match step.error {
+                Some(ref e) => error!("Cleanup step {} failed: {}", step.name, e),
+                None => debug!("Cleanup step {} done", step.name),
             };
         }
+        if !simulate {
+            log.remove(&key)?;
+        }
+        // The disk-manager sanitises the disk once it's out of the cluster
+        Ok(RemovedDisk {
+            osd_id: if record.done.contains(&RemoveStep::Destroy) {
+                Some(osd_id)
+            } else {
+                None
+            },
+            cleanup: cleanup,
+            encryption_key: encryption_key,
+        })
+    }
 
-        Ok(())
+    // The steps of remove_osd that change the cluster
+    fn take_out_osd(
+        &self,
+        key: &str,
+        record: &mut RemoveRecord,
+        cleanup: &mut Vec<CleanupStep>,
+        simulate: bool,
+    ) -> Result<(), String> {
+        let log = self.transaction_log();
+        let osd_id = record.osd_id;
+        let keep_id = self.config.keep_osd_ids.unwrap_or(true);
+        if keep_id && self.swap_config().noout.is_some() {
+            // The data stays put for a quick swap instead of moving elsewhere
+            log.run_step(key, record, RemoveStep::Noout, simulate, |_| {
+                self.set_noout(osd_id, true, simulate)
+            })?;
+        } else {
+            log.run_step(key, record, RemoveStep::Out, simulate, |_| {
+                debug!("Setting osd {} out", osd_id);
+                osd_out(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())
+            })?;
+        }
+        // A running daemon would mark itself up again.  One that can't be
+        // stopped is reported with the cleanup but doesn't stop the removal
+        log.run_step(key, record, RemoveStep::Stop, simulate, |_| {
+            cleanup.push(cleanup_step(
+                format!("stop ceph-osd {}", osd_id),
+                stop_osd_init(osd_id, simulate),
+            ));
+            Ok(())
+        })?;
+        if keep_id {
+            return log.run_step(key, record, RemoveStep::Destroy, simulate, |_| {
+                self.destroy_osd(osd_id, simulate)
+            });
+        }
+        log.run_step(key, record, RemoveStep::CrushRemove, simulate, |_| {
+            debug!("Removing osd {} from crush", osd_id);
+            osd_crush_remove(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())
+        })?;
+        log.run_step(key, record, RemoveStep::AuthDel, simulate, |_| {
+            debug!("Deleting osd {} auth key", osd_id);
+            auth_del(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())
+        })?;
+        log.run_step(key, record, RemoveStep::Rm, simulate, |_| {
+            debug!("Removing osd {}", osd_id);
+            osd_rm(&self.cluster_handle, osd_id, simulate).map_err(|e| e.to_string())
+        })
+    }
+
+    fn undo_remove_step(
+        &self,
+        osd_id: u64,
+        step: RemoveStep,
+        simulate: bool,
+    ) -> Result<(), String> {
+        match step {
+            RemoveStep::Out => {
+                debug!("Setting osd {} in", osd_id);
+                if simulate {
+                    return Ok(());
+                }
+                let ids = vec![osd_id.to_string()];
+                self.mon_command(&json!({"prefix": "osd in", "ids": ids}))?;
+                Ok(())
+            }
+            RemoveStep::Noout => self.set_noout(osd_id, false, simulate),
+            RemoveStep::Stop => setup_osd_init(osd_id, simulate),
+            // Nothing is rolled back once one of these is done
+            RemoveStep::Destroy
+            | RemoveStep::CrushRemove
+            | RemoveStep::AuthDel
+            | RemoveStep::Rm => Ok(()),
+        }
     }
 }
 
@@ -219,27 +2033,270 @@ impl Backend for CephBackend {
         id: Option<u64>,
         journal: Option<&str>,
         journal_partition: Option<u32>,
+        _replica_set: Option<&[String]>,
         simulate: bool,
     ) -> IOResult<()> {
-        self.add_osd(device, id, simulate)
+        self.add_osd(device, id, journal, journal_partition, simulate)
             .map_err(|e| Error::new(ErrorKind::Other, e))?;
         Ok(())
     }
-    fn remove_disk(&self, device: &Path, simulate: bool) -> IOResult<()> {
+    fn remove_disk(&self, device: &Path, simulate: bool) -> IOResult<RemovedDisk> {
         self.remove_osd(device, simulate)
-            .map_err(|e| Error::new(ErrorKind::Other, e))?;
-        Ok(())
+            .map_err(|e| Error::new(ErrorKind::Other, e))
     }
 
     fn safe_to_remove(&self, device: &Path, simulate: bool) -> IOResult<bool> {
-        let diag_map = DiagMap::new().map_err(|e| Error::new(ErrorKind::Other, e))?;
-        debug!("Checking if a disk is safe to remove from ceph");
-        match diag_map.exhaustive_diag(Format::Json) {
-            Status::Safe => return Ok(true),
-            Status::NonSafe => return Ok(false),
-            Status::Unknown => return Ok(false),
+        Ok(self.removal_check(device, simulate)?.safe)
+    }
+
+    fn removal_check(&self, device: &Path, simulate: bool) -> IOResult<RemovalCheck> {
+        debug!("Checking if {} is safe to remove from ceph", device.display());
+        let osd_id = self
+            .resolve_osd_id(device, simulate)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        if simulate {
+            return Ok(RemovalCheck {
+                safe: true,
+                reason: None,
+                blocking_pgs: vec![],
+            });
+        }
+        if self.swap_config().drain.unwrap_or(false) {
+            if let Some(check) = self.drain(osd_id)
+                .map_err(|e| Error::new(ErrorKind::Other, e))?
+            {
+                return Ok(check);
+            }
+        }
+        self.osd_removal_check(osd_id)
+            .map_err(|e| Error::new(ErrorKind::Other, e))
+    }
+
+    fn run_periodic(&self) -> IOResult<()> {
+        if let Err(e) = self.unlock_osds() {
+            error!("Unable to unlock encrypted osds: {}", e);
+        }
+        self.step_reweights()
+            .map_err(|e| Error::new(ErrorKind::Other, e))
+    }
+}
+
+// Pools by id with their (size, min_size)
+fn parse_pools(json: &str) -> Result<HashMap<u64, (u64, u64)>, String> {
+    let v: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let mut pools = HashMap::new();
+    if let Some(list) = v.as_array() {
+        for pool in list {
+            if let (Some(id), Some(size), Some(min_size)) = (
+                pool["pool"].as_u64(),
+                pool["size"].as_u64(),
+                pool["min_size"].as_u64(),
+            ) {
+                pools.insert(id, (size, min_size));
+            }
+        }
+    }
+    Ok(pools)
+}
+
+#[derive(Debug)]
+struct PgInfo {
+    pgid: String,
+    state: String,
+    acting: Vec<u64>,
+}
+
+// Parse pg ls-by-osd.  Luminous returns a bare array and later releases
+// wrap the same entries in pg_stats
+fn parse_pgs(json: &str) -> Result<Vec<PgInfo>, String> {
+    let v: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let list = match v.as_array() {
+        Some(list) => list.clone(),
+        None => v["pg_stats"].as_array().cloned().unwrap_or_default(),
+    };
+    Ok(list
+        .iter()
+        .filter_map(|pg| {
+            let pgid = pg["pgid"].as_str()?;
+            Some(PgInfo {
+                pgid: pgid.to_string(),
+                state: pg["state"].as_str().unwrap_or("unknown").to_string(),
+                acting: pg["acting"]
+                    .as_array()
+                    .map(|a| a.iter().filter_map(|o| o.as_u64()).collect())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect())
+}
+
+// An empty slot in an erasure coded pg's acting set
+const CRUSH_ITEM_NONE: u64 = 2147483647;
+
+// The pgs that would be inactive or below min_size without osd_id
+fn blocking_pgs(osd_id: u64, pgs: &[PgInfo], pools: &HashMap<u64, (u64, u64)>) -> Vec<BlockingPg> {
+    let mut blocking = Vec::new();
+    for pg in pgs {
+        if !pg.state.split('+').any(|s| s == "active") {
+            blocking.push(BlockingPg {
+                pgid: pg.pgid.clone(),
+                state: pg.state.clone(),
+                reason: "Not active".to_string(),
+            });
+            continue;
+        }
+        let pool_id = pg.pgid
+            .split('.')
+            .next()
+            .and_then(|p| u64::from_str(p).ok());
+        let min_size = match pool_id.and_then(|id| pools.get(&id)) {
+            Some(&(_, min_size)) => min_size,
+            None => continue,
         };
+        let remaining = pg.acting
+            .iter()
+            .filter(|o| **o != osd_id && **o != CRUSH_ITEM_NONE)
+            .count() as u64;
+        if remaining < min_size {
+            blocking.push(BlockingPg {
+                pgid: pg.pgid.clone(),
+                state: pg.state.clone(),
+                reason: format!(
+                    "Would be left with {} of the {} copies pool {} needs",
+                    remaining,
+                    min_size,
+                    pool_id.unwrap_or(0)
+                ),
+            });
+        }
+    }
+    blocking
+}
+
+// Ceph's convention is a crush weight of the osd's size in TiB
+fn tib_weight(capacity: u64) -> f64 {
+    capacity as f64 / 1_099_511_627_776_f64
+}
+
+fn device_class(media_type: &block_utils::MediaType) -> Option<&'static str> {
+    match *media_type {
+        block_utils::MediaType::Rotational => Some("hdd"),
+        block_utils::MediaType::SolidState => Some("ssd"),
+        block_utils::MediaType::NVME => Some("nvme"),
+        _ => None,
+    }
+}
+
+// Fill in anything location leaves out from the inventory entry
+fn merge_location(location: CrushLocation, inventory: CrushLocation) -> CrushLocation {
+    CrushLocation {
+        root: location.root.or(inventory.root),
+        datacenter: location.datacenter.or(inventory.datacenter),
+        row: location.row.or(inventory.row),
+        rack: location.rack.or(inventory.rack),
+    }
+}
+
+// The bucket=name arguments of osd crush add
+fn crush_location_args(location: &CrushLocation, hostname: &str) -> Vec<String> {
+    let mut args = vec![format!(
+        "root={}",
+        location.root.as_ref().map(|r| r.as_str()).unwrap_or("default")
+    )];
+    if let Some(ref datacenter) = location.datacenter {
+        args.push(format!("datacenter={}", datacenter));
+    }
+    if let Some(ref row) = location.row {
+        args.push(format!("row={}", row));
+    }
+    if let Some(ref rack) = location.rack {
+        args.push(format!("rack={}", rack));
+    }
+    args.push(format!("host={}", hostname));
+    args
+}
+
+// The weight after one more step from current toward target
+fn next_weight(current: f64, target: f64, step: f64) -> f64 {
+    if step <= 0.0 {
+        return target;
+    }
+    if current < target {
+        (current + step).min(target)
+    } else {
+        (current - step).max(target)
+    }
+}
+
+// The crush weight of an osd in the output of osd tree
+fn parse_crush_weight(json: &str, osd_id: u64) -> Option<f64> {
+    let v: Value = serde_json::from_str(json).ok()?;
+    v["nodes"]
+        .as_array()?
+        .iter()
+        .find(|n| n["type"].as_str() == Some("osd") && n["id"].as_u64() == Some(osd_id))
+        .and_then(|n| n["crush_weight"].as_f64())
+}
+
+// How many pgs aren't active+clean in the output of pg stat.  Later releases
+// wrap the counts in pg_summary
+fn parse_unclean_pgs(json: &str) -> Result<u64, String> {
+    let v: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let summary = if v["pg_summary"].is_object() {
+        &v["pg_summary"]
+    } else {
+        &v
+    };
+    let total = summary["num_pgs"]
+        .as_u64()
+        .ok_or_else(|| "pg stat has no num_pgs".to_string())?;
+    let clean: u64 = summary["num_pg_by_state"]
+        .as_array()
+        .map(|states| {
+            states
+                .iter()
+                .filter(|s| s["name"].as_str() == Some("active+clean"))
+                .filter_map(|s| s["num"].as_u64())
+                .sum()
+        })
+        .unwrap_or(0);
+    Ok(total.saturating_sub(clean))
+}
+
+// Is the osd marked destroyed in the output of osd dump?
+fn is_osd_destroyed(json: &str, osd_id: u64) -> Result<bool, String> {
+    let v: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let osds = match v["osds"].as_array() {
+        Some(osds) => osds,
+        None => return Err("osd dump has no osds".to_string()),
+    };
+    Ok(osds
+        .iter()
+        .filter(|osd| osd["osd"].as_u64() == Some(osd_id))
+        .flat_map(|osd| osd["state"].as_array().cloned().unwrap_or_default())
+        .any(|state| state.as_str() == Some("destroyed")))
+}
+
+// Find the osd on hostname built on dev_name in the output of osd metadata
+fn parse_osd_metadata(json: &str, hostname: &str, dev_name: &str) -> Option<u64> {
+    let v: Value = serde_json::from_str(json).ok()?;
+    for osd in v.as_array()? {
+        if osd["hostname"].as_str() != Some(hostname) {
+            continue;
+        }
+        let on_device = [
+            "backend_filestore_dev_node",
+            "bluestore_bdev_dev_node",
+            "devices",
+        ].iter()
+            .filter_map(|key| osd[*key].as_str())
+            .flat_map(|nodes| nodes.split(','))
+            .any(|node| node.trim().trim_start_matches("/dev/") == dev_name);
+        if on_device {
+            return osd["id"].as_u64();
+        }
     }
+    None
 }
 
 // A fallback function to get the osd id from the mount path.  This isn't
@@ -290,9 +2347,21 @@ fn save_keyring(osd_id: u64, key: &str, simulate: bool) -> IOResult<()> {
 fn add_osd_to_fstab(
     device_info: &block_utils::Device,
     osd_id: u64,
+    encrypted: bool,
     simulate: bool,
 ) -> Result<(), String> {
     let fstab = FsTab::default();
+    let mut mount_options: Vec<String> = vec![
+        "noatime".into(),
+        "inode64".into(),
+        "attr2".into(),
+        "logbsize=256k".into(),
+        "noquota".into(),
+    ];
+    if encrypted {
+        // The mapping isn't open at boot.  Don't hold the boot up waiting
+        mount_options.push("nofail".into());
+    }
     let fstab_entry = fstab::FsEntry {
         fs_spec: format!(
             "UUID={}",
@@ -300,13 +2369,7 @@ fn add_osd_to_fstab(
         ),
         mountpoint: PathBuf::from(&format!("/var/lib/ceph/osd/ceph-{}", osd_id)),
         vfs_type: device_info.fs_type.to_string(),
-        mount_options: vec![
-            "noatime".into(),
-            "inode64".into(),
-            "attr2".into(),
-            "logbsize=256k".into(),
-            "noquota".into(),
-        ],
+        mount_options: mount_options,
         dump: false,
         fsck_order: 2,
     };
@@ -321,6 +2384,137 @@ fn add_osd_to_fstab(
     Ok(())
 }
 
+fn cleanup_step(name: String, result: Result<(), String>) -> CleanupStep {
+    CleanupStep {
+        name: name,
+        error: result.err(),
+    }
+}
+
+// Undo everything adding the osd did to this host.  Every step is tried
+// even if an earlier one failed.  A dead disk often can't be unmounted
+// cleanly but its fstab entry still has to go
+fn cleanup_osd_host(osd_id: u64, dev_path: &Path, simulate: bool) -> Vec<CleanupStep> {
+    let mut steps = Vec::new();
+    let osd_dir = PathBuf::from(format!("/var/lib/ceph/osd/ceph-{}", osd_id));
+
+    let mut units = vec![format!("ceph-osd@{}", osd_id)];
+    // Bluestore osds are brought up at boot by ceph-volume
+    if let Ok(fsid) = read_trimmed(&osd_dir.join("fsid")) {
+        units.push(format!("ceph-volume@lvm-{}-{}", osd_id, fsid));
+    }
+    if let Ok(Daemon::Systemd) = detect_daemon() {
+        for unit in units {
+            let result = run_cmd("systemctl", &["disable", &unit], simulate);
+            steps.push(cleanup_step(format!("disable {}", unit), result));
+        }
+    }
+
+    let keyring = osd_dir.join("keyring");
+    if keyring.exists() {
+        let result = if simulate {
+            Ok(())
+        } else {
+            remove_file(&keyring).map_err(|e| e.to_string())
+        };
+        steps.push(cleanup_step(format!("remove {}", keyring.display()), result));
+    }
+
+    let mounts = read_trimmed(Path::new("/proc/mounts")).unwrap_or_default();
+    if is_mounted(&mounts, &osd_dir) {
+        let dir = osd_dir.to_string_lossy().into_owned();
+        let result = run_cmd("umount", &[&dir], simulate)
+            .or_else(|_| run_cmd("umount", &["-l", &dir], simulate));
+        steps.push(cleanup_step(format!("unmount {}", dir), result));
+    }
+
+    steps.push(cleanup_step(
+        "remove fstab entry".to_string(),
+        remove_fstab_entry(dev_path, &osd_dir, simulate),
+    ));
+
+    if osd_dir.exists() {
+        let result = if simulate {
+            Ok(())
+        } else {
+            remove_dir_all(&osd_dir).map_err(|e| e.to_string())
+        };
+        steps.push(cleanup_step(format!("remove {}", osd_dir.display()), result));
+    }
+    steps
+}
+
+// Whether a mount an add recorded is gone, as it is when the host rebooted
+// before the add finished
+fn mount_lost(record: &AddRecord, osd_dir: &str) -> bool {
+    if !record.done.contains(&AddStep::Mount) {
+        return false;
+    }
+    let mounts = read_trimmed(Path::new("/proc/mounts")).unwrap_or_default();
+    !is_mounted(&mounts, Path::new(osd_dir))
+}
+
+fn read_trimmed(path: &Path) -> IOResult<String> {
+    let mut f = File::open(path)?;
+    let mut s = String::new();
+    f.read_to_string(&mut s)?;
+    Ok(s.trim().to_string())
+}
+
+// Is something mounted at path according to the contents of /proc/mounts?
+fn is_mounted(mounts: &str, path: &Path) -> bool {
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .any(|mountpoint| Path::new(mountpoint) == path)
+}
+
+// Remove the fstab entry add_osd_to_fstab made.  It's found by the
+// filesystem UUID when the disk can still be probed and by the osd's
+// mount point when it can't
+fn remove_fstab_entry(dev_path: &Path, osd_dir: &Path, simulate: bool) -> Result<(), String> {
+    let fstab = FsTab::default();
+    let uuid_spec = block_utils::get_device_info(dev_path)
+        .ok()
+        .and_then(|info| info.id)
+        .map(|id| format!("UUID={}", id.to_hyphenated()));
+    let entries = fstab.get_entries().map_err(|e| e.to_string())?;
+    let spec = entries
+        .iter()
+        .find(|e| Some(&e.fs_spec) == uuid_spec.as_ref())
+        .or_else(|| entries.iter().find(|e| e.mountpoint == osd_dir))
+        .map(|e| e.fs_spec.clone());
+    match spec {
+        Some(spec) => {
+            debug!("Removing fstab entry {}", spec);
+            if !simulate {
+                fstab.remove_entry(&spec).map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        }
+        None => {
+            debug!("No fstab entry for {}", osd_dir.display());
+            Ok(())
+        }
+    }
+}
+
+fn stop_osd_init(osd_id: u64, simulate: bool) -> Result<(), String> {
+    match detect_daemon()? {
+        Daemon::Systemd => run_cmd(
+            "systemctl",
+            &["stop", &format!("ceph-osd@{}", osd_id)],
+            simulate,
+        ),
+        Daemon::Upstart => run_cmd(
+            "stop",
+            &["ceph-osd", &format!("id={}", osd_id)],
+            simulate,
+        ),
+        Daemon::Unknown => Err("Unknown init system.  Cannot stop osd service".to_string()),
+    }
+}
+
 fn setup_osd_init(osd_id: u64, simulate: bool) -> Result<(), String> {
     debug!("Detecting init system");
     let init_daemon = detect_daemon()?;
@@ -370,13 +2564,27 @@ fn settle_udev() -> IOResult<()> {
     Ok(())
 }
 
-// Run ceph-osd --mkfs and return the osd UUID
-fn ceph_mkfs(osd_id: u64, journal: Option<&Path>, simulate: bool) -> Result<(), String> {
+// Run ceph-osd --mkfs.  The fsid has to match the one the osd was
+// created with when a destroyed osd is taken over
+fn ceph_mkfs(
+    osd_id: u64,
+    osd_fsid: &str,
+    journal: Option<&Path>,
+    simulate: bool,
+) -> Result<(), String> {
     debug!("Running ceph-osd --mkfs");
     let journal_str: String;
     let osd_id_str = osd_id.to_string();
 
-    let mut args: Vec<&str> = vec!["--cluster", "ceph", "-i", &osd_id_str, "--mkfs"];
+    let mut args: Vec<&str> = vec![
+        "--cluster",
+        "ceph",
+        "-i",
+        &osd_id_str,
+        "--mkfs",
+        "--osd-uuid",
+        osd_fsid,
+    ];
     if let Some(journal_path) = journal {
         journal_str = journal_path.to_string_lossy().into_owned();
         args.push("--journal");
@@ -393,30 +2601,326 @@ fn ceph_mkfs(osd_id: u64, journal: Option<&Path>, simulate: bool) -> Result<(),
     Ok(())
 }
 
-// Add osds with xfs
-// Jewel or earlier
-fn add_filestore_osd(dev_path: &Path) -> Result<(), String> {
-    //
+// Run ceph-osd --mkfs for a bluestore osd whose block devices are already
+// linked into its directory
+fn bluestore_mkfs(
+    osd_id: u64,
+    osd_fsid: &str,
+    db: Option<&Path>,
+    wal: Option<&Path>,
+    simulate: bool,
+) -> Result<(), String> {
+    debug!("Running ceph-osd --mkfs for bluestore");
+    let osd_id_str = osd_id.to_string();
+    let osd_data = format!("/var/lib/ceph/osd/ceph-{}", osd_id);
+    let db_str: String;
+    let wal_str: String;
+
+    let mut args: Vec<&str> = vec![
+        "--cluster",
+        "ceph",
+        "--osd-objectstore",
+        "bluestore",
+        "--mkfs",
+        "-i",
+        &osd_id_str,
+        "--osd-data",
+        &osd_data,
+        "--osd-uuid",
+        osd_fsid,
+        "--setuser",
+        "ceph",
+        "--setgroup",
+        "ceph",
+    ];
+    if let Some(db_path) = db {
+        db_str = db_path.to_string_lossy().into_owned();
+        args.push("--bluestore-block-db-path");
+        args.push(&db_str);
+    }
+    if let Some(wal_path) = wal {
+        wal_str = wal_path.to_string_lossy().into_owned();
+        args.push("--bluestore-block-wal-path");
+        args.push(&wal_str);
+    }
+    run_cmd("ceph-osd", &args, simulate)
+}
+
+// Mount the tmpfs a bluestore osd directory lives in and link its devices
+// into it
+fn mount_bluestore_dir(
+    osd_dir: &str,
+    block: &Path,
+    db: Option<&Path>,
+    wal: Option<&Path>,
+    simulate: bool,
+) -> Result<(), String> {
+    if !simulate && !Path::new(osd_dir).exists() {
+        debug!("Osd directory {} doesn't exist.  Creating.", osd_dir);
+        create_dir(osd_dir).map_err(|e| e.to_string())?;
+    }
+    run_cmd("mount", &["-t", "tmpfs", "tmpfs", osd_dir], simulate)?;
+    let mut links = vec![("block", block)];
+    if let Some(db) = db {
+        links.push(("block.db", db));
+    }
+    if let Some(wal) = wal {
+        links.push(("block.wal", wal));
+    }
+    for &(name, target) in &links {
+        let link = Path::new(osd_dir).join(name);
+        debug!("Linking {} to {}", link.display(), target.display());
+        if !simulate {
+            symlink(target, &link).map_err(|e| e.to_string())?;
+        }
+        // The osd runs as ceph and has to be able to open its devices
+        run_cmd(
+            "chown",
+            &["-R", "ceph:ceph", &target.to_string_lossy()],
+            simulate,
+        )?;
+    }
+    run_cmd("chown", &["-R", "ceph:ceph", osd_dir], simulate)
+}
+
+// The device name of partition num on device.  Devices that end in a number
+// like nvme0n1 put a p before the partition number
+fn partition_path(device: &str, num: u32) -> String {
+    match device.chars().last() {
+        Some(c) if c.is_digit(10) => format!("{}p{}", device, num),
+        _ => format!("{}{}", device, num),
+    }
+}
+
+// Add a partition to a device shared between osds and return a stable path
+// to it.  The partition gets a uuid of our choosing so it can be found
+// through /dev/disk/by-partuuid no matter what number it ended up with
+fn create_shared_partition(
+    device: &str,
+    size_gb: u64,
+    name: &str,
+    simulate: bool,
+) -> Result<PathBuf, String> {
+    let part_uuid = uuid::Uuid::new_v4().to_hyphenated().to_string();
+    debug!(
+        "Creating a {}GB ceph {} partition on {}",
+        size_gb, name, device
+    );
+    run_cmd(
+        "sgdisk",
+        &[
+            &format!("--new=0:0:+{}G", size_gb),
+            &format!("--change-name=0:ceph {}", name),
+            &format!("--partition-guid=0:{}", part_uuid),
+            "--mbrtogpt",
+            "--",
+            device,
+        ],
+        simulate,
+    )?;
+    if !simulate {
+        run_cmd("partprobe", &[device], simulate)?;
+        let _ = settle_udev();
+    }
+    Ok(PathBuf::from(format!("/dev/disk/by-partuuid/{}", part_uuid)))
+}
+
+// Delete a partition create_shared_partition made.  Its stable path leads
+// back to the device and partition number through sysfs
+fn delete_partition(part: &Path, simulate: bool) -> Result<(), String> {
+    debug!("Deleting partition {}", part.display());
+    if simulate {
+        return Ok(());
+    }
+    let real = part.canonicalize().map_err(|e| e.to_string())?;
+    let name = real
+        .file_name()
+        .ok_or_else(|| format!("{} has no device name", real.display()))?;
+    let sys_path = Path::new("/sys/class/block").join(name);
+    let num = read_trimmed(&sys_path.join("partition")).map_err(|e| e.to_string())?;
+    // /sys/class/block/sdb1 links to .../block/sdb/sdb1
+    let sys_dev = sys_path.canonicalize().map_err(|e| e.to_string())?;
+    let device = match sys_dev.parent().and_then(|p| p.file_name()) {
+        Some(device) => format!("/dev/{}", device.to_string_lossy()),
+        None => return Err(format!("Unable to find the device {} is on", real.display())),
+    };
+    run_cmd("sgdisk", &[&format!("--delete={}", num), &device], simulate)?;
+    run_cmd("partprobe", &[&device], simulate)
+}
+
+fn mapping_name(luks_uuid: &str) -> String {
+    format!("crypt-{}", luks_uuid)
+}
+
+fn mapper_path(luks_uuid: &str) -> PathBuf {
+    PathBuf::from(format!("/dev/mapper/{}", mapping_name(luks_uuid)))
+}
+
+// The LUKS uuid in the name of a mapping encrypt_osd opened
+fn luks_uuid(mapping: &str) -> Option<&str> {
+    if mapping.starts_with("crypt-") && mapping.len() > "crypt-".len() {
+        Some(&mapping["crypt-".len()..])
+    } else {
+        None
+    }
+}
+
+// The device mapper names of what's stacked on a block device.  Ex: the
+// dm-crypt mapping on sdc or the logical volumes on that mapping
+fn dm_holders(dev_name: &str) -> Vec<String> {
+    let holders = Path::new("/sys/class/block").join(dev_name).join("holders");
+    match read_dir(holders) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| read_trimmed(&entry.path().join("dm/name")).ok())
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+// The dm-crypt mapping encrypt_osd opened on a disk if there is one
+fn encrypted_mapping(dev_path: &Path) -> Option<String> {
+    let dev_name = dev_path.file_name()?.to_string_lossy().into_owned();
+    dm_holders(&dev_name)
+        .into_iter()
+        .find(|name| luks_uuid(name).is_some())
+}
+
+// The (device, LUKS uuid) of every container in the output of
+// `blkid -t TYPE=crypto_LUKS -o export`
+fn parse_luks_devices(output: &str) -> Vec<(String, String)> {
+    let mut devices = Vec::new();
+    for block in output.split("\n\n") {
+        let mut dev = None;
+        let mut uuid = None;
+        for line in block.lines() {
+            let mut parts = line.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("DEVNAME"), Some(value)) => dev = Some(value.to_string()),
+                (Some("UUID"), Some(value)) => uuid = Some(value.to_string()),
+                _ => {}
+            };
+        }
+        if let (Some(dev), Some(uuid)) = (dev, uuid) {
+            devices.push((dev, uuid));
+        }
+    }
+    devices
+}
+
+// The ceph-volume tags of the first logical volume in `lvs -o lv_tags`.
+// Ex: ceph.osd_id=3,ceph.type=block
+fn parse_lv_tags(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|tag| {
+            let mut parts = tag.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => Some((name.to_string(), value.to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// Close a dm-crypt mapping.  Logical volumes on it hold it open so they're
+// removed first
+fn close_mapping(mapping: &str, simulate: bool) -> Result<(), String> {
+    let dm_name = Path::new("/dev/mapper")
+        .join(mapping)
+        .canonicalize()
+        .ok()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()));
+    if let Some(dm_name) = dm_name {
+        for holder in dm_holders(&dm_name) {
+            run_cmd("dmsetup", &["remove", &holder], simulate)?;
+        }
+    }
+    run_cmd("cryptsetup", &["luksClose", mapping], simulate)
+}
+
+// A random key for a LUKS container.  It's hex so it can be kept in Vault
+fn new_secret() -> Result<String, String> {
+    let mut f = File::open("/dev/urandom").map_err(|e| e.to_string())?;
+    let mut buf = [0u8; 64];
+    f.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Like run_cmd but feeds input to the command.  Keys are passed this way so
+// they never show up in the process list
+fn run_cmd_with_input(
+    cmd: &str,
+    args: &[&str],
+    input: &str,
+    simulate: bool,
+) -> Result<(), String> {
+    debug!("cmd: {} {:?}", cmd, args);
+    if simulate {
+        return Ok(());
+    }
+    let mut child = Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Unable to run {}: {}", cmd, e))?;
+    {
+        // stdin has to be closed for the command to see the end of the input
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| format!("Unable to write to {}", cmd))?;
+        stdin.write_all(input.as_bytes()).map_err(|e| e.to_string())?;
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
     Ok(())
 }
 
-// Add osds with bluestore
-// Luminous or later
-// TODO: Research bluestore creation
-// SO far it looks like ceph-disk prepare makes a 100MB xfs partition
-// and makes a symlink to the block devices by partition_type
-// block -> /dev/disk/by-partuuid/71b11da1-70b8-4ab5-ba97-036062e6f061
-// cat /var/lib/ceph/tmp/mnt.sYq7No/block_uuid
-// 71b11da1-70b8-4ab5-ba97-036062e6f061
-// cat /var/lib/ceph/tmp/mnt.sYq7No/ceph_fsid
-// c8bb8cb4-6dda-4a8e-9f14-3e5a8d451cf4
-// root@server:~# cat /var/lib/ceph/tmp/mnt.sYq7No/magic
-// ceph osd volume v026
-// cat /var/lib/ceph/tmp/mnt.sYq7No/type
-// bluestore
-// cat /var/lib/ceph/tmp/mnt.sYq7No/fsid
-// a848a7ba-e1c1-4df2-aef5-58895d77895a
-fn add_bluestore_osd(dev_path: &Path) -> Result<(), String> {
-    //
+// Run a command that only looks at things and return what it printed
+fn cmd_output(cmd: &str, args: &[&str]) -> Result<String, String> {
+    debug!("cmd: {} {:?}", cmd, args);
+    let output = Command::new(cmd)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Unable to run {}: {}", cmd, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn run_cmd(cmd: &str, args: &[&str], simulate: bool) -> Result<(), String> {
+    debug!("cmd: {} {:?}", cmd, args);
+    if simulate {
+        return Ok(());
+    }
+    let output = Command::new(cmd)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Unable to run {}: {}", cmd, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
     Ok(())
 }
@@ -1,54 +1,696 @@
-extern crate gluster;
-extern crate tempdir;
+extern crate block_utils;
+extern crate fstab;
+extern crate helpers;
+extern crate serde_json;
 
-use backend::Backend;
+use backend::{Backend, RemovedDisk};
 
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
 use std::io::Result;
-use std::path::Path;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-pub struct GlusterBackend;
-/*
-    Add a disk
-    0. Make sure the brick pid isn't running
-    1. Need to know which brick we're replacing.
-      a. Pull from sqlite3
-    2. Create a dir on the gluster mount that doesn't exist
-    Using Temp
-         mkdir /mnt/r2/<name-of-nonexistent-dir>
-         rmdir /mnt/r2/<name-of-nonexistent-dir>
-         setfattr -n trusted.non-existent-key -v abc /mnt/r2
-         setfattr -x trusted.non-existent-key  /mnt/r2
+use self::fstab::FsTab;
+use self::helpers::host_information::Host;
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    static VOLUME_INFO: &str = r#"
+Volume Name: gv0
+Type: Distributed-Replicate
+Volume ID: 8a9b7c1e-61c2-4d3a-9d2c-5e0f3b1a2c4d
+Status: Started
+Snapshot Count: 0
+Number of Bricks: 2 x 3 = 6
+Transport-type: tcp
+Bricks:
+Brick1: server1:/bricks/sdb/data
+Brick2: server2:/bricks/sdb/data
+Brick3: server3:/bricks/sdb/data
+Brick4: server1:/bricks/sdc/data
+Brick5: server2:/bricks/sdc/data
+Brick6: server3:/bricks/sdc/data
+Options Reconfigured:
+transport.address-family: inet
+
+Volume Name: arb
+Type: Replicate
+Volume ID: 1c2d3e4f-0000-4d3a-9d2c-5e0f3b1a2c4d
+Status: Started
+Number of Bricks: 1 x (2 + 1) = 3
+Transport-type: tcp
+Bricks:
+Brick1: server1:/bricks/sdd/data
+Brick2: server2:/bricks/sdd/data
+Brick3: server3:/bricks/arbiter/data (arbiter)
+"#;
+
+    static VOLUME_STATUS: &str = r#"Status of volume: gv0
+Gluster process                             TCP Port  RDMA Port  Online  Pid
+------------------------------------------------------------------------------
+Brick server1:/bricks/sdb/data              49152     0          Y       1234
+Brick server2:/bricks/sdb/data              49152     0          Y       2345
+Brick server3:/bricks/sdb/data              N/A       N/A        N       N/A
+Brick server1:/bricks/a/very/long/brick/pat
+h/data                                      49153     0          Y       1240
+Self-heal Daemon on localhost               N/A       N/A        Y       3456
+
+Task Status of Volume gv0
+------------------------------------------------------------------------------
+There are no active volume tasks
+"#;
+
+    static HEAL_INFO: &str = r#"Brick server1:/bricks/sdb/data
+Status: Connected
+Number of entries: 0
+
+Brick server2:/bricks/sdb/data
+/dir/file1
+/dir/file2
+Status: Connected
+Number of entries: 2
+
+Brick server3:/bricks/sdb/data
+Status: Transport endpoint is not connected
+Number of entries: -
+"#;
+
+    #[test]
+    fn test_parse_volume_info() {
+        let volumes = super::parse_volume_info(VOLUME_INFO);
+        assert_eq!(volumes.len(), 2);
+        assert_eq!(volumes[0].name, "gv0");
+        assert_eq!(volumes[0].volume_type, "Distributed-Replicate");
+        assert_eq!(volumes[0].replica, Some(3));
+        assert_eq!(volumes[0].bricks.len(), 6);
+        assert_eq!(
+            volumes[0].replica_set("server2:/bricks/sdc/data"),
+            vec![
+                "server1:/bricks/sdc/data".to_string(),
+                "server2:/bricks/sdc/data".to_string(),
+                "server3:/bricks/sdc/data".to_string(),
+            ]
+        );
+        assert_eq!(volumes[1].replica, Some(3));
+        assert_eq!(volumes[1].bricks[2], "server3:/bricks/arbiter/data");
+    }
+
+    #[test]
+    fn test_parse_volume_status() {
+        let status = super::parse_volume_status(VOLUME_STATUS);
+        assert_eq!(status.len(), 4);
+        assert_eq!(status[0].brick, "server1:/bricks/sdb/data");
+        assert!(status[0].online);
+        assert_eq!(status[0].pid, Some(1234));
+        assert!(!status[2].online);
+        assert_eq!(status[2].pid, None);
+        assert_eq!(
+            status[3].brick,
+            "server1:/bricks/a/very/long/brick/path/data"
+        );
+        assert_eq!(status[3].pid, Some(1240));
+    }
+
+    #[test]
+    fn test_parse_heal_info() {
+        let heal = super::parse_heal_info(HEAL_INFO);
+        assert_eq!(heal.get("server1:/bricks/sdb/data"), Some(&Some(0)));
+        assert_eq!(heal.get("server2:/bricks/sdb/data"), Some(&Some(2)));
+        assert_eq!(heal.get("server3:/bricks/sdb/data"), Some(&None));
+    }
+
+    #[test]
+    fn test_find_brick() {
+        let volumes = super::parse_volume_info(VOLUME_INFO);
+        let hostnames = vec!["server1".to_string()];
+        let mounts = "/dev/sda1 / ext4 rw 0 0\n\
+                      /dev/sdc1 /bricks/sdc xfs rw,noatime 0 0\n\
+                      /dev/sdca /bricks/sdca xfs rw,noatime 0 0\n";
+        let (volume, brick, mount) =
+            super::find_brick(&volumes, &hostnames, mounts, Path::new("/dev/sdc")).unwrap();
+        assert_eq!(volume, "gv0");
+        assert_eq!(brick, "server1:/bricks/sdc/data");
+        assert_eq!(mount, "/bricks/sdc");
+        assert!(super::find_brick(&volumes, &hostnames, mounts, Path::new("/dev/sda")).is_none());
+        assert!(super::find_brick(&volumes, &hostnames, mounts, Path::new("/dev/sdd")).is_none());
+    }
+}
 
+/*
     Remove a disk
-    1. Kill the brick pid
-    2. wipe it
-    3. Record in sqlite where it was mounted so we can replace it
-    later
+    1. Find the brick on the disk through its mount point
+    2. Kill the brick pid
+    3. Unmount it and remove its fstab entry
+    4. Record the volume, brick and mount path so the brick can be rebuilt
+
+    Add a disk
+    1. Look up the brick that used to live in the slot
+    2. Format the disk, mount it where the old brick was mounted and add the
+       mount to fstab so it comes back after a reboot
+    3. reset-brick if the brick path is unchanged or replace-brick onto the
+       new path from the replica_set.  Self heal fills the new brick.
 */
 
+/// Gluster cluster
+pub struct GlusterBackend {
+    /// Where bricks on removed disks are remembered until their replacement
+    /// is added
+    record_file: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+struct GlusterConfig {
+    /// Default: /var/lib/bynar/gluster_bricks.json
+    record_file: Option<String>,
+}
+
+/// A brick on a disk that was removed
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BrickRecord {
+    volume: String,
+    brick: String,
+    mount_path: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Volume {
+    name: String,
+    volume_type: String,
+    // Bricks in each replica set.  Arbiters count
+    replica: Option<usize>,
+    bricks: Vec<String>,
+}
+
+impl Volume {
+    // The bricks in the same replica set as brick.  They're listed in order
+    // so every replica sized run of bricks is a set
+    fn replica_set(&self, brick: &str) -> Vec<String> {
+        let replica = match self.replica {
+            Some(replica) if replica > 0 => replica,
+            _ => return vec![brick.to_string()],
+        };
+        match self.bricks.iter().position(|b| b == brick) {
+            Some(i) => {
+                let start = (i / replica) * replica;
+                self.bricks
+                    .iter()
+                    .skip(start)
+                    .take(replica)
+                    .cloned()
+                    .collect()
+            }
+            None => vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct BrickStatus {
+    brick: String,
+    online: bool,
+    pid: Option<u32>,
+}
+
+// "2 x 3 = 6" is 3 per set and "1 x (2 + 1) = 3" is 2 data and an arbiter
+fn parse_replica(count: &str) -> Option<usize> {
+    let x = count.find(" x ")?;
+    let end = count.find('=').unwrap_or(count.len());
+    count[x + 3..end]
+        .trim()
+        .trim_matches(|c| c == '(' || c == ')')
+        .split('+')
+        .map(|n| n.trim().parse::<usize>().ok())
+        .sum()
+}
+
+/// Parse the output of `gluster volume info`
+fn parse_volume_info(output: &str) -> Vec<Volume> {
+    let mut volumes: Vec<Volume> = Vec::new();
+    for line in output.lines() {
+        let mut parts = line.splitn(2, ": ");
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        if key == "Volume Name" {
+            volumes.push(Volume {
+                name: value.to_string(),
+                volume_type: String::new(),
+                replica: None,
+                bricks: vec![],
+            });
+            continue;
+        }
+        let volume = match volumes.last_mut() {
+            Some(volume) => volume,
+            None => continue,
+        };
+        if key == "Type" {
+            volume.volume_type = value.to_string();
+        } else if key == "Number of Bricks" {
+            volume.replica = parse_replica(value);
+        } else if key.starts_with("Brick") && key[5..].parse::<u32>().is_ok() {
+            // Arbiter bricks are suffixed with (arbiter)
+            let brick = value.split_whitespace().next().unwrap_or("");
+            volume.bricks.push(brick.to_string());
+        }
+    }
+    volumes
+}
+
+/// Parse the output of `gluster volume status <volume>`.  Long brick names
+/// wrap onto the next line
+fn parse_volume_status(output: &str) -> Vec<BrickStatus> {
+    let mut statuses = Vec::new();
+    let mut pending: Option<String> = None;
+    for line in output.lines() {
+        let (name, fields) = match pending.take() {
+            Some(mut name) => {
+                let mut tokens = line.split_whitespace();
+                name.push_str(tokens.next().unwrap_or(""));
+                (name, tokens.map(|t| t.to_string()).collect::<Vec<String>>())
+            }
+            None => {
+                if !line.starts_with("Brick ") {
+                    continue;
+                }
+                let mut tokens = line["Brick ".len()..].split_whitespace();
+                let name = tokens.next().unwrap_or("").to_string();
+                (name, tokens.map(|t| t.to_string()).collect::<Vec<String>>())
+            }
+        };
+        // TCP Port, RDMA Port, Online and Pid
+        if fields.len() < 4 {
+            pending = Some(name);
+            continue;
+        }
+        statuses.push(BrickStatus {
+            brick: name,
+            online: fields[2] == "Y",
+            pid: fields[3].parse().ok(),
+        });
+    }
+    statuses
+}
+
+/// Parse the output of `gluster volume heal <volume> info`.  Bricks that
+/// can't be reached have no count
+fn parse_heal_info(output: &str) -> HashMap<String, Option<u64>> {
+    let mut entries = HashMap::new();
+    let mut brick: Option<String> = None;
+    for line in output.lines() {
+        if line.starts_with("Brick ") {
+            brick = Some(line["Brick ".len()..].trim().to_string());
+        } else if line.starts_with("Number of entries:") {
+            if let Some(b) = brick.take() {
+                entries.insert(b, line["Number of entries:".len()..].trim().parse().ok());
+            }
+        }
+    }
+    entries
+}
+
+// Is source the device or one of its partitions?
+fn is_on_device(source: &str, device: &str) -> bool {
+    if !source.starts_with(device) {
+        return false;
+    }
+    let rest = &source[device.len()..];
+    let rest =
+        if rest.starts_with('p') && device.chars().last().map(|c| c.is_digit(10)) == Some(true) {
+            &rest[1..]
+        } else {
+            rest
+        };
+    rest.chars().all(|c| c.is_digit(10))
+}
+
+/// Find the brick living on a device.  Returns the volume, brick and mount
+fn find_brick(
+    volumes: &[Volume],
+    hostnames: &[String],
+    mounts: &str,
+    device: &Path,
+) -> Option<(String, String, String)> {
+    let device = device.to_string_lossy();
+    for mount in mounts.lines() {
+        let mut fields = mount.split_whitespace();
+        let source = fields.next().unwrap_or("");
+        let mount_path = fields.next().unwrap_or("");
+        if !is_on_device(source, &device) {
+            continue;
+        }
+        for volume in volumes {
+            for brick in &volume.bricks {
+                let mut parts = brick.splitn(2, ':');
+                let host = parts.next().unwrap_or("");
+                let path = parts.next().unwrap_or("");
+                let on_mount = path == mount_path || path.starts_with(&format!("{}/", mount_path));
+                if on_mount && hostnames.iter().any(|h| h == host) {
+                    return Some((volume.name.clone(), brick.clone(), mount_path.to_string()));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn run(cmd: &str, args: &[&str]) -> Result<String> {
+    debug!("cmd: {} {:?}", cmd, args);
+    let output = Command::new(cmd).args(args).output()?;
+    if !output.status.success() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "{} {:?} failed: {}",
+                cmd,
+                args,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// The names gluster might know this host by
+fn local_hostnames() -> Result<Vec<String>> {
+    let host = Host::new()?;
+    let mut names = vec![host.hostname.clone()];
+    if let Ok(fqdn) = run("hostname", &["-f"]) {
+        names.push(fqdn.trim().to_string());
+    }
+    if let Some(short) = host.hostname.split('.').next() {
+        names.push(short.to_string());
+    }
+    Ok(names)
+}
+
+fn read_mounts() -> Result<String> {
+    let mut f = File::open("/proc/mounts")?;
+    let mut mounts = String::new();
+    f.read_to_string(&mut mounts)?;
+    Ok(mounts)
+}
+
+// Mount a brick's disk at boot
+fn add_brick_to_fstab(device: &Path, mount_path: &str) -> Result<()> {
+    let info = block_utils::get_device_info(device)
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let id = info.id.ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("{} has no filesystem UUID", device.display()),
+        )
+    })?;
+    let fstab_entry = fstab::FsEntry {
+        fs_spec: format!("UUID={}", id.to_hyphenated()),
+        mountpoint: PathBuf::from(mount_path),
+        vfs_type: info.fs_type.to_string(),
+        mount_options: vec!["noatime".into(), "inode64".into()],
+        dump: false,
+        fsck_order: 2,
+    };
+    debug!("Saving Fstab entry {:?}", fstab_entry);
+    match FsTab::default()
+        .add_entry(fstab_entry)
+        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?
+    {
+        true => debug!("Fstab entry saved"),
+        false => debug!("Fstab entry was updated"),
+    };
+    Ok(())
+}
+
+// Remove the fstab entry add_brick_to_fstab made.  It's found by the
+// filesystem UUID when the disk can still be probed and by the mount path
+// when it can't
+fn remove_fstab_entry(device: &Path, mount_path: &str) -> Result<()> {
+    let fstab = FsTab::default();
+    let uuid_spec = block_utils::get_device_info(device)
+        .ok()
+        .and_then(|info| info.id)
+        .map(|id| format!("UUID={}", id.to_hyphenated()));
+    let entries = fstab
+        .get_entries()
+        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+    let spec = entries
+        .iter()
+        .find(|e| Some(&e.fs_spec) == uuid_spec.as_ref())
+        .or_else(|| {
+            entries
+                .iter()
+                .find(|e| e.mountpoint == Path::new(mount_path))
+        })
+        .map(|e| e.fs_spec.clone());
+    match spec {
+        Some(spec) => {
+            debug!("Removing fstab entry {}", spec);
+            fstab
+                .remove_entry(&spec)
+                .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        }
+        None => debug!("No fstab entry for {}", mount_path),
+    };
+    Ok(())
+}
+
+impl GlusterBackend {
+    pub fn new(config_dir: Option<&Path>) -> Result<GlusterBackend> {
+        let mut record_file = PathBuf::from("/var/lib/bynar/gluster_bricks.json");
+        if let Some(dir) = config_dir {
+            let config_path = dir.join("gluster.json");
+            if config_path.exists() {
+                let mut f = File::open(&config_path)?;
+                let mut s = String::new();
+                f.read_to_string(&mut s)?;
+                let config: GlusterConfig = serde_json::from_str(&s)?;
+                if let Some(file) = config.record_file {
+                    record_file = PathBuf::from(file);
+                }
+            }
+        }
+        Ok(GlusterBackend { record_file })
+    }
+
+    fn load_records(&self) -> Result<HashMap<String, BrickRecord>> {
+        if !self.record_file.exists() {
+            return Ok(HashMap::new());
+        }
+        let mut f = File::open(&self.record_file)?;
+        let mut s = String::new();
+        f.read_to_string(&mut s)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    fn save_records(&self, records: &HashMap<String, BrickRecord>) -> Result<()> {
+        if let Some(parent) = self.record_file.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut f = File::create(&self.record_file)?;
+        f.write_all(serde_json::to_string_pretty(records)?.as_bytes())?;
+        Ok(())
+    }
+
+    fn local_brick(&self, device: &Path) -> Result<Option<(Volume, String, String)>> {
+        let volumes = parse_volume_info(&run("gluster", &["volume", "info"])?);
+        let found = find_brick(&volumes, &local_hostnames()?, &read_mounts()?, device);
+        Ok(found.and_then(|(name, brick, mount)| {
+            volumes
+                .into_iter()
+                .find(|v| v.name == name)
+                .map(|v| (v, brick, mount))
+        }))
+    }
+}
+
 impl Backend for GlusterBackend {
     fn add_disk(
         &self,
-        _device: &Path,
+        device: &Path,
         _id: Option<u64>,
         _journal: Option<&str>,
         _journal_partition: Option<u32>,
-        _simulate: bool,
+        replica_set: Option<&[String]>,
+        simulate: bool,
     ) -> Result<()> {
+        let dev = device.to_string_lossy().into_owned();
+        let mut records = self.load_records()?;
+        let record = records.get(&dev).cloned().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "No brick was recorded for {}.  Was it removed by bynar?",
+                    dev
+                ),
+            )
+        })?;
+        // The replica_set names where this host's brick should go.  Without
+        // one the old brick is rebuilt where it was
+        let new_brick = match replica_set {
+            Some(set) => {
+                let hostnames = local_hostnames()?;
+                set.iter()
+                    .find(|b| {
+                        hostnames
+                            .iter()
+                            .any(|h| b.split(':').next() == Some(h.as_str()))
+                    })
+                    .cloned()
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("replica_set {:?} has no brick on this host", set),
+                        )
+                    })?
+            }
+            None => record.brick.clone(),
+        };
+        let brick_path = new_brick.splitn(2, ':').nth(1).unwrap_or("").to_string();
+        let mount_path = if new_brick == record.brick {
+            record.mount_path.clone()
+        } else {
+            // Bricks live in a directory on the root of their disk
+            Path::new(&brick_path)
+                .parent()
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_else(|| brick_path.clone())
+        };
+        if simulate {
+            info!(
+                "Would format {} mount it on {} and rebuild {} as {}",
+                dev, mount_path, record.brick, new_brick
+            );
+            return Ok(());
+        }
+        run("mkfs.xfs", &["-f", "-i", "size=512", &dev])?;
+        create_dir_all(&mount_path)?;
+        run("mount", &[&dev, &mount_path])?;
+        add_brick_to_fstab(device, &mount_path)?;
+        create_dir_all(&brick_path)?;
+        if new_brick == record.brick {
+            run(
+                "gluster",
+                &[
+                    "volume",
+                    "reset-brick",
+                    &record.volume,
+                    &record.brick,
+                    &new_brick,
+                    "commit",
+                    "force",
+                ],
+            )?;
+        } else {
+            run(
+                "gluster",
+                &[
+                    "volume",
+                    "replace-brick",
+                    &record.volume,
+                    &record.brick,
+                    &new_brick,
+                    "commit",
+                    "force",
+                ],
+            )?;
+        }
+        info!("{} is back in {} as {}", dev, record.volume, new_brick);
+        records.remove(&dev);
+        self.save_records(&records)?;
         Ok(())
     }
 
     /// Remove a disk from a cluster
     /// If simulate is passed no action should be taken
-    fn remove_disk(&self, _device: &Path, _simulate: bool) -> Result<()> {
-        Ok(())
+    fn remove_disk(&self, device: &Path, simulate: bool) -> Result<RemovedDisk> {
+        let (volume, brick, mount_path) = match self.local_brick(device)? {
+            Some(found) => found,
+            None => {
+                info!(
+                    "{} doesn't hold a brick.  Nothing to remove",
+                    device.display()
+                );
+                return Ok(RemovedDisk::default());
+            }
+        };
+        if simulate {
+            info!(
+                "Would stop {} in {} and remove {} from fstab",
+                brick, volume.name, mount_path
+            );
+            return Ok(RemovedDisk::default());
+        }
+        let status = parse_volume_status(&run("gluster", &["volume", "status", &volume.name])?);
+        if let Some(pid) = status.iter().find(|s| s.brick == brick).and_then(|s| s.pid) {
+            info!("Stopping {} pid {}", brick, pid);
+            run("kill", &[&pid.to_string()])?;
+        }
+        // The disk may be too far gone to unmount cleanly
+        if run("umount", &[&mount_path]).is_err() {
+            run("umount", &["-l", &mount_path])?;
+        }
+        // A stale entry would hold up the next boot waiting for the disk
+        remove_fstab_entry(device, &mount_path)?;
+        let mut records = self.load_records()?;
+        records.insert(
+            device.to_string_lossy().into_owned(),
+            BrickRecord {
+                volume: volume.name,
+                brick,
+                mount_path,
+            },
+        );
+        self.save_records(&records)?;
+        // Bricks don't have ids for the replacement to take over
+        Ok(RemovedDisk::default())
     }
 
     /// Check if it's safe to remove a disk from a cluster
     /// If simulate is passed then this always returns true
     /// Take any actions needed with this call to figure out if a disk is safe
     /// to remove from the cluster.
-    fn safe_to_remove(&self, _device: &Path, _simulate: bool) -> Result<bool> {
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> Result<bool> {
+        if simulate {
+            return Ok(true);
+        }
+        let (volume, brick, _) = match self.local_brick(device)? {
+            Some(found) => found,
+            None => return Ok(true),
+        };
+        if !volume.volume_type.contains("Replicate") {
+            info!(
+                "{} is a {} volume.  Removing {} would lose data",
+                volume.name, volume.volume_type, brick
+            );
+            return Ok(false);
+        }
+        let replica_set = volume.replica_set(&brick);
+        let status = parse_volume_status(&run("gluster", &["volume", "status", &volume.name])?);
+        let heal = parse_heal_info(&run("gluster", &["volume", "heal", &volume.name, "info"])?);
+        for other in replica_set.iter().filter(|b| **b != brick) {
+            let online = status.iter().any(|s| s.brick == *other && s.online);
+            if !online {
+                info!("{} is offline.  Not safe to remove {}", other, brick);
+                return Ok(false);
+            }
+        }
+        for member in &replica_set {
+            match heal.get(member) {
+                Some(&Some(0)) => {}
+                // The failing brick itself may already be unreachable
+                Some(&None) if *member == brick => {}
+                Some(&Some(entries)) => {
+                    info!("{} has {} entries to heal", member, entries);
+                    return Ok(false);
+                }
+                _ => {
+                    info!("Unable to get the heal count of {}", member);
+                    return Ok(false);
+                }
+            }
+        }
         Ok(true)
     }
 }
@@ -1,6 +1,7 @@
 pub mod ceph;
 //#[cfg(feature = "gluster")]
 pub mod gluster;
+pub mod transaction;
 
 use std::io::Result;
 use std::path::Path;
@@ -19,24 +20,83 @@ pub trait Backend {
     /// If simulate is passed no action should be taken
     /// An optional osd_id can be provided to ensure the osd is set to that
     /// An optional journal and partition id can be set
+    /// Gluster can be given the replica set the new brick belongs to
     fn add_disk(
         &self,
         device: &Path,
         id: Option<u64>,
         journal: Option<&str>,
         journal_partition: Option<u32>,
+        replica_set: Option<&[String]>,
         simulate: bool,
     ) -> Result<()>;
 
     /// Remove a disk from a cluster
     /// If simulate is passed no action should be taken
-    fn remove_disk(&self, device: &Path, simulate: bool) -> Result<()>;
+    /// Returns the id the cluster kept for the disk's replacement if any and
+    /// how tidying up the host after the disk went
+    fn remove_disk(&self, device: &Path, simulate: bool) -> Result<RemovedDisk>;
 
     /// Check if it's safe to remove a disk from a cluster
     /// If simulate is passed then this always returns true
     /// Take any actions needed with this call to figure out if a disk is safe
     /// to remove from the cluster.
     fn safe_to_remove(&self, device: &Path, simulate: bool) -> Result<bool>;
+
+    /// Like safe_to_remove but explains what is blocking the removal.
+    /// Backends that can't tell which placement groups are affected fall back
+    /// on a plain yes or no
+    fn removal_check(&self, device: &Path, simulate: bool) -> Result<RemovalCheck> {
+        let safe = self.safe_to_remove(device, simulate)?;
+        Ok(RemovalCheck {
+            safe: safe,
+            reason: None,
+            blocking_pgs: vec![],
+        })
+    }
+
+    /// Work that carries on between requests, like stepping an osd's weight.
+    /// disk-manager calls this every minute
+    fn run_periodic(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A placement group that would be put at risk by removing a disk
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockingPg {
+    pub pgid: String,
+    /// The state the cluster reports for the pg.  Ex: active+clean
+    pub state: String,
+    pub reason: String,
+}
+
+/// The answer to whether a disk can be removed and why not
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemovalCheck {
+    pub safe: bool,
+    pub reason: Option<String>,
+    pub blocking_pgs: Vec<BlockingPg>,
+}
+
+/// One step of tidying up a host after a disk leaves the cluster
+#[derive(Clone, Debug, PartialEq)]
+pub struct CleanupStep {
+    /// What was done.  Ex: unmount /var/lib/ceph/osd/ceph-3
+    pub name: String,
+    /// Why the step failed.  None if it succeeded
+    pub error: Option<String>,
+}
+
+/// What removing a disk did
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RemovedDisk {
+    /// The id the cluster kept for the disk's replacement
+    pub osd_id: Option<u64>,
+    pub cleanup: Vec<CleanupStep>,
+    /// Where the key the disk was encrypted with is kept in Vault.  It's
+    /// destroyed once the disk is sanitised
+    pub encryption_key: Option<String>,
 }
 
 /// The supported backend types
@@ -67,7 +127,9 @@ pub fn load_backend(
     let backend: Box<Backend> = match backend_type {
         &BackendType::Ceph => Box::new(CephBackend::new(config_dir).map_err(|e| e.to_string())?),
         //#[cfg(feature = "gluster")]
-        &BackendType::Gluster => Box::new(GlusterBackend {}),
+        &BackendType::Gluster => {
+            Box::new(GlusterBackend::new(config_dir).map_err(|e| e.to_string())?)
+        }
     };
 
     Ok(backend)
@@ -0,0 +1,195 @@
+//! Adding or removing a disk takes many steps that each change the host or
+//! the cluster.  Backends record each step here as it finishes so a failure
+//! can roll the finished steps back and a crash leaves a record the next
+//! attempt picks up from.
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{create_dir_all, rename, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use self::serde_json::Value;
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::{Steps, TransactionLog};
+
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Step {
+        Format,
+        Mount,
+        Mkfs,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Record {
+        done: Vec<Step>,
+    }
+
+    impl Steps for Record {
+        type Step = Step;
+        fn done(&mut self) -> &mut Vec<Step> {
+            &mut self.done
+        }
+    }
+
+    #[test]
+    fn test_transaction_log() {
+        let dir = TempDir::new("bynar").expect("Temp dir creation failed");
+        let log = TransactionLog::new(&dir.path().join("state/transactions.json"));
+        assert_eq!(log.get::<Record>("add /dev/sdc").unwrap(), None);
+
+        let record = Record {
+            done: vec![Step::Format],
+        };
+        log.save("add /dev/sdc", &record).unwrap();
+        log.save("remove /dev/sdd", &Record { done: vec![] }).unwrap();
+        // A new log on the same file sees what was saved before a restart
+        let reopened = TransactionLog::new(&dir.path().join("state/transactions.json"));
+        assert_eq!(reopened.get("add /dev/sdc").unwrap(), Some(record));
+
+        reopened.remove("add /dev/sdc").unwrap();
+        assert_eq!(log.get::<Record>("add /dev/sdc").unwrap(), None);
+        assert!(log.get::<Record>("remove /dev/sdd").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_run_step() {
+        let dir = TempDir::new("bynar").expect("Temp dir creation failed");
+        let log = TransactionLog::new(&dir.path().join("transactions.json"));
+        let mut record = Record {
+            done: vec![Step::Format],
+        };
+        let mut ran = vec![];
+        // A step an earlier attempt finished is skipped
+        log.run_step("add /dev/sdc", &mut record, Step::Format, false, |_| {
+            ran.push(Step::Format);
+            Ok(())
+        }).unwrap();
+        log.run_step("add /dev/sdc", &mut record, Step::Mount, false, |_| {
+            ran.push(Step::Mount);
+            Ok(())
+        }).unwrap();
+        // A failed step isn't recorded
+        let result = log.run_step("add /dev/sdc", &mut record, Step::Mkfs, false, |_| {
+            Err("mkfs failed".to_string())
+        });
+        assert_eq!(result, Err("Mkfs step failed: mkfs failed".to_string()));
+        assert_eq!(ran, vec![Step::Mount]);
+        assert_eq!(
+            log.get::<Record>("add /dev/sdc").unwrap(),
+            Some(Record {
+                done: vec![Step::Format, Step::Mount],
+            })
+        );
+    }
+}
+
+/// An operation record that lists the steps finished so far
+pub trait Steps: Serialize {
+    type Step: Copy + Debug + PartialEq;
+    fn done(&mut self) -> &mut Vec<Self::Step>;
+}
+
+/// Records of unfinished operations kept in a json file, keyed by
+/// operation and device.  Ex: add /dev/sdc
+pub struct TransactionLog {
+    path: PathBuf,
+}
+
+impl TransactionLog {
+    pub fn new(path: &Path) -> TransactionLog {
+        TransactionLog {
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn load(&self) -> Result<HashMap<String, Value>, String> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+        let mut f = File::open(&self.path).map_err(|e| e.to_string())?;
+        let mut s = String::new();
+        f.read_to_string(&mut s).map_err(|e| e.to_string())?;
+        serde_json::from_str(&s).map_err(|e| e.to_string())
+    }
+
+    // Write a new file and rename it over the old one so a crash part way
+    // through a write can't lose the records
+    fn store(&self, records: &HashMap<String, Value>) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut f = File::create(&tmp).map_err(|e| e.to_string())?;
+            let json = serde_json::to_string_pretty(records).map_err(|e| e.to_string())?;
+            f.write_all(json.as_bytes()).map_err(|e| e.to_string())?;
+            f.sync_all().map_err(|e| e.to_string())?;
+        }
+        rename(&tmp, &self.path).map_err(|e| e.to_string())
+    }
+
+    /// The record of an unfinished operation
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, String> {
+        match self.load()?.remove(key) {
+            Some(value) => Ok(Some(
+                serde_json::from_value(value).map_err(|e| e.to_string())?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save<T: Serialize>(&self, key: &str, record: &T) -> Result<(), String> {
+        let mut records = self.load()?;
+        records.insert(
+            key.to_string(),
+            serde_json::to_value(record).map_err(|e| e.to_string())?,
+        );
+        self.store(&records)
+    }
+
+    /// The operation finished or was rolled back
+    pub fn remove(&self, key: &str) -> Result<(), String> {
+        let mut records = self.load()?;
+        if records.remove(key).is_some() {
+            self.store(&records)?;
+        }
+        Ok(())
+    }
+
+    /// Run a step unless an earlier attempt already finished it and save the
+    /// record once it's done.  Nothing is saved when simulating
+    pub fn run_step<R, F>(
+        &self,
+        key: &str,
+        record: &mut R,
+        step: R::Step,
+        simulate: bool,
+        f: F,
+    ) -> Result<(), String>
+    where
+        R: Steps,
+        F: FnOnce(&mut R) -> Result<(), String>,
+    {
+        if record.done().contains(&step) {
+            debug!("{} {:?} was already done", key, step);
+            return Ok(());
+        }
+        f(record).map_err(|e| format!("{:?} step failed: {}", step, e))?;
+        record.done().push(step);
+        if simulate {
+            return Ok(());
+        }
+        self.save(key, record)
+    }
+}
@@ -12,21 +12,24 @@ extern crate protobuf;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 extern crate simplelog;
 extern crate zmq;
 
 mod backend;
+mod sanitize;
 
 use std::fs::File;
 use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::path::Path;
+use std::result::Result as StdResult;
 use std::str::FromStr;
 use std::thread;
 use std::time::Duration;
 
-use api::service::{Disk, DiskType, Disks, Op, OpBoolResult, OpResult, Partition, PartitionInfo,
-                   ResultType};
+use api::service::{BlockingPg, CleanupStep, Disk, DiskType, Disks, Led, Op, OpBoolResult,
+                   OpResult, Partition, PartitionInfo, ResultType};
 use backend::BackendType;
 use block_utils::{Device, MediaType};
 use clap::{App, Arg};
@@ -44,6 +47,8 @@ struct DiskManagerConfig {
     backend: BackendType,
     vault_token: Option<String>,
     vault_endpoint: Option<String>,
+    // How removed disks are sanitised
+    sanitize: Option<sanitize::SanitizeConfig>,
 }
 
 fn convert_media_to_disk_type(m: MediaType) -> DiskType {
@@ -108,6 +113,24 @@ fn setup_curve(s: &mut Socket, config_dir: &Path, vault: bool) -> Result<()> {
     Ok(())
 }
 
+// Give the backend a chance to carry on with work that outlives a request
+// such as stepping an osd's weight
+fn start_periodic(backend_type: &BackendType, config_dir: &Path) {
+    let backend_type = backend_type.clone();
+    let config_dir = config_dir.to_path_buf();
+    thread::spawn(move || loop {
+        match backend::load_backend(&backend_type, Some(&config_dir)) {
+            Ok(backend) => {
+                if let Err(e) = backend.run_periodic() {
+                    error!("Periodic backend work failed: {}", e);
+                }
+            }
+            Err(e) => error!("Unable to load backend for periodic work: {}", e),
+        };
+        thread::sleep(Duration::from_secs(60));
+    });
+}
+
 /*
  Server that manages disks
  */
@@ -129,6 +152,7 @@ fn listen(
             .bind(&format!("tcp://{}:5555", listen_address))
             .is_ok()
     );
+    start_periodic(&backend_type, config_dir);
 
     loop {
         let msg = responder.recv_bytes(0)?;
@@ -160,6 +184,11 @@ fn listen(
                 } else {
                     None
                 };
+                let replica_set = if operation.get_replica_set().is_empty() {
+                    None
+                } else {
+                    Some(operation.get_replica_set())
+                };
                 if !operation.has_disk() {
                     error!("Add operation must include disk field.  Ignoring request");
                     continue;
@@ -171,6 +200,7 @@ fn listen(
                     id,
                     journal,
                     journal_partition,
+                    replica_set,
                     config_dir,
                 ) {
                     Ok(_) => {
@@ -204,6 +234,7 @@ fn listen(
                     operation.get_disk(),
                     &backend_type,
                     config_dir,
+                    operation.get_simulate(),
                 ) {
                     Ok(_) => {
                         info!("Remove disk successful");
@@ -225,10 +256,45 @@ fn listen(
                     config_dir,
                 ) {
                     Ok(_) => {
-                        info!("Remove disk successful");
+                        info!("Safe to remove check successful");
                     }
                     Err(e) => {
-                        error!("Remove disk error: {:?}", e);
+                        error!("Safe to remove check error: {:?}", e);
+                    }
+                };
+            }
+            Op::SetLed => {
+                if !operation.has_disk() || !operation.has_led() {
+                    error!("SetLed operation must include disk and led fields.  Ignoring request");
+                    continue;
+                }
+                match set_led(
+                    &mut responder,
+                    operation.get_disk(),
+                    operation.get_led(),
+                    operation.get_led_on(),
+                ) {
+                    Ok(_) => {
+                        info!("Set led successful");
+                    }
+                    Err(e) => {
+                        error!("Set led error: {:?}", e);
+                    }
+                };
+            }
+            Op::GetWipeCertificate => {
+                if !operation.has_disk() {
+                    error!(
+                        "GetWipeCertificate operation must include disk field.  Ignoring request"
+                    );
+                    continue;
+                }
+                match get_wipe_certificate(&mut responder, operation.get_disk(), config_dir) {
+                    Ok(_) => {
+                        info!("Get wipe certificate successful");
+                    }
+                    Err(e) => {
+                        error!("Get wipe certificate error: {:?}", e);
                     }
                 };
             }
@@ -244,6 +310,7 @@ fn add_disk(
     id: Option<u64>,
     journal: Option<&str>,
     journal_partition: Option<u32>,
+    replica_set: Option<&[String]>,
     config_dir: &Path,
 ) -> Result<()> {
     let backend = backend::load_backend(backend, Some(config_dir))
@@ -251,7 +318,14 @@ fn add_disk(
     let mut result = OpResult::new();
 
     //Send back OpResult
-    match backend.add_disk(&Path::new(d), id, journal, journal_partition, false) {
+    match backend.add_disk(
+        &Path::new(d),
+        id,
+        journal,
+        journal_partition,
+        replica_set,
+        false,
+    ) {
         Ok(_) => {
             result.set_result(ResultType::OK);
         }
@@ -297,6 +371,18 @@ fn get_disks() -> Result<Vec<Disk>> {
         if let Some(serial) = device.serial_number {
             d.set_serial_number(serial);
         }
+        match helpers::enclosure::find_slot(&dev_path) {
+            Ok(Some(slot)) => {
+                d.set_enclosure(slot.enclosure_id.unwrap_or(slot.enclosure));
+                if let Some(number) = slot.slot {
+                    d.set_enclosure_slot(number);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Unable to find the enclosure slot of {}: {}", dev_path.display(), e);
+            }
+        };
         disks.push(d);
     }
 
@@ -341,14 +427,70 @@ fn list_disks(s: &mut Socket) -> Result<()> {
     Ok(())
 }
 
-fn remove_disk(s: &mut Socket, d: &str, backend: &BackendType, config_dir: &Path) -> Result<()> {
+fn remove_disk(
+    s: &mut Socket,
+    d: &str,
+    backend: &BackendType,
+    config_dir: &Path,
+    simulate: bool,
+) -> Result<()> {
     //Returns OpResult
+    let config: DiskManagerConfig =
+        helpers::load_config(&config_dir.to_string_lossy(), "disk-manager.json")?;
     let backend = backend::load_backend(backend, Some(config_dir))
         .map_err(|e| Error::new(ErrorKind::Other, e))?;
     let mut result = OpResult::new();
-    match backend.remove_disk(&Path::new(d), false) {
-        Ok(_) => {
+    match backend.remove_disk(&Path::new(d), simulate) {
+        Ok(removed) => {
             result.set_result(ResultType::OK);
+            if let Some(osd_id) = removed.osd_id {
+                result.set_osd_id(osd_id);
+            }
+            let mut steps: Vec<CleanupStep> = removed
+                .cleanup
+                .into_iter()
+                .map(|step| {
+                    let mut cleanup_step = CleanupStep::new();
+                    cleanup_step.set_name(step.name);
+                    match step.error {
+                        Some(e) => {
+                            cleanup_step.set_result(ResultType::ERR);
+                            cleanup_step.set_error_msg(e);
+                        }
+                        None => cleanup_step.set_result(ResultType::OK),
+                    };
+                    cleanup_step
+                })
+                .collect();
+            // The disk is leaving the datacenter.  Sanitise it in the background
+            // and let bynar collect the proof later
+            match config.sanitize {
+                Some(ref sanitize_config) if sanitize_config.enabled && !simulate => {
+                    match sanitize::start_sanitize(&Path::new(d), sanitize_config) {
+                        Ok(_) => result.set_wipe_pending(true),
+                        Err(e) => {
+                            // The disk is out of the cluster either way
+                            error!("Unable to sanitise {}: {}", d, e);
+                        }
+                    };
+                }
+                _ => debug!("Not sanitising {}", d),
+            };
+            // Without its key nothing the wipe missed can be read back
+            if let (Some(key_path), false) = (removed.encryption_key, simulate) {
+                let mut key_step = CleanupStep::new();
+                key_step.set_name(format!("destroy key {}", key_path));
+                match destroy_key(&config, &key_path) {
+                    Ok(_) => key_step.set_result(ResultType::OK),
+                    Err(e) => {
+                        error!("Unable to destroy key {}: {}", key_path, e);
+                        key_step.set_result(ResultType::ERR);
+                        key_step.set_error_msg(e);
+                    }
+                };
+                steps.push(key_step);
+            }
+            result.set_cleanup_steps(RepeatedField::from_vec(steps));
         }
         Err(e) => {
             result.set_result(ResultType::ERR);
@@ -364,6 +506,55 @@ fn remove_disk(s: &mut Socket, d: &str, backend: &BackendType, config_dir: &Path
     Ok(())
 }
 
+fn get_wipe_certificate(s: &mut Socket, d: &str, config_dir: &Path) -> Result<()> {
+    //Returns OpResult
+    let config: DiskManagerConfig =
+        helpers::load_config(&config_dir.to_string_lossy(), "disk-manager.json")?;
+    let sanitize_config = config.sanitize.unwrap_or_default();
+    let mut result = OpResult::new();
+    match sanitize::wipe_status(&Path::new(d), &sanitize_config) {
+        Ok(sanitize::WipeStatus::Running) => {
+            result.set_result(ResultType::OK);
+            result.set_wipe_pending(true);
+        }
+        Ok(sanitize::WipeStatus::Done(certificate)) => {
+            result.set_result(ResultType::OK);
+            result.set_wipe_certificate(certificate);
+        }
+        Ok(sanitize::WipeStatus::Failed(e)) => {
+            result.set_result(ResultType::ERR);
+            result.set_error_msg(format!("Sanitising {} failed: {}", d, e));
+        }
+        Ok(sanitize::WipeStatus::Unknown) => {
+            result.set_result(ResultType::ERR);
+            result.set_error_msg(format!("{} hasn't been sanitised", d));
+        }
+        Err(e) => {
+            result.set_result(ResultType::ERR);
+            result.set_error_msg(e);
+        }
+    };
+    let encoded = result
+        .write_to_bytes()
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let msg = Message::from_slice(&encoded)?;
+    debug!("Responding to client with msg len: {}", msg.len());
+    s.send_msg(msg, 0)?;
+    Ok(())
+}
+
+// Delete an encrypted disk's key from Vault
+fn destroy_key(config: &DiskManagerConfig, key_path: &str) -> StdResult<(), String> {
+    match (config.vault_endpoint.as_ref(), config.vault_token.as_ref()) {
+        (Some(endpoint), Some(token)) => {
+            let client = VaultClient::new(endpoint.as_str(), token.clone())
+                .map_err(|e| e.to_string())?;
+            client.delete_secret(key_path).map_err(|e| e.to_string())
+        }
+        _ => Err("vault_endpoint and vault_token must be set to destroy keys".to_string()),
+    }
+}
+
 fn safe_to_remove_disk(
     s: &mut Socket,
     d: &str,
@@ -373,10 +564,60 @@ fn safe_to_remove_disk(
     let backend = backend::load_backend(backend, Some(config_dir))
         .map_err(|e| Error::new(ErrorKind::Other, e))?;
     let mut result = OpBoolResult::new();
-    match backend.safe_to_remove(&Path::new(d), false) {
-        Ok(val) => {
+    match backend.removal_check(&Path::new(d), false) {
+        Ok(check) => {
             result.set_result(ResultType::OK);
-            result.set_value(val);
+            result.set_value(check.safe);
+            if let Some(reason) = check.reason {
+                result.set_reason(reason);
+            }
+            let blocking_pgs = check
+                .blocking_pgs
+                .into_iter()
+                .map(|pg| {
+                    let mut blocking = BlockingPg::new();
+                    blocking.set_pgid(pg.pgid);
+                    blocking.set_state(pg.state);
+                    blocking.set_reason(pg.reason);
+                    blocking
+                })
+                .collect();
+            result.set_blocking_pgs(RepeatedField::from_vec(blocking_pgs));
+        }
+        Err(e) => {
+            result.set_result(ResultType::ERR);
+            result.set_error_msg(e.to_string());
+        }
+    };
+    let encoded = result
+        .write_to_bytes()
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let msg = Message::from_slice(&encoded)?;
+    debug!("Responding to client with msg len: {}", msg.len());
+    s.send_msg(msg, 0)?;
+    Ok(())
+}
+
+fn set_led(s: &mut Socket, d: &str, led: Led, on: bool) -> Result<()> {
+    let mut result = OpResult::new();
+    let led = match led {
+        Led::FAULT => helpers::enclosure::Led::Fault,
+        Led::LOCATE => helpers::enclosure::Led::Locate,
+    };
+    match helpers::enclosure::find_slot(&Path::new(d)) {
+        Ok(Some(slot)) => match helpers::enclosure::set_led(&slot, led, on) {
+            Ok(_) => {
+                debug!("{:?} led of {} set to {}", led, slot, on);
+                result.set_result(ResultType::OK);
+            }
+            Err(e) => {
+                result.set_result(ResultType::ERR);
+                result.set_error_msg(format!("Unable to set the led of {}: {}", slot, e));
+            }
+        },
+        Ok(None) => {
+            result.set_result(ResultType::ERR);
+            result.set_error_msg(format!("{} isn't in a known enclosure slot", d));
         }
         Err(e) => {
             result.set_result(ResultType::ERR);
@@ -402,7 +643,7 @@ fn main() {
                 .default_value("ceph")
                 .help("Backend cluster type to manage disks for")
                 .long("backend")
-                .possible_values(&["ceph"])
+                .possible_values(&["ceph", "gluster"])
                 .takes_value(true)
                 .required(false),
         )
@@ -465,6 +706,19 @@ fn main() {
             File::create(matches.value_of("log").unwrap()).unwrap(),
         ),
     ]);
+    let config: Option<DiskManagerConfig> =
+        helpers::load_config(&config_dir.to_string_lossy(), "disk-manager.json").ok();
+    if let Some(config) = config {
+        if let Some(ref sanitize_config) = config.sanitize {
+            if let Err(e) = sanitize_config.validate() {
+                error!("Invalid sanitize config: {}", e);
+                return;
+            }
+            if let Err(e) = sanitize::fail_interrupted(sanitize_config) {
+                error!("Unable to check for interrupted wipes: {}", e);
+            }
+        }
+    }
     match listen(
         backend,
         config_dir,
@@ -0,0 +1,753 @@
+//! Sanitise a disk before it leaves the datacenter and prove it was done.
+//! The disk's own sanitize command is used when it has one: ATA secure erase
+//! or NVMe sanitize/format.  Everything else is overwritten.  Before the disk
+//! is sanitised a marker is written to blocks spread across it.  Afterwards
+//! those blocks are read back and none of them may still hold the marker.
+//! The outcome is written up as a json wipe certificate, signed with openssl
+//! and saved in the certificate directory.  Sanitising can take hours so it
+//! runs in the background and the certificate is collected later.
+extern crate block_utils;
+extern crate libc;
+extern crate serde_json;
+extern crate time;
+
+use std::fs::{self, create_dir_all, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use block_utils::MediaType;
+use helpers::host_information::Host;
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::{finish_wipe, parse_ata_security, parse_nvme_sanicap, parse_sanitize_progress,
+                parse_signature, sample_offsets, wipe_status, AtaSecurity, SanitizeConfig,
+                WipeStatus};
+    use std::fs::File;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_ata_security() {
+        let output = r#"/dev/sdb:
+
+ATA device, with non-removable media
+	Model Number:       ST4000NM0033-9ZM170
+Security:
+	Master password revision code = 65534
+		supported
+	not	enabled
+	not	locked
+	not	frozen
+	not	expired: security count
+		supported: enhanced erase
+	420min for SECURITY ERASE UNIT. 420min for ENHANCED SECURITY ERASE UNIT.
+Logical Unit WWN Device Identifier: 5000c50079d1bb3e
+"#;
+        assert_eq!(
+            parse_ata_security(output),
+            AtaSecurity {
+                supported: true,
+                frozen: false,
+                locked: false,
+                enhanced: true,
+            }
+        );
+        let frozen = output.replace("\tnot\tfrozen", "\t\tfrozen");
+        assert!(parse_ata_security(&frozen).frozen);
+        assert!(!parse_ata_security("/dev/sdb:\nSecurity:\n").supported);
+    }
+
+    #[test]
+    fn test_parse_nvme() {
+        let id_ctrl = "vid       : 0x8086\nsanicap   : 0x3\nhmminds   : 0\n";
+        assert_eq!(parse_nvme_sanicap(id_ctrl), Some(3));
+        assert_eq!(parse_nvme_sanicap("vid       : 0x8086\n"), None);
+
+        let log = r#"Sanitize Progress                      (SPROG) :  65535
+Sanitize Status                        (SSTAT) :  0x101
+"#;
+        assert_eq!(parse_sanitize_progress(log), Some(65535));
+    }
+
+    #[test]
+    fn test_parse_signature() {
+        assert_eq!(
+            parse_signature("RSA-SHA256(stdin)= 5d0a8f3e\n"),
+            Some("5d0a8f3e".to_string())
+        );
+        assert_eq!(parse_signature("garbage"), None);
+    }
+
+    #[test]
+    fn test_sample_offsets() {
+        let offsets = sample_offsets(4_000_787_030_016, 64);
+        assert_eq!(offsets.len(), 64);
+        assert_eq!(offsets[0], 0);
+        assert!(offsets.iter().all(|o| o % 4096 == 0));
+        assert!(offsets.iter().all(|o| o + 4096 <= 4_000_787_030_016));
+        // Tiny disks don't get duplicate samples
+        assert_eq!(sample_offsets(8192, 64), vec![0, 4096]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = TempDir::new("bynar").expect("Temp dir creation failed");
+        let mut config = SanitizeConfig::default();
+        // Nothing is required while sanitising is off
+        assert!(config.validate().is_ok());
+        config.enabled = true;
+        assert!(config.validate().is_err());
+        let key = dir.path().join("key.pem");
+        config.signing_key = Some(key.to_string_lossy().into_owned());
+        assert!(config.validate().is_err());
+        File::create(&key).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_wipe_status() {
+        let dir = TempDir::new("bynar").expect("Temp dir creation failed");
+        let config = SanitizeConfig {
+            certificate_dir: Some(dir.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let sdb = Path::new("/dev/sdb");
+        assert_eq!(wipe_status(sdb, &config).unwrap(), WipeStatus::Unknown);
+        finish_wipe(sdb, &config, Ok("{}".to_string())).unwrap();
+        assert_eq!(
+            wipe_status(sdb, &config).unwrap(),
+            WipeStatus::Done("{}".to_string())
+        );
+        finish_wipe(sdb, &config, Err("no such disk".to_string())).unwrap();
+        assert_eq!(
+            wipe_status(sdb, &config).unwrap(),
+            WipeStatus::Failed("no such disk".to_string())
+        );
+    }
+}
+
+// Every sample and overwrite is done in aligned blocks so O_DIRECT works
+const BLOCK_SIZE: usize = 4096;
+const CHUNK_SIZE: usize = 1024 * 1024;
+const MARKER: &[u8] = b"bynar wipe verification marker ";
+
+/// Settings for sanitising removed disks in disk-manager.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct SanitizeConfig {
+    /// Sanitise disks once they're removed.  Default: false
+    #[serde(default)]
+    pub enabled: bool,
+    /// Where wipe certificates are saved.  Default: /var/lib/bynar/wipe-certificates
+    pub certificate_dir: Option<String>,
+    /// A PEM private key to sign certificates with.  Required when enabled
+    pub signing_key: Option<String>,
+    /// The ATA security password set for the length of a secure erase.  A
+    /// random one is made up for each disk if this isn't set
+    pub ata_password: Option<String>,
+    /// Passes when the disk has to be overwritten.  The last pass writes zeros.
+    /// Default: 3
+    pub overwrite_passes: Option<u32>,
+    /// How many blocks are read back to verify the wipe.  Default: 64
+    pub verify_samples: Option<u32>,
+}
+
+impl Default for SanitizeConfig {
+    fn default() -> Self {
+        SanitizeConfig {
+            enabled: false,
+            certificate_dir: None,
+            signing_key: None,
+            ata_password: None,
+            overwrite_passes: None,
+            verify_samples: None,
+        }
+    }
+}
+
+impl SanitizeConfig {
+    /// Check a certificate can be signed before any disk is sanitised
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        match self.signing_key {
+            Some(ref key) if Path::new(key).exists() => Ok(()),
+            Some(ref key) => Err(format!("signing_key {} doesn't exist", key)),
+            None => Err("signing_key must be set when sanitising is enabled".to_string()),
+        }
+    }
+
+    fn certificate_dir(&self) -> &Path {
+        Path::new(
+            self.certificate_dir
+                .as_ref()
+                .map(|d| d.as_str())
+                .unwrap_or("/var/lib/bynar/wipe-certificates"),
+        )
+    }
+}
+
+/// Where the background wipe of a disk is at
+#[derive(Debug, PartialEq)]
+pub enum WipeStatus {
+    Running,
+    /// The signed certificate json
+    Done(String),
+    /// No certificate could be produced
+    Failed(String),
+    /// This disk hasn't been sanitised
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    AtaSecureErase,
+    AtaEnhancedSecureErase,
+    NvmeSanitize,
+    NvmeFormat,
+    Overwrite,
+}
+
+/// The facts that are signed
+#[derive(Debug, Serialize)]
+pub struct WipeCertificate {
+    pub hostname: String,
+    pub device: String,
+    pub serial_number: Option<String>,
+    pub capacity: u64,
+    pub method: Method,
+    // Only set for overwrites
+    pub passes: Option<u32>,
+    pub started: String,
+    pub finished: String,
+    pub samples_verified: u32,
+    pub verified: bool,
+    // Why sanitising or verification failed
+    pub error: Option<String>,
+}
+
+// certificate holds the exact json that was signed so it can be checked with
+// openssl dgst -sha256 -verify
+#[derive(Debug, Serialize)]
+struct SignedCertificate {
+    certificate: String,
+    algorithm: String,
+    signature: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct AtaSecurity {
+    supported: bool,
+    frozen: bool,
+    locked: bool,
+    enhanced: bool,
+}
+
+// The status of each disk's wipe is kept in files named after the disk in
+// the certificate dir.  <disk>.running exists while the wipe runs, then
+// <disk>.json holds the certificate or <disk>.error why there isn't one
+fn wipe_file(dev_path: &Path, config: &SanitizeConfig, extension: &str) -> PathBuf {
+    let name = dev_path.to_string_lossy().trim_start_matches('/').replace("/", "_");
+    config
+        .certificate_dir()
+        .join("disks")
+        .join(format!("{}.{}", name, extension))
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let mut f = File::create(path).map_err(|e| e.to_string())?;
+    f.write_all(contents.as_bytes()).map_err(|e| e.to_string())
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    let mut f = File::open(path).map_err(|e| e.to_string())?;
+    let mut s = String::new();
+    f.read_to_string(&mut s).map_err(|e| e.to_string())?;
+    Ok(s)
+}
+
+/// Start sanitising a disk on a thread of its own.  Check on it with
+/// wipe_status
+pub fn start_sanitize(dev_path: &Path, config: &SanitizeConfig) -> Result<(), String> {
+    let running = wipe_file(dev_path, config, "running");
+    if running.exists() {
+        return Err(format!("{} is already being sanitised", dev_path.display()));
+    }
+    clear_results(dev_path, config)?;
+    write_file(&running, &time::now_utc().rfc3339().to_string())?;
+    let dev_path = dev_path.to_path_buf();
+    let config = config.clone();
+    thread::spawn(move || {
+        let result = sanitize_disk(&dev_path, &config);
+        if let Err(e) = finish_wipe(&dev_path, &config, result) {
+            error!("Unable to record the wipe of {}: {}", dev_path.display(), e);
+        }
+    });
+    Ok(())
+}
+
+// Forget how an earlier wipe of this disk went
+fn clear_results(dev_path: &Path, config: &SanitizeConfig) -> Result<(), String> {
+    for extension in &["json", "error"] {
+        let path = wipe_file(dev_path, config, extension);
+        if path.exists() {
+            fs::remove_file(&path).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+// Record how the wipe went and clear the running marker
+fn finish_wipe(
+    dev_path: &Path,
+    config: &SanitizeConfig,
+    result: Result<String, String>,
+) -> Result<(), String> {
+    clear_results(dev_path, config)?;
+    match result {
+        Ok(json) => write_file(&wipe_file(dev_path, config, "json"), &json)?,
+        Err(e) => {
+            error!("Unable to sanitise {}: {}", dev_path.display(), e);
+            write_file(&wipe_file(dev_path, config, "error"), &e)?;
+        }
+    };
+    let running = wipe_file(dev_path, config, "running");
+    if running.exists() {
+        fs::remove_file(&running).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+pub fn wipe_status(dev_path: &Path, config: &SanitizeConfig) -> Result<WipeStatus, String> {
+    if wipe_file(dev_path, config, "running").exists() {
+        return Ok(WipeStatus::Running);
+    }
+    let json = wipe_file(dev_path, config, "json");
+    if json.exists() {
+        return Ok(WipeStatus::Done(read_file(&json)?));
+    }
+    let error = wipe_file(dev_path, config, "error");
+    if error.exists() {
+        return Ok(WipeStatus::Failed(read_file(&error)?));
+    }
+    Ok(WipeStatus::Unknown)
+}
+
+/// Wipes that were running when the disk-manager stopped will never finish.
+/// Mark them failed so they can be run again
+pub fn fail_interrupted(config: &SanitizeConfig) -> Result<(), String> {
+    let dir = config.certificate_dir().join("disks");
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(&dir).map_err(|e| e.to_string())? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().map(|e| e == "running").unwrap_or(false) {
+            warn!("{} was interrupted", path.display());
+            write_file(
+                &path.with_extension("error"),
+                "The disk-manager stopped before sanitising finished",
+            )?;
+            fs::remove_file(&path).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Sanitise a disk, verify it and save a wipe certificate.  Returns the
+/// certificate json.  A certificate is produced even when sanitising fails so
+/// the failure is on record.
+pub fn sanitize_disk(dev_path: &Path, config: &SanitizeConfig) -> Result<String, String> {
+    let key = config
+        .signing_key
+        .as_ref()
+        .ok_or_else(|| "signing_key must be set to sanitise disks".to_string())?;
+    let device = block_utils::get_device_info(dev_path)?;
+    let hostname = Host::new().map_err(|e| e.to_string())?.hostname;
+    let offsets = sample_offsets(device.capacity, config.verify_samples.unwrap_or(64));
+    let started = time::now_utc().rfc3339().to_string();
+
+    let capacity = device.capacity;
+    let passes = config.overwrite_passes.unwrap_or(3);
+    let mut method = choose_method(dev_path, device.media_type.clone());
+    let password = match config.ata_password {
+        Some(ref password) => password.clone(),
+        None => random_password()?,
+    };
+    info!("Sanitising {} with {:?}", dev_path.display(), method);
+    let result = write_markers(dev_path, &offsets).and_then(|_| {
+        match run_method(dev_path, method, capacity, passes, &password) {
+            Ok(_) => Ok(()),
+            Err(e) if method != Method::Overwrite => {
+                // The disk's own command didn't work out.  Fall back on overwriting it
+                error!(
+                    "{:?} of {} failed: {}.  Overwriting it instead",
+                    method,
+                    dev_path.display(),
+                    e
+                );
+                method = Method::Overwrite;
+                run_method(dev_path, method, capacity, passes, &password)
+            }
+            Err(e) => Err(e),
+        }
+    });
+    let (samples_verified, verified, error) =
+        match result.and_then(|_| verify_samples(dev_path, &offsets, method == Method::Overwrite)) {
+            Ok(count) => (count, true, None),
+            Err(e) => {
+                error!("Sanitising {} failed: {}", dev_path.display(), e);
+                (0, false, Some(e))
+            }
+        };
+
+    let certificate = WipeCertificate {
+        hostname,
+        device: dev_path.to_string_lossy().into_owned(),
+        serial_number: device.serial_number.clone(),
+        capacity,
+        method,
+        passes: if method == Method::Overwrite {
+            Some(passes)
+        } else {
+            None
+        },
+        started,
+        finished: time::now_utc().rfc3339().to_string(),
+        samples_verified,
+        verified,
+        error,
+    };
+    let body = serde_json::to_string(&certificate).map_err(|e| e.to_string())?;
+    let signature = sign(key, &body)?;
+    let signed = SignedCertificate {
+        certificate: body,
+        algorithm: "sha256".to_string(),
+        signature,
+    };
+    let json = serde_json::to_string_pretty(&signed).map_err(|e| e.to_string())?;
+    let path = save_certificate(config, &certificate, &json)?;
+    info!("Saved wipe certificate {}", path.display());
+    Ok(json)
+}
+
+fn save_certificate(
+    config: &SanitizeConfig,
+    certificate: &WipeCertificate,
+    json: &str,
+) -> Result<PathBuf, String> {
+    let dir = config.certificate_dir();
+    create_dir_all(dir).map_err(|e| e.to_string())?;
+    let name = certificate
+        .serial_number
+        .clone()
+        .unwrap_or_else(|| certificate.device.replace("/", "_"));
+    let path = dir.join(format!("{}-{}.json", name, time::get_time().sec));
+    let mut f = File::create(&path).map_err(|e| e.to_string())?;
+    f.write_all(json.as_bytes()).map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+fn choose_method(dev_path: &Path, media_type: MediaType) -> Method {
+    match media_type {
+        MediaType::NVME => {
+            let sanicap = run("nvme", &["id-ctrl", &dev_path.to_string_lossy()])
+                .ok()
+                .and_then(|output| parse_nvme_sanicap(&output));
+            match sanicap {
+                Some(cap) if cap != 0 => Method::NvmeSanitize,
+                _ => Method::NvmeFormat,
+            }
+        }
+        MediaType::Rotational | MediaType::SolidState => {
+            match run("hdparm", &["-I", &dev_path.to_string_lossy()]) {
+                Ok(output) => {
+                    let security = parse_ata_security(&output);
+                    if !security.supported || security.frozen || security.locked {
+                        debug!("ATA security state: {:?}", security);
+                        Method::Overwrite
+                    } else if security.enhanced {
+                        Method::AtaEnhancedSecureErase
+                    } else {
+                        Method::AtaSecureErase
+                    }
+                }
+                Err(_) => Method::Overwrite,
+            }
+        }
+        _ => Method::Overwrite,
+    }
+}
+
+// A password for a single secure erase
+fn random_password() -> Result<String, String> {
+    let mut f = File::open("/dev/urandom").map_err(|e| e.to_string())?;
+    let mut buff = [0u8; 8];
+    f.read_exact(&mut buff).map_err(|e| e.to_string())?;
+    Ok(buff.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn run_method(
+    dev_path: &Path,
+    method: Method,
+    capacity: u64,
+    passes: u32,
+    password: &str,
+) -> Result<(), String> {
+    let dev = dev_path.to_string_lossy();
+    match method {
+        Method::AtaSecureErase | Method::AtaEnhancedSecureErase => {
+            let erase = if method == Method::AtaEnhancedSecureErase {
+                "--security-erase-enhanced"
+            } else {
+                "--security-erase"
+            };
+            run(
+                "hdparm",
+                &["--user-master", "u", "--security-set-pass", password, &dev],
+            )?;
+            if let Err(e) = run("hdparm", &["--user-master", "u", erase, password, &dev]) {
+                // Don't leave the disk locked with our password
+                if let Err(disable) = run(
+                    "hdparm",
+                    &["--user-master", "u", "--security-disable", password, &dev],
+                ) {
+                    // Whoever handles the disk next needs the password to unlock it
+                    return Err(format!(
+                        "{}.  Unable to clear the security password {}: {}",
+                        e, password, disable
+                    ));
+                }
+                return Err(e);
+            }
+            Ok(())
+        }
+        Method::NvmeSanitize => {
+            // Block erase
+            run("nvme", &["sanitize", &dev, "--sanact=2"])?;
+            // Sanitize runs in the background.  SPROG reads 65535 when it's done
+            for _ in 0..8640 {
+                thread::sleep(Duration::from_secs(10));
+                let log = run("nvme", &["sanitize-log", &dev])?;
+                if parse_sanitize_progress(&log) == Some(65535) {
+                    return Ok(());
+                }
+            }
+            Err(format!("Sanitize of {} didn't finish within a day", dev))
+        }
+        Method::NvmeFormat => {
+            run("nvme", &["format", &dev, "--ses=1"])?;
+            Ok(())
+        }
+        Method::Overwrite => {
+            for pass in 1..=passes {
+                debug!("Overwrite pass {} of {} on {}", pass, passes, dev);
+                overwrite(dev_path, capacity, pass < passes)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn run(cmd: &str, args: &[&str]) -> Result<String, String> {
+    debug!("cmd: {} {:?}", cmd, args);
+    let output = Command::new(cmd)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Unable to run {}: {}", cmd, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} {:?} failed: {}",
+            cmd,
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn parse_ata_security(output: &str) -> AtaSecurity {
+    let section: Vec<&str> = output
+        .lines()
+        .skip_while(|l| !l.starts_with("Security:"))
+        .skip(1)
+        .take_while(|l| l.starts_with('\t'))
+        .map(|l| l.trim())
+        .collect();
+    AtaSecurity {
+        supported: section.iter().any(|l| *l == "supported"),
+        frozen: section.iter().any(|l| *l == "frozen"),
+        locked: section.iter().any(|l| *l == "locked"),
+        enhanced: section.iter().any(|l| *l == "supported: enhanced erase"),
+    }
+}
+
+// Sanitize capabilities from nvme id-ctrl.  0 means sanitize isn't supported
+fn parse_nvme_sanicap(output: &str) -> Option<u32> {
+    let line = output.lines().find(|l| l.starts_with("sanicap"))?;
+    let value = line.split(':').nth(1)?.trim();
+    if value.starts_with("0x") {
+        u32::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+fn parse_sanitize_progress(output: &str) -> Option<u32> {
+    let line = output.lines().find(|l| l.contains("(SPROG)"))?;
+    line.split(':').nth(1)?.trim().parse().ok()
+}
+
+// openssl dgst prints `RSA-SHA256(stdin)= <hex>`
+fn parse_signature(output: &str) -> Option<String> {
+    let hex = output.split("= ").nth(1)?.trim();
+    if hex.is_empty() {
+        None
+    } else {
+        Some(hex.to_string())
+    }
+}
+
+fn sign(key: &str, body: &str) -> Result<String, String> {
+    let mut child = Command::new("openssl")
+        .args(&["dgst", "-sha256", "-hex", "-sign", key])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Unable to run openssl: {}", e))?;
+    child
+        .stdin
+        .as_mut()
+        .ok_or("openssl stdin is missing")?
+        .write_all(body.as_bytes())
+        .map_err(|e| e.to_string())?;
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "Signing the wipe certificate failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    parse_signature(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| "openssl didn't return a signature".to_string())
+}
+
+// Block aligned offsets spread evenly across the disk
+fn sample_offsets(capacity: u64, samples: u32) -> Vec<u64> {
+    let blocks = capacity / BLOCK_SIZE as u64;
+    let samples = u64::from(samples).min(blocks);
+    (0..samples)
+        .map(|i| i * blocks / samples * BLOCK_SIZE as u64)
+        .collect()
+}
+
+// A buffer of len bytes whose start is aligned for O_DIRECT
+struct AlignedBuffer {
+    buff: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let buff = vec![0u8; len + BLOCK_SIZE];
+        let offset = (BLOCK_SIZE - buff.as_ptr() as usize % BLOCK_SIZE) % BLOCK_SIZE;
+        AlignedBuffer { buff, offset, len }
+    }
+
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buff[self.offset..self.offset + self.len]
+    }
+}
+
+fn open_direct(dev_path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(dev_path)
+        .map_err(|e| format!("Unable to open {}: {}", dev_path.display(), e))
+}
+
+fn marker_block(block: &mut [u8]) {
+    for (i, byte) in block.iter_mut().enumerate() {
+        *byte = MARKER[i % MARKER.len()];
+    }
+}
+
+fn write_markers(dev_path: &Path, offsets: &[u64]) -> Result<(), String> {
+    let mut f = open_direct(dev_path)?;
+    let mut buff = AlignedBuffer::new(BLOCK_SIZE);
+    marker_block(buff.as_mut());
+    for offset in offsets {
+        f.seek(SeekFrom::Start(*offset)).map_err(|e| e.to_string())?;
+        f.write_all(buff.as_mut())
+            .map_err(|e| format!("Unable to write marker at {}: {}", offset, e))?;
+    }
+    f.sync_all().map_err(|e| e.to_string())
+}
+
+// Read every sample back.  None may still hold the marker and after an
+// overwrite they must all be zero.  Returns how many samples were checked
+fn verify_samples(dev_path: &Path, offsets: &[u64], expect_zeros: bool) -> Result<u32, String> {
+    let mut f = open_direct(dev_path)?;
+    let mut marker = vec![0u8; BLOCK_SIZE];
+    marker_block(&mut marker);
+    let mut buff = AlignedBuffer::new(BLOCK_SIZE);
+    for offset in offsets {
+        f.seek(SeekFrom::Start(*offset)).map_err(|e| e.to_string())?;
+        let block = buff.as_mut();
+        f.read_exact(block)
+            .map_err(|e| format!("Unable to read back {}: {}", offset, e))?;
+        if block[..] == marker[..] {
+            return Err(format!("Data at {} survived sanitising", offset));
+        }
+        if expect_zeros && block.iter().any(|b| *b != 0) {
+            return Err(format!("Data at {} wasn't zeroed", offset));
+        }
+    }
+    Ok(offsets.len() as u32)
+}
+
+// Overwrite the whole disk with zeros or with pseudo random data
+fn overwrite(dev_path: &Path, capacity: u64, random: bool) -> Result<(), String> {
+    let mut f = open_direct(dev_path)?;
+    let mut buff = AlignedBuffer::new(CHUNK_SIZE);
+    // xorshift is plenty for making the old data unrecoverable
+    let mut state: u64 = time::precise_time_ns() | 1;
+    let mut written = 0u64;
+    while written < capacity {
+        let len = (capacity - written).min(CHUNK_SIZE as u64) as usize;
+        let chunk = &mut buff.as_mut()[..len];
+        if random {
+            for word in chunk.chunks_mut(8) {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                for (i, byte) in word.iter_mut().enumerate() {
+                    *byte = (state >> (i * 8)) as u8;
+                }
+            }
+        } else {
+            for byte in chunk.iter_mut() {
+                *byte = 0;
+            }
+        }
+        f.write_all(chunk)
+            .map_err(|e| format!("Overwrite failed at {}: {}", written, e))?;
+        written += len as u64;
+    }
+    f.sync_all().map_err(|e| e.to_string())
+}
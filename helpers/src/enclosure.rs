@@ -0,0 +1,237 @@
+//! Find the enclosure and slot a disk sits in using the kernel's SES
+//! enclosure tree in /sys/class/enclosure.  Every enclosure has a directory
+//! for each of its components.  A populated slot links to the scsi device in
+//! it which in turn lists its block device.  The fault and locate LEDs of a
+//! slot are switched by writing 1 or 0 to its fault and locate files.
+use std::fmt;
+use std::fs::{read_dir, File, OpenOptions};
+use std::io::{Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::Led;
+    use std::fs::{create_dir_all, File};
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    fn write(path: &Path, contents: &str) {
+        let mut f = File::create(path).unwrap();
+        f.write_all(contents.as_bytes()).unwrap();
+    }
+
+    // enclosure/<enclosure>/<component>/device/block/<disk>
+    fn fake_slot(root: &Path, enclosure: &str, component: &str, disk: Option<&str>) {
+        let dir = root.join(enclosure).join(component);
+        create_dir_all(&dir).unwrap();
+        write(&dir.join("fault"), "0\n");
+        write(&dir.join("locate"), "0\n");
+        if let Some(disk) = disk {
+            create_dir_all(dir.join("device/block").join(disk)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_find_slot() {
+        let dir = TempDir::new("bynar").expect("Temp dir creation failed");
+        let root = dir.path();
+        create_dir_all(root.join("0:0:12:0")).unwrap();
+        write(&root.join("0:0:12:0/id"), "0x500056b36789abff\n");
+        fake_slot(root, "0:0:12:0", "Slot 00", Some("sda"));
+        fake_slot(root, "0:0:12:0", "Slot 01", None);
+        fake_slot(root, "0:0:12:0", "Slot 02", Some("sdk"));
+        // Newer kernels have a slot file which wins over the name
+        write(&root.join("0:0:12:0/Slot 02/slot"), "7\n");
+        // Some enclosures number their components without a slot file
+        fake_slot(root, "1:0:30:0", "5", Some("sdz"));
+
+        let slot = super::find_slot_in(root, "sdk").unwrap().unwrap();
+        assert_eq!(slot.enclosure, "0:0:12:0");
+        assert_eq!(slot.enclosure_id, Some("0x500056b36789abff".to_string()));
+        assert_eq!(slot.component, "Slot 02");
+        assert_eq!(slot.slot, Some(7));
+        assert_eq!(slot.path, root.join("0:0:12:0/Slot 02"));
+
+        let slot = super::find_slot_in(root, "sda").unwrap().unwrap();
+        assert_eq!(slot.slot, Some(0));
+        let slot = super::find_slot_in(root, "sdz").unwrap().unwrap();
+        assert_eq!(slot.enclosure_id, None);
+        assert_eq!(slot.slot, Some(5));
+        assert!(super::find_slot_in(root, "sdb").unwrap().is_none());
+        // No enclosures at all
+        assert!(
+            super::find_slot_in(&root.join("missing"), "sda")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_set_led() {
+        let dir = TempDir::new("bynar").expect("Temp dir creation failed");
+        let root = dir.path();
+        fake_slot(root, "0:0:12:0", "Slot 02", Some("sdk"));
+        let slot = super::find_slot_in(root, "sdk").unwrap().unwrap();
+
+        super::set_led(&slot, Led::Fault, true).unwrap();
+        let mut buff = String::new();
+        File::open(root.join("0:0:12:0/Slot 02/fault"))
+            .unwrap()
+            .read_to_string(&mut buff)
+            .unwrap();
+        assert_eq!(buff.trim(), "1");
+        assert!(super::get_led(&slot, Led::Fault).unwrap());
+        assert!(!super::get_led(&slot, Led::Locate).unwrap());
+        super::set_led(&slot, Led::Fault, false).unwrap();
+        assert!(!super::get_led(&slot, Led::Fault).unwrap());
+    }
+}
+
+const SYSFS_ENCLOSURE: &str = "/sys/class/enclosure";
+
+/// The LEDs a slot can have
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Led {
+    /// Marks the disk as failed
+    Fault,
+    /// Helps a tech find the slot
+    Locate,
+}
+
+impl Led {
+    fn file_name(&self) -> &'static str {
+        match *self {
+            Led::Fault => "fault",
+            Led::Locate => "locate",
+        }
+    }
+}
+
+/// Where a disk physically sits
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnclosureSlot {
+    /// The enclosure's scsi address.  Ex: 0:0:12:0
+    pub enclosure: String,
+    /// The enclosure's logical id (usually its SAS address) if it has one
+    pub enclosure_id: Option<String>,
+    /// The component name the enclosure gives the slot.  Ex: Slot 02
+    pub component: String,
+    /// The slot number if it could be worked out
+    pub slot: Option<u32>,
+    /// The component's sysfs directory
+    pub path: PathBuf,
+}
+
+impl fmt::Display for EnclosureSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "enclosure {}",
+            self.enclosure_id.as_ref().unwrap_or(&self.enclosure)
+        )?;
+        match self.slot {
+            Some(slot) => write!(f, " slot {}", slot),
+            None => write!(f, " component {}", self.component),
+        }
+    }
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    let mut f = File::open(path).ok()?;
+    let mut buff = String::new();
+    f.read_to_string(&mut buff).ok()?;
+    let value = buff.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+// "Slot 02", "SLOT 2", "Disk002" and "2" are all slot 2
+fn slot_from_name(name: &str) -> Option<u32> {
+    let digits: String = name
+        .chars()
+        .rev()
+        .take_while(|c| c.is_digit(10))
+        .collect::<Vec<char>>()
+        .into_iter()
+        .rev()
+        .collect();
+    digits.parse().ok()
+}
+
+/// Find the enclosure slot holding a disk.  dev_path is something like /dev/sdk
+pub fn find_slot(dev_path: &Path) -> Result<Option<EnclosureSlot>> {
+    let dev_name = match dev_path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Ok(None),
+    };
+    find_slot_in(Path::new(SYSFS_ENCLOSURE), &dev_name)
+}
+
+/// find_slot against an enclosure tree rooted somewhere other than sysfs
+pub fn find_slot_in(root: &Path, dev_name: &str) -> Result<Option<EnclosureSlot>> {
+    if !root.exists() {
+        debug!("{} doesn't exist.  No enclosures to search", root.display());
+        return Ok(None);
+    }
+    for enclosure in read_dir(root)? {
+        let enclosure = enclosure?.path();
+        if !enclosure.is_dir() {
+            continue;
+        }
+        for component in read_dir(&enclosure)? {
+            let component = component?.path();
+            let block_dir = component.join("device").join("block");
+            if !block_dir.is_dir() {
+                continue;
+            }
+            let holds_disk = read_dir(&block_dir)?
+                .filter_map(|e| e.ok())
+                .any(|e| e.file_name().to_string_lossy() == dev_name);
+            if !holds_disk {
+                continue;
+            }
+            let component_name = component
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let slot = read_trimmed(&component.join("slot"))
+                .and_then(|s| s.parse().ok())
+                .or_else(|| slot_from_name(&component_name));
+            return Ok(Some(EnclosureSlot {
+                enclosure: enclosure
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                enclosure_id: read_trimmed(&enclosure.join("id")),
+                component: component_name,
+                slot,
+                path: component,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Turn a slot LED on or off
+pub fn set_led(slot: &EnclosureSlot, led: Led, on: bool) -> Result<()> {
+    let path = slot.path.join(led.file_name());
+    debug!("Setting {} to {}", path.display(), on);
+    // sysfs attributes can't be truncated so open them write only
+    let mut f = OpenOptions::new().write(true).open(&path)?;
+    f.write_all(if on { b"1" } else { b"0" })?;
+    Ok(())
+}
+
+/// Check if a slot LED is on
+pub fn get_led(slot: &EnclosureSlot, led: Led) -> Result<bool> {
+    let mut f = File::open(slot.path.join(led.file_name()))?;
+    let mut buff = String::new();
+    f.read_to_string(&mut buff)?;
+    Ok(buff.trim() != "0")
+}
@@ -118,8 +118,6 @@ fn server_serial() -> Result<String> {
     ))
 }
 
-//TODO: smp-utils has a lot of use information about how to interface with sas enclosures
-// http://sg.danny.cz/sg/smp_utils.html#mozTocId356346
 /*
 fn raid_info(dev: &Path) -> Result<()> {
     let info = block_utils::get_raid_info().map_err(|e| {
@@ -128,10 +126,4 @@ fn raid_info(dev: &Path) -> Result<()> {
     println!("raid info: {:?}", info);
     Ok(())
 }
-
-/// Given a disk find out what chassis position this disk is located at
-fn disk_position(dev: &Path, raid_type: RaidType) -> Result<String> {
-    //
-    Ok("".into())
-}
 */
@@ -16,14 +16,16 @@ use std::io::Result as IOResult;
 use std::fs::File;
 use std::path::Path;
 
-use api::service::{Disk, Op, OpBoolResult, Operation, ResultType};
+use api::service::{Disk, Led, Op, OpBoolResult, OpResult, Operation, ResultType};
 use hashicorp_vault::client::VaultClient;
 use protobuf::Message as ProtobufMsg;
+use protobuf::RepeatedField;
 use protobuf::parse_from_bytes;
 use serde::de::DeserializeOwned;
 use zmq::{Message, Socket};
 use zmq::Result as ZmqResult;
 
+pub mod enclosure;
 pub mod host_information;
 
 pub fn load_config<T>(config_dir: &str, name: &str) -> IOResult<T>
@@ -72,6 +74,7 @@ pub fn add_disk_request(
     s: &mut Socket,
     path: &Path,
     id: Option<u64>,
+    replica_set: Option<Vec<String>>,
     simulate: bool,
 ) -> Result<(), String> {
     let mut o = Operation::new();
@@ -82,6 +85,9 @@ pub fn add_disk_request(
     if id.is_some() {
         o.set_osd_id(id.unwrap());
     }
+    if let Some(replica_set) = replica_set {
+        o.set_replica_set(RepeatedField::from_vec(replica_set));
+    }
 
     let encoded = o.write_to_bytes().unwrap();
     let msg = Message::from_slice(&encoded).map_err(|e| e.to_string())?;
@@ -159,7 +165,9 @@ pub fn list_disks_request(s: &mut Socket) -> Result<Vec<Disk>, String> {
     Ok(d)
 }
 
-pub fn safe_to_remove_request(s: &mut Socket, path: &Path) -> Result<bool, String> {
+/// Ask if a disk is safe to remove.  When it isn't the result's reason and
+/// blocking_pgs explain why
+pub fn safe_to_remove_request(s: &mut Socket, path: &Path) -> Result<OpBoolResult, String> {
     let mut o = Operation::new();
     debug!("Creating safe to remove operation request");
     o.set_Op_type(Op::SafeToRemove);
@@ -174,17 +182,36 @@ pub fn safe_to_remove_request(s: &mut Socket, path: &Path) -> Result<bool, Strin
     debug!("Decoding msg len: {}", safe_response.len());
     let op_result = parse_from_bytes::<OpBoolResult>(&safe_response).map_err(|e| e.to_string())?;
     match op_result.get_result() {
-        ResultType::OK => Ok(op_result.get_value()),
+        ResultType::OK => Ok(op_result),
         ResultType::ERR => Err(op_result.get_error_msg().into()),
     }
 }
 
+/// A one line summary of what keeps a disk from being removed
+pub fn removal_blockers(result: &OpBoolResult) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    if result.has_reason() {
+        parts.push(result.get_reason().to_string());
+    }
+    for pg in result.get_blocking_pgs() {
+        parts.push(format!(
+            "pg {} ({}): {}",
+            pg.get_pgid(),
+            pg.get_state(),
+            pg.get_reason()
+        ));
+    }
+    parts.join("; ")
+}
+
+/// Remove a disk.  The result carries the wipe certificate if the disk was
+/// sanitised and the osd id if the cluster kept it for the replacement
 pub fn remove_disk_request(
     s: &mut Socket,
     path: &Path,
     id: Option<u64>,
     simulate: bool,
-) -> Result<(), String> {
+) -> Result<OpResult, String> {
     let mut o = Operation::new();
     debug!("Creating remove operation request");
     o.set_Op_type(Op::Remove);
@@ -203,11 +230,11 @@ pub fn remove_disk_request(
     let remove_response = s.recv_bytes(0).map_err(|e| e.to_string())?;
     debug!("Decoding msg len: {}", remove_response.len());
     let op_result =
-        parse_from_bytes::<api::service::OpResult>(&remove_response).map_err(|e| e.to_string())?;
+        parse_from_bytes::<OpResult>(&remove_response).map_err(|e| e.to_string())?;
     match op_result.get_result() {
         ResultType::OK => {
-            debug!("Add disk successful");
-            Ok(())
+            debug!("Remove disk successful");
+            Ok(op_result)
         }
         ResultType::ERR => {
             if op_result.has_error_msg() {
@@ -221,3 +248,51 @@ pub fn remove_disk_request(
         }
     }
 }
+
+/// Turn the fault or locate LED of a disk's enclosure slot on or off
+pub fn set_led_request(s: &mut Socket, path: &Path, led: Led, on: bool) -> Result<(), String> {
+    let mut o = Operation::new();
+    debug!("Creating set led operation request");
+    o.set_Op_type(Op::SetLed);
+    o.set_disk(format!("{}", path.display()));
+    o.set_led(led);
+    o.set_led_on(on);
+
+    let encoded = o.write_to_bytes().map_err(|e| e.to_string())?;
+    let msg = Message::from_slice(&encoded).map_err(|e| e.to_string())?;
+    debug!("Sending message");
+    s.send_msg(msg, 0).map_err(|e| e.to_string())?;
+
+    debug!("Waiting for response");
+    let led_response = s.recv_bytes(0).map_err(|e| e.to_string())?;
+    debug!("Decoding msg len: {}", led_response.len());
+    let op_result =
+        parse_from_bytes::<api::service::OpResult>(&led_response).map_err(|e| e.to_string())?;
+    match op_result.get_result() {
+        ResultType::OK => Ok(()),
+        ResultType::ERR => Err(op_result.get_error_msg().into()),
+    }
+}
+
+/// Ask for the wipe certificate of a removed disk.  wipe_pending is set on
+/// the result while the disk is still being sanitised
+pub fn get_wipe_certificate_request(s: &mut Socket, path: &Path) -> Result<OpResult, String> {
+    let mut o = Operation::new();
+    debug!("Creating get wipe certificate operation request");
+    o.set_Op_type(Op::GetWipeCertificate);
+    o.set_disk(format!("{}", path.display()));
+
+    let encoded = o.write_to_bytes().map_err(|e| e.to_string())?;
+    let msg = Message::from_slice(&encoded).map_err(|e| e.to_string())?;
+    debug!("Sending message");
+    s.send_msg(msg, 0).map_err(|e| e.to_string())?;
+
+    debug!("Waiting for response");
+    let wipe_response = s.recv_bytes(0).map_err(|e| e.to_string())?;
+    debug!("Decoding msg len: {}", wipe_response.len());
+    let op_result = parse_from_bytes::<OpResult>(&wipe_response).map_err(|e| e.to_string())?;
+    match op_result.get_result() {
+        ResultType::OK => Ok(op_result),
+        ResultType::ERR => Err(op_result.get_error_msg().into()),
+    }
+}
@@ -0,0 +1,348 @@
+//! Keep a human in the loop before bynar pulls a disk out of the cluster.
+//! When a removal approval policy is configured removals are queued in the
+//! repair database instead of being run right away.  They're approved with
+//! `bynar approve <disk>` or by dropping a json file like
+//! `{"disk": "/dev/sdb", "approved_by": "jdoe"}` into the watched directory.
+//! Approval files are only trusted if root or an allowed group owns them and
+//! nobody else can write to them.
+extern crate libc;
+extern crate rusqlite;
+extern crate time;
+
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use helpers;
+use in_progress;
+use ConfigSettings;
+
+use self::rusqlite::Connection;
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use std::fs::{self, File, Permissions};
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    #[test]
+    fn test_process_approval_files() {
+        let dir = TempDir::new("bynar").expect("Temp dir creation failed");
+        let db_path = dir.path().join("approvals.sqlite3");
+        let watch_dir = dir.path().join("approvals");
+        ::std::fs::create_dir(&watch_dir).unwrap();
+
+        let conn = super::in_progress::connect_to_repair_database(&db_path)
+            .expect("sqlite3 creation failed");
+        let expires = super::time::get_time() + super::time::Duration::hours(1);
+        super::in_progress::request_removal_approval(&conn, &Path::new("/dev/sdb"), expires)
+            .unwrap();
+
+        super::in_progress::request_removal_approval(&conn, &Path::new("/dev/sdc"), expires)
+            .unwrap();
+
+        let write = |name: &str, contents: &[u8], mode: u32| {
+            let path = watch_dir.join(name);
+            let mut f = File::create(&path).unwrap();
+            f.write_all(contents).unwrap();
+            fs::set_permissions(&path, Permissions::from_mode(mode)).unwrap();
+        };
+        write(
+            "sdb.json",
+            br#"{"disk": "/dev/sdb", "approved_by": "jdoe"}"#,
+            0o644,
+        );
+        write("garbage.json", b"not json", 0o644);
+        write(
+            "sdc.json",
+            br#"{"disk": "/dev/sdc", "approved_by": "jdoe"}"#,
+            0o666,
+        );
+
+        // Allow whichever group the test runs as
+        let gid = unsafe { super::libc::getegid() };
+        super::process_approval_files(&conn, &watch_dir, &[gid]).unwrap();
+        let approval =
+            super::in_progress::get_open_removal_approval(&conn, &Path::new("/dev/sdb"))
+                .unwrap()
+                .unwrap();
+        assert_eq!(approval.approved_by, Some("jdoe".to_string()));
+        assert_eq!(
+            approval.approver_uid,
+            Some(unsafe { super::libc::geteuid() })
+        );
+        // Anyone could have written the sdc approval
+        let approval =
+            super::in_progress::get_open_removal_approval(&conn, &Path::new("/dev/sdc"))
+                .unwrap()
+                .unwrap();
+        assert_eq!(approval.approved_by, None);
+        // Handled files are removed and bad ones are left for a human to look at
+        assert!(!watch_dir.join("sdb.json").exists());
+        assert!(watch_dir.join("garbage.json").exists());
+        assert!(watch_dir.join("sdc.json").exists());
+    }
+
+    #[test]
+    fn test_request_queued_approvals() {
+        let dir = TempDir::new("bynar").expect("Temp dir creation failed");
+        let db_path = dir.path().join("queued.sqlite3");
+        let conn = super::in_progress::connect_to_repair_database(&db_path)
+            .expect("sqlite3 creation failed");
+        let policy = super::ApprovalPolicy {
+            expiry_hours: None,
+            watch_dir: None,
+            allowed_groups: vec![],
+        };
+        let dev = Path::new("/dev/sdb");
+
+        // It wasn't safe to remove so it's retried on a later run
+        super::in_progress::queue_disk_removal(&conn, dev).unwrap();
+        assert_eq!(
+            super::request_queued_approvals(&conn, &policy).unwrap(),
+            vec!["/dev/sdb".to_string()]
+        );
+        // Only one request waits for a human however many runs it takes
+        assert!(
+            super::request_queued_approvals(&conn, &policy)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            super::in_progress::get_removal_approvals(
+                &conn,
+                Some(super::in_progress::ApprovalStatus::Approved)
+            ).unwrap()
+                .is_empty()
+        );
+        assert!(super::in_progress::approve_removal(&conn, dev, "jdoe", 1000).unwrap());
+        assert!(
+            super::request_queued_approvals(&conn, &policy)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            super::in_progress::get_removal_approvals(
+                &conn,
+                Some(super::in_progress::ApprovalStatus::Approved)
+            ).unwrap()
+                .len(),
+            1
+        );
+        // Removing the approved disk clears it from the queue too
+        super::in_progress::record_disk_removed(&conn, dev).unwrap();
+        assert!(
+            super::in_progress::get_queued_removals(&conn)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_check_file_owner() {
+        assert!(super::check_file_owner(0, 0, 0o100644, &[]).is_ok());
+        assert!(super::check_file_owner(1000, 50, 0o100640, &[50]).is_ok());
+        assert!(super::check_file_owner(1000, 1000, 0o100644, &[50]).is_err());
+        assert!(super::check_file_owner(0, 0, 0o100664, &[]).is_err());
+        assert!(super::check_file_owner(0, 0, 0o100646, &[]).is_err());
+    }
+}
+
+/// Settings for the removal approval gate in bynar.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApprovalPolicy {
+    /// How long a removal request waits for approval.  Default: 72
+    pub expiry_hours: Option<u64>,
+    /// A directory that is checked for approval files on every run
+    pub watch_dir: Option<String>,
+    /// Groups besides root whose approval files are trusted
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
+}
+
+impl ApprovalPolicy {
+    pub fn expires(&self) -> time::Timespec {
+        time::get_time() + time::Duration::hours(self.expiry_hours.unwrap_or(72) as i64)
+    }
+
+    /// Look up the gids of the allowed groups
+    pub fn allowed_gids(&self) -> Result<Vec<u32>, String> {
+        let mut gids = Vec::new();
+        for group in &self.allowed_groups {
+            let name = CString::new(group.as_str()).map_err(|e| e.to_string())?;
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if entry.is_null() {
+                return Err(format!("Unknown group {} in allowed_groups", group));
+            }
+            gids.push(unsafe { (*entry).gr_gid });
+        }
+        Ok(gids)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApprovalFile {
+    disk: String,
+    approved_by: String,
+}
+
+// Only root or an allowed group may own an approval file and it can't be
+// writable by anyone else
+fn check_file_owner(uid: u32, gid: u32, mode: u32, allowed_gids: &[u32]) -> Result<(), String> {
+    if uid != 0 && !allowed_gids.contains(&gid) {
+        return Err(format!(
+            "owned by uid {} gid {} which isn't root or an allowed group",
+            uid, gid
+        ));
+    }
+    if mode & 0o022 != 0 {
+        return Err(format!("mode {:o} is group or world writable", mode & 0o777));
+    }
+    Ok(())
+}
+
+fn read_approval_file(path: &Path) -> Result<ApprovalFile, String> {
+    let mut f = File::open(path).map_err(|e| e.to_string())?;
+    let mut buff = String::new();
+    f.read_to_string(&mut buff).map_err(|e| e.to_string())?;
+    ::serde_json::from_str(&buff).map_err(|e| e.to_string())
+}
+
+/// Apply every approval file in the watched directory.  Files that were
+/// applied are removed and files that can't be parsed or aren't trusted are
+/// left alone.
+pub fn process_approval_files(
+    conn: &Connection,
+    watch_dir: &Path,
+    allowed_gids: &[u32],
+) -> Result<(), String> {
+    let entries: Vec<PathBuf> = fs::read_dir(watch_dir)
+        .map_err(|e| format!("Unable to read {}: {}", watch_dir.display(), e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    for path in entries {
+        // Don't follow symlinks.  The link's target could be owned by anyone
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                error!("Skipping approval file {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if !metadata.file_type().is_file() {
+            continue;
+        }
+        if let Err(e) = check_file_owner(
+            metadata.uid(),
+            metadata.gid(),
+            metadata.permissions().mode(),
+            allowed_gids,
+        ) {
+            error!("Not trusting approval file {}: {}", path.display(), e);
+            continue;
+        }
+        let approval = match read_approval_file(&path) {
+            Ok(approval) => approval,
+            Err(e) => {
+                error!("Skipping approval file {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if in_progress::approve_removal(
+            conn,
+            &Path::new(&approval.disk),
+            &approval.approved_by,
+            metadata.uid(),
+        ).map_err(|e| e.to_string())?
+        {
+            info!(
+                "{} (uid {}) approved removing {} with {}",
+                approval.approved_by,
+                metadata.uid(),
+                approval.disk,
+                path.display()
+            );
+        } else {
+            warn!(
+                "{} has no removal waiting for approval.  Ignoring {}",
+                approval.disk,
+                path.display()
+            );
+        }
+        fs::remove_file(&path).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// bynar approve <disk>
+pub fn handle_approve(config_dir: &str, matches: &ArgMatches) -> Result<(), String> {
+    let config: ConfigSettings =
+        helpers::load_config(config_dir, "bynar.json").map_err(|e| e.to_string())?;
+    let conn = in_progress::connect_to_repair_database(Path::new(&config.db_location))
+        .map_err(|e| e.to_string())?;
+    let disk = matches.value_of("device").unwrap();
+    // Record the human behind sudo rather than root
+    let approved_by = match matches.value_of("by") {
+        Some(by) => by.to_string(),
+        None => env::var("SUDO_USER")
+            .or_else(|_| env::var("USER"))
+            .map_err(|_| "Unable to tell who is approving.  Use --by".to_string())?,
+    };
+    // The real uid behind sudo if there is one
+    let approver_uid = match env::var("SUDO_UID").ok().and_then(|uid| uid.parse().ok()) {
+        Some(uid) => uid,
+        None => unsafe { libc::getuid() },
+    };
+    if in_progress::approve_removal(&conn, &Path::new(disk), &approved_by, approver_uid)
+        .map_err(|e| e.to_string())?
+    {
+        println!("Removal of {} approved by {}", disk, approved_by);
+        Ok(())
+    } else {
+        Err(format!("{} has no removal waiting for approval", disk))
+    }
+}
+
+/// Queue a removal for approval unless one is already waiting.  Returns true
+/// if a new request was queued.
+pub fn queue_removal(
+    conn: &Connection,
+    policy: &ApprovalPolicy,
+    dev_path: &Path,
+) -> Result<bool, String> {
+    if in_progress::get_open_removal_approval(conn, dev_path)
+        .map_err(|e| e.to_string())?
+        .is_some()
+    {
+        debug!("Removal of {} is already queued", dev_path.display());
+        return Ok(false);
+    }
+    in_progress::request_removal_approval(conn, dev_path, policy.expires())
+        .map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+/// Ask for approval of removals that were held back because they weren't safe
+/// or allowed yet.  They're removed once approved like any other request.
+/// Returns the disks that got a new request.
+pub fn request_queued_approvals(
+    conn: &Connection,
+    policy: &ApprovalPolicy,
+) -> Result<Vec<String>, String> {
+    let mut requested = Vec::new();
+    for disk in in_progress::get_queued_removals(conn).map_err(|e| e.to_string())? {
+        if queue_removal(conn, policy, Path::new(&disk))? {
+            requested.push(disk);
+        }
+    }
+    Ok(requested)
+}
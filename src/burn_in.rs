@@ -0,0 +1,520 @@
+//! Verify a replacement disk before it's added back into the cluster.
+//! A resolved ticket only means someone said the disk was swapped.  Before
+//! bynar adds it back the disk in the slot must have a different serial
+//! number than the one that failed, be at least as large and of the same
+//! media type.  It then has to pass the SMART self-tests and an optional
+//! write/read verify pass.  Self-tests run in the background on the disk so
+//! each run of bynar moves the disk along one stage until it's done.
+extern crate block_utils;
+extern crate libc;
+extern crate rusqlite;
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use in_progress;
+
+use self::rusqlite::Connection;
+
+#[cfg(test)]
+mod tests {
+    use super::{fill_pattern, parse_self_test_log, self_test_running, throttle_delay, SelfTest};
+    use std::time::Duration;
+
+    #[test]
+    fn test_self_test_log() {
+        let passed = r#"smartctl 6.6 2016-05-31 r4324 [x86_64-linux-4.15.0-36-generic] (local build)
+Copyright (C) 2002-16, Bruce Allen, Christian Franke, www.smartmontools.org
+
+=== START OF READ SMART DATA SECTION ===
+SMART Self-test log structure revision number 1
+Num  Test_Description    Status                  Remaining  LifeTime(hours)  LBA_of_first_error
+# 1  Short offline       Completed without error       00%     15822         -
+# 2  Extended offline    Completed: read failure       90%     15820         123456
+"#;
+        assert_eq!(
+            parse_self_test_log(passed),
+            Some(SelfTest {
+                description: "Short offline".to_string(),
+                status: "Completed without error".to_string(),
+                passed: true,
+            })
+        );
+
+        let failed = r#"Num  Test_Description    Status                  Remaining  LifeTime(hours)  LBA_of_first_error
+# 1  Extended offline    Completed: read failure       90%     15820         123456
+"#;
+        let test = parse_self_test_log(failed).unwrap();
+        assert_eq!(test.description, "Extended offline");
+        assert_eq!(test.status, "Completed: read failure");
+        assert!(!test.passed);
+
+        assert_eq!(parse_self_test_log("No self-tests have been logged."), None);
+    }
+
+    #[test]
+    fn test_self_test_running() {
+        let running = r#"General SMART Values:
+Offline data collection status:  (0x00)	Offline data collection activity
+					was never started.
+Self-test execution status:      ( 249)	Self-test routine in progress...
+					90% of test remaining.
+Total time to complete Offline
+"#;
+        assert!(self_test_running(running));
+        let done = r#"Self-test execution status:      (   0)	The previous self-test routine completed
+					without error or no self-test has ever
+					been run.
+"#;
+        assert!(!self_test_running(done));
+    }
+
+    #[test]
+    fn test_fill_pattern() {
+        let mut a = vec![0u8; 4096];
+        let mut b = vec![0u8; 4096];
+        fill_pattern(&mut a, 0);
+        fill_pattern(&mut b, 4096);
+        // Every block gets a different pattern so misdirected writes are caught
+        assert!(a != b);
+        let mut c = vec![0u8; 4096];
+        fill_pattern(&mut c, 4096);
+        assert_eq!(b, c);
+    }
+
+    #[test]
+    fn test_throttle_delay() {
+        let mb = 1024 * 1024;
+        // 10MB in 1 second at 5MB/s should wait another second
+        assert_eq!(
+            throttle_delay(10 * mb, Duration::from_secs(1), 5 * mb),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(throttle_delay(5 * mb, Duration::from_secs(2), 5 * mb), None);
+        assert_eq!(throttle_delay(10 * mb, Duration::from_secs(1), 0), None);
+    }
+}
+
+// Verify in 1MB chunks.  O_DIRECT needs the buffer aligned to the block size
+const CHUNK_SIZE: usize = 1024 * 1024;
+const ALIGNMENT: usize = 4096;
+
+/// Settings for verifying replacement disks in bynar.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct BurnInConfig {
+    /// Run a SMART short self-test.  Default: true
+    #[serde(default = "default_true")]
+    pub smart_short: bool,
+    /// Run a SMART extended self-test.  Default: false
+    #[serde(default)]
+    pub smart_long: bool,
+    /// Write and read back this many MB from the start of the disk
+    pub verify_mb: Option<u64>,
+    /// Limit the write/read verify to this many MB/s.  0 is no limit
+    pub verify_max_mb_per_sec: Option<u64>,
+    /// The replacement must have the same media type as the failed disk.
+    /// Default: true
+    #[serde(default = "default_true")]
+    pub require_same_media_type: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for BurnInConfig {
+    fn default() -> Self {
+        BurnInConfig {
+            smart_short: true,
+            smart_long: false,
+            verify_mb: None,
+            verify_max_mb_per_sec: None,
+            require_same_media_type: true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BurnIn {
+    /// The disk can be added back
+    Passed,
+    /// Still being verified.  Check again on the next run
+    InProgress(String),
+    /// The disk failed verification on this run
+    Failed(String),
+    /// The disk failed verification on an earlier run
+    Rejected(String),
+}
+
+// Stages stored in the burn_in table
+const STAGE_CHECKS: &str = "checks";
+const STAGE_SMART_SHORT: &str = "smart_short";
+const STAGE_SMART_LONG: &str = "smart_long";
+const STAGE_VERIFY: &str = "verify";
+const STAGE_PASSED: &str = "passed";
+const STAGE_FAILED: &str = "failed";
+// Detail saved once a SMART self-test has been kicked off
+const STARTED: &str = "started";
+
+#[derive(Debug, PartialEq)]
+struct SelfTest {
+    description: String,
+    status: String,
+    passed: bool,
+}
+
+/// Move the disk in dev_path along its burn in and report where it's at
+pub fn verify_replacement(
+    conn: &Connection,
+    dev_path: &Path,
+    config: &BurnInConfig,
+    simulate: bool,
+) -> Result<BurnIn, String> {
+    let device = match block_utils::get_device_info(dev_path) {
+        Ok(device) => device,
+        Err(e) => {
+            return Ok(BurnIn::InProgress(format!(
+                "No disk found at {}: {}",
+                dev_path.display(),
+                e
+            )))
+        }
+    };
+    let serial = match device.serial_number {
+        Some(ref serial) => serial.clone(),
+        None => {
+            return Ok(BurnIn::InProgress(format!(
+                "{} has no serial number yet",
+                dev_path.display()
+            )))
+        }
+    };
+    // A different disk in the slot starts over
+    let (mut stage, mut started) =
+        match in_progress::get_burn_in(conn, dev_path).map_err(|e| e.to_string())? {
+            Some(ref record) if record.serial_number == serial => (
+                record.stage.clone(),
+                record.detail == Some(STARTED.to_string()),
+            ),
+            _ => (STAGE_CHECKS.to_string(), false),
+        };
+    loop {
+        let next = match stage.as_str() {
+            STAGE_PASSED => return Ok(BurnIn::Passed),
+            STAGE_FAILED => {
+                let record = in_progress::get_burn_in(conn, dev_path).map_err(|e| e.to_string())?;
+                return Ok(BurnIn::Rejected(
+                    record
+                        .and_then(|r| r.detail)
+                        .unwrap_or_else(|| "Failed burn in".to_string()),
+                ));
+            }
+            STAGE_CHECKS => check_replacement(conn, dev_path, &device, config)?
+                .map(Err)
+                .unwrap_or_else(|| Ok(next_stage(STAGE_CHECKS, config))),
+            STAGE_SMART_SHORT | STAGE_SMART_LONG => {
+                let long = stage == STAGE_SMART_LONG;
+                if simulate {
+                    Ok(next_stage(&stage, config))
+                } else if !started {
+                    start_self_test(dev_path, long)?;
+                    save(conn, dev_path, &serial, &stage, Some(STARTED))?;
+                    return Ok(BurnIn::InProgress(format!(
+                        "Started SMART self-test of {}",
+                        dev_path.display()
+                    )));
+                } else {
+                    match self_test_result(dev_path, long)? {
+                        Some(ref test) if test.passed => Ok(next_stage(&stage, config)),
+                        Some(test) => Err(format!(
+                            "SMART {} self-test failed: {}",
+                            test.description, test.status
+                        )),
+                        None => {
+                            return Ok(BurnIn::InProgress(format!(
+                                "Waiting on SMART self-test of {}",
+                                dev_path.display()
+                            )))
+                        }
+                    }
+                }
+            }
+            STAGE_VERIFY => {
+                let mb = config.verify_mb.unwrap_or(0);
+                if simulate || mb == 0 {
+                    Ok(next_stage(STAGE_VERIFY, config))
+                } else {
+                    write_read_verify(
+                        dev_path,
+                        mb * 1024 * 1024,
+                        config.verify_max_mb_per_sec.map(|m| m * 1024 * 1024),
+                    ).map(|_| next_stage(STAGE_VERIFY, config))
+                }
+            }
+            other => Err(format!("Unknown burn in stage {}", other)),
+        };
+        match next {
+            Ok(next) => {
+                debug!("{} burn in moving from {} to {}", dev_path.display(), stage, next);
+                save(conn, dev_path, &serial, next, None)?;
+                stage = next.to_string();
+                started = false;
+            }
+            Err(reason) => {
+                error!("{} failed burn in: {}", dev_path.display(), reason);
+                save(conn, dev_path, &serial, STAGE_FAILED, Some(&reason))?;
+                return Ok(BurnIn::Failed(reason));
+            }
+        }
+    }
+}
+
+/// Remember the disk in dev_path so its replacement can be checked against it.
+/// The disk must already be ticketed.
+pub fn save_failed_disk(conn: &Connection, dev_path: &Path) -> Result<(), String> {
+    let device = block_utils::get_device_info(dev_path)?;
+    in_progress::save_failed_disk_info(
+        conn,
+        dev_path,
+        device.serial_number.as_ref().map(|s| s.as_str()),
+        Some(device.capacity),
+        Some(&format!("{:?}", device.media_type)),
+    ).map_err(|e| e.to_string())
+}
+
+fn save(
+    conn: &Connection,
+    dev_path: &Path,
+    serial: &str,
+    stage: &str,
+    detail: Option<&str>,
+) -> Result<(), String> {
+    in_progress::save_burn_in(conn, dev_path, serial, stage, detail).map_err(|e| e.to_string())
+}
+
+fn next_stage(stage: &str, config: &BurnInConfig) -> &'static str {
+    let stages = [
+        (STAGE_SMART_SHORT, config.smart_short),
+        (STAGE_SMART_LONG, config.smart_long),
+        (STAGE_VERIFY, config.verify_mb.is_some()),
+        (STAGE_PASSED, true),
+    ];
+    let start = stages
+        .iter()
+        .position(|s| s.0 == stage)
+        .map(|p| p + 1)
+        .unwrap_or(0);
+    stages[start..]
+        .iter()
+        .find(|s| s.1)
+        .map(|s| s.0)
+        .unwrap_or(STAGE_PASSED)
+}
+
+// Compare the new disk against the one that failed.  Returns why it doesn't
+// fit the slot if it doesn't.
+fn check_replacement(
+    conn: &Connection,
+    dev_path: &Path,
+    device: &block_utils::Device,
+    config: &BurnInConfig,
+) -> Result<Option<String>, String> {
+    let failed = match in_progress::get_failed_disk_info(conn, dev_path)
+        .map_err(|e| e.to_string())?
+    {
+        Some(failed) => failed,
+        None => {
+            // Without it a disk that's too small or the failed disk itself
+            // could be added back
+            return Ok(Some(format!(
+                "Nothing is known about the disk that failed in {}.  Unable to check the \
+                 replacement",
+                dev_path.display()
+            )));
+        }
+    };
+    if let Some(ref old_serial) = failed.serial_number {
+        if device.serial_number.as_ref() == Some(old_serial) {
+            return Ok(Some(format!(
+                "{} still has the failed disk {} in it",
+                dev_path.display(),
+                old_serial
+            )));
+        }
+    }
+    if let Some(capacity) = failed.capacity {
+        if device.capacity < capacity {
+            return Ok(Some(format!(
+                "Replacement is {} bytes but the slot needs at least {}",
+                device.capacity, capacity
+            )));
+        }
+    }
+    if config.require_same_media_type {
+        let media_type = format!("{:?}", device.media_type);
+        if let Some(ref old_media_type) = failed.media_type {
+            if &media_type != old_media_type {
+                return Ok(Some(format!(
+                    "Replacement is {} but the slot needs {}",
+                    media_type, old_media_type
+                )));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn smartctl(args: &[&str]) -> Result<String, String> {
+    debug!("cmd: smartctl {:?}", args);
+    let output = Command::new("smartctl")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Unable to run smartctl: {}", e))?;
+    // smartctl's exit code is a bitmask of warnings so look at the output instead
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn start_self_test(dev_path: &Path, long: bool) -> Result<(), String> {
+    info!(
+        "Starting SMART {} self-test on {}",
+        if long { "long" } else { "short" },
+        dev_path.display()
+    );
+    smartctl(&["-t", if long { "long" } else { "short" }, &dev_path.to_string_lossy()])?;
+    Ok(())
+}
+
+// The result of the self-test that was started.  None while it's still running
+fn self_test_result(dev_path: &Path, long: bool) -> Result<Option<SelfTest>, String> {
+    let dev = dev_path.to_string_lossy();
+    if self_test_running(&smartctl(&["-c", &dev])?) {
+        return Ok(None);
+    }
+    let description = if long { "Extended" } else { "Short" };
+    match parse_self_test_log(&smartctl(&["-l", "selftest", &dev])?) {
+        Some(test) => {
+            if test.description.starts_with(description) {
+                Ok(Some(test))
+            } else {
+                Err(format!(
+                    "Expected a {} self-test in the log but found {}",
+                    description, test.description
+                ))
+            }
+        }
+        None => Err(format!("No SMART self-test was logged for {}", dev)),
+    }
+}
+
+fn self_test_running(output: &str) -> bool {
+    output
+        .lines()
+        .skip_while(|l| !l.starts_with("Self-test execution status"))
+        .take(2)
+        .any(|l| l.contains("in progress"))
+}
+
+// Pull the most recent entry out of smartctl -l selftest
+fn parse_self_test_log(output: &str) -> Option<SelfTest> {
+    let line = output.lines().find(|l| l.starts_with("# 1 "))?;
+    let fields: Vec<&str> = line["# 1 ".len()..]
+        .split("  ")
+        .map(|f| f.trim())
+        .filter(|f| !f.is_empty())
+        .collect();
+    if fields.len() < 2 {
+        return None;
+    }
+    Some(SelfTest {
+        description: fields[0].to_string(),
+        status: fields[1].to_string(),
+        passed: fields[1] == "Completed without error",
+    })
+}
+
+// Every 8 bytes hold their own offset on the disk
+fn fill_pattern(buff: &mut [u8], offset: u64) {
+    for (i, word) in buff.chunks_mut(8).enumerate() {
+        let value = (offset + (i * 8) as u64) ^ 0xb7a3_5c1d_9e4f_0a26;
+        for (j, byte) in word.iter_mut().enumerate() {
+            *byte = (value >> (j * 8)) as u8;
+        }
+    }
+}
+
+// How long to sleep to keep under max_bytes_per_sec.  0 is unthrottled
+fn throttle_delay(done: u64, elapsed: Duration, max_bytes_per_sec: u64) -> Option<Duration> {
+    if max_bytes_per_sec == 0 {
+        return None;
+    }
+    let expected = Duration::from_millis(done * 1000 / max_bytes_per_sec);
+    if expected > elapsed {
+        Some(expected - elapsed)
+    } else {
+        None
+    }
+}
+
+// A CHUNK_SIZE slice of buff that is aligned for O_DIRECT
+fn aligned(buff: &mut Vec<u8>) -> &mut [u8] {
+    let offset = ALIGNMENT - (buff.as_ptr() as usize % ALIGNMENT);
+    let offset = if offset == ALIGNMENT { 0 } else { offset };
+    &mut buff[offset..offset + CHUNK_SIZE]
+}
+
+// Write a pattern to the start of the disk and read it back.  This destroys
+// any data on the disk.  O_DIRECT keeps the page cache from answering reads.
+fn write_read_verify(
+    dev_path: &Path,
+    bytes: u64,
+    max_bytes_per_sec: Option<u64>,
+) -> Result<(), String> {
+    info!("Write/read verifying {} bytes of {}", bytes, dev_path.display());
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(dev_path)
+        .map_err(|e| format!("Unable to open {}: {}", dev_path.display(), e))?;
+    let mut write_buff = vec![0u8; CHUNK_SIZE + ALIGNMENT];
+    let mut read_buff = vec![0u8; CHUNK_SIZE + ALIGNMENT];
+    let chunks = (bytes + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
+    let start = Instant::now();
+    // Write everything first so the reads can't come from a drive's cache
+    for chunk in 0..chunks {
+        let offset = chunk * CHUNK_SIZE as u64;
+        let buff = aligned(&mut write_buff);
+        fill_pattern(buff, offset);
+        f.write_all(buff)
+            .map_err(|e| format!("Write failed at byte {}: {}", offset, e))?;
+        if let Some(max) = max_bytes_per_sec {
+            if let Some(delay) = throttle_delay(offset + CHUNK_SIZE as u64, start.elapsed(), max) {
+                thread::sleep(delay);
+            }
+        }
+    }
+    f.sync_all().map_err(|e| e.to_string())?;
+    f.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    for chunk in 0..chunks {
+        let offset = chunk * CHUNK_SIZE as u64;
+        fill_pattern(aligned(&mut write_buff), offset);
+        let read = aligned(&mut read_buff);
+        f.read_exact(read)
+            .map_err(|e| format!("Read failed at byte {}: {}", offset, e))?;
+        if read != aligned(&mut write_buff) {
+            return Err(format!("Data read back didn't match at byte {}", offset));
+        }
+        if let Some(max) = max_bytes_per_sec {
+            if let Some(delay) = throttle_delay(offset + CHUNK_SIZE as u64, start.elapsed(), max) {
+                thread::sleep(delay);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,280 @@
+//! Transitions that run a site provided executable instead of compiled in
+//! Rust code.  This allows vendor specific diagnostics to be plugged into
+//! the state machine without forking bynar.
+//! The command is handed a json document on stdin describing the device,
+//! the state it is in and the state the transition would move it to.
+//! An exit code of 0 moves the disk to the `to` state and anything else
+//! fails the transition.  A command can also pick the next state itself by
+//! printing `{"state": "<state name>"}` on stdout.  The state machine only
+//! follows a picked state the graph has a transition to from the current state.
+extern crate block_utils;
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::block_utils::Device;
+use test_disk::State;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::block_utils::{Device, FilesystemType, MediaType};
+    use super::CommandTransition;
+    use test_disk::State;
+
+    fn device() -> Device {
+        Device {
+            id: None,
+            name: "sdz".to_string(),
+            media_type: MediaType::Rotational,
+            capacity: 26214400,
+            fs_type: FilesystemType::Xfs,
+            serial_number: Some("123456".into()),
+        }
+    }
+
+    fn shell(script: &str, timeout: u64) -> CommandTransition {
+        CommandTransition {
+            command: "/bin/sh".into(),
+            args: vec!["-c".to_string(), script.to_string()],
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+
+    #[test]
+    fn test_command_exit_code() {
+        let ok = shell("cat > /dev/null", 10);
+        assert_eq!(
+            ok.transition(&State::Scanned, &State::Good, &device(), false),
+            State::Good
+        );
+        let fail = shell("cat > /dev/null; exit 3", 10);
+        assert_eq!(
+            fail.transition(&State::Scanned, &State::Good, &device(), false),
+            State::Fail
+        );
+    }
+
+    #[test]
+    fn test_command_reply() {
+        // The command can read the device it was given and pick the next state
+        let cmd = shell(
+            "grep -q '\"device\":\"/dev/sdz\"' && echo '{\"state\": \"worn_out\"}'",
+            10,
+        );
+        assert_eq!(
+            cmd.transition(&State::Scanned, &State::Good, &device(), false),
+            State::WornOut
+        );
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let cmd = shell("sleep 30", 1);
+        assert_eq!(
+            cmd.transition(&State::Scanned, &State::Good, &device(), false),
+            State::Fail
+        );
+    }
+
+    #[test]
+    fn test_command_simulate() {
+        let cmd = shell("exit 1", 10);
+        assert_eq!(
+            cmd.transition(&State::Scanned, &State::Good, &device(), true),
+            State::Good
+        );
+    }
+}
+
+/// How long a command is given to finish if the graph doesn't say otherwise
+pub const DEFAULT_COMMAND_TIMEOUT: u64 = 300;
+
+#[derive(Serialize)]
+struct DiskInfo<'a> {
+    name: &'a str,
+    id: Option<String>,
+    media_type: String,
+    capacity: u64,
+    fs_type: String,
+    serial_number: Option<&'a str>,
+}
+
+// The json document that is written to the command's stdin
+#[derive(Serialize)]
+struct CommandRequest<'a> {
+    device: String,
+    state: &'a State,
+    to_state: &'a State,
+    simulate: bool,
+    disk: DiskInfo<'a>,
+}
+
+// The optional json reply read from the command's stdout
+#[derive(Deserialize)]
+struct CommandReply {
+    state: State,
+}
+
+#[derive(Clone, Debug)]
+pub struct CommandTransition {
+    pub command: PathBuf,
+    pub args: Vec<String>,
+    pub timeout: Duration,
+}
+
+impl CommandTransition {
+    pub fn new(command: &str, args: &[String], timeout: Option<u64>) -> Self {
+        CommandTransition {
+            command: PathBuf::from(command),
+            args: args.to_vec(),
+            timeout: Duration::from_secs(timeout.unwrap_or(DEFAULT_COMMAND_TIMEOUT)),
+        }
+    }
+
+    /// Run the command and decide which state the device moves to
+    pub fn transition(
+        &self,
+        from_state: &State,
+        to_state: &State,
+        device: &Device,
+        simulate: bool,
+    ) -> State {
+        debug!("running {} command transition", self.command.display());
+        if simulate {
+            debug!("Simulate is set.  Skipping {}", self.command.display());
+            return *to_state;
+        }
+        let request = CommandRequest {
+            device: format!("/dev/{}", device.name),
+            state: from_state,
+            to_state,
+            simulate,
+            disk: DiskInfo {
+                name: &device.name,
+                id: device.id.map(|id| id.to_string()),
+                media_type: format!("{:?}", device.media_type),
+                capacity: device.capacity,
+                fs_type: device.fs_type.to_string(),
+                serial_number: device.serial_number.as_ref().map(|s| s.as_str()),
+            },
+        };
+        let input = match ::serde_json::to_string(&request) {
+            Ok(input) => input,
+            Err(e) => {
+                error!("Failed to encode request for {}: {}", self.command.display(), e);
+                return State::Fail;
+            }
+        };
+        let (status, stdout, stderr) = match self.run(&input) {
+            Ok(output) => output,
+            Err(e) => {
+                error!("{} failed: {}", self.command.display(), e);
+                return State::Fail;
+            }
+        };
+        if !stderr.is_empty() {
+            debug!("{} stderr: {}", self.command.display(), stderr.trim());
+        }
+        if !status.success() {
+            error!(
+                "{} exited with {:?} for /dev/{}",
+                self.command.display(),
+                status.code(),
+                device.name
+            );
+            return State::Fail;
+        }
+        if stdout.trim().is_empty() {
+            return *to_state;
+        }
+        match ::serde_json::from_str::<CommandReply>(stdout.trim()) {
+            Ok(reply) => {
+                debug!("{} selected state {}", self.command.display(), reply.state);
+                reply.state
+            }
+            Err(e) => {
+                error!(
+                    "Unable to parse reply from {}: {}.  Reply was: {}",
+                    self.command.display(),
+                    e,
+                    stdout
+                );
+                State::Fail
+            }
+        }
+    }
+
+    // Spawn the command, feed it the request and wait for it up to the timeout
+    fn run(&self, input: &str) -> Result<(ExitStatus, String, String)> {
+        debug!("cmd: {} {:?}", self.command.display(), self.args);
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A command that exits without reading stdin is fine
+            if let Err(e) = stdin.write_all(input.as_bytes()) {
+                debug!("Writing request to {} failed: {}", self.command.display(), e);
+            }
+        }
+        // Drain the pipes in the background so a chatty command can't block
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if start.elapsed() >= self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "{} timed out after {} seconds",
+                        self.command.display(),
+                        self.timeout.as_secs()
+                    ),
+                ));
+            }
+            thread::sleep(Duration::from_millis(100));
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+
+        Ok((status, stdout, stderr))
+    }
+}
+
+fn read_in_background<R>(pipe: Option<R>) -> thread::JoinHandle<String>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buff = String::new();
+        if let Some(mut p) = pipe {
+            let _ = p.read_to_string(&mut buff);
+        }
+        buff
+    })
+}
+
+/// Check that a command transition points at something that can be run
+pub fn validate_command(command: &str) -> ::std::result::Result<(), String> {
+    let path = Path::new(command);
+    if !path.is_absolute() {
+        return Err(format!("Command {} must be an absolute path", command));
+    }
+    if !path.exists() {
+        return Err(format!("Command {} does not exist", command));
+    }
+    Ok(())
+}
@@ -0,0 +1,473 @@
+//! Operator commands for looking at and changing what bynar thinks of disks
+//! without having to open the repair database by hand.
+extern crate rusqlite;
+extern crate time;
+
+use std::path::Path;
+
+use clap::ArgMatches;
+use helpers;
+use in_progress;
+use helpers::host_information::Host;
+use test_disk::State;
+use ConfigSettings;
+
+use self::rusqlite::Connection;
+use self::time::Timespec;
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::time;
+    use super::{all_disk_status, format_table, parse_until};
+    use in_progress;
+    use std::path::Path;
+    use test_disk::State;
+
+    #[test]
+    fn test_parse_until() {
+        let now = time::Timespec::new(1_000_000, 0);
+        assert_eq!(parse_until("2h", now).unwrap().sec, 1_000_000 + 7200);
+        assert_eq!(parse_until("7d", now).unwrap().sec, 1_000_000 + 7 * 86400);
+        assert_eq!(parse_until("30m", now).unwrap().sec, 1_000_000 + 1800);
+        assert_eq!(parse_until("2018-10-01", now).unwrap().sec, 1_538_352_000);
+        assert_eq!(parse_until("2018-10-01T12:00:00Z", now).unwrap().sec, 1_538_395_200);
+        assert!(parse_until("tomorrow", now).is_err());
+        assert!(parse_until("5y", now).is_err());
+    }
+
+    #[test]
+    fn test_format_table() {
+        let table = format_table(
+            &["DISK", "STATE"],
+            &[
+                vec!["/dev/sda".to_string(), "good".to_string()],
+                vec!["/dev/nvme0n1".to_string(), "suspect".to_string()],
+            ],
+        );
+        assert_eq!(
+            table,
+            "DISK          STATE\n/dev/sda      good\n/dev/nvme0n1  suspect\n"
+        );
+    }
+
+    #[test]
+    fn test_all_disk_status() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("status.sqlite3");
+        let conn =
+            in_progress::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let sda = Path::new("/dev/sda");
+        in_progress::save_state(&conn, sda, State::WaitingForReplacement).unwrap();
+        in_progress::record_new_repair_ticket(&conn, "001", sda).unwrap();
+        // Written before tickets shared the state's row
+        in_progress::save_state(&conn, Path::new("/dev/sdb"), State::WaitingForReplacement)
+            .unwrap();
+        conn.execute(
+            "INSERT INTO repairs (ticket_id, disk_path) VALUES ('002', '/dev/sdb')",
+            &[],
+        ).unwrap();
+
+        let status = all_disk_status(&conn).unwrap();
+        assert_eq!(status.len(), 2);
+        for (disk, ticket_id) in status.iter().zip(&["001", "002"]) {
+            assert_eq!(disk.state, Some(State::WaitingForReplacement));
+            assert_eq!(disk.ticket_id, Some(ticket_id.to_string()));
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DiskStatus {
+    disk: String,
+    state: Option<State>,
+    ticket_id: Option<String>,
+    ticket_created: Option<String>,
+    smart_passed: Option<bool>,
+    mount_path: Option<String>,
+    ignored: bool,
+    ignored_until: Option<String>,
+    ignore_reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HistoryEntry {
+    time: String,
+    from: State,
+    to: State,
+    transition: String,
+    result: State,
+}
+
+#[derive(Serialize)]
+struct DiskDetail {
+    status: DiskStatus,
+    history: Vec<HistoryEntry>,
+}
+
+#[derive(Serialize)]
+struct MaintenanceStatus {
+    maintenance: bool,
+    started: Option<String>,
+    ends: Option<String>,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Ticket {
+    ticket_id: String,
+    disk: String,
+    created: String,
+}
+
+fn format_time(ts: Timespec) -> String {
+    time::at_utc(ts).rfc3339().to_string()
+}
+
+fn or_dash<T: ToString>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+// Line up columns separated by 2 spaces
+fn format_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.len());
+        }
+    }
+    let mut lines: Vec<Vec<String>> = vec![headers.iter().map(|h| h.to_string()).collect()];
+    lines.extend(rows.iter().cloned());
+    let mut table = String::new();
+    for line in lines {
+        let last = line.len() - 1;
+        for (i, cell) in line.iter().enumerate() {
+            if i == last {
+                table.push_str(cell);
+            } else {
+                table.push_str(&format!("{:width$}  ", cell, width = widths[i]));
+            }
+        }
+        table.push('\n');
+    }
+    table
+}
+
+/// Parse a relative duration like 12h, 7d or 30m or an absolute date like
+/// 2018-10-01 or 2018-10-01T12:00:00Z into a point in time
+fn parse_until(until: &str, now: Timespec) -> Result<Timespec, String> {
+    for format in &["%Y-%m-%dT%H:%M:%SZ", "%Y-%m-%d"] {
+        if let Ok(tm) = time::strptime(until, format) {
+            return Ok(tm.to_timespec());
+        }
+    }
+    if until.len() < 2 || !until.is_ascii() {
+        return Err(format!("Invalid time {}.  Ex: 12h, 7d or 2018-10-01", until));
+    }
+    let (number, unit) = until.split_at(until.len() - 1);
+    let number: i64 = number
+        .parse()
+        .map_err(|_| format!("Invalid time {}.  Ex: 12h, 7d or 2018-10-01", until))?;
+    let duration = match unit {
+        "m" => time::Duration::minutes(number),
+        "h" => time::Duration::hours(number),
+        "d" => time::Duration::days(number),
+        "w" => time::Duration::weeks(number),
+        _ => return Err(format!("Unknown time unit {}.  Use m, h, d or w", unit)),
+    };
+    Ok(now + duration)
+}
+
+// Older databases kept a disk's ticket and state in separate rows so each
+// field comes from the first row that has it
+fn disk_status(
+    conn: &Connection,
+    disk: &str,
+    repairs: &[&in_progress::DiskRepair],
+    ignored: Option<&in_progress::IgnoredDisk>,
+) -> Result<DiskStatus, String> {
+    let dev_path = Path::new(disk);
+    Ok(DiskStatus {
+        disk: disk.to_string(),
+        state: repairs.iter().filter_map(|r| r.state).next(),
+        ticket_id: repairs.iter().filter_map(|r| r.ticket_id.clone()).next(),
+        ticket_created: repairs
+            .iter()
+            .filter_map(|r| r.time_created)
+            .next()
+            .map(format_time),
+        smart_passed: repairs.iter().filter_map(|r| r.smart_passed).next(),
+        mount_path: repairs.iter().filter_map(|r| r.mount_path.clone()).next(),
+        ignored: in_progress::is_disk_ignored(conn, &dev_path).map_err(|e| e.to_string())?,
+        ignored_until: ignored.and_then(|i| i.ignored_until).map(format_time),
+        ignore_reason: ignored.and_then(|i| i.reason.clone()),
+    })
+}
+
+// Every disk bynar knows about from either the repairs or ignored tables
+fn all_disk_status(conn: &Connection) -> Result<Vec<DiskStatus>, String> {
+    let repairs = in_progress::get_repairs(conn).map_err(|e| e.to_string())?;
+    let ignored = in_progress::get_ignored_disks(conn).map_err(|e| e.to_string())?;
+    let mut disks: Vec<String> = repairs
+        .iter()
+        .map(|r| r.disk_path.clone())
+        .chain(ignored.iter().map(|i| i.disk_path.clone()))
+        .collect();
+    disks.sort();
+    disks.dedup();
+
+    let mut status = Vec::new();
+    for disk in disks {
+        status.push(disk_status(
+            conn,
+            &disk,
+            &repairs
+                .iter()
+                .filter(|r| r.disk_path == disk)
+                .collect::<Vec<_>>(),
+            ignored.iter().find(|i| i.disk_path == disk),
+        )?);
+    }
+    Ok(status)
+}
+
+fn status_row(status: &DiskStatus) -> Vec<String> {
+    vec![
+        status.disk.clone(),
+        or_dash(&status.state),
+        or_dash(&status.ticket_id),
+        if status.ignored {
+            status
+                .ignored_until
+                .clone()
+                .unwrap_or_else(|| "forever".to_string())
+        } else {
+            "-".to_string()
+        },
+    ]
+}
+
+const STATUS_HEADERS: &[&str] = &["DISK", "STATE", "TICKET", "IGNORED UNTIL"];
+
+fn connect(config_dir: &str) -> Result<Connection, String> {
+    let config: ConfigSettings =
+        helpers::load_config(config_dir, "bynar.json").map_err(|e| e.to_string())?;
+    in_progress::connect_to_repair_database(Path::new(&config.db_location))
+        .map_err(|e| e.to_string())
+}
+
+/// bynar status
+pub fn handle_status(config_dir: &str, matches: &ArgMatches) -> Result<(), String> {
+    let conn = connect(config_dir)?;
+    let status = all_disk_status(&conn)?;
+    match matches.value_of("format") {
+        Some("json") => {
+            let json = ::serde_json::to_string_pretty(&status).map_err(|e| e.to_string())?;
+            println!("{}", json);
+        }
+        _ => {
+            let rows: Vec<Vec<String>> = status.iter().map(status_row).collect();
+            print!("{}", format_table(STATUS_HEADERS, &rows));
+        }
+    };
+    Ok(())
+}
+
+/// bynar disk show|ignore|unignore|reset
+pub fn handle_disk(config_dir: &str, matches: &ArgMatches) -> Result<(), String> {
+    let conn = &connect(config_dir)?;
+    match matches.subcommand() {
+        ("show", Some(m)) => {
+            let disk = m.value_of("device").unwrap();
+            let status = all_disk_status(conn)?
+                .into_iter()
+                .find(|s| s.disk == disk)
+                .ok_or_else(|| format!("{} is not in the repair database", disk))?;
+            let history: Vec<HistoryEntry> =
+                in_progress::get_state_history(conn, &Path::new(disk))
+                    .map_err(|e| e.to_string())?
+                    .into_iter()
+                    .map(|h| HistoryEntry {
+                        time: format_time(h.time_recorded),
+                        from: h.from_state,
+                        to: h.to_state,
+                        transition: h.transition,
+                        result: h.result_state,
+                    }).collect();
+            match m.value_of("format") {
+                Some("json") => {
+                    let detail = DiskDetail { status, history };
+                    let json =
+                        ::serde_json::to_string_pretty(&detail).map_err(|e| e.to_string())?;
+                    println!("{}", json);
+                }
+                _ => {
+                    print!("{}", format_table(STATUS_HEADERS, &[status_row(&status)]));
+                    if let Some(ref reason) = status.ignore_reason {
+                        println!("Ignore reason: {}", reason);
+                    }
+                    if !history.is_empty() {
+                        let rows: Vec<Vec<String>> = history
+                            .iter()
+                            .map(|h| {
+                                vec![
+                                    h.time.clone(),
+                                    h.transition.clone(),
+                                    format!("{} -> {}", h.from, h.to),
+                                    h.result.to_string(),
+                                ]
+                            }).collect();
+                        println!();
+                        print!(
+                            "{}",
+                            format_table(&["TIME", "TRANSITION", "EDGE", "RESULT"], &rows)
+                        );
+                    }
+                }
+            };
+        }
+        ("ignore", Some(m)) => {
+            let disk = m.value_of("device").unwrap();
+            let until = match m.value_of("until") {
+                Some(until) => Some(parse_until(until, time::get_time())?),
+                None => None,
+            };
+            in_progress::ignore_disk(conn, &Path::new(disk), until, m.value_of("reason"))
+                .map_err(|e| e.to_string())?;
+            match until {
+                Some(until) => println!("Ignoring {} until {}", disk, format_time(until)),
+                None => println!("Ignoring {}", disk),
+            };
+        }
+        ("unignore", Some(m)) => {
+            let disk = m.value_of("device").unwrap();
+            in_progress::unignore_disk(conn, &Path::new(disk)).map_err(|e| e.to_string())?;
+            println!("No longer ignoring {}", disk);
+        }
+        ("reset", Some(m)) => {
+            let disk = m.value_of("device").unwrap();
+            let dev_path = Path::new(disk);
+            in_progress::save_state(conn, &dev_path, State::Unscanned)
+                .map_err(|e| e.to_string())?;
+            in_progress::clear_evaluations(conn, &dev_path).map_err(|e| e.to_string())?;
+            println!("Reset {} to {}", disk, State::Unscanned);
+        }
+        _ => return Err(matches.usage().to_string()),
+    };
+    Ok(())
+}
+
+/// bynar ticket list
+pub fn handle_ticket(config_dir: &str, matches: &ArgMatches) -> Result<(), String> {
+    let conn = &connect(config_dir)?;
+    match matches.subcommand() {
+        ("list", Some(m)) => {
+            let tickets: Vec<Ticket> = in_progress::get_outstanding_repair_tickets(conn)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(|t| Ticket {
+                    ticket_id: t.ticket_id,
+                    disk: t.disk_path,
+                    created: format_time(t.time_created),
+                }).collect();
+            match m.value_of("format") {
+                Some("json") => {
+                    let json =
+                        ::serde_json::to_string_pretty(&tickets).map_err(|e| e.to_string())?;
+                    println!("{}", json);
+                }
+                _ => {
+                    let rows: Vec<Vec<String>> = tickets
+                        .iter()
+                        .map(|t| vec![t.ticket_id.clone(), t.disk.clone(), t.created.clone()])
+                        .collect();
+                    print!("{}", format_table(&["TICKET", "DISK", "CREATED"], &rows));
+                }
+            };
+        }
+        _ => return Err(matches.usage().to_string()),
+    };
+    Ok(())
+}
+
+// Let the channel know about maintenance changes on this host
+fn notify_maintenance(config: &ConfigSettings, msg: &str) -> Result<(), String> {
+    if config.slack_webhook.is_some() {
+        let host_info = Host::new().map_err(|e| e.to_string())?;
+        let _ = super::notify_slack(config, &format!("{} on host: {}", msg, host_info.hostname));
+    }
+    Ok(())
+}
+
+/// bynar maintenance start|stop|status
+pub fn handle_maintenance(config_dir: &str, matches: &ArgMatches) -> Result<(), String> {
+    let config: ConfigSettings =
+        helpers::load_config(config_dir, "bynar.json").map_err(|e| e.to_string())?;
+    let conn = &in_progress::connect_to_repair_database(Path::new(&config.db_location))
+        .map_err(|e| e.to_string())?;
+    match matches.subcommand() {
+        ("start", Some(m)) => {
+            let until = match m.value_of("until") {
+                Some(until) => Some(parse_until(until, time::get_time())?),
+                None => None,
+            };
+            in_progress::start_maintenance(conn, until, m.value_of("reason"))
+                .map_err(|e| e.to_string())?;
+            let msg = match until {
+                Some(until) => format!("Maintenance started until {}", format_time(until)),
+                None => "Maintenance started".to_string(),
+            };
+            println!("{}", msg);
+            notify_maintenance(&config, &msg)?;
+        }
+        ("stop", Some(_)) => {
+            if in_progress::end_maintenance(conn).map_err(|e| e.to_string())? {
+                println!("Maintenance ended");
+                notify_maintenance(&config, "Maintenance has ended")?;
+            } else {
+                println!("Not in maintenance");
+            }
+        }
+        ("status", Some(m)) => {
+            // Don't report maintenance that is past its end time
+            let maintenance = in_progress::get_maintenance(conn)
+                .map_err(|e| e.to_string())?
+                .filter(|maint| maint.time_ends.map(|e| e > time::get_time()).unwrap_or(true));
+            let status = MaintenanceStatus {
+                maintenance: maintenance.is_some(),
+                started: maintenance.as_ref().map(|m| format_time(m.time_started)),
+                ends: maintenance
+                    .as_ref()
+                    .and_then(|m| m.time_ends)
+                    .map(format_time),
+                reason: maintenance.as_ref().and_then(|m| m.reason.clone()),
+            };
+            match m.value_of("format") {
+                Some("json") => {
+                    let json =
+                        ::serde_json::to_string_pretty(&status).map_err(|e| e.to_string())?;
+                    println!("{}", json);
+                }
+                _ => {
+                    let row = vec![
+                        if status.maintenance { "yes" } else { "no" }.to_string(),
+                        or_dash(&status.started),
+                        or_dash(&status.ends),
+                        or_dash(&status.reason),
+                    ];
+                    print!(
+                        "{}",
+                        format_table(&["MAINTENANCE", "STARTED", "ENDS", "REASON"], &[row])
+                    );
+                }
+            };
+        }
+        _ => return Err(matches.usage().to_string()),
+    };
+    Ok(())
+}
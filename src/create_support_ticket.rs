@@ -3,6 +3,8 @@ extern crate log;
 extern crate reqwest;
 extern crate serde_json;
 
+use std::io::Cursor;
+
 use self::goji::issues::*;
 use self::goji::Error as GojiError;
 use self::goji::{Credentials, Jira};
@@ -67,6 +69,53 @@ pub fn create_support_ticket(
     Ok(results.id)
 }
 
+/// Attach a file to a JIRA support ticket
+pub fn attach_to_ticket(
+    settings: &ConfigSettings,
+    issue_id: &str,
+    file_name: &str,
+    contents: Vec<u8>,
+) -> Result<(), String> {
+    let client = match settings.proxy {
+        Some(ref url) => reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(url).map_err(|e| e.to_string())?)
+            .build(),
+        None => reqwest::Client::builder().build(),
+    }.map_err(|e| e.to_string())?;
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::reader(Cursor::new(contents)).file_name(file_name.to_string()),
+    );
+    // JIRA rejects attachments without this header
+    let mut headers = reqwest::header::Headers::new();
+    headers.set_raw("X-Atlassian-Token", "no-check");
+    let url = format!(
+        "{}/rest/api/2/issue/{}/attachments",
+        settings.jira_host.trim_end_matches('/'),
+        issue_id
+    );
+    debug!("Attaching {} to JIRA ticket {}", file_name, issue_id);
+    let response = client
+        .post(&url)
+        .headers(headers)
+        .basic_auth(
+            settings.jira_user.clone(),
+            Some(settings.jira_password.clone()),
+        )
+        .multipart(form)
+        .send()
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "Attaching {} to {} failed with {}",
+            file_name,
+            issue_id,
+            response.status()
+        ));
+    }
+    Ok(())
+}
+
 /// Check to see if a JIRA support ticket is marked as resolved
 pub fn ticket_resolved(settings: &ConfigSettings, issue_id: &str) -> Result<bool, GojiError> {
     let jira: Jira = match settings.proxy {
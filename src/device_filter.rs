@@ -0,0 +1,466 @@
+//! Decide which block devices bynar is allowed to evaluate.
+//! Sites can pin bynar to a set of devices with include rules and keep it
+//! away from others with exclude rules in bynar.json.  Every field of a rule
+//! has to match for the rule to match.  The disk holding the root filesystem
+//! is always skipped no matter what the rules say.
+extern crate block_utils;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+
+use self::block_utils::Device;
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        glob_match, parse_mountinfo, parse_size, DeviceFacts, DeviceFilterConfig, DeviceRule,
+    };
+    use std::path::PathBuf;
+
+    fn facts() -> DeviceFacts {
+        DeviceFacts {
+            name: "sdb".to_string(),
+            by_id: vec!["wwn-0x5000c500a1b2c3d4".into(), "ata-ST4000NM0033_Z1Z0ABCD".into()],
+            serial: Some("Z1Z0ABCD".to_string()),
+            model: Some("ST4000NM0033".to_string()),
+            capacity: 4_000_787_030_016,
+            transport: "ata".to_string(),
+            mountpoints: vec![PathBuf::from("/srv/ceph/osd-1")],
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("sd*", "sdb"));
+        assert!(glob_match("sd?", "sdb"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("nvme*n1", "nvme0n1"));
+        assert!(!glob_match("sd?", "sdab"));
+        assert!(!glob_match("nvme*", "sda"));
+        assert!(glob_match("ata-*_Z1Z0*", "ata-ST4000NM0033_Z1Z0ABCD"));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("1K").unwrap(), 1024);
+        assert_eq!(parse_size("2TB").unwrap(), 2 * 1024 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("100 GiB").unwrap(), 100 * 1024 * 1024 * 1024);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("100000P").is_err());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let mut config = DeviceFilterConfig {
+            include: vec![DeviceRule {
+                min_size: Some("1T".to_string()),
+                ..Default::default()
+            }],
+            exclude: vec![DeviceRule {
+                max_size: Some("2T".to_string()),
+                ..Default::default()
+            }],
+        };
+        assert!(config.validate().is_ok());
+        config.exclude[0].max_size = Some("2 terabytes".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_rule_match() {
+        let rule = DeviceRule {
+            name: Some("sd*".to_string()),
+            model: Some("ST4000*".to_string()),
+            min_size: Some("1T".to_string()),
+            ..Default::default()
+        };
+        assert!(rule.matches(&facts()).unwrap());
+
+        let usb = DeviceRule {
+            transport: Some("usb".to_string()),
+            ..Default::default()
+        };
+        assert!(!usb.matches(&facts()).unwrap());
+
+        let by_id = DeviceRule {
+            by_id: Some("/dev/disk/by-id/wwn-0x5000c500*".to_string()),
+            ..Default::default()
+        };
+        assert!(by_id.matches(&facts()).unwrap());
+
+        let small = DeviceRule {
+            max_size: Some("500G".to_string()),
+            ..Default::default()
+        };
+        assert!(!small.matches(&facts()).unwrap());
+
+        let mounted = DeviceRule {
+            mountpoint: Some("/srv/ceph/*".to_string()),
+            serial: Some("Z1Z0*".to_string()),
+            ..Default::default()
+        };
+        assert!(mounted.matches(&facts()).unwrap());
+
+        let bad = DeviceRule {
+            min_size: Some("big".to_string()),
+            ..Default::default()
+        };
+        assert!(bad.matches(&facts()).is_err());
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw,errors=remount-ro
+25 22 8:1 / /boot rw,relatime shared:7 - ext4 /dev/sda1 rw
+26 22 0:23 / /sys rw,nosuid shared:8 - sysfs sysfs rw
+30 22 8:17 / /srv/ceph/osd\\0401 rw,relatime shared:9 - xfs /dev/sdb1 rw
+";
+        let mounts = parse_mountinfo(mountinfo);
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(mounts[0], ("8:2".to_string(), PathBuf::from("/")));
+        assert_eq!(mounts[1], ("8:1".to_string(), PathBuf::from("/boot")));
+        assert_eq!(mounts[3].1, PathBuf::from("/srv/ceph/osd 1"));
+    }
+}
+
+/// Include and exclude rules from bynar.json
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DeviceFilterConfig {
+    /// If set only devices matching one of these rules are evaluated
+    #[serde(default)]
+    pub include: Vec<DeviceRule>,
+    /// Devices matching any of these rules are never evaluated
+    #[serde(default)]
+    pub exclude: Vec<DeviceRule>,
+}
+
+impl DeviceFilterConfig {
+    /// Check every size in the rules parses so a typo is caught when the
+    /// config is loaded instead of when a disk is evaluated
+    pub fn validate(&self) -> Result<()> {
+        for rule in self.include.iter().chain(self.exclude.iter()) {
+            if let Some(ref min_size) = rule.min_size {
+                parse_size(min_size)?;
+            }
+            if let Some(ref max_size) = rule.max_size {
+                parse_size(max_size)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A rule matches a device if every field that is set matches
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DeviceRule {
+    /// Glob on the kernel name.  Ex: sd*
+    pub name: Option<String>,
+    /// Glob on any /dev/disk/by-id link pointing at the device
+    pub by_id: Option<String>,
+    /// Glob on the disk serial number
+    pub serial: Option<String>,
+    /// Glob on the model from sysfs
+    pub model: Option<String>,
+    /// Smallest matching capacity.  Ex: 500G
+    pub min_size: Option<String>,
+    /// Largest matching capacity.  Ex: 2T
+    pub max_size: Option<String>,
+    /// One of usb, iscsi, nvme, ata, scsi or virtio
+    pub transport: Option<String>,
+    /// Glob on the mountpoint of the device or one of its partitions
+    pub mountpoint: Option<String>,
+}
+
+// Everything a rule can match on, gathered once per device
+#[derive(Debug)]
+struct DeviceFacts {
+    name: String,
+    // File names in /dev/disk/by-id
+    by_id: Vec<String>,
+    serial: Option<String>,
+    model: Option<String>,
+    capacity: u64,
+    transport: String,
+    mountpoints: Vec<PathBuf>,
+}
+
+impl DeviceRule {
+    fn matches(&self, facts: &DeviceFacts) -> Result<bool> {
+        if let Some(ref pattern) = self.name {
+            if !glob_match(pattern, &facts.name) {
+                return Ok(false);
+            }
+        }
+        if let Some(ref pattern) = self.by_id {
+            // Allow either the link name or the full path
+            let pattern = pattern.trim_start_matches("/dev/disk/by-id/");
+            if !facts.by_id.iter().any(|id| glob_match(pattern, id)) {
+                return Ok(false);
+            }
+        }
+        if let Some(ref pattern) = self.serial {
+            match facts.serial {
+                Some(ref serial) if glob_match(pattern, serial) => {}
+                _ => return Ok(false),
+            }
+        }
+        if let Some(ref pattern) = self.model {
+            match facts.model {
+                Some(ref model) if glob_match(pattern, model) => {}
+                _ => return Ok(false),
+            }
+        }
+        if let Some(ref min_size) = self.min_size {
+            if facts.capacity < parse_size(min_size)? {
+                return Ok(false);
+            }
+        }
+        if let Some(ref max_size) = self.max_size {
+            if facts.capacity > parse_size(max_size)? {
+                return Ok(false);
+            }
+        }
+        if let Some(ref transport) = self.transport {
+            if !transport.eq_ignore_ascii_case(&facts.transport) {
+                return Ok(false);
+            }
+        }
+        if let Some(ref pattern) = self.mountpoint {
+            if !facts
+                .mountpoints
+                .iter()
+                .any(|m| glob_match(pattern, &m.to_string_lossy()))
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+pub struct DeviceFilter {
+    config: DeviceFilterConfig,
+    // Kernel disk name -> mountpoints of the disk and its partitions
+    mounts: HashMap<String, Vec<PathBuf>>,
+}
+
+impl DeviceFilter {
+    pub fn new(config: Option<&DeviceFilterConfig>) -> Result<Self> {
+        if let Some(config) = config {
+            config.validate()?;
+        }
+        let mut f = File::open("/proc/self/mountinfo")?;
+        let mut buff = String::new();
+        f.read_to_string(&mut buff)?;
+
+        let mut mounts: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for (dev_num, mountpoint) in parse_mountinfo(&buff) {
+            for disk in disks_for_dev_num(&dev_num) {
+                mounts.entry(disk).or_default().push(mountpoint.clone());
+            }
+        }
+        Ok(DeviceFilter {
+            config: config.cloned().unwrap_or_default(),
+            mounts,
+        })
+    }
+
+    /// Returns true if bynar may evaluate this device
+    pub fn allowed(&self, device: &Device) -> Result<bool> {
+        let facts = self.facts(device);
+        if facts.mountpoints.iter().any(|m| m == Path::new("/")) {
+            debug!("Skipping {}.  It holds the root filesystem", device.name);
+            return Ok(false);
+        }
+        if !self.config.include.is_empty() {
+            let mut included = false;
+            for rule in &self.config.include {
+                if rule.matches(&facts)? {
+                    included = true;
+                    break;
+                }
+            }
+            if !included {
+                debug!("Skipping {}.  It matches no include rule", device.name);
+                return Ok(false);
+            }
+        }
+        for rule in &self.config.exclude {
+            if rule.matches(&facts)? {
+                debug!("Skipping {}.  It matches exclude rule {:?}", device.name, rule);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn facts(&self, device: &Device) -> DeviceFacts {
+        let sys_path = Path::new("/sys/block").join(&device.name);
+        DeviceFacts {
+            name: device.name.clone(),
+            by_id: by_id_links(&device.name),
+            serial: device.serial_number.clone(),
+            model: fs::read_to_string(sys_path.join("device/model"))
+                .ok()
+                .map(|m| m.trim().to_string()),
+            capacity: device.capacity,
+            transport: transport(&device.name, &sys_path),
+            mountpoints: self.mounts.get(&device.name).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+// The names of every /dev/disk/by-id link pointing at this device
+fn by_id_links(name: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    let dev_path = Path::new("/dev").join(name);
+    if let Ok(entries) = fs::read_dir("/dev/disk/by-id") {
+        for entry in entries.filter_map(|e| e.ok()) {
+            if let Ok(target) = fs::canonicalize(entry.path()) {
+                if target == dev_path {
+                    links.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+    links
+}
+
+// Guess how the device is attached from where it sits in the sysfs tree
+fn transport(name: &str, sys_path: &Path) -> String {
+    if name.starts_with("nvme") {
+        return "nvme".to_string();
+    }
+    if name.starts_with("vd") {
+        return "virtio".to_string();
+    }
+    let device_path = fs::canonicalize(sys_path)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if device_path.contains("/usb") {
+        "usb".to_string()
+    } else if device_path.contains("/session") {
+        // iSCSI disks hang off a session of their host
+        "iscsi".to_string()
+    } else if device_path.contains("/ata") {
+        "ata".to_string()
+    } else {
+        "scsi".to_string()
+    }
+}
+
+// Find the whole disks behind a major:minor device number.  Partitions map
+// to their parent disk and device mapper devices map to the disks under them.
+fn disks_for_dev_num(dev_num: &str) -> Vec<String> {
+    match fs::canonicalize(Path::new("/sys/dev/block").join(dev_num)) {
+        Ok(path) => disks_for_sys_path(&path),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn disks_for_sys_path(path: &Path) -> Vec<String> {
+    if path.join("partition").exists() {
+        return path
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|p| vec![p.to_string_lossy().into_owned()])
+            .unwrap_or_default();
+    }
+    let mut disks = Vec::new();
+    if let Ok(slaves) = fs::read_dir(path.join("slaves")) {
+        for slave in slaves.filter_map(|e| e.ok()) {
+            if let Ok(slave_path) = fs::canonicalize(slave.path()) {
+                disks.extend(disks_for_sys_path(&slave_path));
+            }
+        }
+    }
+    if disks.is_empty() {
+        if let Some(name) = path.file_name() {
+            disks.push(name.to_string_lossy().into_owned());
+        }
+    }
+    disks
+}
+
+// Pull the major:minor device number and mountpoint out of each line of
+// /proc/self/mountinfo
+fn parse_mountinfo(buff: &str) -> Vec<(String, PathBuf)> {
+    buff.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 5 {
+                return None;
+            }
+            // Spaces in mountpoints are escaped as \040
+            let mountpoint = fields[4].replace("\\040", " ");
+            Some((fields[2].to_string(), PathBuf::from(mountpoint)))
+        }).collect()
+}
+
+/// Parse a size like 500G or 2TB into bytes.  Units are powers of 1024.
+fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number.parse().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid size {}.  Ex: 500G", size),
+        )
+    })?;
+    let shift = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        "P" | "PB" | "PIB" => 50,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown size unit {} in {}", unit, size),
+            ))
+        }
+    };
+    number.checked_mul(1 << shift).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Size {} is too large", size),
+        )
+    })
+}
+
+/// Match a shell style glob supporting * and ?
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume if a * needs to swallow more characters
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    while p < pattern.len() && pattern[p] == '*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
@@ -0,0 +1,275 @@
+//! Gather what a field tech needs to know about a failed disk into a single
+//! text bundle.  The bundle is attached to the disk's repair ticket and can
+//! also be kept in a local directory.
+extern crate rusqlite;
+extern crate time;
+
+use std::fs::{create_dir_all, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use create_support_ticket::attach_to_ticket;
+use helpers::enclosure;
+use in_progress;
+use ConfigSettings;
+
+use self::rusqlite::Connection;
+
+#[cfg(test)]
+mod tests {
+    use super::{filter_kernel_log, filter_mounts, mentions_device};
+
+    #[test]
+    fn test_mentions_device() {
+        assert!(mentions_device("[  12.3] sd 0:0:1:0: [sdb] Sense Key : Medium Error", "sdb"));
+        assert!(mentions_device("blk_update_request: I/O error, dev sdb, sector 2048", "sdb"));
+        assert!(mentions_device("XFS (sdb1): metadata I/O error", "sdb"));
+        assert!(!mentions_device("sd 0:0:1:0: [sdba] Attached SCSI disk", "sdb"));
+        assert!(!mentions_device("EXT4-fs (sda1): mounted filesystem", "sdb"));
+        assert!(mentions_device("nvme0n1: p1 p2", "nvme0n1"));
+        assert!(mentions_device("nvme0n1p2: I/O error", "nvme0n1"));
+    }
+
+    #[test]
+    fn test_filter_kernel_log() {
+        let log = "sdb: first\nsda: other\nsdb: second\nsdb: third\n";
+        assert_eq!(
+            filter_kernel_log(log, "sdb", 2),
+            vec!["sdb: second".to_string(), "sdb: third".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_filter_mounts() {
+        let mounts = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                      /dev/sdb1 /var/lib/ceph/osd/ceph-3 xfs rw,noatime 0 0\n\
+                      proc /proc proc rw 0 0\n";
+        assert_eq!(
+            filter_mounts(mounts, "sdb"),
+            vec!["/dev/sdb1 /var/lib/ceph/osd/ceph-3 xfs rw,noatime 0 0".to_string()]
+        );
+    }
+}
+
+/// Settings for diagnostics bundles in bynar.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct DiagnosticsConfig {
+    /// Keep a copy of every bundle in this directory
+    pub directory: Option<String>,
+    /// Attach bundles to the repair ticket.  Default: true
+    #[serde(default = "default_attach")]
+    pub attach_to_ticket: bool,
+    /// The most kernel log lines to include for a disk.  Default: 200
+    pub kernel_log_lines: Option<usize>,
+}
+
+fn default_attach() -> bool {
+    true
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        DiagnosticsConfig {
+            directory: None,
+            attach_to_ticket: true,
+            kernel_log_lines: None,
+        }
+    }
+}
+
+/// A titled section of a diagnostics bundle
+pub struct Section {
+    pub title: String,
+    pub body: String,
+}
+
+impl Section {
+    fn new(title: &str, body: String) -> Self {
+        Section {
+            title: title.to_string(),
+            body,
+        }
+    }
+}
+
+// Does a kernel log line talk about this disk or one of its partitions?
+fn mentions_device(line: &str, dev_name: &str) -> bool {
+    line.match_indices(dev_name).any(|(i, _)| {
+        let before = line[..i].chars().next_back();
+        let after = &line[i + dev_name.len()..];
+        let boundary_before = before.map(|c| !c.is_alphanumeric()).unwrap_or(true);
+        // Partitions look like sdb1 or nvme0n1p1
+        let rest = after.trim_start_matches(|c: char| c == 'p' && dev_name.starts_with("nvme"));
+        let rest = rest.trim_start_matches(|c: char| c.is_digit(10));
+        let boundary_after = rest.chars().next().map(|c| !c.is_alphanumeric()).unwrap_or(true);
+        boundary_before && boundary_after
+    })
+}
+
+// The last max lines of the kernel log that mention the disk
+fn filter_kernel_log(log: &str, dev_name: &str, max: usize) -> Vec<String> {
+    let lines: Vec<String> = log
+        .lines()
+        .filter(|l| mentions_device(l, dev_name))
+        .map(|l| l.to_string())
+        .collect();
+    let skip = lines.len().saturating_sub(max);
+    lines.into_iter().skip(skip).collect()
+}
+
+// /proc/mounts entries for the disk and its partitions
+fn filter_mounts(mounts: &str, dev_name: &str) -> Vec<String> {
+    mounts
+        .lines()
+        .filter(|l| {
+            l.split_whitespace()
+                .next()
+                .map(|source| mentions_device(source, dev_name))
+                .unwrap_or(false)
+        })
+        .map(|l| l.to_string())
+        .collect()
+}
+
+fn command_output(cmd: &str, args: &[&str]) -> String {
+    debug!("cmd: {} {:?}", cmd, args);
+    match Command::new(cmd).args(args).output() {
+        // smartctl exits non zero for failing disks which is exactly when we
+        // want its output so don't look at the exit code
+        Ok(output) => {
+            let mut out = String::from_utf8_lossy(&output.stdout).into_owned();
+            out.push_str(&String::from_utf8_lossy(&output.stderr));
+            out
+        }
+        Err(e) => format!("Unable to run {}: {}", cmd, e),
+    }
+}
+
+fn state_history(conn: &Connection, dev_path: &Path) -> String {
+    match in_progress::get_state_history(conn, dev_path) {
+        Ok(history) => history
+            .iter()
+            .map(|h| {
+                format!(
+                    "{} {} -> {} via {} returned {}",
+                    time::at_utc(h.time_recorded).rfc3339(),
+                    h.from_state,
+                    h.to_state,
+                    h.transition,
+                    h.result_state
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+        Err(e) => format!("Unable to get the state history: {}", e),
+    }
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    let mut f = File::open(path).map_err(|e| e.to_string())?;
+    let mut buff = String::new();
+    f.read_to_string(&mut buff).map_err(|e| e.to_string())?;
+    Ok(buff)
+}
+
+/// Collect everything known about a failed disk.  This needs to happen before
+/// the disk is removed while its SMART data, mounts and kernel log still
+/// describe the failure
+pub fn collect(config: &ConfigSettings, conn: &Connection, dev_path: &Path) -> Vec<Section> {
+    let default_config = DiagnosticsConfig::default();
+    let diag_config = config.diagnostics.as_ref().unwrap_or(&default_config);
+    collect_sections(conn, dev_path, diag_config)
+}
+
+fn collect_sections(
+    conn: &Connection,
+    dev_path: &Path,
+    config: &DiagnosticsConfig,
+) -> Vec<Section> {
+    let dev = dev_path.to_string_lossy().into_owned();
+    let dev_name = dev_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| dev.clone());
+    let max_lines = config.kernel_log_lines.unwrap_or(200);
+
+    let mut sections = Vec::new();
+    sections.push(Section::new("SMART", command_output("smartctl", &["-x", &dev])));
+    let kernel_log = filter_kernel_log(&command_output("dmesg", &["-T"]), &dev_name, max_lines);
+    sections.push(Section::new("Kernel log", kernel_log.join("\n")));
+    sections.push(Section::new("State history", state_history(conn, dev_path)));
+    let mounts = match read_file("/proc/mounts") {
+        Ok(mounts) => filter_mounts(&mounts, &dev_name).join("\n"),
+        Err(e) => format!("Unable to read /proc/mounts: {}", e),
+    };
+    sections.push(Section::new("Mounts", mounts));
+    let location = match enclosure::find_slot(dev_path) {
+        Ok(Some(slot)) => format!("{}\nsysfs: {}", slot, slot.path.display()),
+        Ok(None) => "Not in a known enclosure".to_string(),
+        Err(e) => format!("Unable to search enclosures: {}", e),
+    };
+    sections.push(Section::new("Enclosure slot", location));
+    sections
+}
+
+fn render(dev_path: &Path, hostname: &str, sections: &[Section]) -> String {
+    let mut bundle = format!(
+        "Bynar diagnostics for {} on {}\nCollected: {}\n",
+        dev_path.display(),
+        hostname,
+        time::now_utc().rfc3339()
+    );
+    for section in sections {
+        bundle.push_str(&format!("\n===== {} =====\n", section.title));
+        if section.body.trim().is_empty() {
+            bundle.push_str("(nothing found)\n");
+        } else {
+            bundle.push_str(section.body.trim_end());
+            bundle.push('\n');
+        }
+    }
+    bundle
+}
+
+fn save(directory: &str, file_name: &str, bundle: &str) -> Result<PathBuf, String> {
+    create_dir_all(directory).map_err(|e| e.to_string())?;
+    let path = Path::new(directory).join(file_name);
+    let mut f = File::create(&path).map_err(|e| e.to_string())?;
+    f.write_all(bundle.as_bytes()).map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+/// Build the diagnostics bundle from the collected sections and deliver it to
+/// the ticket and the local directory.  Failures are logged and don't stop
+/// the ticket from being filed.
+pub fn deliver_bundle(
+    config: &ConfigSettings,
+    dev_path: &Path,
+    hostname: &str,
+    ticket_id: &str,
+    sections: &[Section],
+) {
+    let default_config = DiagnosticsConfig::default();
+    let diag_config = config.diagnostics.as_ref().unwrap_or(&default_config);
+    let bundle = render(dev_path, hostname, sections);
+    let file_name = format!(
+        "bynar-diagnostics-{}-{}.txt",
+        dev_path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        time::get_time().sec
+    );
+    if let Some(ref directory) = diag_config.directory {
+        match save(directory, &file_name, &bundle) {
+            Ok(path) => info!("Saved diagnostics bundle {}", path.display()),
+            Err(e) => error!("Unable to save diagnostics bundle to {}: {}", directory, e),
+        };
+    }
+    if diag_config.attach_to_ticket {
+        if let Err(e) = attach_to_ticket(config, ticket_id, &file_name, bundle.into_bytes()) {
+            error!("Unable to attach diagnostics to {}: {}", ticket_id, e);
+        }
+    }
+}
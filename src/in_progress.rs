@@ -2,6 +2,7 @@
 extern crate rusqlite;
 extern crate time;
 
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
@@ -15,7 +16,10 @@ mod tests {
     extern crate tempdir;
 
     use self::tempdir::TempDir;
+    use super::time;
+    use super::ApprovalStatus;
     use std::path::Path;
+    use test_disk::State;
 
     #[test]
     fn test_in_progress() {
@@ -34,6 +38,330 @@ mod tests {
 
         assert!(result);
     }
+
+    #[test]
+    fn test_open_ticket() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("tickets.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let dev = Path::new("/dev/sda");
+        super::save_state(&conn, dev, State::WaitingForReplacement).unwrap();
+        // A saved state alone is not a ticket
+        assert!(super::is_disk_in_progress(&conn, dev).unwrap());
+        assert!(!super::has_open_ticket(&conn, dev).unwrap());
+        super::record_new_repair_ticket(&conn, "005", dev).unwrap();
+        assert!(super::has_open_ticket(&conn, dev).unwrap());
+        // The ticket shares the row the state was saved in
+        let repairs = super::get_repairs(&conn).unwrap();
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs[0].ticket_id, Some("005".to_string()));
+        assert_eq!(repairs[0].state, Some(State::WaitingForReplacement));
+        super::resolve_ticket(&conn, "005").unwrap();
+        assert!(!super::has_open_ticket(&conn, dev).unwrap());
+        assert_eq!(
+            super::get_state(&conn, dev).unwrap(),
+            Some(State::WaitingForReplacement)
+        );
+    }
+
+    #[test]
+    fn test_state_history() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("history.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let dev = Path::new("/dev/sda");
+        super::record_transition(
+            &conn,
+            dev,
+            State::Unscanned,
+            State::Scanned,
+            "Scan",
+            State::Scanned,
+        ).expect("record transition failed");
+        super::record_transition(&conn, dev, State::Scanned, State::Good, "Eval", State::Fail)
+            .expect("record transition failed");
+        let history = super::get_state_history(&conn, dev).expect("history lookup failed");
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].transition, "Scan");
+        assert_eq!(history[1].result_state, State::Fail);
+        assert!(
+            super::get_state_history(&conn, &Path::new("/dev/sdb"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_failed_evaluations() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("evaluations.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let dev = Path::new("/dev/sda");
+        for failed in &[true, false, true, true] {
+            super::record_evaluation(&conn, dev, *failed).expect("record evaluation failed");
+        }
+        let count = |runs, minutes| {
+            super::count_failed_evaluations(&conn, dev, runs, minutes)
+                .expect("count evaluations failed")
+        };
+        assert_eq!(count(None, None), 3);
+        assert_eq!(count(Some(2), None), 2);
+        assert_eq!(count(Some(3), None), 2);
+        assert_eq!(count(None, Some(60)), 3);
+        assert_eq!(
+            super::count_failed_evaluations(&conn, &Path::new("/dev/sdb"), None, None).unwrap(),
+            0
+        );
+        // Nothing inside the window is lost
+        super::prune_evaluations(&conn, dev, None, Some(60)).expect("prune evaluations failed");
+        assert_eq!(count(None, None), 3);
+        super::prune_evaluations(&conn, dev, Some(3), None).expect("prune evaluations failed");
+        assert_eq!(count(None, None), 2);
+        assert_eq!(count(Some(3), None), 2);
+        super::clear_evaluations(&conn, dev).expect("clear evaluations failed");
+        assert_eq!(count(None, None), 0);
+    }
+
+    #[test]
+    fn test_ignored_disks() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("ignored.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let now = time::get_time();
+        super::ignore_disk(&conn, &Path::new("/dev/sda"), None, Some("bad backplane"))
+            .expect("ignore disk failed");
+        super::ignore_disk(
+            &conn,
+            &Path::new("/dev/sdb"),
+            Some(now + time::Duration::hours(1)),
+            None,
+        ).expect("ignore disk failed");
+        super::ignore_disk(
+            &conn,
+            &Path::new("/dev/sdc"),
+            Some(now - time::Duration::hours(1)),
+            None,
+        ).expect("ignore disk failed");
+        assert!(super::is_disk_ignored(&conn, &Path::new("/dev/sda")).unwrap());
+        assert!(super::is_disk_ignored(&conn, &Path::new("/dev/sdb")).unwrap());
+        // Expired
+        assert!(!super::is_disk_ignored(&conn, &Path::new("/dev/sdc")).unwrap());
+        assert!(!super::is_disk_ignored(&conn, &Path::new("/dev/sdd")).unwrap());
+
+        let ignored = super::get_ignored_disks(&conn).unwrap();
+        assert_eq!(ignored.len(), 3);
+        assert_eq!(ignored[0].reason, Some("bad backplane".to_string()));
+
+        super::unignore_disk(&conn, &Path::new("/dev/sda")).unwrap();
+        assert!(!super::is_disk_ignored(&conn, &Path::new("/dev/sda")).unwrap());
+    }
+
+    #[test]
+    fn test_removal_approvals() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("approvals.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let now = time::get_time();
+        let sda = Path::new("/dev/sda");
+        let sdb = Path::new("/dev/sdb");
+        super::request_removal_approval(&conn, sda, now + time::Duration::hours(1)).unwrap();
+        super::request_removal_approval(&conn, sdb, now - time::Duration::hours(1)).unwrap();
+
+        // Only unexpired requests can be approved
+        assert!(super::approve_removal(&conn, sda, "alice", 1000).unwrap());
+        assert!(!super::approve_removal(&conn, sdb, "bob", 1001).unwrap());
+        let expired = super::expire_removal_approvals(&conn).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].disk_path, "/dev/sdb");
+        assert!(super::get_open_removal_approval(&conn, sdb).unwrap().is_none());
+
+        let approved = super::get_open_removal_approval(&conn, sda).unwrap().unwrap();
+        assert_eq!(approved.status, ApprovalStatus::Approved);
+        assert_eq!(approved.approved_by, Some("alice".to_string()));
+        assert_eq!(approved.approver_uid, Some(1000));
+        super::set_approval_status(&conn, approved.id, ApprovalStatus::Removed).unwrap();
+        assert!(super::get_open_removal_approval(&conn, sda).unwrap().is_none());
+        assert_eq!(
+            super::get_removal_approvals(&conn, Some(ApprovalStatus::Removed))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(super::get_removal_approvals(&conn, None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_removals() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("removals.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let sda = Path::new("/dev/sda");
+        let sdb = Path::new("/dev/sdb");
+        super::queue_disk_removal(&conn, sda).unwrap();
+        // Queueing twice doesn't duplicate the removal
+        super::queue_disk_removal(&conn, sda).unwrap();
+        assert_eq!(super::get_queued_removals(&conn).unwrap(), vec!["/dev/sda"]);
+        assert_eq!(super::count_disks_out(&conn).unwrap(), 0);
+
+        super::record_disk_removed(&conn, sda).unwrap();
+        super::record_disk_removed(&conn, sdb).unwrap();
+        assert!(super::get_queued_removals(&conn).unwrap().is_empty());
+        assert_eq!(super::count_disks_out(&conn).unwrap(), 2);
+
+        super::record_disk_added(&conn, sda).unwrap();
+        assert_eq!(super::count_disks_out(&conn).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_failed_disk_info() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("failed.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        // Opening again must not try to add the columns twice
+        let conn2 = super::connect_to_repair_database(&db_path).expect("sqlite3 reopen failed");
+        drop(conn2);
+        let dev = Path::new("/dev/sda");
+        assert!(super::get_failed_disk_info(&conn, dev).unwrap().is_none());
+        super::record_new_repair_ticket(&conn, "003", dev).unwrap();
+        // A ticket alone doesn't say anything about the disk
+        assert!(super::get_failed_disk_info(&conn, dev).unwrap().is_none());
+        super::save_failed_disk_info(
+            &conn,
+            dev,
+            Some("Z1Z0ABCD"),
+            Some(4_000_787_030_016),
+            Some("Rotational"),
+        ).unwrap();
+        let info = super::get_failed_disk_info(&conn, dev).unwrap().unwrap();
+        assert_eq!(info.serial_number, Some("Z1Z0ABCD".to_string()));
+        assert_eq!(info.capacity, Some(4_000_787_030_016));
+        assert_eq!(info.media_type, Some("Rotational".to_string()));
+
+        super::save_burn_in(&conn, dev, "Z2Z0ABCD", "smart_short", None).unwrap();
+        super::save_burn_in(&conn, dev, "Z2Z0ABCD", "failed", Some("read failure")).unwrap();
+        let burn_in = super::get_burn_in(&conn, dev).unwrap().unwrap();
+        assert_eq!(burn_in.stage, "failed");
+        assert_eq!(burn_in.detail, Some("read failure".to_string()));
+        super::clear_burn_in(&conn, dev).unwrap();
+        assert!(super::get_burn_in(&conn, dev).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_wipe_certificates() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("wipe.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let dev = Path::new("/dev/sdc");
+        super::record_wipe_certificate(&conn, dev, r#"{"certificate": "{}"}"#).unwrap();
+        let unattached = super::get_unattached_wipe_certificates(&conn, dev).unwrap();
+        assert_eq!(unattached.len(), 1);
+        assert!(super::get_ticket_id(&conn, dev).unwrap().is_none());
+
+        super::record_new_repair_ticket(&conn, "004", dev).unwrap();
+        assert_eq!(super::get_ticket_id(&conn, dev).unwrap(), Some("004".to_string()));
+        super::mark_wipe_certificate_attached(&conn, unattached[0].id, "004").unwrap();
+        assert!(super::get_unattached_wipe_certificates(&conn, dev).unwrap().is_empty());
+
+        assert!(!super::is_wipe_pending(&conn, dev).unwrap());
+        super::add_pending_wipe(&conn, dev).unwrap();
+        super::add_pending_wipe(&conn, dev).unwrap();
+        assert!(super::is_wipe_pending(&conn, dev).unwrap());
+        assert_eq!(super::get_pending_wipes(&conn).unwrap(), vec!["/dev/sdc".to_string()]);
+        super::clear_pending_wipe(&conn, dev).unwrap();
+        assert!(super::get_pending_wipes(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_md_members() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("md.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let dev = Path::new("/dev/sdc");
+        super::record_md_member(&conn, dev, "md0", "sdc1", "removed").unwrap();
+        super::record_md_member(&conn, dev, "md1", "sdc2", "removed").unwrap();
+        assert_eq!(super::get_md_members(&conn, dev).unwrap().len(), 2);
+        assert!(super::get_rebuilding_md_members(&conn).unwrap().is_empty());
+        super::record_md_member(&conn, dev, "md0", "sdc1", "rebuilding").unwrap();
+        let rebuilding = super::get_rebuilding_md_members(&conn).unwrap();
+        assert_eq!(rebuilding.len(), 1);
+        assert_eq!(rebuilding[0].member, "sdc1");
+        super::clear_md_member(&conn, "md0", "sdc1").unwrap();
+        assert_eq!(super::get_md_members(&conn, dev).unwrap().len(), 1);
+
+        assert!(super::get_md_resync(&conn, "md0").unwrap().is_none());
+        super::save_md_resync(&conn, "md0", "recovery", 1024).unwrap();
+        super::mark_md_resync_stalled(&conn, "md0").unwrap();
+        let resync = super::get_md_resync(&conn, "md0").unwrap().unwrap();
+        assert_eq!(resync.completed, 1024);
+        assert!(resync.stalled);
+        super::clear_md_resync(&conn, "md0").unwrap();
+        assert!(super::get_md_resync(&conn, "md0").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_osd_ids() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("osd_ids.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let dev = Path::new("/dev/sdd");
+        assert_eq!(super::get_osd_id(&conn, dev).unwrap(), None);
+        super::record_osd_id(&conn, dev, 12).unwrap();
+        super::record_osd_id(&conn, dev, 14).unwrap();
+        assert_eq!(super::get_osd_id(&conn, dev).unwrap(), Some(14));
+        super::clear_osd_id(&conn, dev).unwrap();
+        assert_eq!(super::get_osd_id(&conn, dev).unwrap(), None);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("maintenance.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        assert!(super::get_maintenance(&conn).unwrap().is_none());
+        assert!(!super::end_maintenance(&conn).unwrap());
+
+        super::start_maintenance(&conn, None, Some("PSU swap")).unwrap();
+        let maintenance = super::get_maintenance(&conn).unwrap().unwrap();
+        assert_eq!(maintenance.reason, Some("PSU swap".to_string()));
+        assert!(super::expire_maintenance(&conn).unwrap().is_none());
+        assert!(super::end_maintenance(&conn).unwrap());
+        assert!(super::get_maintenance(&conn).unwrap().is_none());
+
+        // Maintenance with an end time in the past expires on the next check
+        let ended = time::get_time() - time::Duration::minutes(5);
+        super::start_maintenance(&conn, Some(ended), None).unwrap();
+        assert!(super::get_maintenance(&conn).unwrap().is_some());
+        let expired = super::expire_maintenance(&conn).unwrap().unwrap();
+        assert!(expired.time_ends.is_some());
+        assert!(super::get_maintenance(&conn).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_repairs() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("repairs.sqlite3");
+
+        let conn = super::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        super::save_state(&conn, &Path::new("/dev/sda"), State::Good).unwrap();
+        super::record_new_repair_ticket(&conn, "002", &Path::new("/dev/sdb")).unwrap();
+        let repairs = super::get_repairs(&conn).unwrap();
+        assert_eq!(repairs.len(), 2);
+        assert_eq!(repairs[0].state, Some(State::Good));
+        assert_eq!(repairs[0].ticket_id, None);
+        assert_eq!(repairs[1].ticket_id, Some("002".to_string()));
+        assert_eq!(repairs[1].state, None);
+    }
 }
 
 #[derive(Debug)]
@@ -44,6 +372,170 @@ pub struct DiskRepairTicket {
     pub disk_path: String,
 }
 
+/// Everything the repairs table knows about a disk
+#[derive(Debug)]
+pub struct DiskRepair {
+    pub disk_path: String,
+    pub ticket_id: Option<String>,
+    pub time_created: Option<Timespec>,
+    pub smart_passed: Option<bool>,
+    pub mount_path: Option<String>,
+    pub state: Option<test_disk::State>,
+}
+
+/// A disk an operator has told bynar to leave alone
+#[derive(Debug)]
+pub struct IgnoredDisk {
+    pub disk_path: String,
+    pub time_ignored: Timespec,
+    // Ignored forever if this isn't set
+    pub ignored_until: Option<Timespec>,
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApprovalStatus {
+    // Waiting on a human
+    Pending,
+    // A human said yes but the disk hasn't been removed yet
+    Approved,
+    // Nobody answered before the request expired
+    Expired,
+    Removed,
+}
+
+impl FromStr for ApprovalStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(ApprovalStatus::Pending),
+            "approved" => Ok(ApprovalStatus::Approved),
+            "expired" => Ok(ApprovalStatus::Expired),
+            "removed" => Ok(ApprovalStatus::Removed),
+            _ => Err(format!("Unknown approval status: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ApprovalStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApprovalStatus::Pending => write!(f, "pending"),
+            ApprovalStatus::Approved => write!(f, "approved"),
+            ApprovalStatus::Expired => write!(f, "expired"),
+            ApprovalStatus::Removed => write!(f, "removed"),
+        }
+    }
+}
+
+/// A request for a human to approve removing a disk.  The rows are never
+/// deleted so they also serve as an audit trail of who approved what.
+#[derive(Debug)]
+pub struct RemovalApproval {
+    pub id: i32,
+    pub disk_path: String,
+    pub time_requested: Timespec,
+    pub time_expires: Timespec,
+    pub status: ApprovalStatus,
+    pub approved_by: Option<String>,
+    pub time_approved: Option<Timespec>,
+    // The uid behind the approval.  approved_by is whatever name they gave
+    pub approver_uid: Option<u32>,
+}
+
+/// A period where bynar only observes the disks on this host
+#[derive(Debug)]
+pub struct Maintenance {
+    pub id: i32,
+    pub time_started: Timespec,
+    // Lasts until it's stopped if this isn't set
+    pub time_ends: Option<Timespec>,
+    pub reason: Option<String>,
+}
+
+/// The disk that was in a slot when it failed
+#[derive(Debug)]
+pub struct FailedDiskInfo {
+    pub serial_number: Option<String>,
+    pub capacity: Option<u64>,
+    pub media_type: Option<String>,
+}
+
+/// How far a replacement disk has gotten through burn in
+#[derive(Debug)]
+pub struct BurnInRecord {
+    pub disk_path: String,
+    pub serial_number: String,
+    pub stage: String,
+    pub time_updated: Timespec,
+    pub detail: Option<String>,
+}
+
+/// A signed wipe certificate handed back when a disk was removed
+#[derive(Debug)]
+pub struct WipeCertificateRecord {
+    pub id: i32,
+    pub disk_path: String,
+    pub time_recorded: Timespec,
+    pub certificate: String,
+    // The ticket it was attached to
+    pub ticket_id: Option<String>,
+}
+
+/// A member of an md array that was pulled out for a disk replacement
+#[derive(Debug)]
+pub struct MdMemberRecord {
+    pub disk_path: String,
+    pub array: String,
+    pub member: String,
+    // removed or rebuilding
+    pub status: String,
+}
+
+/// The last progress seen for an md array's resync
+#[derive(Debug)]
+pub struct MdResyncRecord {
+    pub array: String,
+    pub action: String,
+    pub completed: u64,
+    // When progress was last made
+    pub time_updated: Timespec,
+    pub stalled: bool,
+}
+
+/// A single transition attempt the state machine made for a disk
+#[derive(Debug)]
+pub struct StateHistory {
+    pub id: i32,
+    pub disk_path: String,
+    pub time_recorded: Timespec,
+    pub from_state: test_disk::State,
+    pub to_state: test_disk::State,
+    pub transition: String,
+    // The state the transition actually returned
+    pub result_state: test_disk::State,
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, kind: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map(&[], |row| {
+        let name: String = row.get(1);
+        name
+    })?;
+    for c in columns {
+        if c? == column {
+            return Ok(());
+        }
+    }
+    debug!("Adding column {} to {}", column, table);
+    conn.execute(
+        &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, kind),
+        &[],
+    )?;
+    Ok(())
+}
+
 pub fn connect_to_repair_database(db_path: &Path) -> Result<Connection> {
     let conn = Connection::open(db_path)?;
     debug!("Opening or creating repairs table if needed");
@@ -60,10 +552,135 @@ pub fn connect_to_repair_database(db_path: &Path) -> Result<Connection> {
                   state           TEXT)",
         &[],
     )?;
+    // What the disk looked like when it failed so the replacement can be
+    // checked against it.  Older databases won't have these columns yet.
+    add_column_if_missing(&conn, "repairs", "serial_number", "TEXT")?;
+    add_column_if_missing(&conn, "repairs", "capacity", "INTEGER")?;
+    add_column_if_missing(&conn, "repairs", "media_type", "TEXT")?;
+    debug!("Opening or creating state_history table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists state_history (
+                  id              INTEGER PRIMARY KEY,
+                  disk_path       TEXT NOT NULL,
+                  time_recorded   TEXT,
+                  from_state      TEXT NOT NULL,
+                  to_state        TEXT NOT NULL,
+                  transition      TEXT NOT NULL,
+                  result_state    TEXT NOT NULL)",
+        &[],
+    )?;
+    debug!("Opening or creating evaluations table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists evaluations (
+                  id              INTEGER PRIMARY KEY,
+                  disk_path       TEXT NOT NULL,
+                  time_evaluated  TEXT,
+                  failed          BOOLEAN NOT NULL)",
+        &[],
+    )?;
+    debug!("Opening or creating ignored_disks table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists ignored_disks (
+                  disk_path       TEXT PRIMARY KEY,
+                  time_ignored    TEXT,
+                  ignored_until   TEXT,
+                  reason          TEXT)",
+        &[],
+    )?;
+    debug!("Opening or creating removal_approvals table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists removal_approvals (
+                  id              INTEGER PRIMARY KEY,
+                  disk_path       TEXT NOT NULL,
+                  time_requested  TEXT,
+                  time_expires    TEXT,
+                  status          TEXT NOT NULL,
+                  approved_by     TEXT,
+                  time_approved   TEXT)",
+        &[],
+    )?;
+    add_column_if_missing(&conn, "removal_approvals", "approver_uid", "INTEGER")?;
+    debug!("Opening or creating removals table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists removals (
+                  id              INTEGER PRIMARY KEY,
+                  disk_path       TEXT NOT NULL,
+                  time_queued     TEXT,
+                  time_removed    TEXT,
+                  time_added      TEXT)",
+        &[],
+    )?;
+    debug!("Opening or creating maintenance table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists maintenance (
+                  id              INTEGER PRIMARY KEY,
+                  time_started    TEXT,
+                  time_ends       TEXT,
+                  time_ended      TEXT,
+                  reason          TEXT)",
+        &[],
+    )?;
+    debug!("Opening or creating burn_in table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists burn_in (
+                  disk_path       TEXT PRIMARY KEY,
+                  serial_number   TEXT NOT NULL,
+                  stage           TEXT NOT NULL,
+                  time_updated    TEXT,
+                  detail          TEXT)",
+        &[],
+    )?;
+    debug!("Opening or creating wipe_certificates table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists wipe_certificates (
+                  id              INTEGER PRIMARY KEY,
+                  disk_path       TEXT NOT NULL,
+                  time_recorded   TEXT,
+                  certificate     TEXT NOT NULL,
+                  ticket_id       TEXT)",
+        &[],
+    )?;
+    debug!("Opening or creating pending_wipes table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists pending_wipes (
+                  disk_path       TEXT PRIMARY KEY,
+                  time_removed    TEXT)",
+        &[],
+    )?;
+    debug!("Opening or creating md_members table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists md_members (
+                  disk_path       TEXT NOT NULL,
+                  array_name      TEXT NOT NULL,
+                  member          TEXT NOT NULL,
+                  status          TEXT NOT NULL,
+                  time_updated    TEXT,
+                  PRIMARY KEY (array_name, member))",
+        &[],
+    )?;
+    debug!("Opening or creating md_resync table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists md_resync (
+                  array_name      TEXT PRIMARY KEY,
+                  action          TEXT NOT NULL,
+                  completed       INTEGER NOT NULL,
+                  time_updated    TEXT,
+                  stalled         BOOLEAN NOT NULL)",
+        &[],
+    )?;
+    debug!("Opening or creating osd_ids table if needed");
+    conn.execute(
+        "CREATE TABLE if not exists osd_ids (
+                  disk_path       TEXT PRIMARY KEY,
+                  osd_id          INTEGER NOT NULL,
+                  time_recorded   TEXT)",
+        &[],
+    )?;
     Ok(conn)
 }
 
-/// Create a new repair ticket
+/// Create a new repair ticket.  It goes on the row the disk's state is saved
+/// in if there is one
 pub fn record_new_repair_ticket(
     conn: &Connection,
     ticket_id: &str,
@@ -74,20 +691,38 @@ pub fn record_new_repair_ticket(
         ticket_id,
         disk_path.display()
     );
-    conn.execute(
-        "INSERT INTO repairs (ticket_id, time_created, disk_path)
-                  VALUES (?1, ?2, ?3)",
+    let updated = conn.execute(
+        "UPDATE repairs set ticket_id=?1, time_created=?2
+        where id=(SELECT min(id) FROM repairs where disk_path=?3 and ticket_id IS NULL)",
         &[
             &ticket_id.to_string(),
             &time::get_time(),
             &disk_path.to_string_lossy().into_owned(),
         ],
     )?;
+    if updated == 0 {
+        conn.execute(
+            "INSERT INTO repairs (ticket_id, time_created, disk_path)
+                  VALUES (?1, ?2, ?3)",
+            &[
+                &ticket_id.to_string(),
+                &time::get_time(),
+                &disk_path.to_string_lossy().into_owned(),
+            ],
+        )?;
+    }
     Ok(())
 }
 
+/// Close a repair ticket.  The disk keeps its saved state so the state
+/// machine picks the replacement up from there
 pub fn resolve_ticket(conn: &Connection, ticket_id: &str) -> Result<()> {
     debug!("Resolving ticket: {}", ticket_id);
+    conn.execute(
+        "UPDATE repairs set ticket_id=NULL, time_created=NULL
+        where ticket_id=? and state IS NOT NULL",
+        &[&ticket_id.to_string()],
+    )?;
     conn.execute(
         "DELETE FROM repairs where ticket_id=?",
         &[&ticket_id.to_string()],
@@ -107,6 +742,16 @@ pub fn is_disk_in_progress(conn: &Connection, dev_path: &Path) -> Result<bool> {
     Ok(in_progress)
 }
 
+/// Check if a disk already has a repair ticket open.  A disk can have a
+/// repairs row without a ticket because the state machine saves its state there.
+pub fn has_open_ticket(conn: &Connection, dev_path: &Path) -> Result<bool> {
+    debug!("Searching for open ticket for disk: {}", dev_path.display());
+    let mut stmt =
+        conn.prepare("SELECT id FROM repairs where disk_path=? and ticket_id IS NOT NULL")?;
+    let open = stmt.exists(&[&dev_path.to_string_lossy().into_owned()])?;
+    Ok(open)
+}
+
 /// Gather all the outstanding repair tickets
 pub fn get_outstanding_repair_tickets(conn: &Connection) -> Result<Vec<DiskRepairTicket>> {
     let mut tickets: Vec<DiskRepairTicket> = Vec::new();
@@ -235,3 +880,874 @@ pub fn save_state(conn: &Connection, dev_path: &Path, state: test_disk::State) -
     }
     Ok(())
 }
+
+/// Record a transition attempt made by the state machine
+pub fn record_transition(
+    conn: &Connection,
+    dev_path: &Path,
+    from_state: test_disk::State,
+    to_state: test_disk::State,
+    transition: &str,
+    result_state: test_disk::State,
+) -> Result<()> {
+    debug!(
+        "Recording {} transition for {}: {} -> {} resulted in {}",
+        transition,
+        dev_path.display(),
+        from_state,
+        to_state,
+        result_state
+    );
+    conn.execute(
+        "INSERT INTO state_history
+            (disk_path, time_recorded, from_state, to_state, transition, result_state)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        &[
+            &dev_path.to_string_lossy().into_owned(),
+            &time::get_time(),
+            &from_state.to_string(),
+            &to_state.to_string(),
+            &transition.to_string(),
+            &result_state.to_string(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Gather every recorded transition attempt for a disk, oldest first
+pub fn get_state_history(conn: &Connection, dev_path: &Path) -> Result<Vec<StateHistory>> {
+    debug!("Searching state history for disk: {}", dev_path.display());
+    let mut history: Vec<StateHistory> = Vec::new();
+    let mut stmt = conn.prepare(
+        "SELECT id, disk_path, time_recorded, from_state, to_state, transition, result_state
+        FROM state_history where disk_path=? ORDER BY id",
+    )?;
+    let history_iter = stmt.query_map(&[&dev_path.to_string_lossy().into_owned()], |row| {
+        let from_state: String = row.get(3);
+        let to_state: String = row.get(4);
+        let result_state: String = row.get(6);
+        StateHistory {
+            id: row.get(0),
+            disk_path: row.get(1),
+            time_recorded: row.get(2),
+            from_state: test_disk::State::from_str(&from_state).unwrap_or(test_disk::State::Fail),
+            to_state: test_disk::State::from_str(&to_state).unwrap_or(test_disk::State::Fail),
+            transition: row.get(5),
+            result_state: test_disk::State::from_str(&result_state)
+                .unwrap_or(test_disk::State::Fail),
+        }
+    })?;
+
+    for h in history_iter {
+        history.push(h?);
+    }
+    Ok(history)
+}
+
+/// Record the outcome of one run of the state machine against a disk
+pub fn record_evaluation(conn: &Connection, dev_path: &Path, failed: bool) -> Result<()> {
+    debug!(
+        "Recording evaluation for {} failed: {}",
+        dev_path.display(),
+        failed
+    );
+    conn.execute(
+        "INSERT INTO evaluations (disk_path, time_evaluated, failed) VALUES (?1, ?2, ?3)",
+        &[
+            &dev_path.to_string_lossy().into_owned(),
+            &time::get_time(),
+            &failed,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Count the failed evaluations of a disk.  Only the last `window_runs`
+/// evaluations and the ones from the last `window_minutes` are counted
+/// if they're set.
+pub fn count_failed_evaluations(
+    conn: &Connection,
+    dev_path: &Path,
+    window_runs: Option<u32>,
+    window_minutes: Option<u64>,
+) -> Result<u32> {
+    debug!("Counting failed evaluations for disk: {}", dev_path.display());
+    let oldest = window_minutes.map(|m| time::get_time() - time::Duration::minutes(m as i64));
+    let mut stmt = conn.prepare(
+        "SELECT time_evaluated, failed FROM evaluations where disk_path=? ORDER BY id DESC",
+    )?;
+    let evaluations = stmt.query_map(&[&dev_path.to_string_lossy().into_owned()], |row| {
+        let time_evaluated: Timespec = row.get(0);
+        let failed: bool = row.get(1);
+        (time_evaluated, failed)
+    })?;
+
+    let mut failures = 0;
+    for (run, evaluation) in evaluations.enumerate() {
+        let (time_evaluated, failed) = evaluation?;
+        if let Some(runs) = window_runs {
+            if run as u32 >= runs {
+                break;
+            }
+        }
+        if let Some(oldest) = oldest {
+            if time_evaluated < oldest {
+                break;
+            }
+        }
+        if failed {
+            failures += 1;
+        }
+    }
+    Ok(failures)
+}
+
+/// Evaluations kept per disk when the policy doesn't limit the runs counted
+pub const MAX_EVALUATIONS: u32 = 1000;
+
+/// Delete the evaluations of a disk that have fallen out of the window so the
+/// table doesn't grow forever
+pub fn prune_evaluations(
+    conn: &Connection,
+    dev_path: &Path,
+    window_runs: Option<u32>,
+    window_minutes: Option<u64>,
+) -> Result<()> {
+    debug!("Pruning evaluations for {}", dev_path.display());
+    let disk_path = dev_path.to_string_lossy().into_owned();
+    if let Some(minutes) = window_minutes {
+        let oldest = time::get_time() - time::Duration::minutes(minutes as i64);
+        conn.execute(
+            "DELETE FROM evaluations where disk_path=?1 AND time_evaluated < ?2",
+            &[&disk_path, &oldest],
+        )?;
+    }
+    let keep = i64::from(window_runs.unwrap_or(MAX_EVALUATIONS));
+    conn.execute(
+        "DELETE FROM evaluations where disk_path=?1 AND id NOT IN
+        (SELECT id FROM evaluations where disk_path=?1 ORDER BY id DESC LIMIT ?2)",
+        &[&disk_path, &keep],
+    )?;
+    Ok(())
+}
+
+/// Forget every evaluation recorded for a disk
+pub fn clear_evaluations(conn: &Connection, dev_path: &Path) -> Result<()> {
+    debug!("Clearing evaluations for {}", dev_path.display());
+    conn.execute(
+        "DELETE FROM evaluations where disk_path=?",
+        &[&dev_path.to_string_lossy().into_owned()],
+    )?;
+    Ok(())
+}
+
+/// Gather every disk in the repairs table
+pub fn get_repairs(conn: &Connection) -> Result<Vec<DiskRepair>> {
+    let mut repairs: Vec<DiskRepair> = Vec::new();
+    let mut stmt = conn.prepare(
+        "SELECT disk_path, ticket_id, time_created, smart_passed, mount_path, state
+        FROM repairs ORDER BY id",
+    )?;
+    let repair_iter = stmt.query_map(&[], |row| {
+        let state: Option<String> = row.get(5);
+        DiskRepair {
+            disk_path: row.get(0),
+            ticket_id: row.get(1),
+            time_created: row.get(2),
+            smart_passed: row.get(3),
+            mount_path: row.get(4),
+            state: state.and_then(|s| test_disk::State::from_str(&s).ok()),
+        }
+    })?;
+
+    for repair in repair_iter {
+        repairs.push(repair?);
+    }
+    Ok(repairs)
+}
+
+/// Tell bynar to leave a disk alone until the given time or forever
+pub fn ignore_disk(
+    conn: &Connection,
+    dev_path: &Path,
+    until: Option<Timespec>,
+    reason: Option<&str>,
+) -> Result<()> {
+    debug!("Ignoring {} until {:?}", dev_path.display(), until);
+    conn.execute(
+        "INSERT OR REPLACE INTO ignored_disks (disk_path, time_ignored, ignored_until, reason)
+                  VALUES (?1, ?2, ?3, ?4)",
+        &[
+            &dev_path.to_string_lossy().into_owned(),
+            &time::get_time(),
+            &until,
+            &reason.map(|r| r.to_string()),
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn unignore_disk(conn: &Connection, dev_path: &Path) -> Result<()> {
+    debug!("No longer ignoring {}", dev_path.display());
+    conn.execute(
+        "DELETE FROM ignored_disks where disk_path=?",
+        &[&dev_path.to_string_lossy().into_owned()],
+    )?;
+    Ok(())
+}
+
+/// Check if an operator has asked bynar to leave a disk alone
+pub fn is_disk_ignored(conn: &Connection, dev_path: &Path) -> Result<bool> {
+    let mut stmt = conn.prepare("SELECT ignored_until FROM ignored_disks where disk_path=?")?;
+    let mut rows = stmt.query(&[&dev_path.to_string_lossy().into_owned()])?;
+    match rows.next() {
+        Some(row) => {
+            let until: Option<Timespec> = row?.get(0);
+            Ok(until.map(|u| u > time::get_time()).unwrap_or(true))
+        }
+        None => Ok(false),
+    }
+}
+
+/// Gather every ignored disk including ones whose ignore has expired
+pub fn get_ignored_disks(conn: &Connection) -> Result<Vec<IgnoredDisk>> {
+    let mut ignored: Vec<IgnoredDisk> = Vec::new();
+    let mut stmt = conn.prepare(
+        "SELECT disk_path, time_ignored, ignored_until, reason FROM ignored_disks
+        ORDER BY disk_path",
+    )?;
+    let ignored_iter = stmt.query_map(&[], |row| IgnoredDisk {
+        disk_path: row.get(0),
+        time_ignored: row.get(1),
+        ignored_until: row.get(2),
+        reason: row.get(3),
+    })?;
+
+    for i in ignored_iter {
+        ignored.push(i?);
+    }
+    Ok(ignored)
+}
+
+/// Queue a disk removal until a human approves it or it expires
+pub fn request_removal_approval(
+    conn: &Connection,
+    dev_path: &Path,
+    expires: Timespec,
+) -> Result<()> {
+    debug!("Requesting approval to remove {}", dev_path.display());
+    conn.execute(
+        "INSERT INTO removal_approvals (disk_path, time_requested, time_expires, status)
+                  VALUES (?1, ?2, ?3, ?4)",
+        &[
+            &dev_path.to_string_lossy().into_owned(),
+            &time::get_time(),
+            &expires,
+            &ApprovalStatus::Pending.to_string(),
+        ],
+    )?;
+    Ok(())
+}
+
+fn approval_from_row(row: &rusqlite::Row) -> RemovalApproval {
+    let status: String = row.get(4);
+    RemovalApproval {
+        id: row.get(0),
+        disk_path: row.get(1),
+        time_requested: row.get(2),
+        time_expires: row.get(3),
+        status: ApprovalStatus::from_str(&status).unwrap_or(ApprovalStatus::Expired),
+        approved_by: row.get(5),
+        time_approved: row.get(6),
+        approver_uid: row.get::<_, Option<i64>>(7).map(|uid| uid as u32),
+    }
+}
+
+/// Gather every removal approval request, optionally only those with a status
+pub fn get_removal_approvals(
+    conn: &Connection,
+    status: Option<ApprovalStatus>,
+) -> Result<Vec<RemovalApproval>> {
+    let mut approvals: Vec<RemovalApproval> = Vec::new();
+    let mut stmt = conn.prepare(
+        "SELECT id, disk_path, time_requested, time_expires, status, approved_by, time_approved,
+        approver_uid FROM removal_approvals ORDER BY id",
+    )?;
+    let approval_iter = stmt.query_map(&[], approval_from_row)?;
+
+    for approval in approval_iter {
+        let approval = approval?;
+        if status.map(|s| s == approval.status).unwrap_or(true) {
+            approvals.push(approval);
+        }
+    }
+    Ok(approvals)
+}
+
+/// The pending or approved request for a disk if there is one
+pub fn get_open_removal_approval(
+    conn: &Connection,
+    dev_path: &Path,
+) -> Result<Option<RemovalApproval>> {
+    Ok(get_removal_approvals(conn, None)?
+        .into_iter()
+        .filter(|a| a.disk_path == dev_path.to_string_lossy())
+        .find(|a| a.status == ApprovalStatus::Pending || a.status == ApprovalStatus::Approved))
+}
+
+/// Approve the pending request for a disk.  Returns false if there is no
+/// unexpired request waiting for approval.
+pub fn approve_removal(
+    conn: &Connection,
+    dev_path: &Path,
+    approved_by: &str,
+    approver_uid: u32,
+) -> Result<bool> {
+    let approval = match get_open_removal_approval(conn, dev_path)? {
+        Some(a) => a,
+        None => return Ok(false),
+    };
+    if approval.status != ApprovalStatus::Pending || approval.time_expires <= time::get_time() {
+        return Ok(false);
+    }
+    debug!(
+        "{} (uid {}) approved removing {}",
+        approved_by,
+        approver_uid,
+        dev_path.display()
+    );
+    conn.execute(
+        "UPDATE removal_approvals set status=?1, approved_by=?2, time_approved=?3,
+        approver_uid=?4 where id=?5",
+        &[
+            &ApprovalStatus::Approved.to_string(),
+            &approved_by.to_string(),
+            &time::get_time(),
+            &i64::from(approver_uid),
+            &approval.id,
+        ],
+    )?;
+    Ok(true)
+}
+
+pub fn set_approval_status(conn: &Connection, id: i32, status: ApprovalStatus) -> Result<()> {
+    debug!("Setting removal approval {} to {}", id, status);
+    conn.execute(
+        "UPDATE removal_approvals set status=?1 where id=?2",
+        &[&status.to_string(), &id],
+    )?;
+    Ok(())
+}
+
+/// Expire every pending request nobody approved in time and return them
+pub fn expire_removal_approvals(conn: &Connection) -> Result<Vec<RemovalApproval>> {
+    let now = time::get_time();
+    let expired: Vec<RemovalApproval> =
+        get_removal_approvals(conn, Some(ApprovalStatus::Pending))?
+            .into_iter()
+            .filter(|a| a.time_expires <= now)
+            .collect();
+    for approval in &expired {
+        set_approval_status(conn, approval.id, ApprovalStatus::Expired)?;
+    }
+    Ok(expired)
+}
+
+/// Remember that a disk should be removed once the safety policy allows it
+pub fn queue_disk_removal(conn: &Connection, dev_path: &Path) -> Result<()> {
+    let dev = dev_path.to_string_lossy().into_owned();
+    let mut stmt =
+        conn.prepare("SELECT id FROM removals where disk_path=? and time_removed IS NULL")?;
+    if stmt.exists(&[&dev])? {
+        debug!("Removal of {} is already queued", dev_path.display());
+        return Ok(());
+    }
+    debug!("Queueing removal of {}", dev_path.display());
+    conn.execute(
+        "INSERT INTO removals (disk_path, time_queued) VALUES (?1, ?2)",
+        &[&dev, &time::get_time()],
+    )?;
+    Ok(())
+}
+
+/// Disks that are waiting to be removed
+pub fn get_queued_removals(conn: &Connection) -> Result<Vec<String>> {
+    let mut disks: Vec<String> = Vec::new();
+    let mut stmt =
+        conn.prepare("SELECT disk_path FROM removals where time_removed IS NULL ORDER BY id")?;
+    let disk_iter = stmt.query_map(&[], |row| row.get(0))?;
+
+    for disk in disk_iter {
+        disks.push(disk?);
+    }
+    Ok(disks)
+}
+
+/// Record that a disk was taken out of the cluster
+pub fn record_disk_removed(conn: &Connection, dev_path: &Path) -> Result<()> {
+    debug!("Recording removal of {}", dev_path.display());
+    let dev = dev_path.to_string_lossy().into_owned();
+    let updated = conn.execute(
+        "UPDATE removals set time_removed=?1 where disk_path=?2 and time_removed IS NULL",
+        &[&time::get_time(), &dev],
+    )?;
+    if updated == 0 {
+        conn.execute(
+            "INSERT INTO removals (disk_path, time_queued, time_removed) VALUES (?1, ?2, ?2)",
+            &[&dev, &time::get_time()],
+        )?;
+    }
+    Ok(())
+}
+
+/// Record that a removed disk was added back into the cluster
+pub fn record_disk_added(conn: &Connection, dev_path: &Path) -> Result<()> {
+    debug!("Recording add of {}", dev_path.display());
+    conn.execute(
+        "UPDATE removals set time_added=?1
+        where disk_path=?2 and time_removed IS NOT NULL and time_added IS NULL",
+        &[&time::get_time(), &dev_path.to_string_lossy().into_owned()],
+    )?;
+    Ok(())
+}
+
+/// How many disks have been removed from this host and not added back
+pub fn count_disks_out(conn: &Connection) -> Result<u32> {
+    let count: i64 = conn.query_row(
+        "SELECT count(*) FROM removals where time_removed IS NOT NULL and time_added IS NULL",
+        &[],
+        |row| row.get(0),
+    )?;
+    Ok(count as u32)
+}
+
+/// Put this host into maintenance, replacing any maintenance already running
+pub fn start_maintenance(
+    conn: &Connection,
+    until: Option<Timespec>,
+    reason: Option<&str>,
+) -> Result<()> {
+    debug!("Starting maintenance until {:?}", until);
+    end_maintenance(conn)?;
+    conn.execute(
+        "INSERT INTO maintenance (time_started, time_ends, reason) VALUES (?1, ?2, ?3)",
+        &[&time::get_time(), &until, &reason.map(|r| r.to_string())],
+    )?;
+    Ok(())
+}
+
+/// End the running maintenance.  Returns false if there wasn't any
+pub fn end_maintenance(conn: &Connection) -> Result<bool> {
+    let ended = conn.execute(
+        "UPDATE maintenance set time_ended=? where time_ended IS NULL",
+        &[&time::get_time()],
+    )?;
+    Ok(ended > 0)
+}
+
+/// The maintenance that hasn't been ended yet if there is one.  Its end
+/// time may have already passed if expire_maintenance hasn't been called.
+pub fn get_maintenance(conn: &Connection) -> Result<Option<Maintenance>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, time_started, time_ends, reason FROM maintenance
+        where time_ended IS NULL ORDER BY id DESC",
+    )?;
+    let mut rows = stmt.query(&[])?;
+    match rows.next() {
+        Some(row) => {
+            let row = row?;
+            Ok(Some(Maintenance {
+                id: row.get(0),
+                time_started: row.get(1),
+                time_ends: row.get(2),
+                reason: row.get(3),
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// End the running maintenance if its end time has passed and return it
+pub fn expire_maintenance(conn: &Connection) -> Result<Option<Maintenance>> {
+    let maintenance = match get_maintenance(conn)? {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+    match maintenance.time_ends {
+        Some(ends) if ends <= time::get_time() => {
+            debug!("Maintenance {} has ended", maintenance.id);
+            conn.execute(
+                "UPDATE maintenance set time_ended=?1 where id=?2",
+                &[&ends, &maintenance.id],
+            )?;
+            Ok(Some(maintenance))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Remember what a disk looked like when it failed
+pub fn save_failed_disk_info(
+    conn: &Connection,
+    dev_path: &Path,
+    serial_number: Option<&str>,
+    capacity: Option<u64>,
+    media_type: Option<&str>,
+) -> Result<()> {
+    debug!(
+        "Saving failed disk info for {}: serial: {:?}",
+        dev_path.display(),
+        serial_number
+    );
+    conn.execute(
+        "UPDATE repairs set serial_number=?1, capacity=?2, media_type=?3 where disk_path=?4",
+        &[
+            &serial_number.map(|s| s.to_string()),
+            &capacity.map(|c| c as i64),
+            &media_type.map(|m| m.to_string()),
+            &dev_path.to_string_lossy().into_owned(),
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn get_failed_disk_info(conn: &Connection, dev_path: &Path) -> Result<Option<FailedDiskInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT serial_number, capacity, media_type FROM repairs where disk_path=?
+        ORDER BY id DESC",
+    )?;
+    let mut rows = stmt.query(&[&dev_path.to_string_lossy().into_owned()])?;
+    match rows.next() {
+        Some(row) => {
+            let row = row?;
+            let capacity: Option<i64> = row.get(1);
+            let info = FailedDiskInfo {
+                serial_number: row.get(0),
+                capacity: capacity.map(|c| c as u64),
+                media_type: row.get(2),
+            };
+            // The disk was ticketed without saving anything about it
+            if info.serial_number.is_none() && info.capacity.is_none() && info.media_type.is_none()
+            {
+                return Ok(None);
+            }
+            Ok(Some(info))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn get_burn_in(conn: &Connection, dev_path: &Path) -> Result<Option<BurnInRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT disk_path, serial_number, stage, time_updated, detail FROM burn_in
+        where disk_path=?",
+    )?;
+    let mut rows = stmt.query(&[&dev_path.to_string_lossy().into_owned()])?;
+    match rows.next() {
+        Some(row) => {
+            let row = row?;
+            Ok(Some(BurnInRecord {
+                disk_path: row.get(0),
+                serial_number: row.get(1),
+                stage: row.get(2),
+                time_updated: row.get(3),
+                detail: row.get(4),
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Record the burn in stage the disk in a slot has reached
+pub fn save_burn_in(
+    conn: &Connection,
+    dev_path: &Path,
+    serial_number: &str,
+    stage: &str,
+    detail: Option<&str>,
+) -> Result<()> {
+    debug!(
+        "Saving burn in stage {} for {} serial: {}",
+        stage,
+        dev_path.display(),
+        serial_number
+    );
+    conn.execute(
+        "INSERT OR REPLACE INTO burn_in (disk_path, serial_number, stage, time_updated, detail)
+                  VALUES (?1, ?2, ?3, ?4, ?5)",
+        &[
+            &dev_path.to_string_lossy().into_owned(),
+            &serial_number.to_string(),
+            &stage.to_string(),
+            &time::get_time(),
+            &detail.map(|d| d.to_string()),
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn clear_burn_in(conn: &Connection, dev_path: &Path) -> Result<()> {
+    conn.execute(
+        "DELETE FROM burn_in where disk_path=?",
+        &[&dev_path.to_string_lossy().into_owned()],
+    )?;
+    Ok(())
+}
+
+/// The ticket filed for a disk if there is one
+pub fn get_ticket_id(conn: &Connection, dev_path: &Path) -> Result<Option<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT ticket_id FROM repairs where disk_path=? and ticket_id IS NOT NULL
+        ORDER BY id DESC",
+    )?;
+    let mut rows = stmt.query(&[&dev_path.to_string_lossy().into_owned()])?;
+    match rows.next() {
+        Some(row) => Ok(Some(row?.get(0))),
+        None => Ok(None),
+    }
+}
+
+pub fn record_wipe_certificate(
+    conn: &Connection,
+    dev_path: &Path,
+    certificate: &str,
+) -> Result<()> {
+    debug!("Recording wipe certificate for {}", dev_path.display());
+    conn.execute(
+        "INSERT INTO wipe_certificates (disk_path, time_recorded, certificate)
+                  VALUES (?1, ?2, ?3)",
+        &[
+            &dev_path.to_string_lossy().into_owned(),
+            &time::get_time(),
+            &certificate.to_string(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Wipe certificates for a disk that haven't been attached to a ticket yet
+pub fn get_unattached_wipe_certificates(
+    conn: &Connection,
+    dev_path: &Path,
+) -> Result<Vec<WipeCertificateRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, disk_path, time_recorded, certificate, ticket_id FROM wipe_certificates
+        where disk_path=? and ticket_id IS NULL ORDER BY id",
+    )?;
+    let records = stmt.query_map(&[&dev_path.to_string_lossy().into_owned()], |row| {
+        WipeCertificateRecord {
+            id: row.get(0),
+            disk_path: row.get(1),
+            time_recorded: row.get(2),
+            certificate: row.get(3),
+            ticket_id: row.get(4),
+        }
+    })?;
+    let mut certificates = Vec::new();
+    for record in records {
+        certificates.push(record?);
+    }
+    Ok(certificates)
+}
+
+pub fn mark_wipe_certificate_attached(conn: &Connection, id: i32, ticket_id: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE wipe_certificates set ticket_id=?1 where id=?2",
+        &[&ticket_id.to_string(), &id],
+    )?;
+    Ok(())
+}
+
+/// The disk-manager is sanitising a removed disk.  Its certificate is
+/// collected on a later run
+pub fn add_pending_wipe(conn: &Connection, dev_path: &Path) -> Result<()> {
+    debug!("Waiting on the wipe of {}", dev_path.display());
+    conn.execute(
+        "INSERT OR REPLACE INTO pending_wipes (disk_path, time_removed) VALUES (?1, ?2)",
+        &[&dev_path.to_string_lossy().into_owned(), &time::get_time()],
+    )?;
+    Ok(())
+}
+
+pub fn get_pending_wipes(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT disk_path FROM pending_wipes ORDER BY time_removed")?;
+    let disks = stmt.query_map(&[], |row| row.get(0))?;
+    let mut pending = Vec::new();
+    for disk in disks {
+        pending.push(disk?);
+    }
+    Ok(pending)
+}
+
+pub fn is_wipe_pending(conn: &Connection, dev_path: &Path) -> Result<bool> {
+    let mut stmt = conn.prepare("SELECT disk_path FROM pending_wipes where disk_path=?")?;
+    let pending = stmt.exists(&[&dev_path.to_string_lossy().into_owned()])?;
+    Ok(pending)
+}
+
+pub fn clear_pending_wipe(conn: &Connection, dev_path: &Path) -> Result<()> {
+    conn.execute(
+        "DELETE FROM pending_wipes where disk_path=?",
+        &[&dev_path.to_string_lossy().into_owned()],
+    )?;
+    Ok(())
+}
+
+pub fn record_md_member(
+    conn: &Connection,
+    dev_path: &Path,
+    array: &str,
+    member: &str,
+    status: &str,
+) -> Result<()> {
+    debug!("Recording {} of {} as {}", member, array, status);
+    conn.execute(
+        "INSERT OR REPLACE INTO md_members (disk_path, array_name, member, status, time_updated)
+                  VALUES (?1, ?2, ?3, ?4, ?5)",
+        &[
+            &dev_path.to_string_lossy().into_owned(),
+            &array.to_string(),
+            &member.to_string(),
+            &status.to_string(),
+            &time::get_time(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// The md members that were on a disk when it was pulled
+pub fn get_md_members(conn: &Connection, dev_path: &Path) -> Result<Vec<MdMemberRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT disk_path, array_name, member, status FROM md_members where disk_path=?
+        ORDER BY array_name",
+    )?;
+    let records = stmt.query_map(&[&dev_path.to_string_lossy().into_owned()], |row| {
+        MdMemberRecord {
+            disk_path: row.get(0),
+            array: row.get(1),
+            member: row.get(2),
+            status: row.get(3),
+        }
+    })?;
+    let mut members = Vec::new();
+    for record in records {
+        members.push(record?);
+    }
+    Ok(members)
+}
+
+pub fn get_rebuilding_md_members(conn: &Connection) -> Result<Vec<MdMemberRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT disk_path, array_name, member, status FROM md_members where status='rebuilding'
+        ORDER BY array_name",
+    )?;
+    let records = stmt.query_map(&[], |row| MdMemberRecord {
+        disk_path: row.get(0),
+        array: row.get(1),
+        member: row.get(2),
+        status: row.get(3),
+    })?;
+    let mut members = Vec::new();
+    for record in records {
+        members.push(record?);
+    }
+    Ok(members)
+}
+
+pub fn clear_md_member(conn: &Connection, array: &str, member: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM md_members where array_name=?1 and member=?2",
+        &[&array.to_string(), &member.to_string()],
+    )?;
+    Ok(())
+}
+
+pub fn get_md_resync(conn: &Connection, array: &str) -> Result<Option<MdResyncRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT array_name, action, completed, time_updated, stalled FROM md_resync
+        where array_name=?",
+    )?;
+    let mut rows = stmt.query(&[&array.to_string()])?;
+    match rows.next() {
+        Some(row) => {
+            let row = row?;
+            let completed: i64 = row.get(2);
+            Ok(Some(MdResyncRecord {
+                array: row.get(0),
+                action: row.get(1),
+                completed: completed as u64,
+                time_updated: row.get(3),
+                stalled: row.get(4),
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Record resync progress.  Only called when progress was made
+pub fn save_md_resync(conn: &Connection, array: &str, action: &str, completed: u64) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO md_resync (array_name, action, completed, time_updated, stalled)
+                  VALUES (?1, ?2, ?3, ?4, 0)",
+        &[
+            &array.to_string(),
+            &action.to_string(),
+            &(completed as i64),
+            &time::get_time(),
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn mark_md_resync_stalled(conn: &Connection, array: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE md_resync set stalled=1 where array_name=?",
+        &[&array.to_string()],
+    )?;
+    Ok(())
+}
+
+pub fn clear_md_resync(conn: &Connection, array: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM md_resync where array_name=?",
+        &[&array.to_string()],
+    )?;
+    Ok(())
+}
+
+/// Remember the id the cluster kept for a removed disk so its replacement
+/// can take it over
+pub fn record_osd_id(conn: &Connection, dev_path: &Path, osd_id: u64) -> Result<()> {
+    debug!("Recording osd id {} for {}", osd_id, dev_path.display());
+    conn.execute(
+        "INSERT OR REPLACE INTO osd_ids (disk_path, osd_id, time_recorded)
+                  VALUES (?1, ?2, ?3)",
+        &[
+            &dev_path.to_string_lossy().into_owned(),
+            &(osd_id as i64),
+            &time::get_time(),
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn get_osd_id(conn: &Connection, dev_path: &Path) -> Result<Option<u64>> {
+    let mut stmt = conn.prepare("SELECT osd_id FROM osd_ids where disk_path=?")?;
+    let mut rows = stmt.query(&[&dev_path.to_string_lossy().into_owned()])?;
+    match rows.next() {
+        Some(row) => {
+            let osd_id: i64 = row?.get(0);
+            Ok(Some(osd_id as u64))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The replacement took over the id
+pub fn clear_osd_id(conn: &Connection, dev_path: &Path) -> Result<()> {
+    conn.execute(
+        "DELETE FROM osd_ids where disk_path=?",
+        &[&dev_path.to_string_lossy().into_owned()],
+    )?;
+    Ok(())
+}
@@ -16,6 +16,7 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 extern crate protobuf;
+extern crate rusqlite;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -24,20 +25,35 @@ extern crate simplelog;
 extern crate slack_hook;
 extern crate zmq;
 
+mod approval;
+mod burn_in;
+mod command_transition;
+mod control;
 mod create_support_ticket;
+mod device_filter;
+mod diagnostics;
 mod in_progress;
+mod mdraid;
+mod policy;
+mod raid;
+mod state_graph;
 mod test_disk;
 
 use std::fs::File;
 use std::io::Result as IOResult;
 use std::io::{Error, ErrorKind, Read};
 use std::path::{Path, PathBuf};
+use std::process;
 
 use self::test_disk::State;
-use clap::{App, Arg};
-use create_support_ticket::{create_support_ticket, ticket_resolved};
+use api::service::{Led, ResultType};
+use burn_in::BurnIn;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use create_support_ticket::{attach_to_ticket, create_support_ticket, ticket_resolved};
 use helpers::host_information::Host;
+use policy::{Decision, SafetyGate};
 use simplelog::{CombinedLogger, Config, TermLogger, WriteLogger};
+use state_graph::DiskOverlay;
 use slack_hook::{PayloadBuilder, Slack};
 
 #[derive(Clone, Debug, Deserialize)]
@@ -58,6 +74,34 @@ pub struct ConfigSettings {
     pub jira_project_id: String,
     pub jira_ticket_assignee: String,
     pub proxy: Option<String>,
+    // Require repeated failures before a disk is condemned
+    pub replacement_confirmation: Option<ConfirmationPolicy>,
+    // Which devices bynar is allowed to evaluate
+    pub device_filter: Option<device_filter::DeviceFilterConfig>,
+    // Wait for a human to approve disk removals
+    pub removal_approval: Option<approval::ApprovalPolicy>,
+    // Limits on when and how many disks can be removed and added
+    pub safety_policy: Option<policy::SafetyPolicy>,
+    // Checks a replacement disk has to pass before it's added back
+    pub burn_in: Option<burn_in::BurnInConfig>,
+    // Where diagnostics bundles for failed disks go
+    pub diagnostics: Option<diagnostics::DiagnosticsConfig>,
+    // Hardware RAID controllers to check physical drives on
+    pub raid: Option<raid::RaidConfig>,
+    // Software raid array checks
+    pub mdraid: Option<mdraid::MdRaidConfig>,
+}
+
+/// How many failed evaluations a disk needs before it's allowed to enter
+/// WaitingForReplacement.  Until then the disk is marked Suspect.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConfirmationPolicy {
+    /// Failed evaluations needed to condemn a disk
+    pub failures: u32,
+    /// Only count failures from this many of the most recent runs
+    pub window_runs: Option<u32>,
+    /// Only count failures from this many of the most recent minutes
+    pub window_minutes: Option<u64>,
 }
 
 fn notify_slack(config: &ConfigSettings, msg: &str) -> Result<(), slack_hook::Error> {
@@ -97,12 +141,19 @@ fn get_public_key(config: &ConfigSettings, host_info: &Host) -> IOResult<String>
     }
 }
 
-fn check_for_failed_disks(config_dir: &str, simulate: bool) -> Result<(), String> {
-    let config: ConfigSettings = helpers::load_config(config_dir, "").map_err(|e| e.to_string())?;
+fn check_for_failed_disks(
+    config_dir: &str,
+    simulate: bool,
+    observe_only: bool,
+    gate: &mut SafetyGate,
+) -> Result<(), String> {
+    let config: ConfigSettings =
+        helpers::load_config(config_dir, "bynar.json").map_err(|e| e.to_string())?;
     let host_info = Host::new().map_err(|e| e.to_string())?;
     debug!("Gathered host info: {:?}", host_info);
     let public_key = get_public_key(&config, &host_info).map_err(|e| e.to_string())?;
     let config_location = Path::new(&config.db_location);
+    let graph = state_graph::load_state_graph(config_dir).map_err(|e| e.to_string())?;
     //Host information to use in ticket creation
     let mut description = format!("A disk on {} failed. Please replace.", host_info.hostname);
     let environment = format!(
@@ -117,23 +168,55 @@ fn check_for_failed_disks(config_dir: &str, simulate: bool) -> Result<(), String
     info!("Checking all drives");
     let conn =
         in_progress::connect_to_repair_database(&config_location).map_err(|e| e.to_string())?;
-    for result in test_disk::check_all_disks(&config_location).map_err(|e| e.to_string())? {
+    // Faulty md members are handled first so their disks are already in the
+    // repair queue when the state machine gets to them
+    check_md_arrays(
+        &config,
+        &conn,
+        &public_key,
+        &host_info.hostname,
+        &environment,
+        simulate,
+        observe_only,
+        gate,
+    )?;
+    for result in
+        test_disk::check_all_disks(&config, &graph, observe_only).map_err(|e| e.to_string())?
+    {
         match result {
             Ok(state) => {
                 info!("Disk status: {:?}", state);
                 let mut dev_path = PathBuf::from("/dev");
                 dev_path.push(state.disk.name);
 
-                if state.state == State::WaitingForReplacement {
+                let ignored =
+                    in_progress::is_disk_ignored(&conn, &dev_path).map_err(|e| e.to_string())?;
+                if ignored {
+                    debug!("{} is being ignored", dev_path.display());
+                } else if state.state == State::WaitingForReplacement {
                     description.push_str(&format!("\nDisk path: {}", dev_path.display()));
-                    if let Some(serial) = state.disk.serial_number {
+                    if let Some(ref serial) = state.disk.serial_number {
                         description.push_str(&format!("\nDisk serial: {}", serial));
                     }
+                    match helpers::enclosure::find_slot(&dev_path) {
+                        Ok(Some(slot)) => {
+                            description.push_str(&format!("\nDisk location: {}", slot));
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!("Unable to find the slot of {}: {}", dev_path.display(), e),
+                    };
                     info!("Connecting to database to check if disk is in progress");
                     let in_progress = in_progress::is_disk_in_progress(&conn, &dev_path)
                         .map_err(|e| e.to_string())?;
-                    if !simulate {
+                    if observe_only {
+                        info!(
+                            "Host is in maintenance.  Not acting on {}",
+                            dev_path.display()
+                        );
+                    } else if !simulate {
                         if !in_progress {
+                            // Gather diagnostics while the disk is still in place
+                            let sections = diagnostics::collect(&config, &conn, &dev_path);
                             debug!("Asking disk-manager if it's safe to remove disk");
                             // CALL RPC
                             let mut socket = helpers::connect(
@@ -144,40 +227,51 @@ fn check_for_failed_disks(config_dir: &str, simulate: bool) -> Result<(), String
                             match helpers::safe_to_remove_request(&mut socket, &dev_path) {
                                 Ok(result) => {
                                     //Ok to remove the disk
-                                    if result {
+                                    if result.get_value() {
+                                        if let Some(ref policy) = config.removal_approval {
+                                            queue_removal(
+                                                &config,
+                                                policy,
+                                                &conn,
+                                                &dev_path,
+                                                &host_info.hostname,
+                                            )?;
+                                        } else if !remove_disk(
+                                            &config,
+                                            gate,
+                                            &conn,
+                                            &mut socket,
+                                            &dev_path,
+                                            &host_info.hostname,
+                                            "",
+                                        )? {
+                                            // Try again on a later run
+                                            defer_removal(
+                                                &config,
+                                                &conn,
+                                                &dev_path,
+                                                &host_info.hostname,
+                                            )?;
+                                        }
+                                    } else {
+                                        // The cluster may settle or the osd
+                                        // may be draining.  Check again on
+                                        // later runs
+                                        in_progress::queue_disk_removal(&conn, &dev_path)
+                                            .map_err(|e| e.to_string())?;
                                         if config.slack_webhook.is_some() {
                                             let _ = notify_slack(
                                                 &config,
                                                 &format!(
-                                                    "Removing disk: {} on host: {}",
+                                                    "Need to remove disk {} but it's not safe \
+                                                     on host: {}: {}. Filing a ticket and \
+                                                     trying again on later runs",
                                                     dev_path.display(),
-                                                    host_info.hostname
+                                                    host_info.hostname,
+                                                    helpers::removal_blockers(&result),
                                                 ),
                                             );
                                         }
-                                        match helpers::remove_disk_request(
-                                            &mut socket,
-                                            &dev_path,
-                                            None,
-                                            false,
-                                        ) {
-                                            Ok(_) => {
-                                                debug!("Disk removal successful");
-                                            }
-                                            Err(e) => {
-                                                error!("Disk removal failed: {}", e);
-                                            }
-                                        };
-                                    } else if config.slack_webhook.is_some() {
-                                        let _ = notify_slack(
-                                            &config,
-                                            &format!(
-                                                "Need to remove disk {} but it's not safe \
-                                                 on host: {}. I need a human.  Filing a ticket",
-                                                dev_path.display(),
-                                                host_info.hostname,
-                                            ),
-                                        );
                                     }
                                 }
                                 Err(err) => {
@@ -206,10 +300,39 @@ fn check_for_failed_disks(config_dir: &str, simulate: bool) -> Result<(), String
                             debug!("Recording ticket id {} in database", ticket_id);
                             in_progress::record_new_repair_ticket(&conn, &ticket_id, &dev_path)
                                 .map_err(|e| e.to_string())?;
+                            // Remember the failed disk so its replacement can be checked
+                            in_progress::save_failed_disk_info(
+                                &conn,
+                                &dev_path,
+                                state.disk.serial_number.as_ref().map(|s| s.as_str()),
+                                Some(state.disk.capacity),
+                                Some(&format!("{:?}", state.disk.media_type)),
+                            ).map_err(|e| e.to_string())?;
+                            attach_wipe_certificates(&config, &conn, &dev_path);
+                            // Light up the slot so the right disk gets pulled
+                            if let Err(e) =
+                                helpers::set_led_request(&mut socket, &dev_path, Led::FAULT, true)
+                            {
+                                warn!("Unable to light the slot of {}: {}", dev_path.display(), e);
+                            }
+                            diagnostics::deliver_bundle(
+                                &config,
+                                &dev_path,
+                                &host_info.hostname,
+                                &ticket_id,
+                                &sections,
+                            );
+                            in_progress::clear_burn_in(&conn, &dev_path)
+                                .map_err(|e| e.to_string())?;
                         } else {
                             debug!("Device is already in the repair queue");
                         }
                     }
+                } else if state.state == State::Suspect {
+                    info!(
+                        "Disk {} is suspect.  Waiting for more failures before replacing it",
+                        dev_path.display()
+                    );
                 // Handle the ones that ended up stuck in Fail
                 } else if state.state == State::Fail {
                     error!("Disk {} ended in a Fail state", dev_path.display(),);
@@ -223,10 +346,665 @@ fn check_for_failed_disks(config_dir: &str, simulate: bool) -> Result<(), String
             }
         };
     }
+    check_raid_drives(
+        &config,
+        &conn,
+        &host_info.hostname,
+        &environment,
+        simulate,
+        observe_only,
+    )?;
+    Ok(())
+}
+
+// Turn the fault led of a disk's slot on or off.  Failures are only logged
+fn set_fault_led(config: &ConfigSettings, public_key: &str, dev_path: &Path, on: bool) {
+    let mut socket = match helpers::connect(
+        &config.manager_host,
+        &config.manager_port.to_string(),
+        public_key,
+    ) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Unable to connect to the disk-manager: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = helpers::set_led_request(&mut socket, dev_path, Led::FAULT, on) {
+        warn!("Unable to set the slot led of {}: {}", dev_path.display(), e);
+    }
+}
+
+// Ticket disks with faulty members and pull the members out of their md
+// arrays.  Stalled resyncs, degraded arrays and finished rebuilds are
+// reported to slack
+fn check_md_arrays(
+    config: &ConfigSettings,
+    conn: &rusqlite::Connection,
+    public_key: &str,
+    hostname: &str,
+    environment: &str,
+    simulate: bool,
+    observe_only: bool,
+    gate: &mut SafetyGate,
+) -> Result<(), String> {
+    let default_config = mdraid::MdRaidConfig::default();
+    let md_config = config.mdraid.as_ref().unwrap_or(&default_config);
+    let arrays = mdraid::get_arrays()?;
+    if arrays.is_empty() {
+        return Ok(());
+    }
+    info!("Checking md arrays");
+    for event in mdraid::check_arrays(conn, &arrays, md_config)? {
+        let msg = match event {
+            mdraid::MdEvent::Faulty { array, member } => {
+                let dev_path = PathBuf::from(format!("/dev/{}", mdraid::parent_disk(&member)));
+                if in_progress::is_disk_ignored(conn, &dev_path).map_err(|e| e.to_string())? {
+                    debug!("{} is being ignored", dev_path.display());
+                    continue;
+                }
+                if in_progress::has_open_ticket(conn, &dev_path).map_err(|e| e.to_string())? {
+                    debug!("{} is already in the repair queue", dev_path.display());
+                    continue;
+                }
+                error!("{} member {} is faulty", array, member);
+                if observe_only {
+                    info!("Host is in maintenance.  Not acting on {}", dev_path.display());
+                    continue;
+                }
+                // Gather diagnostics while the members are still in their arrays
+                let sections = diagnostics::collect(config, conn, &dev_path);
+                if simulate {
+                    mdraid::remove_disk_members(conn, &arrays, &dev_path, simulate)?;
+                    continue;
+                }
+                let previous = in_progress::get_state(conn, &dev_path)
+                    .map_err(|e| e.to_string())?
+                    .unwrap_or(State::Unscanned);
+                in_progress::record_transition(
+                    conn,
+                    &dev_path,
+                    previous,
+                    State::WaitingForReplacement,
+                    "MdFaulty",
+                    State::WaitingForReplacement,
+                ).map_err(|e| e.to_string())?;
+                in_progress::save_state(conn, &dev_path, State::WaitingForReplacement)
+                    .map_err(|e| e.to_string())?;
+                let mut description = format!(
+                    "A software raid disk on {} failed. Please replace.\nDisk path: {}",
+                    hostname,
+                    dev_path.display()
+                );
+                for (array, member) in mdraid::disk_members(&arrays, &dev_path) {
+                    description.push_str(&format!("\nRemoving {} from {}", member, array));
+                }
+                match helpers::enclosure::find_slot(&dev_path) {
+                    Ok(Some(slot)) => description.push_str(&format!("\nDisk location: {}", slot)),
+                    Ok(None) => {}
+                    Err(e) => warn!("Unable to find the slot of {}: {}", dev_path.display(), e),
+                };
+                // The ticket is filed before the members are pulled so a
+                // failed removal still leaves the disk in the repair queue
+                let ticket_id =
+                    create_support_ticket(config, "Dead disk", &description, environment)
+                        .map_err(|e| format!("{:?}", e))?;
+                in_progress::record_new_repair_ticket(conn, &ticket_id, &dev_path)
+                    .map_err(|e| e.to_string())?;
+                // Remember the failed disk so its replacement can be checked
+                burn_in::save_failed_disk(conn, &dev_path)?;
+                in_progress::clear_burn_in(conn, &dev_path).map_err(|e| e.to_string())?;
+                // Light up the slot so the right disk gets pulled
+                set_fault_led(config, public_key, &dev_path, true);
+                diagnostics::deliver_bundle(config, &dev_path, hostname, &ticket_id, &sections);
+                match remove_md_members(gate, conn, &arrays, &dev_path) {
+                    Ok(true) => format!(
+                        "{} member {} on host: {} is faulty.  Filed ticket {} to replace {}",
+                        array,
+                        member,
+                        hostname,
+                        ticket_id,
+                        dev_path.display()
+                    ),
+                    Ok(false) => {
+                        defer_removal(config, conn, &dev_path, hostname)?;
+                        format!(
+                            "{} member {} on host: {} is faulty.  Filed ticket {} to replace {}",
+                            array,
+                            member,
+                            hostname,
+                            ticket_id,
+                            dev_path.display()
+                        )
+                    }
+                    Err(e) => {
+                        error!("Unable to remove {} from its arrays: {}", dev_path.display(), e);
+                        format!(
+                            "{} member {} on host: {} is faulty.  Filed ticket {} to replace {} \
+                             but removing it from its arrays failed: {}. I need a human",
+                            array,
+                            member,
+                            hostname,
+                            ticket_id,
+                            dev_path.display(),
+                            e
+                        )
+                    }
+                }
+            }
+            mdraid::MdEvent::Degraded { array } => {
+                warn!("{} is degraded", array);
+                continue;
+            }
+            mdraid::MdEvent::Stalled {
+                array,
+                action,
+                minutes,
+            } => {
+                error!("{} {} made no progress in {} minutes", array, action, minutes);
+                format!(
+                    "{} {} on host: {} has made no progress in {} minutes. I need a human",
+                    array, action, hostname, minutes
+                )
+            }
+            mdraid::MdEvent::Rebuilt { array, member } => {
+                info!("{} finished rebuilding onto {}", array, member);
+                format!("{} on host: {} finished rebuilding onto {}", array, hostname, member)
+            }
+        };
+        if config.slack_webhook.is_some() {
+            let _ = notify_slack(config, &msg);
+        }
+    }
+    Ok(())
+}
+
+// Drives behind a hardware RAID controller never show up as block devices.
+// A failed drive in a redundant logical volume leaves the virtual disk
+// looking healthy so ask the controllers directly and file tickets for them.
+// The controller rebuilds onto the replacement so nothing is removed or added
+fn check_raid_drives(
+    config: &ConfigSettings,
+    conn: &rusqlite::Connection,
+    hostname: &str,
+    environment: &str,
+    simulate: bool,
+    observe_only: bool,
+) -> Result<(), String> {
+    let default_config = raid::RaidConfig::default();
+    let raid_config = config.raid.as_ref().unwrap_or(&default_config);
+    info!("Checking drives behind RAID controllers");
+    for drive in raid::physical_drives(raid_config)? {
+        let disk_path = drive.disk_path();
+        let dev_path = Path::new(&disk_path);
+        if !drive.needs_ticket(conn, raid_config)? {
+            continue;
+        }
+        if observe_only {
+            info!("Host is in maintenance.  Not acting on {}", drive);
+            continue;
+        }
+        if simulate {
+            info!("Would file a ticket for {}", drive);
+            continue;
+        }
+        let mut description = format!(
+            "A RAID member disk on {} failed. Please replace.\nController: {} {}\n\
+             Drive slot: {}\nStatus: {}",
+            hostname, drive.vendor, drive.controller, drive.slot, drive.status
+        );
+        if let Some(problem) = drive.problem(raid_config) {
+            description.push_str(&format!("\nReason: {}", problem));
+        }
+        if let Some(ref logical_drive) = drive.logical_drive {
+            description.push_str(&format!("\nLogical drive: {}", logical_drive));
+        }
+        if let Some(ref serial) = drive.serial_number {
+            description.push_str(&format!("\nDisk serial: {}", serial));
+        }
+        if let Some(ref model) = drive.model {
+            description.push_str(&format!("\nDisk model: {}", model));
+        }
+        if config.slack_webhook.is_some() {
+            let _ = notify_slack(
+                config,
+                &format!(
+                    "{} on host: {} needs to be replaced.  Filing a ticket",
+                    drive, hostname
+                ),
+            );
+        }
+        debug!("Creating support ticket");
+        let ticket_id = create_support_ticket(config, "Dead disk", &description, environment)
+            .map_err(|e| format!("{:?}", e))?;
+        debug!("Recording ticket id {} in database", ticket_id);
+        in_progress::record_new_repair_ticket(conn, &ticket_id, &dev_path)
+            .map_err(|e| e.to_string())?;
+        // The controller doesn't report size or media type
+        in_progress::save_failed_disk_info(
+            conn,
+            &dev_path,
+            drive.serial_number.as_ref().map(|s| s.as_str()),
+            None,
+            None,
+        ).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Check if this host is in maintenance and announce any maintenance that ended
+fn in_maintenance(config: &ConfigSettings) -> Result<bool, String> {
+    let conn = in_progress::connect_to_repair_database(Path::new(&config.db_location))
+        .map_err(|e| e.to_string())?;
+    if let Some(ended) = in_progress::expire_maintenance(&conn).map_err(|e| e.to_string())? {
+        info!("Maintenance {} has ended", ended.id);
+        if config.slack_webhook.is_some() {
+            let host_info = Host::new().map_err(|e| e.to_string())?;
+            let _ = notify_slack(
+                config,
+                &format!("Maintenance on host: {} has ended", host_info.hostname),
+            );
+        }
+    }
+    Ok(in_progress::get_maintenance(&conn)
+        .map_err(|e| e.to_string())?
+        .is_some())
+}
+
+// Hold a removal the safety policy didn't allow until a later run
+fn defer_removal(
+    config: &ConfigSettings,
+    conn: &rusqlite::Connection,
+    dev_path: &Path,
+    hostname: &str,
+) -> Result<(), String> {
+    in_progress::queue_disk_removal(conn, dev_path).map_err(|e| e.to_string())?;
+    if config.slack_webhook.is_some() {
+        let _ = notify_slack(
+            config,
+            &format!(
+                "Removal of disk {} on host: {} is deferred.  It will be retried on \
+                 later runs",
+                dev_path.display(),
+                hostname
+            ),
+        );
+    }
+    Ok(())
+}
+
+// Hold a removal until a human approves it and let them know it's waiting
+fn queue_removal(
+    config: &ConfigSettings,
+    policy: &approval::ApprovalPolicy,
+    conn: &rusqlite::Connection,
+    dev_path: &Path,
+    hostname: &str,
+) -> Result<(), String> {
+    if approval::queue_removal(conn, policy, dev_path)? {
+        notify_approval_needed(config, dev_path, hostname);
+    }
+    Ok(())
+}
+
+fn notify_approval_needed(config: &ConfigSettings, dev_path: &Path, hostname: &str) {
+    info!("Removal of {} is waiting for approval", dev_path.display());
+    if config.slack_webhook.is_some() {
+        let _ = notify_slack(
+            config,
+            &format!(
+                "Disk {} on host: {} needs to be removed.  Approve it with \
+                 `bynar approve {}` on the host",
+                dev_path.display(),
+                hostname,
+                dev_path.display()
+            ),
+        );
+    }
+}
+
+// Remove a disk if the safety policy allows it.  Returns true if it was removed
+fn remove_disk(
+    config: &ConfigSettings,
+    gate: &mut SafetyGate,
+    conn: &rusqlite::Connection,
+    socket: &mut zmq::Socket,
+    dev_path: &Path,
+    hostname: &str,
+    note: &str,
+) -> Result<bool, String> {
+    if let Decision::Denied(reason) = gate.check_removal(conn)? {
+        info!("Not removing {} yet.  {}", dev_path.display(), reason);
+        return Ok(false);
+    }
+    if config.slack_webhook.is_some() {
+        let _ = notify_slack(
+            config,
+            &format!(
+                "Removing disk: {} on host: {}{}",
+                dev_path.display(),
+                hostname,
+                note
+            ),
+        );
+    }
+    match helpers::remove_disk_request(socket, dev_path, None, false) {
+        Ok(result) => {
+            debug!("Disk removal successful");
+            gate.record_removal(conn, dev_path)?;
+            for step in result.get_cleanup_steps() {
+                if step.get_result() == ResultType::ERR {
+                    warn!(
+                        "Cleaning up after {} failed at {}: {}",
+                        dev_path.display(),
+                        step.get_name(),
+                        step.get_error_msg()
+                    );
+                }
+            }
+            if result.has_osd_id() {
+                // The replacement takes over the id when it's added
+                in_progress::record_osd_id(conn, dev_path, result.get_osd_id())
+                    .map_err(|e| e.to_string())?;
+            }
+            if result.get_wipe_pending() {
+                // Sanitising takes a while.  The certificate is collected on a later run
+                in_progress::add_pending_wipe(conn, dev_path).map_err(|e| e.to_string())?;
+            } else {
+                warn!("{} isn't being sanitised", dev_path.display());
+            }
+            Ok(true)
+        }
+        Err(e) => {
+            error!("Disk removal failed: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+// Pull a disk's members out of their md arrays if the safety policy allows
+// it.  Returns true if they were removed
+fn remove_md_members(
+    gate: &mut SafetyGate,
+    conn: &rusqlite::Connection,
+    arrays: &[mdraid::MdArray],
+    dev_path: &Path,
+) -> Result<bool, String> {
+    if let Decision::Denied(reason) = gate.check_removal(conn)? {
+        info!("Not removing {} from its arrays yet.  {}", dev_path.display(), reason);
+        return Ok(false);
+    }
+    mdraid::remove_disk_members(conn, arrays, dev_path, false)?;
+    gate.record_removal(conn, dev_path)?;
+    Ok(true)
+}
+
+// Collect the certificates of disks the disk-manager finished sanitising
+fn collect_wipe_certificates(config_dir: &str) -> Result<(), String> {
+    let config: ConfigSettings =
+        helpers::load_config(config_dir, "bynar.json").map_err(|e| e.to_string())?;
+    let conn = in_progress::connect_to_repair_database(Path::new(&config.db_location))
+        .map_err(|e| e.to_string())?;
+    let pending = in_progress::get_pending_wipes(&conn).map_err(|e| e.to_string())?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let host_info = Host::new().map_err(|e| e.to_string())?;
+    let public_key = get_public_key(&config, &host_info).map_err(|e| e.to_string())?;
+    let mut socket = helpers::connect(
+        &config.manager_host,
+        &config.manager_port.to_string(),
+        &public_key,
+    ).map_err(|e| e.to_string())?;
+    for disk in pending {
+        let dev_path = Path::new(&disk);
+        match helpers::get_wipe_certificate_request(&mut socket, &dev_path) {
+            Ok(ref result) if result.get_wipe_pending() => {
+                debug!("{} is still being sanitised", dev_path.display());
+                continue;
+            }
+            Ok(result) => {
+                let certificate = result.get_wipe_certificate();
+                in_progress::record_wipe_certificate(&conn, &dev_path, certificate)
+                    .map_err(|e| e.to_string())?;
+                if !wipe_verified(certificate) && config.slack_webhook.is_some() {
+                    let _ = notify_slack(
+                        &config,
+                        &format!(
+                            "Disk {} on host: {} was not sanitised.  \
+                             Check its wipe certificate before it's shipped",
+                            dev_path.display(),
+                            host_info.hostname
+                        ),
+                    );
+                }
+                attach_wipe_certificates(&config, &conn, &dev_path);
+            }
+            Err(e) => {
+                error!("No wipe certificate for {}: {}", dev_path.display(), e);
+                if config.slack_webhook.is_some() {
+                    let _ = notify_slack(
+                        &config,
+                        &format!(
+                            "Disk {} on host: {} was not sanitised: {}",
+                            dev_path.display(),
+                            host_info.hostname,
+                            e
+                        ),
+                    );
+                }
+            }
+        };
+        in_progress::clear_pending_wipe(&conn, &dev_path).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Did the disk-manager verify the wipe?
+fn wipe_verified(certificate: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(certificate)
+        .ok()
+        .and_then(|signed| {
+            signed["certificate"]
+                .as_str()
+                .and_then(|c| serde_json::from_str::<serde_json::Value>(c).ok())
+        })
+        .and_then(|c| c["verified"].as_bool())
+        .unwrap_or(false)
+}
+
+// Attach any wipe certificates for the disk to its ticket.  Certificates for
+// disks without a ticket yet are attached once one is filed.
+fn attach_wipe_certificates(config: &ConfigSettings, conn: &rusqlite::Connection, dev_path: &Path) {
+    let ticket_id = match in_progress::get_ticket_id(conn, dev_path) {
+        Ok(Some(ticket_id)) => ticket_id,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Unable to find the ticket for {}: {}", dev_path.display(), e);
+            return;
+        }
+    };
+    let certificates = match in_progress::get_unattached_wipe_certificates(conn, dev_path) {
+        Ok(certificates) => certificates,
+        Err(e) => {
+            error!("Unable to get wipe certificates for {}: {}", dev_path.display(), e);
+            return;
+        }
+    };
+    for record in certificates {
+        let file_name = format!("wipe-certificate-{}.json", record.id);
+        match attach_to_ticket(config, &ticket_id, &file_name, record.certificate.into_bytes()) {
+            Ok(_) => {
+                if let Err(e) =
+                    in_progress::mark_wipe_certificate_attached(conn, record.id, &ticket_id)
+                {
+                    error!("Unable to record {} as attached: {}", file_name, e);
+                }
+            }
+            Err(e) => error!("Unable to attach {} to {}: {}", file_name, ticket_id, e),
+        };
+    }
+}
+
+// Remove the disks that were held back by the safety policy or a human
+fn remove_pending_disks(
+    config_dir: &str,
+    simulate: bool,
+    gate: &mut SafetyGate,
+) -> Result<(), String> {
+    let config: ConfigSettings =
+        helpers::load_config(config_dir, "bynar.json").map_err(|e| e.to_string())?;
+    let host_info = Host::new().map_err(|e| e.to_string())?;
+    let config_location = Path::new(&config.db_location);
+    let conn =
+        in_progress::connect_to_repair_database(&config_location).map_err(|e| e.to_string())?;
+
+    // Disk path, approval request id and a note for the notification
+    let mut pending: Vec<(String, Option<i32>, String)> = Vec::new();
+    if let Some(ref policy) = config.removal_approval {
+        if let Some(ref watch_dir) = policy.watch_dir {
+            let allowed_gids = policy.allowed_gids()?;
+            approval::process_approval_files(&conn, Path::new(watch_dir), &allowed_gids)?;
+        }
+        for expired in in_progress::expire_removal_approvals(&conn).map_err(|e| e.to_string())? {
+            let dev_path = Path::new(&expired.disk_path);
+            warn!("Removal approval for {} expired", dev_path.display());
+            // Keep asking as long as the disk is waiting on repairs
+            if in_progress::has_open_ticket(&conn, &dev_path).map_err(|e| e.to_string())? {
+                queue_removal(&config, policy, &conn, &dev_path, &host_info.hostname)?;
+            }
+        }
+        for request in in_progress::get_removal_approvals(
+            &conn,
+            Some(in_progress::ApprovalStatus::Approved),
+        ).map_err(|e| e.to_string())?
+        {
+            let approved_by = request.approved_by.unwrap_or_else(|| "unknown".to_string());
+            let note = match request.approver_uid {
+                Some(uid) => format!(" approved by {} (uid {})", approved_by, uid),
+                None => format!(" approved by {}", approved_by),
+            };
+            pending.push((request.disk_path, Some(request.id), note));
+        }
+    }
+    if let Some(ref policy) = config.removal_approval {
+        // Removals held back by the cluster or the safety policy still need a human
+        for disk in approval::request_queued_approvals(&conn, policy)? {
+            notify_approval_needed(&config, Path::new(&disk), &host_info.hostname);
+        }
+    } else {
+        for disk in in_progress::get_queued_removals(&conn).map_err(|e| e.to_string())? {
+            pending.push((disk, None, String::new()));
+        }
+    }
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let public_key = get_public_key(&config, &host_info).map_err(|e| e.to_string())?;
+    let arrays = mdraid::get_arrays()?;
+    for (disk, approval_id, note) in pending {
+        let dev_path = Path::new(&disk);
+        if in_progress::is_disk_ignored(&conn, &dev_path).map_err(|e| e.to_string())? {
+            debug!("{} is being ignored.  Not removing it", dev_path.display());
+            continue;
+        }
+        if simulate {
+            info!("Simulate is set.  Not removing {}{}", dev_path.display(), note);
+            continue;
+        }
+        if let Decision::Denied(reason) = gate.check_removal(&conn)? {
+            info!("Not removing {} yet.  {}", dev_path.display(), reason);
+            continue;
+        }
+        let removed = if !mdraid::disk_members(&arrays, &dev_path).is_empty() {
+            // Software raid members come out of their arrays rather than the cluster
+            match remove_md_members(gate, &conn, &arrays, &dev_path) {
+                Ok(removed) => removed,
+                Err(e) => {
+                    error!("Unable to remove {} from its arrays: {}", dev_path.display(), e);
+                    continue;
+                }
+            }
+        } else {
+            let mut socket = helpers::connect(
+                &config.manager_host,
+                &config.manager_port.to_string(),
+                &public_key,
+            ).map_err(|e| e.to_string())?;
+            // The cluster may have changed since the removal was requested
+            match helpers::safe_to_remove_request(&mut socket, &dev_path) {
+                Ok(ref result) if result.get_value() => {}
+                Ok(result) => {
+                    warn!(
+                        "{} is waiting to be removed but it's not safe to remove: {}.  \
+                         Trying next run",
+                        dev_path.display(),
+                        helpers::removal_blockers(&result)
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    error!("Unable to tell if {} is safe to remove: {}", dev_path.display(), e);
+                    continue;
+                }
+            };
+            remove_disk(
+                &config,
+                gate,
+                &conn,
+                &mut socket,
+                &dev_path,
+                &host_info.hostname,
+                &note,
+            )?
+        };
+        if let (true, Some(id)) = (removed, approval_id) {
+            in_progress::set_approval_status(&conn, id, in_progress::ApprovalStatus::Removed)
+                .map_err(|e| e.to_string())?;
+        }
+    }
     Ok(())
 }
 
-fn add_repaired_disks(config_dir: &str, simulate: bool) -> Result<(), String> {
+// Run the replacement disk through its burn in.  Returns true once it passes
+fn replacement_verified(
+    config: &ConfigSettings,
+    conn: &rusqlite::Connection,
+    disk_path: &str,
+    simulate: bool,
+) -> Result<bool, String> {
+    let default_config = burn_in::BurnInConfig::default();
+    let burn_in_config = config.burn_in.as_ref().unwrap_or(&default_config);
+    match burn_in::verify_replacement(conn, Path::new(disk_path), burn_in_config, simulate)? {
+        BurnIn::Passed => Ok(true),
+        BurnIn::InProgress(status) => {
+            info!("Not adding {} yet.  {}", disk_path, status);
+            Ok(false)
+        }
+        BurnIn::Failed(reason) => {
+            error!("Replacement {} failed verification: {}", disk_path, reason);
+            if config.slack_webhook.is_some() {
+                let host_info = Host::new().map_err(|e| e.to_string())?;
+                let _ = notify_slack(
+                    config,
+                    &format!(
+                        "Replacement disk {} on host: {} failed verification: {}.  \
+                         It needs to be swapped again",
+                        disk_path, host_info.hostname, reason
+                    ),
+                );
+            }
+            Ok(false)
+        }
+        BurnIn::Rejected(reason) => {
+            debug!("{} was rejected earlier: {}", disk_path, reason);
+            Ok(false)
+        }
+    }
+}
+
+fn add_repaired_disks(config_dir: &str, simulate: bool, gate: &SafetyGate) -> Result<(), String> {
     let config: ConfigSettings =
         helpers::load_config(config_dir, "bynar.json").map_err(|e| e.to_string())?;
     let host_info = Host::new().map_err(|e| e.to_string())?;
@@ -240,9 +1018,78 @@ fn add_repaired_disks(config_dir: &str, simulate: bool) -> Result<(), String> {
     let tickets = in_progress::get_outstanding_repair_tickets(&conn).map_err(|e| e.to_string())?;
     info!("Checking for resolved repair tickets");
     for ticket in tickets {
+        if in_progress::is_disk_ignored(&conn, &Path::new(&ticket.disk_path))
+            .map_err(|e| e.to_string())?
+        {
+            debug!("{} is being ignored.  Skipping ticket", ticket.disk_path);
+            continue;
+        }
         match ticket_resolved(&config, &ticket.ticket_id.to_string()) {
             Ok(resolved) => {
-                if resolved {
+                if resolved && raid::is_raid_drive(&ticket.disk_path) {
+                    // The controller rebuilds onto the new drive by itself
+                    info!("{} was replaced", ticket.disk_path);
+                    if let Err(e) = in_progress::resolve_ticket(&conn, &ticket.ticket_id) {
+                        error!("Failed to resolve {}.  {:?}", ticket.ticket_id, e);
+                    }
+                } else if resolved {
+                    if in_progress::is_wipe_pending(&conn, &Path::new(&ticket.disk_path))
+                        .map_err(|e| e.to_string())?
+                    {
+                        info!("Not adding {} while it's being sanitised", ticket.disk_path);
+                        continue;
+                    }
+                    if let Decision::Denied(reason) = gate.check_add() {
+                        info!("Not adding {} yet.  {}", ticket.disk_path, reason);
+                        continue;
+                    }
+                    match replacement_verified(&config, &conn, &ticket.disk_path, simulate) {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(e) => {
+                            error!("Unable to verify {}: {}", ticket.disk_path, e);
+                            continue;
+                        }
+                    };
+                    let md_members =
+                        in_progress::get_md_members(&conn, &Path::new(&ticket.disk_path))
+                            .map_err(|e| e.to_string())?;
+                    if !md_members.is_empty() {
+                        // Software raid members go back into their arrays
+                        // rather than the cluster
+                        match mdraid::readd_members(&conn, &Path::new(&ticket.disk_path), simulate)
+                        {
+                            Ok(_) => {
+                                if !simulate {
+                                    if let Err(e) = in_progress::record_disk_added(
+                                        &conn,
+                                        &Path::new(&ticket.disk_path),
+                                    ) {
+                                        error!(
+                                            "Failed to record add of {}.  {:?}",
+                                            ticket.disk_path, e
+                                        );
+                                    }
+                                    set_fault_led(
+                                        &config,
+                                        &public_key,
+                                        &Path::new(&ticket.disk_path),
+                                        false,
+                                    );
+                                    if let Err(e) =
+                                        in_progress::resolve_ticket(&conn, &ticket.ticket_id)
+                                    {
+                                        error!(
+                                            "Failed to resolve {}.  {:?}",
+                                            ticket.ticket_id, e
+                                        );
+                                    }
+                                }
+                            }
+                            Err(e) => error!("Unable to re-add {}: {}", ticket.disk_path, e),
+                        };
+                        continue;
+                    }
                     //CALL RPC
                     debug!("Connecting to disk-manager");
                     let mut socket = helpers::connect(
@@ -250,14 +1097,38 @@ fn add_repaired_disks(config_dir: &str, simulate: bool) -> Result<(), String> {
                         &config.manager_port.to_string(),
                         &public_key,
                     ).map_err(|e| e.to_string())?;
+                    // The replacement checked out so the slot no longer needs attention
+                    if let Err(e) = helpers::set_led_request(
+                        &mut socket,
+                        &Path::new(&ticket.disk_path),
+                        Led::FAULT,
+                        false,
+                    ) {
+                        warn!("Unable to turn off the slot led of {}: {}", ticket.disk_path, e);
+                    }
+                    // A destroyed osd's id is taken over by its replacement
+                    let osd_id = in_progress::get_osd_id(&conn, &Path::new(&ticket.disk_path))
+                        .map_err(|e| e.to_string())?;
                     match helpers::add_disk_request(
                         &mut socket,
                         &Path::new(&ticket.disk_path),
+                        osd_id,
                         None,
                         simulate,
                     ) {
                         Ok(_) => {
                             debug!("Disk added successfully");
+                            if let Err(e) =
+                                in_progress::clear_osd_id(&conn, &Path::new(&ticket.disk_path))
+                            {
+                                error!("Failed to clear osd id of {}.  {:?}", ticket.disk_path, e);
+                            }
+                            if let Err(e) = in_progress::record_disk_added(
+                                &conn,
+                                &Path::new(&ticket.disk_path),
+                            ) {
+                                error!("Failed to record add of {}.  {:?}", ticket.disk_path, e);
+                            }
                             match in_progress::resolve_ticket(&conn, &ticket.ticket_id) {
                                 Ok(_) => {
                                     debug!("Database updated");
@@ -287,6 +1158,52 @@ fn add_repaired_disks(config_dir: &str, simulate: bool) -> Result<(), String> {
     Ok(())
 }
 
+// Print the state machine graph and optionally how a disk has moved through it
+fn handle_graph(config_dir: &str, matches: &ArgMatches) -> Result<(), String> {
+    let graph = state_graph::load_state_graph(config_dir).map_err(|e| e.to_string())?;
+    let overlay = match matches.value_of("disk") {
+        Some(disk) => {
+            let config: ConfigSettings =
+                helpers::load_config(config_dir, "bynar.json").map_err(|e| e.to_string())?;
+            let conn = in_progress::connect_to_repair_database(Path::new(&config.db_location))
+                .map_err(|e| e.to_string())?;
+            let dev_path = Path::new(disk);
+            Some(DiskOverlay {
+                disk: disk.to_string(),
+                state: in_progress::get_state(&conn, &dev_path).map_err(|e| e.to_string())?,
+                history: in_progress::get_state_history(&conn, &dev_path)
+                    .map_err(|e| e.to_string())?,
+            })
+        }
+        None => None,
+    };
+    match matches.value_of("format") {
+        Some("json") => {
+            let json = graph.to_json(overlay.as_ref()).map_err(|e| e.to_string())?;
+            println!("{}", json);
+        }
+        _ => print!("{}", graph.to_dot(overlay.as_ref())),
+    };
+    Ok(())
+}
+
+fn device_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("device")
+        .help("The disk to act on. Ex: /dev/sda")
+        .required(true)
+        .index(1)
+}
+
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .default_value("table")
+        .help("The output format")
+        .long("format")
+        .possible_values(&["table", "json"])
+        .takes_value(true)
+        .required(false)
+}
+
 // 1. Gather a list of all the disks
 // 2. Check every disk
 // 3. Decide if a disk needs to be replaced
@@ -315,20 +1232,130 @@ fn main() {
                 .short("v")
                 .multiple(true)
                 .help("Sets the level of verbosity"),
+        ).subcommand(
+            SubCommand::with_name("status")
+                .about("Show every disk in the repair database")
+                .arg(format_arg()),
+        ).subcommand(
+            SubCommand::with_name("disk")
+                .about("Inspect or change what bynar thinks of a disk")
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show a disk's state and history")
+                        .arg(device_arg())
+                        .arg(format_arg()),
+                ).subcommand(
+                    SubCommand::with_name("ignore")
+                        .about("Skip a disk when checking and replacing disks")
+                        .arg(device_arg())
+                        .arg(
+                            Arg::with_name("until")
+                                .help("Ignore until. Ex: 12h, 7d or 2018-10-01. Default: forever")
+                                .long("until")
+                                .takes_value(true)
+                                .required(false),
+                        ).arg(
+                            Arg::with_name("reason")
+                                .help("Why the disk is being ignored")
+                                .long("reason")
+                                .takes_value(true)
+                                .required(false),
+                        ),
+                ).subcommand(
+                    SubCommand::with_name("unignore")
+                        .about("Stop ignoring a disk")
+                        .arg(device_arg()),
+                ).subcommand(
+                    SubCommand::with_name("reset")
+                        .about("Send a disk back to Unscanned and forget its failures")
+                        .arg(device_arg()),
+                ),
+        ).subcommand(
+            SubCommand::with_name("ticket")
+                .about("Inspect repair tickets")
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List outstanding repair tickets")
+                        .arg(format_arg()),
+                ),
+        ).subcommand(
+            SubCommand::with_name("approve")
+                .about("Approve a queued disk removal")
+                .arg(device_arg())
+                .arg(
+                    Arg::with_name("by")
+                        .help("Who is approving the removal. Default: $SUDO_USER or $USER")
+                        .long("by")
+                        .takes_value(true)
+                        .required(false),
+                ),
+        ).subcommand(
+            SubCommand::with_name("maintenance")
+                .about("Stop bynar from changing anything on this host")
+                .subcommand(
+                    SubCommand::with_name("start")
+                        .about("Only observe disks until maintenance is stopped")
+                        .arg(
+                            Arg::with_name("until")
+                                .help("End automatically. Ex: 4h or 2018-10-01")
+                                .long("until")
+                                .takes_value(true)
+                                .required(false),
+                        ).arg(
+                            Arg::with_name("reason")
+                                .help("Why the host is in maintenance")
+                                .long("reason")
+                                .takes_value(true)
+                                .required(false),
+                        ),
+                ).subcommand(SubCommand::with_name("stop").about("End maintenance"))
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("Show if the host is in maintenance")
+                        .arg(format_arg()),
+                ),
+        ).subcommand(
+            SubCommand::with_name("graph")
+                .about("Print the state machine graph")
+                .arg(
+                    Arg::with_name("format")
+                        .default_value("dot")
+                        .help("The output format")
+                        .long("format")
+                        .possible_values(&["dot", "json"])
+                        .takes_value(true)
+                        .required(false),
+                ).arg(
+                    Arg::with_name("disk")
+                        .help("Overlay this disk's current state and path. Ex: /dev/sda")
+                        .long("disk")
+                        .takes_value(true)
+                        .required(false),
+                ),
         ).get_matches();
     let level = match matches.occurrences_of("v") {
         0 => log::LevelFilter::Info, //default
         1 => log::LevelFilter::Debug,
         _ => log::LevelFilter::Trace,
     };
-    let _ = CombinedLogger::init(vec![
-        TermLogger::new(level, Config::default()).unwrap(),
-        WriteLogger::new(
-            level,
-            Config::default(),
-            File::create("/var/log/bynar.log").unwrap(),
-        ),
-    ]);
+    if matches.subcommand_name().is_some() {
+        // Subcommands print their results to the terminal.  Keep the
+        // logging quiet and leave the log file of the regular run alone.
+        let level = match matches.occurrences_of("v") {
+            0 => log::LevelFilter::Warn,
+            _ => level,
+        };
+        let _ = TermLogger::init(level, Config::default());
+    } else {
+        let _ = CombinedLogger::init(vec![
+            TermLogger::new(level, Config::default()).unwrap(),
+            WriteLogger::new(
+                level,
+                Config::default(),
+                File::create("/var/log/bynar.log").unwrap(),
+            ),
+        ]);
+    }
     info!("Starting up");
 
     //Sanity check
@@ -339,7 +1366,56 @@ fn main() {
     let simulate = matches.is_present("simulate");
     let config_dir = matches.value_of("configdir").unwrap();
 
-    match check_for_failed_disks(config_dir, simulate) {
+    let result = match matches.subcommand() {
+        ("graph", Some(sub_matches)) => Some(handle_graph(config_dir, sub_matches)),
+        ("status", Some(sub_matches)) => Some(control::handle_status(config_dir, sub_matches)),
+        ("disk", Some(sub_matches)) => Some(control::handle_disk(config_dir, sub_matches)),
+        ("ticket", Some(sub_matches)) => Some(control::handle_ticket(config_dir, sub_matches)),
+        ("approve", Some(sub_matches)) => Some(approval::handle_approve(config_dir, sub_matches)),
+        ("maintenance", Some(sub_matches)) => {
+            Some(control::handle_maintenance(config_dir, sub_matches))
+        }
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(e) = result {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let config: ConfigSettings = match helpers::load_config(config_dir, "bynar.json") {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Unable to load bynar.json: {}", e);
+            return;
+        }
+    };
+    if let Some(ref filter) = config.device_filter {
+        if let Err(e) = filter.validate() {
+            error!("Invalid device_filter: {}", e);
+            return;
+        }
+    }
+    let mut gate = match SafetyGate::new(config.safety_policy.as_ref(), config_dir) {
+        Ok(gate) => gate,
+        Err(e) => {
+            error!("Invalid safety_policy: {}", e);
+            return;
+        }
+    };
+    let observe_only = match in_maintenance(&config) {
+        Ok(maintenance) => maintenance,
+        Err(e) => {
+            error!("Unable to check for maintenance: {}", e);
+            return;
+        }
+    };
+    if observe_only {
+        info!("Host is in maintenance.  Only observing disks");
+    }
+    match check_for_failed_disks(config_dir, simulate, observe_only, &mut gate) {
         Err(e) => {
             error!("Check for failed disks failed with error: {}", e);
         }
@@ -347,7 +1423,26 @@ fn main() {
             info!("Check for failed disks completed");
         }
     };
-    match add_repaired_disks(config_dir, simulate) {
+    if observe_only {
+        return;
+    }
+    match remove_pending_disks(config_dir, simulate, &mut gate) {
+        Err(e) => {
+            error!("Remove pending disks failed with error: {}", e);
+        }
+        _ => {
+            info!("Remove pending disks completed");
+        }
+    };
+    match collect_wipe_certificates(config_dir) {
+        Err(e) => {
+            error!("Collect wipe certificates failed with error: {}", e);
+        }
+        _ => {
+            info!("Collect wipe certificates completed");
+        }
+    };
+    match add_repaired_disks(config_dir, simulate, &gate) {
         Err(e) => {
             error!("Add repaired disks failed with error: {}", e);
         }
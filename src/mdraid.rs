@@ -0,0 +1,614 @@
+//! Linux software raid (md) health.  The arrays in /proc/mdstat are checked
+//! for faulty members, missing members and resyncs that stopped making
+//! progress.  A faulty member is pulled out of its arrays and its disk is
+//! ticketed for replacement.  Once the replacement is in, bynar gives it the
+//! surviving members' partition layout, adds it back and follows the rebuild.
+extern crate rusqlite;
+extern crate time;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use in_progress;
+
+use self::rusqlite::Connection;
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use std::fs::{create_dir_all, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    static MDSTAT: &str = r#"Personalities : [raid1] [raid6] [raid5] [raid4]
+md1 : active raid5 sdd1[3] sdc1[1](F) sdb1[0]
+      1953260544 blocks super 1.2 level 5, 512k chunk, algorithm 2 [3/2] [U_U]
+      [=>...................]  recovery =  8.5% (83046144/976630272) finish=84.3min speed=176556K/sec
+      bitmap: 0/8 pages [0KB], 65536KB chunk
+
+md0 : active raid1 nvme1n1p2[1] nvme0n1p2[0]
+      523264 blocks super 1.2 [2/2] [UU]
+      	resync=DELAYED
+
+md127 : inactive sde1[0](S)
+      976630272 blocks super 1.2
+
+unused devices: <none>
+"#;
+
+    #[test]
+    fn test_parse_mdstat() {
+        let arrays = super::parse_mdstat(MDSTAT);
+        assert_eq!(arrays.len(), 3);
+
+        let md1 = &arrays[0];
+        assert_eq!(md1.name, "md1");
+        assert!(md1.active);
+        assert_eq!(md1.level, Some("raid5".to_string()));
+        assert_eq!(md1.raid_disks, Some(3));
+        assert_eq!(md1.working_disks, Some(2));
+        assert!(md1.degraded());
+        assert_eq!(md1.members.len(), 3);
+        assert_eq!(md1.members[1].device, "sdc1");
+        assert_eq!(md1.members[1].role, Some(1));
+        assert!(md1.members[1].faulty);
+        let faulty: Vec<&str> = md1
+            .faulty_members()
+            .iter()
+            .map(|m| m.device.as_str())
+            .collect();
+        assert_eq!(faulty, vec!["sdc1"]);
+        let sync = md1.sync.as_ref().unwrap();
+        assert_eq!(sync.action, "recovery");
+        assert_eq!(sync.percent, Some(8.5));
+        assert_eq!(sync.completed, Some((83046144, 976630272)));
+        assert_eq!(sync.finish, Some("84.3min".to_string()));
+
+        let md0 = &arrays[1];
+        assert!(!md0.degraded());
+        assert!(md0.faulty_members().is_empty());
+        let sync = md0.sync.as_ref().unwrap();
+        assert_eq!(sync.action, "resync");
+        assert_eq!(sync.completed, None);
+
+        let md127 = &arrays[2];
+        assert!(!md127.active);
+        assert_eq!(md127.level, None);
+        assert!(md127.members[0].spare);
+        assert!(!md127.degraded());
+    }
+
+    #[test]
+    fn test_disk_members() {
+        let arrays = super::parse_mdstat(MDSTAT);
+        assert_eq!(
+            super::disk_members(&arrays, Path::new("/dev/sdc")),
+            vec![("md1".to_string(), "sdc1".to_string())]
+        );
+        assert_eq!(
+            super::disk_members(&arrays, Path::new("/dev/nvme0n1")),
+            vec![("md0".to_string(), "nvme0n1p2".to_string())]
+        );
+        assert!(super::disk_members(&arrays, Path::new("/dev/sdf")).is_empty());
+    }
+
+    #[test]
+    fn test_parent_disk() {
+        assert_eq!(super::parent_disk("sdc1"), "sdc");
+        assert_eq!(super::parent_disk("sdc"), "sdc");
+        assert_eq!(super::parent_disk("nvme0n1p2"), "nvme0n1");
+        assert_eq!(super::parent_disk("nvme0n1"), "nvme0n1");
+        assert_eq!(super::parent_disk("mmcblk0p1"), "mmcblk0");
+    }
+
+    #[test]
+    fn test_sysfs() {
+        assert_eq!(super::parse_sync_completed("none\n"), None);
+        assert_eq!(
+            super::parse_sync_completed("83046144 / 976630272\n"),
+            Some((83046144, 976630272))
+        );
+
+        let dir = TempDir::new("bynar").expect("Temp dir creation failed");
+        let md = dir.path().join("md1/md");
+        create_dir_all(md.join("dev-sdb1")).unwrap();
+        create_dir_all(md.join("dev-sdc1")).unwrap();
+        let mut f = File::create(md.join("dev-sdb1/state")).unwrap();
+        f.write_all(b"in_sync\n").unwrap();
+        let mut f = File::create(md.join("dev-sdc1/state")).unwrap();
+        f.write_all(b"faulty,write_error\n").unwrap();
+        let mut f = File::create(md.join("sync_completed")).unwrap();
+        f.write_all(b"1024 / 4096\n").unwrap();
+
+        let mut arrays = super::parse_mdstat(
+            "md1 : active raid1 sdc1[1] sdb1[0]\n      100 blocks [2/2] [UU]\n",
+        );
+        super::apply_sysfs(dir.path(), &mut arrays[0]);
+        assert!(arrays[0].members[0].faulty);
+        assert!(!arrays[0].members[1].faulty);
+        assert_eq!(arrays[0].sync_completed, Some((1024, 4096)));
+    }
+}
+
+/// Settings for md array checks in bynar.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct MdRaidConfig {
+    /// Minutes a resync can go without progress before it's reported as
+    /// stalled.  Default: 60
+    pub stall_minutes: Option<i64>,
+}
+
+impl Default for MdRaidConfig {
+    fn default() -> Self {
+        MdRaidConfig {
+            stall_minutes: None,
+        }
+    }
+}
+
+/// A member device of an md array
+#[derive(Clone, Debug, PartialEq)]
+pub struct MdMember {
+    /// Ex: sdb1
+    pub device: String,
+    /// The member's slot in the array
+    pub role: Option<u32>,
+    pub faulty: bool,
+    pub spare: bool,
+}
+
+/// A resync, recovery, check or reshape that's running or waiting to run
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncProgress {
+    pub action: String,
+    pub percent: Option<f32>,
+    /// Blocks done and blocks in total
+    pub completed: Option<(u64, u64)>,
+    /// Estimated time left.  Ex: 84.3min
+    pub finish: Option<String>,
+}
+
+/// An md array as /proc/mdstat and sysfs describe it
+#[derive(Clone, Debug, PartialEq)]
+pub struct MdArray {
+    /// Ex: md0
+    pub name: String,
+    pub active: bool,
+    pub level: Option<String>,
+    pub members: Vec<MdMember>,
+    /// The members the array should have
+    pub raid_disks: Option<u32>,
+    /// The members the array has working
+    pub working_disks: Option<u32>,
+    pub sync: Option<SyncProgress>,
+    /// Sectors done and in total from sysfs when a sync is running
+    pub sync_completed: Option<(u64, u64)>,
+}
+
+impl MdArray {
+    pub fn degraded(&self) -> bool {
+        match (self.raid_disks, self.working_disks) {
+            (Some(raid), Some(working)) => working < raid,
+            _ => false,
+        }
+    }
+
+    pub fn faulty_members(&self) -> Vec<&MdMember> {
+        self.members.iter().filter(|m| m.faulty).collect()
+    }
+
+    // Sync progress from sysfs if it's there, otherwise from mdstat
+    fn progress(&self) -> Option<u64> {
+        self.sync_completed
+            .or_else(|| self.sync.as_ref().and_then(|s| s.completed))
+            .map(|(done, _)| done)
+    }
+}
+
+// sdc1[1](F) is member sdc1 in slot 1 and faulty
+fn parse_member(token: &str) -> Option<MdMember> {
+    let open = token.find('[')?;
+    let close = token[open..].find(']')? + open;
+    let flags = &token[close + 1..];
+    Some(MdMember {
+        device: token[..open].to_string(),
+        role: token[open + 1..close].parse().ok(),
+        faulty: flags.contains("(F)"),
+        spare: flags.contains("(S)"),
+    })
+}
+
+// "[3/2]" is 3 members with 2 working
+fn parse_disk_counts(line: &str) -> Option<(u32, u32)> {
+    line.split_whitespace()
+        .filter(|t| t.starts_with('[') && t.ends_with(']') && t.contains('/'))
+        .filter_map(|t| {
+            let mut parts = t.trim_matches(|c| c == '[' || c == ']').split('/');
+            let raid = parts.next()?.parse().ok()?;
+            let working = parts.next()?.parse().ok()?;
+            Some((raid, working))
+        })
+        .next()
+}
+
+// recovery =  8.5% (83046144/976630272) finish=84.3min speed=176556K/sec
+// or resync=DELAYED
+fn parse_sync(line: &str) -> Option<SyncProgress> {
+    let line = line
+        .trim()
+        .trim_start_matches(|c| c == '[' || c == '=' || c == '>' || c == '.');
+    let line = line.trim_start_matches(']').trim();
+    let eq = line.find('=')?;
+    let action = line[..eq].trim();
+    match action {
+        "resync" | "recovery" | "check" | "repair" | "reshape" => {}
+        _ => return None,
+    };
+    let rest = line[eq + 1..].trim();
+    let mut percent = None;
+    let mut completed = None;
+    let mut finish = None;
+    for token in rest.split_whitespace() {
+        if token.ends_with('%') {
+            percent = token.trim_end_matches('%').parse().ok();
+        } else if token.starts_with('(') && token.contains('/') {
+            let mut parts = token.trim_matches(|c| c == '(' || c == ')').split('/');
+            let done = parts.next().and_then(|p| p.parse().ok());
+            let total = parts.next().and_then(|p| p.parse().ok());
+            if let (Some(done), Some(total)) = (done, total) {
+                completed = Some((done, total));
+            }
+        } else if token.starts_with("finish=") {
+            finish = Some(token["finish=".len()..].to_string());
+        }
+    }
+    Some(SyncProgress {
+        action: action.to_string(),
+        percent,
+        completed,
+        finish,
+    })
+}
+
+/// Parse the contents of /proc/mdstat
+pub fn parse_mdstat(mdstat: &str) -> Vec<MdArray> {
+    let mut arrays: Vec<MdArray> = Vec::new();
+    for line in mdstat.lines() {
+        if line.starts_with("Personalities") || line.starts_with("unused devices") {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) && line.contains(" : ") {
+            let mut parts = line.splitn(2, " : ");
+            let name = parts.next().unwrap_or("").trim().to_string();
+            let mut tokens = parts.next().unwrap_or("").split_whitespace().peekable();
+            let active = tokens.next() == Some("active");
+            // (read-only) and (auto-read-only) follow the state
+            while tokens.peek().map(|t| t.starts_with('(')).unwrap_or(false) {
+                tokens.next();
+            }
+            let mut level = None;
+            let mut members = Vec::new();
+            for token in tokens {
+                match parse_member(token) {
+                    Some(member) => members.push(member),
+                    None => level = Some(token.to_string()),
+                }
+            }
+            arrays.push(MdArray {
+                name,
+                active,
+                level,
+                members,
+                raid_disks: None,
+                working_disks: None,
+                sync: None,
+                sync_completed: None,
+            });
+            continue;
+        }
+        let array = match arrays.last_mut() {
+            Some(array) => array,
+            None => continue,
+        };
+        if line.contains(" blocks") {
+            if let Some((raid, working)) = parse_disk_counts(line) {
+                array.raid_disks = Some(raid);
+                array.working_disks = Some(working);
+            }
+        } else if let Some(sync) = parse_sync(line) {
+            array.sync = Some(sync);
+        }
+    }
+    arrays
+}
+
+/// Parse /sys/block/<md>/md/sync_completed.  Ex: 83046144 / 976630272
+pub fn parse_sync_completed(contents: &str) -> Option<(u64, u64)> {
+    let mut parts = contents.trim().split('/');
+    let done = parts.next()?.trim().parse().ok()?;
+    let total = parts.next()?.trim().parse().ok()?;
+    Some((done, total))
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    let mut f = File::open(path).ok()?;
+    let mut buff = String::new();
+    f.read_to_string(&mut buff).ok()?;
+    Some(buff.trim().to_string())
+}
+
+// sysfs knows about faulty members before mdstat catches up and has sync
+// progress in sectors
+fn apply_sysfs(sys_block: &Path, array: &mut MdArray) {
+    let md_dir = sys_block.join(&array.name).join("md");
+    for member in &mut array.members {
+        if let Some(state) =
+            read_trimmed(&md_dir.join(format!("dev-{}", member.device)).join("state"))
+        {
+            if state.split(',').any(|s| s == "faulty") {
+                member.faulty = true;
+            }
+        }
+    }
+    array.sync_completed =
+        read_trimmed(&md_dir.join("sync_completed")).and_then(|c| parse_sync_completed(&c));
+}
+
+/// The md arrays on this host
+pub fn get_arrays() -> Result<Vec<MdArray>, String> {
+    let mdstat = match read_trimmed(Path::new("/proc/mdstat")) {
+        Some(mdstat) => mdstat,
+        // No md module loaded
+        None => return Ok(vec![]),
+    };
+    let mut arrays = parse_mdstat(&mdstat);
+    for array in &mut arrays {
+        apply_sysfs(Path::new("/sys/block"), array);
+    }
+    Ok(arrays)
+}
+
+/// The disk a member partition lives on.  Ex: sdc1 -> sdc, nvme0n1p2 -> nvme0n1
+pub fn parent_disk(member: &str) -> String {
+    if member.starts_with("nvme") || member.starts_with("mmcblk") {
+        let trimmed = member.trim_end_matches(|c: char| c.is_digit(10));
+        if trimmed.ends_with('p') && trimmed.len() < member.len() {
+            return trimmed.trim_end_matches('p').to_string();
+        }
+        return member.to_string();
+    }
+    member
+        .trim_end_matches(|c: char| c.is_digit(10))
+        .to_string()
+}
+
+fn run(cmd: &str, args: &[&str]) -> Result<String, String> {
+    debug!("cmd: {} {:?}", cmd, args);
+    let output = Command::new(cmd)
+        .args(args)
+        .output()
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} {:?} failed: {}",
+            cmd,
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The (array, member) pairs of every member that lives on a disk
+pub fn disk_members(arrays: &[MdArray], dev_path: &Path) -> Vec<(String, String)> {
+    let disk = dev_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut members = Vec::new();
+    for array in arrays {
+        for member in array
+            .members
+            .iter()
+            .filter(|m| parent_disk(&m.device) == disk)
+        {
+            members.push((array.name.clone(), member.device.clone()));
+        }
+    }
+    members
+}
+
+/// Fail and remove every member on a disk from its arrays so the disk can be
+/// pulled.  Returns the (array, member) pairs that were removed
+pub fn remove_disk_members(
+    conn: &Connection,
+    arrays: &[MdArray],
+    dev_path: &Path,
+    simulate: bool,
+) -> Result<Vec<(String, String)>, String> {
+    let mut removed = Vec::new();
+    for (array, member) in disk_members(arrays, dev_path) {
+        let md_dev = format!("/dev/{}", array);
+        let member_dev = format!("/dev/{}", member);
+        info!("Removing {} from {}", member_dev, md_dev);
+        if !simulate {
+            run(
+                "mdadm",
+                &[
+                    "--manage",
+                    &md_dev,
+                    "--fail",
+                    &member_dev,
+                    "--remove",
+                    &member_dev,
+                ],
+            )?;
+            in_progress::record_md_member(conn, dev_path, &array, &member, "removed")
+                .map_err(|e| e.to_string())?;
+        }
+        removed.push((array, member));
+    }
+    Ok(removed)
+}
+
+// Give a blank replacement the partition table of a surviving member's disk
+fn copy_partition_table(from: &str, to: &str) -> Result<(), String> {
+    info!("Copying the partition table of {} to {}", from, to);
+    let table = run("sfdisk", &["--dump", from])?;
+    let mut child = Command::new("sfdisk")
+        .arg(to)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    if let Some(ref mut stdin) = child.stdin {
+        stdin
+            .write_all(table.as_bytes())
+            .map_err(|e| e.to_string())?;
+    }
+    let status = child.wait().map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!("sfdisk {} failed", to));
+    }
+    Ok(())
+}
+
+/// Add a replacement disk back into the arrays its predecessor was pulled
+/// from.  The arrays rebuild onto it in the background
+pub fn readd_members(conn: &Connection, dev_path: &Path, simulate: bool) -> Result<(), String> {
+    let members = in_progress::get_md_members(conn, dev_path).map_err(|e| e.to_string())?;
+    let arrays = get_arrays()?;
+    let disk = dev_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut partitioned = false;
+    for record in members.iter().filter(|m| m.status == "removed") {
+        let member_dev = format!("/dev/{}", record.member);
+        let md_dev = format!("/dev/{}", record.array);
+        if record.member != disk && !partitioned {
+            // Members are partitions so the new disk needs the same layout
+            let survivor = arrays
+                .iter()
+                .find(|a| a.name == record.array)
+                .and_then(|a| {
+                    a.members
+                        .iter()
+                        .map(|m| parent_disk(&m.device))
+                        .find(|d| *d != disk)
+                })
+                .ok_or_else(|| format!("No surviving member of {} to copy", md_dev))?;
+            if !simulate {
+                copy_partition_table(&format!("/dev/{}", survivor), &dev_path.to_string_lossy())?;
+            }
+            partitioned = true;
+        }
+        info!("Adding {} to {}", member_dev, md_dev);
+        if !simulate {
+            run("mdadm", &["--manage", &md_dev, "--add", &member_dev])?;
+            in_progress::record_md_member(
+                conn,
+                dev_path,
+                &record.array,
+                &record.member,
+                "rebuilding",
+            )
+            .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// What happened to an array since the last run
+pub enum MdEvent {
+    /// A member that's faulty and whose disk isn't being repaired yet
+    Faulty { array: String, member: String },
+    /// Missing members without a faulty one to blame
+    Degraded { array: String },
+    /// A sync that stopped making progress
+    Stalled {
+        array: String,
+        action: String,
+        minutes: i64,
+    },
+    /// A replacement member finished rebuilding
+    Rebuilt { array: String, member: String },
+}
+
+/// Look over the arrays for faults, stalled resyncs and finished rebuilds
+pub fn check_arrays(
+    conn: &Connection,
+    arrays: &[MdArray],
+    config: &MdRaidConfig,
+) -> Result<Vec<MdEvent>, String> {
+    let stall_minutes = config.stall_minutes.unwrap_or(60);
+    let mut events = Vec::new();
+    for array in arrays {
+        let faulty = array.faulty_members();
+        for member in &faulty {
+            events.push(MdEvent::Faulty {
+                array: array.name.clone(),
+                member: member.device.clone(),
+            });
+        }
+        if array.degraded() && faulty.is_empty() && array.sync.is_none() {
+            events.push(MdEvent::Degraded {
+                array: array.name.clone(),
+            });
+        }
+        match (array.sync.as_ref(), array.progress()) {
+            (Some(sync), Some(done)) => {
+                if let Some(ref percent) = sync.percent {
+                    info!("{} {} is {}% done", array.name, sync.action, percent);
+                }
+                let record =
+                    in_progress::get_md_resync(conn, &array.name).map_err(|e| e.to_string())?;
+                match record {
+                    Some(ref r) if r.completed == done => {
+                        let minutes = (time::get_time() - r.time_updated).num_minutes();
+                        if minutes >= stall_minutes && !r.stalled {
+                            events.push(MdEvent::Stalled {
+                                array: array.name.clone(),
+                                action: sync.action.clone(),
+                                minutes,
+                            });
+                            in_progress::mark_md_resync_stalled(conn, &array.name)
+                                .map_err(|e| e.to_string())?;
+                        }
+                    }
+                    _ => {
+                        in_progress::save_md_resync(conn, &array.name, &sync.action, done)
+                            .map_err(|e| e.to_string())?;
+                    }
+                };
+            }
+            _ => {
+                in_progress::clear_md_resync(conn, &array.name).map_err(|e| e.to_string())?;
+            }
+        };
+    }
+    // Replacements that were added and have caught up
+    for record in in_progress::get_rebuilding_md_members(conn).map_err(|e| e.to_string())? {
+        let array = match arrays.iter().find(|a| a.name == record.array) {
+            Some(array) => array,
+            None => continue,
+        };
+        let in_sync = array
+            .members
+            .iter()
+            .any(|m| m.device == record.member && !m.faulty && !m.spare);
+        if in_sync && array.sync.is_none() && !array.degraded() {
+            events.push(MdEvent::Rebuilt {
+                array: record.array.clone(),
+                member: record.member.clone(),
+            });
+            in_progress::clear_md_member(conn, &record.array, &record.member)
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(events)
+}
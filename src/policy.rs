@@ -0,0 +1,324 @@
+//! Cluster safety checks consulted before any disk is removed or added.
+//! A site can limit how many disks are out of a host at once, how many are
+//! removed in a single run and when removals and adds are allowed to happen.
+//! Maintenance windows are cron like expressions of 5 fields:
+//! `minute hour day-of-month month day-of-week` in local time.  Ex:
+//! `* 1-5 * * 1-5` allows changes from 01:00 to 05:59 on weekdays.
+//! Creating the kill switch file pauses every removal and add while still
+//! letting bynar check disks and file tickets.
+extern crate rusqlite;
+extern crate time;
+
+use std::path::{Path, PathBuf};
+
+use in_progress;
+
+use self::rusqlite::Connection;
+use self::time::Tm;
+
+#[cfg(test)]
+mod tests {
+    use super::time;
+    use super::{CronExpr, Decision, SafetyGate, SafetyPolicy};
+
+    // A time in October 2018 which started on a Monday
+    fn at(hour: i32, minute: i32, mday: i32) -> time::Tm {
+        let mut tm = time::strptime("2018-10-01 00:00", "%Y-%m-%d %H:%M").unwrap();
+        tm.tm_hour = hour;
+        tm.tm_min = minute;
+        tm.tm_mday = mday;
+        tm.tm_wday = mday % 7;
+        tm
+    }
+
+    #[test]
+    fn test_cron_parse() {
+        assert!(CronExpr::parse("* * * * *").is_ok());
+        assert!(CronExpr::parse("*/15 1-5,22 1,15 */2 1-5").is_ok());
+        assert!(CronExpr::parse("* * * *").is_err());
+        assert!(CronExpr::parse("60 * * * *").is_err());
+        assert!(CronExpr::parse("* 5-1 * * *").is_err());
+        assert!(CronExpr::parse("* * * * mon").is_err());
+        assert!(CronExpr::parse("*/0 * * * *").is_err());
+    }
+
+    #[test]
+    fn test_cron_matches() {
+        let weekday_nights = CronExpr::parse("* 1-5 * * 1-5").unwrap();
+        // Wednesday 03:30
+        assert!(weekday_nights.matches(&at(3, 30, 3)));
+        // Wednesday 06:00
+        assert!(!weekday_nights.matches(&at(6, 0, 3)));
+        // Sunday 03:30
+        assert!(!weekday_nights.matches(&at(3, 30, 7)));
+
+        let quarter_hours = CronExpr::parse("*/15 * * * *").unwrap();
+        assert!(quarter_hours.matches(&at(12, 45, 3)));
+        assert!(!quarter_hours.matches(&at(12, 46, 3)));
+
+        // 7 is also Sunday
+        let sundays = CronExpr::parse("* * * * 7").unwrap();
+        assert!(sundays.matches(&at(12, 0, 7)));
+
+        // Day of month and day of week are or'd together like cron
+        let first_or_monday = CronExpr::parse("* * 1 * 1").unwrap();
+        assert!(first_or_monday.matches(&at(12, 0, 1)));
+        assert!(first_or_monday.matches(&at(12, 0, 8)));
+        assert!(!first_or_monday.matches(&at(12, 0, 3)));
+    }
+
+    #[test]
+    fn test_gate() {
+        let policy = SafetyPolicy {
+            max_disks_out_per_host: Some(2),
+            max_removals_per_run: Some(1),
+            maintenance_windows: vec!["* 1-5 * * *".to_string()],
+            kill_switch: Some("/nonexistent/bynar/pause".to_string()),
+        };
+        let mut gate = SafetyGate::new(Some(&policy), "/etc/bynar").unwrap();
+        assert_eq!(gate.check(&at(3, 0, 3), Some(0)), Decision::Allowed);
+        match gate.check(&at(12, 0, 3), Some(0)) {
+            Decision::Denied(_) => {}
+            Decision::Allowed => panic!("Allowed outside of the maintenance window"),
+        };
+        match gate.check(&at(3, 0, 3), Some(2)) {
+            Decision::Denied(_) => {}
+            Decision::Allowed => panic!("Allowed too many disks out"),
+        };
+        // Adds only care about the window
+        assert_eq!(gate.check(&at(3, 0, 3), None), Decision::Allowed);
+        gate.removals_this_run = 1;
+        match gate.check(&at(3, 0, 3), Some(0)) {
+            Decision::Denied(_) => {}
+            Decision::Allowed => panic!("Allowed too many removals in one run"),
+        };
+
+        let policy = SafetyPolicy {
+            maintenance_windows: vec!["bad".to_string()],
+            ..policy
+        };
+        assert!(SafetyGate::new(Some(&policy), "/etc/bynar").is_err());
+    }
+}
+
+/// Settings for the safety policy in bynar.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct SafetyPolicy {
+    /// The most disks a host can have removed and not yet added back
+    pub max_disks_out_per_host: Option<u32>,
+    /// The most disks removed in a single run of bynar
+    pub max_removals_per_run: Option<u32>,
+    /// Cron like expressions for when removals and adds may happen
+    #[serde(default)]
+    pub maintenance_windows: Vec<String>,
+    /// Removals and adds are paused while this file exists.
+    /// Default: pause in the config directory
+    pub kill_switch: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Allowed,
+    // Why it was denied
+    Denied(String),
+}
+
+// One field of a cron expression.  The allowed values are stored as a bitmask
+#[derive(Debug)]
+struct CronField(u64);
+
+impl CronField {
+    fn parse(field: &str, min: u32, max: u32) -> Result<Self, String> {
+        let mut mask = 0u64;
+        for part in field.split(',') {
+            let (range, step) = match part.find('/') {
+                Some(i) => {
+                    let step: u32 = part[i + 1..]
+                        .parse()
+                        .map_err(|_| format!("Invalid step in {}", part))?;
+                    if step == 0 {
+                        return Err(format!("Step can't be 0 in {}", part));
+                    }
+                    (&part[..i], step)
+                }
+                None => (part, 1),
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else {
+                let mut bounds = range.splitn(2, '-');
+                let start: u32 = bounds
+                    .next()
+                    .unwrap_or("")
+                    .parse()
+                    .map_err(|_| format!("Invalid value in {}", part))?;
+                let end: u32 = match bounds.next() {
+                    Some(end) => end
+                        .parse()
+                        .map_err(|_| format!("Invalid value in {}", part))?,
+                    None => start,
+                };
+                (start, end)
+            };
+            if start < min || end > max || start > end {
+                return Err(format!(
+                    "{} is out of range.  Values must be between {} and {}",
+                    part, min, max
+                ));
+            }
+            for value in (start..=end).step_by(step as usize) {
+                mask |= 1 << value;
+            }
+        }
+        Ok(CronField(mask))
+    }
+
+    fn contains(&self, value: i32) -> bool {
+        (0..64).contains(&value) && self.0 & (1 << value) != 0
+    }
+
+    fn is_wildcard(&self, min: u32, max: u32) -> bool {
+        (min..=max).all(|v| self.contains(v as i32))
+    }
+}
+
+#[derive(Debug)]
+struct CronExpr {
+    minute: CronField,
+    hour: CronField,
+    day_of_month: CronField,
+    month: CronField,
+    day_of_week: CronField,
+}
+
+impl CronExpr {
+    fn parse(expr: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "{} should have 5 fields: minute hour day-of-month month day-of-week",
+                expr
+            ));
+        }
+        Ok(CronExpr {
+            minute: CronField::parse(fields[0], 0, 59)?,
+            hour: CronField::parse(fields[1], 0, 23)?,
+            day_of_month: CronField::parse(fields[2], 1, 31)?,
+            month: CronField::parse(fields[3], 1, 12)?,
+            // 0 and 7 are both Sunday
+            day_of_week: CronField::parse(fields[4], 0, 7)?,
+        })
+    }
+
+    fn matches(&self, tm: &Tm) -> bool {
+        let dom = self.day_of_month.contains(tm.tm_mday);
+        let dow = self.day_of_week.contains(tm.tm_wday)
+            || (tm.tm_wday == 0 && self.day_of_week.contains(7));
+        // Like cron if both days are restricted either one can match
+        let day = match (
+            self.day_of_month.is_wildcard(1, 31),
+            self.day_of_week.is_wildcard(0, 7),
+        ) {
+            (false, false) => dom || dow,
+            _ => dom && dow,
+        };
+        self.minute.contains(tm.tm_min)
+            && self.hour.contains(tm.tm_hour)
+            && self.month.contains(tm.tm_mon + 1)
+            && day
+    }
+}
+
+/// Decides if a removal or add may go ahead right now
+pub struct SafetyGate {
+    max_disks_out_per_host: Option<u32>,
+    max_removals_per_run: Option<u32>,
+    windows: Vec<CronExpr>,
+    kill_switch: PathBuf,
+    removals_this_run: u32,
+}
+
+impl SafetyGate {
+    pub fn new(policy: Option<&SafetyPolicy>, config_dir: &str) -> Result<Self, String> {
+        let default_kill_switch = Path::new(config_dir).join("pause");
+        let policy = match policy {
+            Some(policy) => policy,
+            None => {
+                return Ok(SafetyGate {
+                    max_disks_out_per_host: None,
+                    max_removals_per_run: None,
+                    windows: Vec::new(),
+                    kill_switch: default_kill_switch,
+                    removals_this_run: 0,
+                })
+            }
+        };
+        let mut windows = Vec::new();
+        for window in &policy.maintenance_windows {
+            windows.push(
+                CronExpr::parse(window)
+                    .map_err(|e| format!("Invalid maintenance window {}: {}", window, e))?,
+            );
+        }
+        Ok(SafetyGate {
+            max_disks_out_per_host: policy.max_disks_out_per_host,
+            max_removals_per_run: policy.max_removals_per_run,
+            windows,
+            kill_switch: policy
+                .kill_switch
+                .as_ref()
+                .map(PathBuf::from)
+                .unwrap_or(default_kill_switch),
+            removals_this_run: 0,
+        })
+    }
+
+    // disks_out is only set for removals
+    fn check(&self, now: &Tm, disks_out: Option<u32>) -> Decision {
+        if self.kill_switch.exists() {
+            return Decision::Denied(format!(
+                "Kill switch {} is set",
+                self.kill_switch.display()
+            ));
+        }
+        if !self.windows.is_empty() && !self.windows.iter().any(|w| w.matches(now)) {
+            return Decision::Denied("Outside of the maintenance windows".to_string());
+        }
+        if let Some(disks_out) = disks_out {
+            if let Some(max) = self.max_disks_out_per_host {
+                if disks_out >= max {
+                    return Decision::Denied(format!(
+                        "{} disks are already out of this host.  The limit is {}",
+                        disks_out, max
+                    ));
+                }
+            }
+            if let Some(max) = self.max_removals_per_run {
+                if self.removals_this_run >= max {
+                    return Decision::Denied(format!(
+                        "Already removed {} disks this run.  The limit is {}",
+                        self.removals_this_run, max
+                    ));
+                }
+            }
+        }
+        Decision::Allowed
+    }
+
+    /// Check if a disk may be removed from this host right now
+    pub fn check_removal(&self, conn: &Connection) -> Result<Decision, String> {
+        let disks_out = in_progress::count_disks_out(conn).map_err(|e| e.to_string())?;
+        Ok(self.check(&time::now(), Some(disks_out)))
+    }
+
+    /// Check if a disk may be added to this host right now
+    pub fn check_add(&self) -> Decision {
+        self.check(&time::now(), None)
+    }
+
+    /// Count a removal against this run and remember the disk is out
+    pub fn record_removal(&mut self, conn: &Connection, dev_path: &Path) -> Result<(), String> {
+        self.removals_this_run += 1;
+        in_progress::record_disk_removed(conn, dev_path).map_err(|e| e.to_string())
+    }
+}
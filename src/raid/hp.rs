@@ -1,3 +1,162 @@
-pub fn scan_for_smart_errors() {
-    //
+//! HP Smart Array controllers through ssacli.  ssacli has no JSON output so
+//! this parses the indented text of `ssacli ctrl all show config detail`.
+//!
+//! ssacli doesn't report per drive error counters.  The read and write error
+//! counts only show up in the Array Diagnostic Utility report, a zipped XML
+//! bundle from `ssacli ctrl slot=N diag file=...` that takes minutes to
+//! build.  HP drives are judged on their status instead.  The controller
+//! raises Predictive Failure when those same counters cross its thresholds,
+//! so media_errors is always None for them.
+use super::{run_cli, DriveState, PhysicalDrive};
+
+#[cfg(test)]
+mod tests {
+    use super::super::DriveState;
+
+    #[test]
+    fn test_parse_config_detail() {
+        let output = include_str!("fixtures/ssacli_show_config_detail.txt");
+        let drives = super::parse_config_detail(output);
+        assert_eq!(drives.len(), 5);
+
+        assert_eq!(drives[0].vendor, "hp");
+        assert_eq!(drives[0].controller, "0");
+        assert_eq!(drives[0].slot, "1I:1:1");
+        assert_eq!(drives[0].serial_number, Some("S0K2XAAA0000K".to_string()));
+        assert_eq!(drives[0].model, Some("HP EG0600FBDSR".to_string()));
+        assert_eq!(drives[0].logical_drive, Some("Array A".to_string()));
+        assert_eq!(drives[0].state, DriveState::Ok);
+        assert_eq!(drives[0].media_errors, None);
+        assert!(!drives[0].predictive_failure);
+
+        assert_eq!(drives[1].slot, "1I:1:2");
+        assert_eq!(drives[1].state, DriveState::Ok);
+        assert!(drives[1].predictive_failure);
+
+        assert_eq!(drives[2].slot, "1I:1:3");
+        assert_eq!(drives[2].state, DriveState::Failed);
+        assert_eq!(drives[2].status, "Failed");
+
+        assert_eq!(drives[3].state, DriveState::Rebuilding);
+
+        assert_eq!(drives[4].slot, "2I:1:5");
+        assert_eq!(drives[4].logical_drive, None);
+        assert_eq!(drives[4].model, Some("ATA MK0480GEYKH".to_string()));
+    }
+
+    #[test]
+    fn test_parse_no_controllers() {
+        let output = "\nError: No controllers detected. Possible causes:\n";
+        assert!(super::parse_config_detail(output).is_empty());
+    }
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+// "Smart Array P440ar in Slot 0 (Embedded)" is controller 0
+fn controller_slot(line: &str) -> Option<String> {
+    let start = line.find(" in Slot ")? + " in Slot ".len();
+    line[start..]
+        .split_whitespace()
+        .next()
+        .map(|s| s.to_string())
+}
+
+fn new_drive(controller: &str, slot: &str, array: &Option<String>) -> PhysicalDrive {
+    PhysicalDrive {
+        vendor: "hp".to_string(),
+        controller: controller.to_string(),
+        slot: slot.to_string(),
+        serial_number: None,
+        model: None,
+        logical_drive: array.clone(),
+        state: DriveState::Unknown,
+        status: String::new(),
+        predictive_failure: false,
+        // Not in ssacli's output.  See the top of this file
+        media_errors: None,
+    }
+}
+
+fn set_status(drive: &mut PhysicalDrive, status: &str) {
+    let (state, predictive_failure) = match status {
+        "OK" => (DriveState::Ok, false),
+        "Predictive Failure" => (DriveState::Ok, true),
+        "Rebuilding" => (DriveState::Rebuilding, false),
+        s if s.starts_with("Failed") => (DriveState::Failed, false),
+        _ => (DriveState::Unknown, false),
+    };
+    drive.state = state;
+    drive.predictive_failure = predictive_failure;
+    drive.status = status.to_string();
+}
+
+/// Parse the output of `ssacli ctrl all show config detail`
+pub fn parse_config_detail(output: &str) -> Vec<PhysicalDrive> {
+    let mut drives = Vec::new();
+    let mut controller = String::new();
+    let mut array: Option<String> = None;
+    // The drive whose detail block is being read and its indentation
+    let mut current: Option<(PhysicalDrive, usize)> = None;
+
+    for line in output.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let indent = indentation(line);
+        let in_block = match current {
+            Some((_, drive_indent)) => indent > drive_indent,
+            None => false,
+        };
+        if in_block {
+            if let Some((ref mut drive, _)) = current {
+                let mut parts = trimmed.splitn(2, ':');
+                let key = parts.next().unwrap_or("").trim();
+                let value = parts.next().unwrap_or("").trim();
+                match key {
+                    "Status" => set_status(drive, value),
+                    "Serial Number" => drive.serial_number = Some(value.to_string()),
+                    "Model" => {
+                        let model = value.split_whitespace().collect::<Vec<&str>>().join(" ");
+                        drive.model = Some(model);
+                    }
+                    _ => {}
+                };
+            }
+            continue;
+        }
+        if let Some((drive, _)) = current.take() {
+            drives.push(drive);
+        }
+        if indent == 0 {
+            if let Some(slot) = controller_slot(trimmed) {
+                controller = slot;
+                array = None;
+            }
+        } else if trimmed.starts_with("Array: ") {
+            array = Some(format!("Array {}", trimmed["Array: ".len()..].trim()));
+        } else if trimmed == "Unassigned" {
+            array = None;
+        } else if trimmed.starts_with("physicaldrive ") && !trimmed.contains('(') {
+            // The one line summaries have a (port ...) suffix.  The detail
+            // blocks are the bare drive id followed by indented fields
+            let slot = trimmed["physicaldrive ".len()..].trim();
+            current = Some((new_drive(&controller, slot, &array), indent));
+        }
+    }
+    if let Some((drive, _)) = current.take() {
+        drives.push(drive);
+    }
+    drives
+}
+
+/// Physical drives on every Smart Array controller.  Empty if ssacli isn't installed
+pub fn physical_drives(ssacli: &str) -> Result<Vec<PhysicalDrive>, String> {
+    match run_cli(ssacli, &["ctrl", "all", "show", "config", "detail"])? {
+        Some(output) => Ok(parse_config_detail(&output)),
+        None => Ok(vec![]),
+    }
 }
@@ -1,3 +1,143 @@
-pub fn scan_for_smart_errors() {
-    //
+//! LSI MegaRAID controllers through storcli's JSON output
+extern crate serde_json;
+
+use self::serde_json::Value;
+use super::{run_cli, DriveState, PhysicalDrive};
+
+#[cfg(test)]
+mod tests {
+    use super::super::DriveState;
+
+    #[test]
+    fn test_parse_show_all() {
+        let output = include_str!("fixtures/storcli_show_all.json");
+        let drives = super::parse_show_all(output).unwrap();
+        assert_eq!(drives.len(), 4);
+        let find = |slot: &str| drives.iter().find(|d| d.slot == slot).unwrap().clone();
+
+        let healthy = find("252:0");
+        assert_eq!(healthy.vendor, "lsi");
+        assert_eq!(healthy.controller, "0");
+        assert_eq!(healthy.serial_number, Some("Z1Z0ABCD".to_string()));
+        assert_eq!(healthy.model, Some("ST4000NM0033-9ZM170".to_string()));
+        assert_eq!(healthy.logical_drive, Some("DG 0".to_string()));
+        assert_eq!(healthy.state, DriveState::Ok);
+        assert_eq!(healthy.media_errors, Some(0));
+        assert!(!healthy.predictive_failure);
+
+        let failing = find("252:1");
+        assert_eq!(failing.state, DriveState::Ok);
+        assert_eq!(failing.media_errors, Some(37));
+        assert!(failing.predictive_failure);
+
+        let bad = find("252:2");
+        assert_eq!(bad.state, DriveState::Failed);
+        assert_eq!(bad.status, "UBad");
+        assert_eq!(bad.logical_drive, None);
+
+        // No detailed information for this one
+        let rebuilding = find("252:3");
+        assert_eq!(rebuilding.state, DriveState::Rebuilding);
+        assert_eq!(rebuilding.serial_number, None);
+        assert_eq!(rebuilding.media_errors, None);
+    }
+
+    #[test]
+    fn test_parse_no_controllers() {
+        let output = r#"{"Controllers":[{"Command Status":{"Controller":"0","Status":"Failure","Description":"No Controller found"}}]}"#;
+        assert!(super::parse_show_all(output).unwrap().is_empty());
+        assert!(super::parse_show_all("not json").is_err());
+    }
+}
+
+fn drive_state(state: &str) -> DriveState {
+    match state {
+        "Onln" | "UGood" | "GHS" | "DHS" | "JBOD" => DriveState::Ok,
+        "Rbld" | "Cpybck" => DriveState::Rebuilding,
+        "Offln" | "UBad" | "Msng" | "Failed" => DriveState::Failed,
+        _ => DriveState::Unknown,
+    }
+}
+
+// storcli mixes numbers and strings for the same field between versions
+fn as_string(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.trim().to_string()),
+        Value::Number(ref n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn as_u64(value: &Value) -> Option<u64> {
+    match *value {
+        Value::Number(ref n) => n.as_u64(),
+        Value::String(ref s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Parse the output of `storcli /call/eall/sall show all J`
+pub fn parse_show_all(output: &str) -> Result<Vec<PhysicalDrive>, String> {
+    let json: Value = serde_json::from_str(output).map_err(|e| e.to_string())?;
+    let controllers = json["Controllers"]
+        .as_array()
+        .ok_or_else(|| "storcli output has no Controllers".to_string())?;
+    let mut drives = Vec::new();
+    for controller in controllers {
+        let controller_id =
+            as_string(&controller["Command Status"]["Controller"]).unwrap_or_default();
+        let data = match controller["Response Data"].as_object() {
+            Some(data) => data,
+            None => {
+                debug!(
+                    "storcli returned no drives for controller {}",
+                    controller_id
+                );
+                continue;
+            }
+        };
+        for (name, summary) in data {
+            // "Drive /c0/e252/s0" holds a one row table.  The detailed
+            // information is under "Drive /c0/e252/s0 - Detailed Information"
+            let summary = match summary.as_array().and_then(|rows| rows.first()) {
+                Some(summary) if name.starts_with("Drive ") => summary,
+                _ => continue,
+            };
+            let detail = data
+                .get(&format!("{} - Detailed Information", name))
+                .cloned()
+                .unwrap_or(Value::Null);
+            let counters = &detail[&format!("{} State", name)];
+            let attributes = &detail[&format!("{} Device attributes", name)];
+            let status = as_string(&summary["State"]).unwrap_or_default();
+            let logical_drive = match as_string(&summary["DG"]) {
+                Some(ref dg) if dg != "-" => Some(format!("DG {}", dg)),
+                _ => None,
+            };
+            let predictive_failure = as_u64(&counters["Predictive Failure Count"])
+                .map(|count| count > 0)
+                .unwrap_or(false);
+            drives.push(PhysicalDrive {
+                vendor: "lsi".to_string(),
+                controller: controller_id.clone(),
+                slot: as_string(&summary["EID:Slt"]).unwrap_or_else(|| name.clone()),
+                serial_number: as_string(&attributes["SN"]),
+                model: as_string(&summary["Model"]),
+                logical_drive,
+                state: drive_state(&status),
+                status,
+                predictive_failure,
+                media_errors: as_u64(&counters["Media Error Count"]),
+            });
+        }
+    }
+    Ok(drives)
+}
+
+/// Physical drives on every MegaRAID controller.  Empty if storcli isn't installed
+pub fn physical_drives(storcli: &str) -> Result<Vec<PhysicalDrive>, String> {
+    match run_cli(storcli, &["/call/eall/sall", "show", "all", "J"])? {
+        Some(output) => parse_show_all(&output),
+        None => Ok(vec![]),
+    }
 }
@@ -0,0 +1,282 @@
+//! Physical drives hidden behind hardware RAID controllers.  The OS only sees
+//! the controller's virtual disks so a dead drive in a redundant logical
+//! volume never shows up in the libatasmart checks.  Each provider asks the
+//! vendor CLI for its physical drives and bynar maps their health into the
+//! state machine states used for every other disk.
+extern crate rusqlite;
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+use in_progress;
+use test_disk::State;
+
+use self::rusqlite::Connection;
+
+pub mod hp;
+pub mod lsi;
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::{DriveState, PhysicalDrive, RaidConfig};
+    use in_progress;
+    use std::path::Path;
+    use test_disk::State;
+
+    fn drive(
+        state: DriveState,
+        predictive_failure: bool,
+        media_errors: Option<u64>,
+    ) -> PhysicalDrive {
+        PhysicalDrive {
+            vendor: "lsi".to_string(),
+            controller: "0".to_string(),
+            slot: "252:3".to_string(),
+            serial_number: Some("Z1Z0ABCD".to_string()),
+            model: None,
+            logical_drive: None,
+            state,
+            status: "Onln".to_string(),
+            predictive_failure,
+            media_errors,
+        }
+    }
+
+    #[test]
+    fn test_disk_state() {
+        let config = RaidConfig::default();
+        let d = drive(DriveState::Ok, false, Some(0));
+        assert_eq!(d.disk_path(), "raid:lsi/0/252:3");
+        assert_eq!(d.disk_state(&config), State::Good);
+        assert_eq!(d.problem(&config), None);
+        assert_eq!(
+            drive(DriveState::Failed, false, None).disk_state(&config),
+            State::WaitingForReplacement
+        );
+        assert_eq!(
+            drive(DriveState::Ok, true, None).disk_state(&config),
+            State::WaitingForReplacement
+        );
+        assert_eq!(
+            drive(DriveState::Ok, false, Some(10)).disk_state(&config),
+            State::WaitingForReplacement
+        );
+        assert_eq!(
+            drive(DriveState::Ok, false, Some(9)).disk_state(&config),
+            State::Good
+        );
+        // A rebuilding drive is on its way back in
+        assert_eq!(
+            drive(DriveState::Rebuilding, false, None).disk_state(&config),
+            State::Good
+        );
+        assert!(super::is_raid_drive("raid:hp/0/1I:1:2"));
+        assert!(!super::is_raid_drive("/dev/sda"));
+    }
+
+    #[test]
+    fn test_needs_ticket() {
+        let sql_dir = TempDir::new("bynar").expect("Temp file creation failed");
+        let db_path = sql_dir.path().join("raid.sqlite3");
+        let conn =
+            in_progress::connect_to_repair_database(&db_path).expect("sqlite3 creation failed");
+        let config = RaidConfig::default();
+
+        let good = drive(DriveState::Ok, false, Some(0));
+        assert!(!good.needs_ticket(&conn, &config).unwrap());
+        assert_eq!(
+            in_progress::get_state(&conn, Path::new(&good.disk_path())).unwrap(),
+            Some(State::Good)
+        );
+
+        // The drive fails and is ticketed once
+        let failed = drive(DriveState::Failed, false, None);
+        let dev = failed.disk_path();
+        assert!(failed.needs_ticket(&conn, &config).unwrap());
+        assert_eq!(
+            in_progress::get_state(&conn, Path::new(&dev)).unwrap(),
+            Some(State::WaitingForReplacement)
+        );
+        assert!(failed.needs_ticket(&conn, &config).unwrap());
+        in_progress::record_new_repair_ticket(&conn, "001", Path::new(&dev)).unwrap();
+        assert!(!failed.needs_ticket(&conn, &config).unwrap());
+    }
+}
+
+/// Settings for hardware RAID controllers in bynar.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct RaidConfig {
+    /// Path to ssacli for HP Smart Array controllers.  Default: ssacli
+    pub ssacli: Option<String>,
+    /// Path to storcli for LSI MegaRAID controllers.  Default: storcli64
+    pub storcli: Option<String>,
+    /// Media errors a drive can report before it's replaced.  Default: 10
+    pub media_error_threshold: Option<u64>,
+}
+
+impl Default for RaidConfig {
+    fn default() -> Self {
+        RaidConfig {
+            ssacli: None,
+            storcli: None,
+            media_error_threshold: None,
+        }
+    }
+}
+
+/// How the controller sees a physical drive
+#[derive(Clone, Debug, PartialEq)]
+pub enum DriveState {
+    Ok,
+    Rebuilding,
+    Failed,
+    /// Anything the controller reports that bynar doesn't understand
+    Unknown,
+}
+
+/// A physical drive attached to a RAID controller
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhysicalDrive {
+    /// The provider that found the drive.  Ex: hp
+    pub vendor: String,
+    /// The controller's slot or number
+    pub controller: String,
+    /// Where the drive sits on the controller.  Ex: 1I:1:3 or 252:3
+    pub slot: String,
+    pub serial_number: Option<String>,
+    pub model: Option<String>,
+    /// The array or drive group the drive belongs to
+    pub logical_drive: Option<String>,
+    pub state: DriveState,
+    /// The status exactly as the controller reported it
+    pub status: String,
+    pub predictive_failure: bool,
+    /// None if the controller doesn't count media errors
+    pub media_errors: Option<u64>,
+}
+
+impl fmt::Display for PhysicalDrive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} controller {} drive {}",
+            self.vendor, self.controller, self.slot
+        )
+    }
+}
+
+/// Is this repair database disk_path a drive behind a RAID controller?
+pub fn is_raid_drive(disk_path: &str) -> bool {
+    disk_path.starts_with("raid:")
+}
+
+impl PhysicalDrive {
+    /// The name the drive is tracked under in the repair database.  These
+    /// drives have no block device so they get a path of their own
+    pub fn disk_path(&self) -> String {
+        format!("raid:{}/{}/{}", self.vendor, self.controller, self.slot)
+    }
+
+    /// Why the drive needs replacing if it does
+    pub fn problem(&self, config: &RaidConfig) -> Option<String> {
+        let threshold = config.media_error_threshold.unwrap_or(10);
+        if self.state == DriveState::Failed {
+            Some(format!("Controller reports the drive as {}", self.status))
+        } else if self.predictive_failure {
+            Some("Controller reports a predictive failure".to_string())
+        } else {
+            match self.media_errors {
+                Some(errors) if errors >= threshold => Some(format!("{} media errors", errors)),
+                _ => None,
+            }
+        }
+    }
+
+    /// The state machine state matching what the controller reports
+    pub fn disk_state(&self, config: &RaidConfig) -> State {
+        match self.problem(config) {
+            Some(_) => State::WaitingForReplacement,
+            None => State::Good,
+        }
+    }
+
+    /// Save the drive's state and say whether it needs a new ticket.  Ignored
+    /// drives and drives that already have a ticket open don't
+    pub fn needs_ticket(&self, conn: &Connection, config: &RaidConfig) -> Result<bool, String> {
+        let disk_path = self.disk_path();
+        let dev_path = Path::new(&disk_path);
+        let state = self.disk_state(config);
+        debug!("{} is {} ({})", self, state, self.status);
+        let previous = in_progress::get_state(conn, &dev_path).map_err(|e| e.to_string())?;
+        if previous != Some(state) {
+            in_progress::record_transition(
+                conn,
+                &dev_path,
+                previous.unwrap_or(State::Unscanned),
+                state,
+                "RaidController",
+                state,
+            ).map_err(|e| e.to_string())?;
+            in_progress::save_state(conn, &dev_path, state).map_err(|e| e.to_string())?;
+        }
+        if state != State::WaitingForReplacement {
+            return Ok(false);
+        }
+        if in_progress::is_disk_ignored(conn, &dev_path).map_err(|e| e.to_string())? {
+            debug!("{} is being ignored", disk_path);
+            return Ok(false);
+        }
+        if in_progress::has_open_ticket(conn, &dev_path).map_err(|e| e.to_string())? {
+            debug!("{} is already in the repair queue", disk_path);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+// Run a vendor cli.  A missing binary means there's no such controller here
+fn run_cli(cmd: &str, args: &[&str]) -> Result<Option<String>, String> {
+    debug!("cmd: {} {:?}", cmd, args);
+    match Command::new(cmd).args(args).output() {
+        Ok(output) => {
+            if !output.status.success() && output.stdout.is_empty() {
+                return Err(format!(
+                    "{} failed: {}",
+                    cmd,
+                    String::from_utf8_lossy(&output.stderr)
+                ));
+            }
+            Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            debug!("{} isn't installed", cmd);
+            Ok(None)
+        }
+        Err(e) => Err(format!("Unable to run {}: {}", cmd, e)),
+    }
+}
+
+/// Every physical drive on every controller bynar knows how to talk to
+pub fn physical_drives(config: &RaidConfig) -> Result<Vec<PhysicalDrive>, String> {
+    let mut drives = Vec::new();
+    drives.extend(hp::physical_drives(
+        config
+            .ssacli
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or("ssacli"),
+    )?);
+    drives.extend(lsi::physical_drives(
+        config
+            .storcli
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or("storcli64"),
+    )?);
+    Ok(drives)
+}
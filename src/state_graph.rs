@@ -0,0 +1,629 @@
+//! The graph of states and transitions every disk is walked through.
+//! The graph can be loaded from a state_machine.json file in the config
+//! directory so that different clusters can run with different repair
+//! policies.  If the file doesn't exist the built-in default graph is used.
+//! Every graph is validated when it is loaded and bad graphs are rejected
+//! before any disk is touched.
+extern crate time;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+use std::io::{Error, ErrorKind, Result as IOResult};
+use std::path::Path;
+
+use command_transition::validate_command;
+use helpers;
+use in_progress::StateHistory;
+use test_disk::{is_builtin_transition, State};
+
+/// The transition name used for site provided executables
+pub const COMMAND_TRANSITION: &str = "Command";
+
+#[cfg(test)]
+mod tests {
+    use super::time;
+    use super::{DiskOverlay, StateGraph, TransitionConfig};
+    use in_progress::StateHistory;
+    use test_disk::State;
+
+    fn history(from: State, to: State, name: &str, result: State) -> StateHistory {
+        StateHistory {
+            id: 0,
+            disk_path: "/dev/sda".to_string(),
+            time_recorded: time::get_time(),
+            from_state: from,
+            to_state: to,
+            transition: name.to_string(),
+            result_state: result,
+        }
+    }
+
+    fn transition(from: State, to: State, name: &str, priority: u32) -> TransitionConfig {
+        TransitionConfig {
+            from,
+            to,
+            transition: name.to_string(),
+            priority,
+            command: None,
+            args: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn test_default_graph_is_valid() {
+        let graph = StateGraph::default();
+        graph.validate().unwrap();
+    }
+
+    #[test]
+    fn test_load_graph_from_json() {
+        let graph: StateGraph = ::serde_json::from_str(
+            r#"{
+                "terminal_states": ["good", "waiting_for_replacement"],
+                "transitions": [
+                    {"from": "unscanned", "to": "scanned", "transition": "Scan"},
+                    {"from": "scanned", "to": "worn_out", "transition": "CheckWearLeveling",
+                     "priority": 2},
+                    {"from": "scanned", "to": "good", "transition": "Eval", "priority": 1},
+                    {"from": "worn_out", "to": "waiting_for_replacement",
+                     "transition": "MarkForReplacement"}
+                ]
+            }"#,
+        ).unwrap();
+        graph.validate().unwrap();
+        let order: Vec<State> = graph
+            .sorted_transitions()
+            .iter()
+            .filter(|t| t.from == State::Scanned)
+            .map(|t| t.to)
+            .collect();
+        assert_eq!(order, vec![State::Good, State::WornOut]);
+    }
+
+    #[test]
+    fn test_unreachable_state() {
+        let mut graph = StateGraph::default();
+        graph
+            .transitions
+            .push(transition(State::Suspect, State::Scanned, "Scan", 1));
+        assert!(graph.validate().is_err());
+    }
+
+    #[test]
+    fn test_default_mount_edges() {
+        let graph = StateGraph::default();
+        let mounts: Vec<State> = graph
+            .transitions
+            .iter()
+            .filter(|t| t.from == State::NotMounted && t.transition == "Mount")
+            .map(|t| t.to)
+            .collect();
+        assert_eq!(mounts, vec![State::Mounted, State::MountFailed]);
+    }
+
+    #[test]
+    fn test_dot_escapes_labels() {
+        let mut graph = StateGraph::default();
+        let mut check = transition(State::Scanned, State::WaitingForReplacement, "Command", 0);
+        check.command = Some("/opt/check \"quoted\".sh".to_string());
+        graph.transitions.push(check);
+        let overlay = DiskOverlay {
+            disk: "/dev/\"sda\"".to_string(),
+            state: None,
+            history: vec![],
+        };
+        let dot = graph.to_dot(Some(&overlay));
+        assert!(dot.contains("label=\"/dev/\\\"sda\\\"\";"));
+        assert!(dot.contains("[label=\"Command(/opt/check \\\"quoted\\\".sh)\"];"));
+    }
+
+    #[test]
+    fn test_missing_terminal_state() {
+        let mut graph = StateGraph::default();
+        graph.terminal_states = vec![State::Good];
+        graph.transitions.retain(|t| t.to != State::Good);
+        assert!(graph.validate().is_err());
+    }
+
+    #[test]
+    fn test_stuck_state() {
+        // RepairFailed can no longer reach Good or WaitingForReplacement
+        let mut graph = StateGraph::default();
+        graph.transitions.retain(|t| t.from != State::RepairFailed);
+        assert!(graph.validate().is_err());
+    }
+
+    #[test]
+    fn test_never_reformat() {
+        let mut graph = StateGraph::default();
+        graph
+            .transitions
+            .retain(|t| t.transition != "Reformat" && t.from != State::Reformatted);
+        graph.validate().unwrap();
+    }
+
+    #[test]
+    fn test_cycle_without_progress() {
+        let mut graph = StateGraph::default();
+        graph
+            .transitions
+            .push(transition(State::Scanned, State::Mounted, "NoOp", 10));
+        assert!(graph.validate().is_err());
+    }
+
+    #[test]
+    fn test_command_transition() {
+        let mut graph = StateGraph::default();
+        let mut check = transition(State::Scanned, State::WaitingForReplacement, "Command", 0);
+        graph.transitions.push(check.clone());
+        // Command transitions need a command to run
+        assert!(graph.validate().is_err());
+
+        check.command = Some("/bin/true".to_string());
+        graph.transitions.pop();
+        graph.transitions.push(check);
+        graph.validate().unwrap();
+        assert_eq!(graph.transitions.last().unwrap().label(), "Command(/bin/true)");
+    }
+
+    #[test]
+    fn test_duplicate_transition() {
+        let mut graph = StateGraph::default();
+        graph
+            .transitions
+            .push(transition(State::Scanned, State::Good, "CheckWearLeveling", 5));
+        assert!(graph.validate().is_err());
+    }
+
+    #[test]
+    fn test_graph_overlay() {
+        let graph = StateGraph::default();
+        let history = vec![
+            history(State::Unscanned, State::Scanned, "Scan", State::Scanned),
+            history(State::Scanned, State::Good, "Eval", State::WriteFailed),
+            history(State::Scanned, State::WriteFailed, "Eval", State::WriteFailed),
+        ];
+        let overlay = DiskOverlay {
+            disk: "/dev/sda".to_string(),
+            state: Some(State::WriteFailed),
+            history,
+        };
+        let dot = graph.to_dot(Some(&overlay));
+        assert!(dot.starts_with("digraph state_machine {"));
+        assert!(dot.contains("unscanned -> scanned [label=\"Scan (1)\", color=blue"));
+        assert!(dot.contains("scanned -> write_failed [label=\"Eval (1)\", color=blue"));
+        assert!(dot.contains("scanned -> good [label=\"Eval\"];"));
+        assert!(dot.contains("write_failed [label=\"write_failed\", style=filled"));
+
+        let json: ::serde_json::Value =
+            ::serde_json::from_str(&graph.to_json(Some(&overlay)).unwrap()).unwrap();
+        assert_eq!(json["disk"]["state"], "write_failed");
+        assert_eq!(json["disk"]["history"].as_array().unwrap().len(), 3);
+        let edges = json["edges"].as_array().unwrap();
+        assert_eq!(edges.len(), graph.transitions.len());
+        assert!(
+            edges
+                .iter()
+                .any(|e| e["from"] == "scanned" && e["to"] == "write_failed" && e["taken"] == 1)
+        );
+    }
+
+    #[test]
+    fn test_unknown_transition() {
+        let mut graph = StateGraph::default();
+        graph.transitions[0].transition = "Defrag".to_string();
+        assert!(graph.validate().is_err());
+    }
+}
+
+/// A single edge in the state machine graph
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TransitionConfig {
+    pub from: State,
+    pub to: State,
+    /// The name of the transition to run.  Ex: Scan, Eval, Reformat or Command
+    pub transition: String,
+    /// Transitions out of the same state are attempted from the lowest
+    /// priority to the highest.  Ties are attempted in file order.
+    #[serde(default)]
+    pub priority: u32,
+    /// The executable to run for a Command transition
+    pub command: Option<String>,
+    /// Extra arguments handed to the command
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Seconds a Command transition is given before it is killed and failed
+    pub timeout: Option<u64>,
+}
+
+impl TransitionConfig {
+    /// The name used for this transition in graphs and the state history
+    pub fn label(&self) -> String {
+        match self.command {
+            Some(ref command) if self.transition == COMMAND_TRANSITION => {
+                format!("{}({})", COMMAND_TRANSITION, command)
+            }
+            _ => self.transition.clone(),
+        }
+    }
+}
+
+/// The full state machine graph
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StateGraph {
+    /// The state machine stops advancing once it reaches one of these
+    #[serde(default = "default_terminal_states")]
+    pub terminal_states: Vec<State>,
+    pub transitions: Vec<TransitionConfig>,
+}
+
+fn default_terminal_states() -> Vec<State> {
+    vec![State::Good, State::WaitingForReplacement]
+}
+
+impl Default for StateGraph {
+    // The graph bynar has always shipped with.  The states are ordered
+    // from most to least ideal outcome.
+    fn default() -> Self {
+        let edges = vec![
+            (State::Unscanned, State::Scanned, "Scan"),
+            (State::Unscanned, State::Fail, "Scan"),
+            (State::NotMounted, State::Mounted, "Mount"),
+            (State::NotMounted, State::MountFailed, "Mount"),
+            (State::MountFailed, State::Corrupt, "CheckForCorruption"),
+            (State::Scanned, State::Good, "Eval"),
+            (State::Scanned, State::WriteFailed, "Eval"),
+            (State::Scanned, State::WornOut, "CheckWearLeveling"),
+            (State::Mounted, State::Scanned, "NoOp"),
+            (State::ReadOnly, State::Mounted, "Remount"),
+            (State::ReadOnly, State::MountFailed, "Remount"),
+            (State::Corrupt, State::Repaired, "AttemptRepair"),
+            (State::Corrupt, State::RepairFailed, "NoOp"),
+            (State::RepairFailed, State::Reformatted, "Reformat"),
+            (State::RepairFailed, State::ReformatFailed, "NoOp"),
+            (State::ReformatFailed, State::WaitingForReplacement, "NoOp"),
+            (State::Reformatted, State::Unscanned, "NoOp"),
+            (State::WornOut, State::WaitingForReplacement, "MarkForReplacement"),
+            (State::Repaired, State::Good, "NoOp"),
+            (State::WaitingForReplacement, State::Replaced, "Replace"),
+            (State::Replaced, State::Unscanned, "NoOp"),
+            (State::WriteFailed, State::ReadOnly, "CheckReadOnly"),
+            // Fsck can either conclude here that everything is fine or the filesystem is corrupt
+            (State::WriteFailed, State::Corrupt, "CheckForCorruption"),
+        ];
+        // Number the edges out of each state in the order they're listed
+        let mut priorities: HashMap<State, u32> = HashMap::new();
+        let transitions = edges
+            .into_iter()
+            .map(|(from, to, name)| {
+                let priority = priorities.entry(from).or_insert(0);
+                *priority += 1;
+                TransitionConfig {
+                    from,
+                    to,
+                    transition: name.to_string(),
+                    priority: *priority,
+                    command: None,
+                    args: Vec::new(),
+                    timeout: None,
+                }
+            }).collect();
+
+        StateGraph {
+            terminal_states: default_terminal_states(),
+            transitions,
+        }
+    }
+}
+
+impl StateGraph {
+    /// All the transitions ordered by the state they leave from and then by priority.
+    pub fn sorted_transitions(&self) -> Vec<&TransitionConfig> {
+        let mut transitions: Vec<&TransitionConfig> = self.transitions.iter().collect();
+        // sort_by_key is stable so equal priorities keep their file order
+        transitions.sort_by_key(|t| (t.from, t.priority));
+        transitions
+    }
+
+    /// Every state that is mentioned in the graph
+    pub fn states(&self) -> BTreeSet<State> {
+        let mut states = BTreeSet::new();
+        for t in &self.transitions {
+            states.insert(t.from);
+            states.insert(t.to);
+        }
+        states
+    }
+
+    // Walk the graph from the given starting states and return everything visited
+    fn reachable_from(&self, start: &[State], include_noop_only: bool) -> HashSet<State> {
+        let mut seen: HashSet<State> = HashSet::new();
+        let mut stack: Vec<State> = start.to_vec();
+        while let Some(state) = stack.pop() {
+            if !seen.insert(state) {
+                continue;
+            }
+            for t in &self.transitions {
+                if t.from == state && (!include_noop_only || t.transition == "NoOp") {
+                    stack.push(t.to);
+                }
+            }
+        }
+        seen
+    }
+
+    /// Check the graph for problems that would leave disks stuck or looping:
+    /// unknown transitions, states that can't be reached from Unscanned,
+    /// states that can't reach a terminal state and cycles that make no progress.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.transitions.is_empty() {
+            return Err("State machine graph has no transitions".to_string());
+        }
+        for t in &self.transitions {
+            if t.transition == COMMAND_TRANSITION {
+                match t.command {
+                    Some(ref command) => validate_command(command)?,
+                    None => {
+                        return Err(format!(
+                            "Command transition from {} to {} has no command set",
+                            t.from, t.to
+                        ))
+                    }
+                };
+            } else if !is_builtin_transition(&t.transition) {
+                return Err(format!(
+                    "Unknown transition {} from {} to {}",
+                    t.transition, t.from, t.to
+                ));
+            } else if t.command.is_some() {
+                return Err(format!(
+                    "Only Command transitions can set a command.  Found one on {}",
+                    t.transition
+                ));
+            }
+            if t.from == t.to {
+                return Err(format!(
+                    "Transition {} loops from {} back to itself without progress",
+                    t.transition, t.from
+                ));
+            }
+            if t.from == State::Fail {
+                return Err(format!(
+                    "Fail can't be the start of a transition.  Found {} from fail to {}",
+                    t.transition, t.to
+                ));
+            }
+        }
+        let mut edges: HashSet<(State, State)> = HashSet::new();
+        for t in &self.transitions {
+            if !edges.insert((t.from, t.to)) {
+                return Err(format!(
+                    "Only one transition can go from {} to {}",
+                    t.from, t.to
+                ));
+            }
+        }
+        if !self.transitions.iter().any(|t| t.from == State::Unscanned) {
+            return Err("No transitions leave the unscanned starting state".to_string());
+        }
+        if self.terminal_states.is_empty() {
+            return Err("At least one terminal state is required".to_string());
+        }
+
+        // Disks start at Unscanned but can also be resumed from a terminal
+        // state that was saved to the database on a previous run.  Scan
+        // hands back NotMounted for a disk that should be mounted but isn't
+        // so no edge has to lead there.
+        let mut roots = vec![State::Unscanned, State::NotMounted];
+        roots.extend(self.terminal_states.iter().cloned());
+        let reachable = self.reachable_from(&roots, false);
+        for terminal in &self.terminal_states {
+            if !self.transitions.iter().any(|t| t.to == *terminal) {
+                return Err(format!("Terminal state {} is never transitioned to", terminal));
+            }
+        }
+        for state in self.states() {
+            if !reachable.contains(&state) {
+                return Err(format!("State {} is unreachable from unscanned", state));
+            }
+            // Fail means "try the next transition".  It is never entered.
+            if state == State::Fail || self.terminal_states.contains(&state) {
+                continue;
+            }
+            let ends = self.reachable_from(&[state], false);
+            if !self.terminal_states.iter().any(|t| ends.contains(t)) {
+                return Err(format!(
+                    "State {} can never reach a terminal state {:?}",
+                    state, self.terminal_states
+                ));
+            }
+        }
+
+        // A cycle made only of NoOp transitions never changes anything about
+        // the disk and would spin forever.
+        for t in self.transitions.iter().filter(|t| t.transition == "NoOp") {
+            if self.reachable_from(&[t.to], true).contains(&t.from) {
+                return Err(format!(
+                    "Cycle without progress through {} and {}",
+                    t.from, t.to
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A disk's current state and the transitions it has attempted, drawn on top
+/// of the graph so operators can see why a disk is where it is.
+pub struct DiskOverlay {
+    pub disk: String,
+    pub state: Option<State>,
+    pub history: Vec<StateHistory>,
+}
+
+impl DiskOverlay {
+    // How many times the disk successfully walked an edge
+    fn times_taken(&self, from: State, to: State) -> usize {
+        self.history
+            .iter()
+            .filter(|h| h.from_state == from && h.to_state == to && h.result_state == to)
+            .count()
+    }
+}
+
+#[derive(Serialize)]
+struct JsonNode {
+    state: State,
+    terminal: bool,
+    current: bool,
+}
+
+#[derive(Serialize)]
+struct JsonEdge {
+    from: State,
+    to: State,
+    transition: String,
+    priority: u32,
+    taken: usize,
+}
+
+#[derive(Serialize)]
+struct JsonHistory {
+    time: String,
+    from: State,
+    to: State,
+    transition: String,
+    result: State,
+}
+
+#[derive(Serialize)]
+struct JsonDisk {
+    path: String,
+    state: Option<State>,
+    history: Vec<JsonHistory>,
+}
+
+#[derive(Serialize)]
+struct JsonGraph {
+    nodes: Vec<JsonNode>,
+    edges: Vec<JsonEdge>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disk: Option<JsonDisk>,
+}
+
+impl StateGraph {
+    /// Render the graph in Graphviz dot format.  Convert it with
+    /// `dot -Tsvg graph.dot -o graph.svg`.  If a disk is given its current
+    /// state is filled in and the edges it has taken are drawn in blue.
+    pub fn to_dot(&self, overlay: Option<&DiskOverlay>) -> String {
+        let mut dot = String::new();
+        let _ = writeln!(dot, "digraph state_machine {{");
+        if let Some(o) = overlay {
+            let _ = writeln!(dot, "\tlabel=\"{}\";", dot_escape(&o.disk));
+        }
+        for state in self.states() {
+            let mut attrs = format!("label=\"{}\"", state);
+            if self.terminal_states.contains(&state) {
+                attrs.push_str(", shape=doublecircle");
+            }
+            if let Some(o) = overlay {
+                if o.state == Some(state) {
+                    attrs.push_str(", style=filled, fillcolor=yellow");
+                }
+            }
+            let _ = writeln!(dot, "\t{} [{}];", state, attrs);
+        }
+        for t in self.sorted_transitions() {
+            let taken = overlay.map(|o| o.times_taken(t.from, t.to)).unwrap_or(0);
+            if taken > 0 {
+                let _ = writeln!(
+                    dot,
+                    "\t{} -> {} [label=\"{} ({})\", color=blue, penwidth=2];",
+                    t.from,
+                    t.to,
+                    dot_escape(&t.label()),
+                    taken
+                );
+            } else {
+                let _ = writeln!(
+                    dot,
+                    "\t{} -> {} [label=\"{}\"];",
+                    t.from,
+                    t.to,
+                    dot_escape(&t.label())
+                );
+            }
+        }
+        let _ = writeln!(dot, "}}");
+        dot
+    }
+
+    /// Render the graph as json.  If a disk is given its current state and
+    /// full transition history are included.
+    pub fn to_json(&self, overlay: Option<&DiskOverlay>) -> Result<String, ::serde_json::Error> {
+        let current = overlay.and_then(|o| o.state);
+        let graph = JsonGraph {
+            nodes: self
+                .states()
+                .into_iter()
+                .map(|state| JsonNode {
+                    state,
+                    terminal: self.terminal_states.contains(&state),
+                    current: current == Some(state),
+                }).collect(),
+            edges: self
+                .sorted_transitions()
+                .into_iter()
+                .map(|t| JsonEdge {
+                    from: t.from,
+                    to: t.to,
+                    transition: t.label(),
+                    priority: t.priority,
+                    taken: overlay.map(|o| o.times_taken(t.from, t.to)).unwrap_or(0),
+                }).collect(),
+            disk: overlay.map(|o| JsonDisk {
+                path: o.disk.clone(),
+                state: o.state,
+                history: o
+                    .history
+                    .iter()
+                    .map(|h| JsonHistory {
+                        time: time::at_utc(h.time_recorded).rfc3339().to_string(),
+                        from: h.from_state,
+                        to: h.to_state,
+                        transition: h.transition.clone(),
+                        result: h.result_state,
+                    }).collect(),
+            }),
+        };
+        ::serde_json::to_string_pretty(&graph)
+    }
+}
+
+// Labels are quoted in dot so quotes and backslashes in them have to be escaped
+fn dot_escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Load the state_machine.json graph from the config directory or fall back
+/// on the built-in default if the file doesn't exist.
+pub fn load_state_graph(config_dir: &str) -> IOResult<StateGraph> {
+    let path = Path::new(config_dir).join("state_machine.json");
+    let graph: StateGraph = if path.exists() {
+        debug!("Loading state machine graph from {}", path.display());
+        helpers::load_config(config_dir, "state_machine.json")?
+    } else {
+        debug!("{} not found.  Using default state machine", path.display());
+        StateGraph::default()
+    };
+    graph.validate().map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid state machine graph: {}", e),
+        )
+    })?;
+
+    Ok(graph)
+}
@@ -6,7 +6,7 @@
 //! `dot -Tps example.dot -o example.ps` to postscript or
 //! `dot -Tsvg example.dot -o example.svg` to svg.
 //! See comments on the run() function for StateMachine and also
-//! the comments under setup_state_machine() to learn more about how it works.
+//! state_graph.rs to learn more about how the graph is put together.
 extern crate blkid;
 extern crate block_utils;
 extern crate fstab;
@@ -20,7 +20,11 @@ extern crate rusqlite;
 extern crate tempdir;
 extern crate uuid;
 
+use command_transition::CommandTransition;
+use device_filter::DeviceFilter;
 use in_progress;
+use state_graph::{StateGraph, COMMAND_TRANSITION};
+use {ConfigSettings, ConfirmationPolicy};
 
 use self::blkid::BlkId;
 use self::block_utils::{
@@ -36,8 +40,9 @@ use self::rayon::prelude::*;
 use self::rusqlite::Connection;
 use self::tempdir::TempDir;
 use self::uuid::Uuid;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
 
-use std::collections::HashSet;
 use std::fmt;
 use std::fs::OpenOptions;
 use std::io::{Error, ErrorKind};
@@ -65,6 +70,7 @@ mod tests {
     use super::mocktopus::mocking::*;
     use super::uuid::Uuid;
     use simplelog::{Config, TermLogger};
+    use state_graph::{StateGraph, TransitionConfig};
 
     lazy_static! {
         // This prevents all threads from getting the same loopback device
@@ -156,7 +162,7 @@ mod tests {
             serial_number: Some("123456".into()),
         };
         let mut s = super::StateMachine::new(d, conn, true);
-        s.setup_state_machine();
+        s.setup_state_machine(&StateGraph::default());
         s.print_graph();
         s.restore_state().unwrap();
         s.run();
@@ -208,7 +214,7 @@ mod tests {
             serial_number: Some("123456".into()),
         };
         let mut s = super::StateMachine::new(d, conn, true);
-        s.setup_state_machine();
+        s.setup_state_machine(&StateGraph::default());
         s.print_graph();
         s.restore_state().unwrap();
         s.run();
@@ -257,7 +263,7 @@ mod tests {
             serial_number: Some("123456".into()),
         };
         let mut s = super::StateMachine::new(d, conn, false);
-        s.setup_state_machine();
+        s.setup_state_machine(&StateGraph::default());
         s.print_graph();
         s.restore_state().unwrap();
         s.run();
@@ -300,7 +306,7 @@ mod tests {
         };
 
         let mut s = super::StateMachine::new(d, conn, true);
-        s.setup_state_machine();
+        s.setup_state_machine(&StateGraph::default());
         s.print_graph();
         s.restore_state().unwrap();
         s.run();
@@ -308,6 +314,190 @@ mod tests {
         assert_eq!(s.state, super::State::Good);
     }
 
+    fn command(from: super::State, to: super::State, script: &str) -> TransitionConfig {
+        TransitionConfig {
+            from,
+            to,
+            transition: "Command".to_string(),
+            priority: 0,
+            command: Some("/bin/sh".to_string()),
+            args: vec!["-c".to_string(), script.to_string()],
+            timeout: Some(10),
+        }
+    }
+
+    fn command_machine(db_path: &Path, graph: &StateGraph) -> super::StateMachine {
+        let conn = super::connect_to_repair_database(db_path).unwrap();
+        let d = super::Device {
+            id: None,
+            name: "sdz".to_string(),
+            media_type: super::MediaType::Rotational,
+            capacity: 26214400,
+            fs_type: super::FilesystemType::Xfs,
+            serial_number: Some("123456".into()),
+        };
+        let mut s = super::StateMachine::new(d, conn, false);
+        s.setup_state_machine(graph);
+        s
+    }
+
+    #[test]
+    fn test_state_machine_command_reply() {
+        let sql_dir = TempDir::new("bynar").unwrap();
+        // The vendor check sends the disk down the Corrupt path instead of Scanned
+        let graph = StateGraph {
+            terminal_states: vec![super::State::Good],
+            transitions: vec![
+                command(
+                    super::State::Unscanned,
+                    super::State::Scanned,
+                    "cat > /dev/null; echo '{\"state\": \"corrupt\"}'",
+                ),
+                command(super::State::Unscanned, super::State::Corrupt, "exit 1"),
+                command(super::State::Scanned, super::State::Good, "cat > /dev/null"),
+                command(super::State::Corrupt, super::State::Good, "cat > /dev/null"),
+            ],
+        };
+        let mut s = command_machine(&sql_dir.path().join("reply.sqlite3"), &graph);
+        s.run();
+        assert_eq!(s.state, super::State::Good);
+        let history = in_progress::get_state_history(&s.db_conn, Path::new("/dev/sdz")).unwrap();
+        let steps: Vec<(super::State, super::State)> = history
+            .iter()
+            .map(|h| (h.from_state, h.result_state))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (super::State::Unscanned, super::State::Corrupt),
+                (super::State::Corrupt, super::State::Good),
+            ]
+        );
+
+        // A reply that isn't a transition out of the current state is rejected
+        for reply in &["worn_out", "waiting_for_replacement"] {
+            let graph = StateGraph {
+                terminal_states: vec![super::State::Good, super::State::WaitingForReplacement],
+                transitions: vec![
+                    command(
+                        super::State::Unscanned,
+                        super::State::Scanned,
+                        &format!("cat > /dev/null; echo '{{\"state\": \"{}\"}}'", reply),
+                    ),
+                    command(super::State::Scanned, super::State::Good, "cat > /dev/null"),
+                    command(
+                        super::State::WornOut,
+                        super::State::WaitingForReplacement,
+                        "cat > /dev/null",
+                    ),
+                ],
+            };
+            let mut s = command_machine(
+                &sql_dir.path().join(format!("{}.sqlite3", reply)),
+                &graph,
+            );
+            s.run();
+            assert_eq!(s.state, super::State::Unscanned);
+        }
+    }
+
+    #[test]
+    fn test_state_machine_observe_only() {
+        let sql_dir = TempDir::new("bynar").unwrap();
+        let noop = |from, to| TransitionConfig {
+            from,
+            to,
+            transition: "NoOp".to_string(),
+            priority: 1,
+            command: None,
+            args: vec![],
+            timeout: None,
+        };
+        // Skipping the repair must not fall through to giving up on the disk
+        let graph = StateGraph {
+            terminal_states: vec![super::State::Good, super::State::WaitingForReplacement],
+            transitions: vec![
+                noop(super::State::Unscanned, super::State::Corrupt),
+                command(super::State::Corrupt, super::State::Good, "cat > /dev/null"),
+                noop(super::State::Corrupt, super::State::WaitingForReplacement),
+            ],
+        };
+        let mut s = command_machine(&sql_dir.path().join("observe.sqlite3"), &graph);
+        s.observe_only = true;
+        s.run();
+        assert_eq!(s.state, super::State::Corrupt);
+        let dev = Path::new("/dev/sdz");
+        assert_eq!(
+            in_progress::get_state(&s.db_conn, dev).unwrap(),
+            Some(super::State::Corrupt)
+        );
+        assert_eq!(in_progress::get_state_history(&s.db_conn, dev).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_confirm_replacement() {
+        let sql_dir = TempDir::new("bynar").unwrap();
+        let graph = StateGraph {
+            terminal_states: vec![super::State::Good],
+            transitions: vec![],
+        };
+        let mut s = command_machine(&sql_dir.path().join("confirm.sqlite3"), &graph);
+        let dev = Path::new("/dev/sdz");
+        let policy = super::ConfirmationPolicy {
+            failures: 2,
+            window_runs: None,
+            window_minutes: None,
+        };
+        // Each scan ends with the state machine saving its failed state
+        let failed_scan = |s: &mut super::StateMachine| {
+            let was_suspect = s.state == super::State::Suspect;
+            s.state = super::State::WaitingForReplacement;
+            in_progress::save_state(&s.db_conn, dev, s.state).unwrap();
+            super::confirm_replacement(s, &policy, was_suspect).unwrap();
+        };
+        failed_scan(&mut s);
+        assert_eq!(s.state, super::State::Suspect);
+        failed_scan(&mut s);
+        assert_eq!(s.state, super::State::WaitingForReplacement);
+
+        // Once ticketed the failure isn't counted again
+        in_progress::record_new_repair_ticket(&s.db_conn, "001", dev).unwrap();
+        failed_scan(&mut s);
+        assert_eq!(s.state, super::State::WaitingForReplacement);
+        assert_eq!(
+            in_progress::count_failed_evaluations(&s.db_conn, dev, None, None).unwrap(),
+            2
+        );
+    }
+}
+
+type TransitionFn =
+    fn(to_state: &State, device: &mut Device, db_conn: &Connection, simulate: bool) -> State;
+
+// What runs when the state machine walks an edge
+enum TransitionAction {
+    // A transition compiled into bynar
+    BuiltIn(TransitionFn),
+    // A site provided executable
+    Command(CommandTransition),
+}
+
+impl TransitionAction {
+    fn run(
+        &self,
+        from_state: &State,
+        to_state: &State,
+        device: &mut Device,
+        db_conn: &Connection,
+        simulate: bool,
+    ) -> State {
+        match *self {
+            TransitionAction::BuiltIn(callback) => callback(to_state, device, db_conn, simulate),
+            TransitionAction::Command(ref cmd) => {
+                cmd.transition(from_state, to_state, device, simulate)
+            }
+        }
+    }
 }
 
 trait Transition {
@@ -657,7 +847,7 @@ impl Transition for Scan {
     ) -> State {
         debug!("running Scan transition");
 
-        // TODO: Handle devices that live behind a raid controller
+        // Drives behind a raid controller are checked by the raid module
         let dev_path = format!("/dev/{}", device.name);
         // Run a smart check on the base device without partition
         match run_smart_checks(&Path::new(&dev_path)) {
@@ -683,19 +873,17 @@ impl Transition for Scan {
 }
 
 pub struct StateMachine {
-    // A record of the transitions so they can be written as a dot graph
-    // for later visual debugging
-    dot_graph: Vec<(State, State, String)>,
-    // Mapping of valid From -> To transitions
-    graph: GraphMap<
-        State,
-        fn(to_state: &State, device: &mut Device, db_conn: &Connection, simulate: bool) -> State,
-        Directed,
-    >,
+    // The graph this machine was set up from so it can be written as a dot
+    // graph for later visual debugging
+    graph_config: StateGraph,
+    // Mapping of valid From -> To transitions and the label they're recorded under
+    graph: GraphMap<State, (String, TransitionAction), Directed>,
     pub state: State,
     pub disk: Device,
     pub db_conn: Connection,
     simulate: bool,
+    // Only run transitions that don't change anything on the disk
+    observe_only: bool,
 }
 
 impl fmt::Debug for StateMachine {
@@ -707,12 +895,16 @@ impl fmt::Debug for StateMachine {
 impl StateMachine {
     fn new(disk: Device, db_conn: Connection, simulate: bool) -> Self {
         StateMachine {
-            dot_graph: Vec::new(),
+            graph_config: StateGraph {
+                terminal_states: Vec::new(),
+                transitions: Vec::new(),
+            },
             graph: GraphMap::new(),
             state: State::Unscanned,
             disk,
             db_conn,
             simulate,
+            observe_only: false,
         }
     }
 
@@ -720,14 +912,15 @@ impl StateMachine {
         &mut self,
         from_state: State,
         to_state: State,
-        callback: fn(to_state: &State, device: &mut Device, db_conn: &Connection, simulate: bool)
-            -> State,
-        // Just for debugging dot graph creation
+        action: TransitionAction,
+        // Recorded in the state history
         transition_label: &str,
     ) {
-        self.dot_graph
-            .push((from_state, to_state, transition_label.to_string()));
-        self.graph.add_edge(from_state, to_state, callback);
+        self.graph.add_edge(
+            from_state,
+            to_state,
+            (transition_label.to_string(), action),
+        );
     }
 
     // Restore the state of this machine from the database if it was previously saved
@@ -748,45 +941,81 @@ impl StateMachine {
         debug!("Starting state: {}", self.state);
         let tmp = format!("/dev/{}", self.disk.name);
         let dev_path = Path::new(&tmp);
+        if self.state == State::Suspect {
+            // Suspect disks are evaluated again from the beginning
+            self.state = State::Unscanned;
+        }
         'outer: loop {
             // Gather all the possible edges from this current State
-            let edges: Vec<(
-                State,
-                State,
-                &fn(to_state: &State, device: &mut Device, db_conn: &Connection, simulate: bool)
-                    -> State,
-            )> = self.graph.edges(self.state).collect();
+            let edges: Vec<(State, State, &(String, TransitionAction))> =
+                self.graph.edges(self.state).collect();
             // Some states have multiple paths they could go down.
             // If the state transition returns State::Fail try the next path
             let beginning_state = self.state;
             for e in edges {
+                let (ref label, ref action) = *e.2;
+                if self.observe_only && !is_read_only_transition(label) {
+                    // The edges after this one are fallbacks for when it fails.
+                    // It never ran so stay where we are
+                    debug!("Observe only.  Stopping at {} transition", label);
+                    break 'outer;
+                }
                 debug!("Attempting {} to {} transition", &e.0, &e.1);
-                let state = e.2(&e.1, &mut self.disk, &self.db_conn, self.simulate);
+                let state = action.run(&e.0, &e.1, &mut self.disk, &self.db_conn, self.simulate);
+                // Keep a record of every attempt so operators can see how a disk got here
+                if let Err(err) =
+                    record_transition(&self.db_conn, &dev_path, e.0, e.1, label, state)
+                {
+                    error!(
+                        "Failed to record {} transition for {}: {:?}",
+                        label,
+                        dev_path.display(),
+                        err
+                    );
+                }
                 if state == State::Fail {
                     // Try the next transition if there is one
                     debug!("Fail. Trying next transition");
                     continue;
                 }
-                if state == State::WaitingForReplacement {
-                    // TODO: Is this the only state we shouldn't advance further from?
-                    debug!("state==State::WaitingForReplacement");
-                    self.state = state;
-                    save_state(&self.db_conn, &dev_path, self.state).expect("save_state failed");
-                    break 'outer;
-                } else if state == State::Good {
-                    debug!("state==State::Good");
+                // A command can pick a different next state but only one the
+                // graph allows from where it started
+                if let TransitionAction::Command(_) = *action {
+                    if !self.graph.contains_edge(e.0, state) {
+                        error!(
+                            "{} transition replied {} which isn't a transition out of {}.  \
+                             Trying next transition",
+                            label, state, e.0
+                        );
+                        continue;
+                    }
+                }
+                if self.graph_config.terminal_states.contains(&state) {
+                    debug!("Reached terminal state {}", state);
                     self.state = state;
                     save_state(&self.db_conn, &dev_path, self.state).expect("save_state failed");
                     break 'outer;
                 }
                 // transition succeeded.  Save state and go around the loop again
-                // This won't detect if the transitions return something unexpected
                 if state == e.1 {
                     debug!("state==e.1 {}=={}", state, e.1);
                     self.state = state;
                     save_state(&self.db_conn, &dev_path, self.state).expect("save_state failed");
                     break;
                 }
+                // A transition can pick a different next state.  Follow it as long
+                // as the graph knows where to go from there
+                if self.graph.neighbors(state).next().is_some() {
+                    debug!("{} transition selected {} instead of {}", label, state, e.1);
+                    self.state = state;
+                    save_state(&self.db_conn, &dev_path, self.state).expect("save_state failed");
+                    break;
+                }
+                error!(
+                    "{} transition returned {} which has no transitions out of it.  Trying next \
+                     transition",
+                    label, state
+                );
             }
             // At this point we should've advanced further.  If not then we're stuck in an infinite loop
             // Note this won't detect more complicated infinite loops where it changes through 2 states
@@ -800,144 +1029,43 @@ impl StateMachine {
     }
 
     fn print_graph(&self) {
-        // FIXME: Too simple.  Doesn't label the transitions
-        // Walk the graph and create a Dot
-        let mut states = HashSet::new();
-        println!("digraph state_machine{{");
-        for n in &self.dot_graph {
-            states.insert(n.0);
-            states.insert(n.1);
-            println!("\t{:?} -> {:?}[label=\"{}\"];", n.0, n.1, n.2);
-        }
-        for n in states {
-            println!("\t{:?}[label=\"{:?}\"];", n, n);
-        }
-        //for edge in self.graph.all_edges() {
-        //println!("\t{:?} -> {:?}[label=\"\"];", edge.0, edge.1);
-        //}
-        println!("}}");
+        println!("{}", self.graph_config.to_dot(None));
     }
 
-    // Add all the transition states here
-    fn setup_state_machine(&mut self) {
-        // GraphMap will run the transitions in the order they're added here
-        // If Unscanned has 2 edges it will run the first added one first
-        // and then the second one.  To deal with this the
-        // states are ordered from most to least ideal outcome.
-        self.add_transition(State::Unscanned, State::Scanned, Scan::transition, "Scan");
-        self.add_transition(State::Unscanned, State::Fail, Scan::transition, "Scan");
-        self.add_transition(
-            State::NotMounted,
-            State::Mounted,
-            Mount::transition,
-            "Mount",
-        );
-        self.add_transition(
-            State::NotMounted,
-            State::MountFailed,
-            Mount::transition,
-            "Mount",
-        );
-        self.add_transition(
-            State::MountFailed,
-            State::Corrupt,
-            CheckForCorruption::transition,
-            "CheckForCorruption",
-        );
-
-        self.add_transition(State::Scanned, State::Good, Eval::transition, "Eval");
-        //self.add_transition(State::Scanned, State::NotMounted, Scan::transition);
-        self.add_transition(State::Scanned, State::WriteFailed, Eval::transition, "Eval");
-        self.add_transition(
-            State::Scanned,
-            State::WornOut,
-            CheckWearLeveling::transition,
-            "CheckWearLeveling",
-        );
-
-        self.add_transition(State::Mounted, State::Scanned, NoOp::transition, "NoOp");
-        self.add_transition(
-            State::ReadOnly,
-            State::Mounted,
-            Remount::transition,
-            "Remount",
-        );
-        self.add_transition(
-            State::ReadOnly,
-            State::MountFailed,
-            Remount::transition,
-            "Remount",
-        );
-
-        self.add_transition(
-            State::Corrupt,
-            State::Repaired,
-            AttemptRepair::transition,
-            "AttemptRepair",
-        );
-        self.add_transition(
-            State::Corrupt,
-            State::RepairFailed,
-            NoOp::transition,
-            "NoOp",
-        );
-
-        self.add_transition(
-            State::RepairFailed,
-            State::Reformatted,
-            Reformat::transition,
-            "Reformat",
-        );
-        self.add_transition(
-            State::RepairFailed,
-            State::ReformatFailed,
-            NoOp::transition,
-            "NoOp",
-        );
-
-        self.add_transition(
-            State::ReformatFailed,
-            State::WaitingForReplacement,
-            NoOp::transition,
-            "NoOp",
-        );
-
-        self.add_transition(
-            State::Reformatted,
-            State::Unscanned,
-            NoOp::transition,
-            "NoOp",
-        );
-
-        self.add_transition(
-            State::WornOut,
-            State::WaitingForReplacement,
-            MarkForReplacement::transition,
-            "MarkForReplacement",
-        );
-
-        self.add_transition(State::Repaired, State::Good, NoOp::transition, "NoOp");
-        self.add_transition(
-            State::WaitingForReplacement,
-            State::Replaced,
-            Replace::transition,
-            "Replace",
-        );
-        self.add_transition(State::Replaced, State::Unscanned, NoOp::transition, "NoOp");
-
-        self.add_transition(
-            State::WriteFailed,
-            State::ReadOnly,
-            CheckReadOnly::transition,
-            "CheckReadOnly",
-        );
-        // Fsck can either conclude here that everything is fine or the filesystem is corrupt
-        self.add_transition(
-            State::WriteFailed,
-            State::Corrupt,
-            CheckForCorruption::transition,
-            "CheckForCorruption",
-        );
+    // Add all the transition states from the graph.  GraphMap will run the
+    // transitions in the order they're added here.  If Unscanned has 2 edges
+    // it will run the first added one first and then the second one.  The
+    // graph is sorted by priority so the most ideal outcome is tried first.
+    fn setup_state_machine(&mut self, graph: &StateGraph) {
+        self.graph_config = graph.clone();
+        for t in graph.sorted_transitions() {
+            let action = if t.transition == COMMAND_TRANSITION {
+                match t.command {
+                    Some(ref command) => TransitionAction::Command(CommandTransition::new(
+                        command, &t.args, t.timeout,
+                    )),
+                    None => {
+                        error!(
+                            "Command transition from {} to {} has no command.  Skipping",
+                            t.from, t.to
+                        );
+                        continue;
+                    }
+                }
+            } else {
+                match builtin_transition(&t.transition) {
+                    Some(callback) => TransitionAction::BuiltIn(callback),
+                    None => {
+                        error!(
+                            "Unknown transition {} from {} to {}.  Skipping",
+                            t.transition, t.from, t.to
+                        );
+                        continue;
+                    }
+                }
+            };
+            self.add_transition(t.from, t.to, action, &t.label());
+        }
     }
 }
 
@@ -961,6 +1089,8 @@ pub enum State {
     Repaired,
     Replaced,
     Scanned,
+    // The disk failed but not often enough yet to be replaced
+    Suspect,
     Unscanned,
     // The disk could not be repaired and needs to be replaced
     WaitingForReplacement,
@@ -979,6 +1109,7 @@ impl FromStr for State {
             "good" => Ok(State::Good),
             "mounted" => Ok(State::Mounted),
             "mount_failed" => Ok(State::MountFailed),
+            "not_mounted" => Ok(State::NotMounted),
             "readonly" => Ok(State::ReadOnly),
             "reformatted" => Ok(State::Reformatted),
             "reformat_failed" => Ok(State::ReformatFailed),
@@ -986,9 +1117,11 @@ impl FromStr for State {
             "repair_failed" => Ok(State::RepairFailed),
             "replaced" => Ok(State::Replaced),
             "scanned" => Ok(State::Scanned),
+            "suspect" => Ok(State::Suspect),
             "unscanned" => Ok(State::Unscanned),
             "waiting_for_replacement" => Ok(State::WaitingForReplacement),
             "worn_out" => Ok(State::WornOut),
+            "write_failed" => Ok(State::WriteFailed),
             _ => Err(format!("Unknown state: {}", s)),
         }
     }
@@ -1010,6 +1143,7 @@ impl fmt::Display for State {
             State::Repaired => write!(f, "repaired"),
             State::Replaced => write!(f, "replaced"),
             State::Scanned => write!(f, "scanned"),
+            State::Suspect => write!(f, "suspect"),
             State::Unscanned => write!(f, "unscanned"),
             State::WaitingForReplacement => write!(f, "waiting_for_replacement"),
             State::WriteFailed => write!(f, "write_failed"),
@@ -1018,6 +1152,25 @@ impl fmt::Display for State {
     }
 }
 
+impl Serialize for State {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for State {
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        State::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
 // Transitions
 #[derive(Debug)]
 struct AttemptRepair;
@@ -1056,12 +1209,51 @@ struct Reformat;
 struct Scan;
 // Transitions
 
+// Look up a built-in transition by the name used in the state machine graph
+fn builtin_transition(name: &str) -> Option<TransitionFn> {
+    match name {
+        "AttemptRepair" => Some(AttemptRepair::transition as TransitionFn),
+        "CheckForCorruption" => Some(CheckForCorruption::transition as TransitionFn),
+        "CheckReadOnly" => Some(CheckReadOnly::transition as TransitionFn),
+        "CheckWearLeveling" => Some(CheckWearLeveling::transition as TransitionFn),
+        "Eval" => Some(Eval::transition as TransitionFn),
+        "MarkForReplacement" => Some(MarkForReplacement::transition as TransitionFn),
+        "Mount" => Some(Mount::transition as TransitionFn),
+        "NoOp" => Some(NoOp::transition as TransitionFn),
+        "Reformat" => Some(Reformat::transition as TransitionFn),
+        "Remount" => Some(Remount::transition as TransitionFn),
+        "Replace" => Some(Replace::transition as TransitionFn),
+        "Scan" => Some(Scan::transition as TransitionFn),
+        _ => None,
+    }
+}
+
+/// Returns true if the transition only looks at a disk.  These are the only
+/// transitions run while a host is in maintenance.
+pub fn is_read_only_transition(name: &str) -> bool {
+    match name {
+        "CheckForCorruption" | "CheckReadOnly" | "CheckWearLeveling" | "MarkForReplacement"
+        | "NoOp" | "Scan" => true,
+        _ => false,
+    }
+}
+
+/// Returns true if name is a transition that can be used in the state machine graph
+pub fn is_builtin_transition(name: &str) -> bool {
+    builtin_transition(name).is_some()
+}
+
 enum Fsck {
     Ok,
     Corrupt,
 }
 
-pub fn check_all_disks(db: &Path) -> Result<Vec<Result<StateMachine>>> {
+pub fn check_all_disks(
+    config: &ConfigSettings,
+    graph: &StateGraph,
+    observe_only: bool,
+) -> Result<Vec<Result<StateMachine>>> {
+    let db = Path::new(&config.db_location);
     // Udev will only show the disks that are currently attached to the tree
     // It will fail to show disks that have died and disconnected but are still
     // shown as mounted in /etc/mtab
@@ -1077,6 +1269,8 @@ pub fn check_all_disks(db: &Path) -> Result<Vec<Result<StateMachine>>> {
         .filter(|d| !(d.media_type == MediaType::LVM))
         // Get rid of ram devices
         .filter(|d| !(d.media_type == MediaType::Ram))
+        // md arrays are checked through their members by the mdraid module
+        .filter(|d| !(d.media_type == MediaType::MdRaid))
         .collect();
 
     // Gather info on all the currently mounted devices
@@ -1097,9 +1291,32 @@ pub fn check_all_disks(db: &Path) -> Result<Vec<Result<StateMachine>>> {
 
     //TODO: Add nvme devices to block-utils
 
+    // Drop anything the site has excluded along with the root disk
+    let filter = DeviceFilter::new(config.device_filter.as_ref())?;
+    let device_info: Vec<Device> = device_info
+        .into_iter()
+        .filter_map(|d| match filter.allowed(&d) {
+            Ok(true) => Some(Ok(d)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }).collect::<Result<Vec<Device>>>()?;
+
+    // Skip any disk an operator has asked bynar to leave alone
+    let conn = connect_to_repair_database(db).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let mut checked_devices: Vec<Device> = Vec::new();
+    for device in device_info {
+        let tmp = format!("/dev/{}", device.name);
+        if is_disk_ignored(&conn, &Path::new(&tmp))
+            .map_err(|e| Error::new(ErrorKind::Other, e))?
+        {
+            info!("Skipping {}.  It is being ignored", tmp);
+            continue;
+        }
+        checked_devices.push(device);
+    }
+
     // Create 1 state machine per Device and evaulate all devices in parallel
-    let disk_states: Vec<Result<StateMachine>> = device_info
-        .clone()
+    let disk_states: Vec<Result<StateMachine>> = checked_devices
         .into_par_iter()
         .map(|device| {
             // Lookup the disk and see if it's in progress.  If so then
@@ -1108,18 +1325,14 @@ pub fn check_all_disks(db: &Path) -> Result<Vec<Result<StateMachine>>> {
             let conn =
                 connect_to_repair_database(db).map_err(|e| Error::new(ErrorKind::Other, e))?;
             let mut s = StateMachine::new(device, conn, false);
-            s.setup_state_machine();
+            s.observe_only = observe_only;
+            s.setup_state_machine(graph);
             s.restore_state()
                 .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            let was_suspect = s.state == State::Suspect;
             s.run();
-            // Possibly serialize the state here to the database to resume later
-            if s.state == State::WaitingForReplacement {
-                info!("Connecting to database to check if disk is in progress");
-                let tmp = format!("/dev/{}", s.disk.name);
-                let disk_path = Path::new(&tmp);
-                let conn =
-                    connect_to_repair_database(db).map_err(|e| Error::new(ErrorKind::Other, e))?;
-                let in_progress = is_disk_in_progress(&conn, &disk_path)
+            if let Some(ref policy) = config.replacement_confirmation {
+                confirm_replacement(&mut s, policy, was_suspect)
                     .map_err(|e| Error::new(ErrorKind::Other, e))?;
             }
             Ok(s)
@@ -1128,6 +1341,58 @@ pub fn check_all_disks(db: &Path) -> Result<Vec<Result<StateMachine>>> {
     Ok(disk_states)
 }
 
+// A single bad write or fsck hiccup shouldn't condemn a disk.  Hold it in
+// Suspect until it has failed often enough to satisfy the policy.
+fn confirm_replacement(
+    s: &mut StateMachine,
+    policy: &ConfirmationPolicy,
+    was_suspect: bool,
+) -> ::std::result::Result<(), rusqlite::Error> {
+    let tmp = format!("/dev/{}", s.disk.name);
+    let dev_path = Path::new(&tmp);
+    let failed = s.state == State::WaitingForReplacement;
+    if failed && has_open_ticket(&s.db_conn, &dev_path)? {
+        // Already confirmed and ticketed on an earlier run
+        return Ok(());
+    }
+    record_evaluation(&s.db_conn, &dev_path, failed)?;
+    prune_evaluations(
+        &s.db_conn,
+        &dev_path,
+        policy.window_runs,
+        policy.window_minutes,
+    )?;
+    if !failed {
+        if was_suspect {
+            info!("{} recovered.  Clearing suspect status", dev_path.display());
+        }
+        return Ok(());
+    }
+    let failures = count_failed_evaluations(
+        &s.db_conn,
+        &dev_path,
+        policy.window_runs,
+        policy.window_minutes,
+    )?;
+    if failures < policy.failures {
+        info!(
+            "{} failed {} of {} evaluations needed for replacement.  Marking suspect",
+            dev_path.display(),
+            failures,
+            policy.failures
+        );
+        s.state = State::Suspect;
+        save_state(&s.db_conn, &dev_path, s.state)?;
+    } else {
+        info!(
+            "{} failed {} evaluations.  Confirmed for replacement",
+            dev_path.display(),
+            failures
+        );
+    }
+    Ok(())
+}
+
 #[cfg_attr(test, mockable)]
 fn check_filesystem(filesystem_type: &FilesystemType, device: &Path) -> Result<Fsck> {
     match *filesystem_type {